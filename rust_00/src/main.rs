@@ -1,21 +1,163 @@
 use std::env;
+use std::io::{self, BufRead};
+
+/// Built-in greetings: language code, language name, a template with
+/// `{name}` where the name goes, and the word joining the last two names
+const GREETINGS: [(&str, &str, &str, &str); 7] = [
+    ("en", "English", "Hello, {name}!", "and"),
+    ("fr", "French", "Bonjour, {name}!", "et"),
+    ("de", "German", "Hallo, {name}!", "und"),
+    ("es", "Spanish", "¡Hola, {name}!", "y"),
+    ("it", "Italian", "Ciao, {name}!", "e"),
+    ("pt", "Portuguese", "Olá, {name}!", "e"),
+    ("ja", "Japanese", "{name}さん、こんにちは！", "と"),
+];
+
+/// The greeting template and joining word for a language code
+fn greeting_for(lang: &str) -> Option<(&'static str, &'static str)> {
+    GREETINGS
+        .iter()
+        .find(|(code, _, _, _)| *code == lang)
+        .map(|(_, _, template, and)| (*template, *and))
+}
+
+/// Supported codes, comma-separated
+fn language_codes() -> String {
+    GREETINGS
+        .iter()
+        .map(|(code, _, _, _)| *code)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Names as one list: "Alice", "Alice and Bob", "Alice, Bob and Carol"
+fn join_names(names: &[String], and: &str) -> String {
+    match names {
+        [] => String::new(),
+        [name] => name.clone(),
+        [rest @ .., last] => format!("{} {} {}", rest.join(", "), and, last),
+    }
+}
+
+/// Placeholders a greeting template may use
+const PLACEHOLDERS: [&str; 3] = ["{name}", "{NAME}", "{name_lower}"];
+
+/// Fill in a greeting template: `{name}` as given, `{NAME}` uppercased,
+/// `{name_lower}` lowercased; `{{` and `}}` stand for `{` and `}`
+fn render(template: &str, name: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("{{").or(rest.strip_prefix("}}")) {
+            out.push_str(&rest[..1]);
+            rest = after;
+            continue;
+        }
+        if rest.starts_with('}') {
+            out.push('}');
+            rest = &rest[1..];
+            continue;
+        }
+        let Some(end) = rest.find('}') else {
+            return Err(format!(
+                "unclosed '{{' in template (use {{{{ for a literal brace; placeholders: {})",
+                PLACEHOLDERS.join(", ")
+            ));
+        };
+        match &rest[..=end] {
+            "{name}" => out.push_str(name),
+            "{NAME}" => out.push_str(&name.to_uppercase()),
+            "{name_lower}" => out.push_str(&name.to_lowercase()),
+            other => {
+                return Err(format!(
+                    "unknown placeholder {} in template (valid: {})",
+                    other,
+                    PLACEHOLDERS.join(", ")
+                ));
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// One greeting per name in order, or with `joined` a single greeting for
+/// them all; no names and `joined` greet nobody
+fn greetings(
+    names: &[String],
+    template: &str,
+    and: &str,
+    joined: bool,
+) -> Result<Vec<String>, String> {
+    if joined && !names.is_empty() {
+        Ok(vec![render(template, &join_names(names, and))?])
+    } else if joined {
+        Ok(Vec::new())
+    } else {
+        names.iter().map(|name| render(template, name)).collect()
+    }
+}
+
+/// The greetings `repeat` times over: each in a row with `per_name`,
+/// otherwise the whole block again
+fn repeated(greetings: &[String], repeat: usize, per_name: bool) -> Vec<&String> {
+    if per_name {
+        greetings
+            .iter()
+            .flat_map(|greeting| std::iter::repeat_n(greeting, repeat))
+            .collect()
+    } else {
+        (0..repeat).flat_map(|_| greetings).collect()
+    }
+}
+
+/// Names from stdin, one per line, trailing whitespace trimmed and empty
+/// lines skipped
+fn read_names(input: impl BufRead) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let name = line.trim_end();
+        if !name.is_empty() {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
 
 /// Print usage/help information
 fn print_help() {
     println!("Rusty Hello - CLI arguments et ownership\n");
-    println!("Usage: hello [OPTIONS] [NAME]\n");
+    println!("Usage: hello [OPTIONS] [NAME]...\n");
     println!("Arguments:");
-    println!("  [NAME]              Name to greet [default: World]");
+    println!("  [NAME]...           Names to greet, one per line [default: World];");
+    println!("                      - reads more from stdin, one per line");
     println!("\nOptions:");
     println!("      --upper         Convert to uppercase");
-    println!("      --repeat N      Repeat greeting N times [default: 1]");
+    println!("      --repeat N      Repeat the greetings N times [default: 1]");
+    println!("                      (with stdin, each name's greeting N times)");
+    println!("      --joined        Greet all names in a single line");
+    println!("      --lang CODE     Greet in another language [default: en]");
+    println!("                      (--lang list shows the codes)");
+    println!("      --template T    Greeting to use instead, with {{name}}, {{NAME}}");
+    println!("                      or {{name_lower}} in it; {{{{ for a literal brace");
+    println!("      --stdin         Read names from stdin, like -");
+    println!("      --verbose       Print the number of greetings to stderr");
     println!("  -h, --help         Print help");
 }
 
 fn main() {
-    let mut name: Option<String> = None;
+    let mut names: Vec<String> = Vec::new();
     let mut upper = false;
+    let mut joined = false;
     let mut repeat: usize = 1;
+    let mut lang = String::from("en");
+    let mut custom: Option<String> = None;
+    let mut from_stdin = false;
+    let mut verbose = false;
 
     let mut args = env::args().skip(1).peekable();
     while let Some(arg) = args.next() {
@@ -25,6 +167,9 @@ fn main() {
                 return;
             }
             "--upper" => upper = true,
+            "--joined" => joined = true,
+            "-" | "--stdin" => from_stdin = true,
+            "--verbose" => verbose = true,
             "--repeat" => {
                 if let Some(n) = args.next() {
                     repeat = n.parse().unwrap_or(1);
@@ -33,26 +178,247 @@ fn main() {
                     return;
                 }
             }
+            "--lang" => {
+                if let Some(code) = args.next() {
+                    lang = code;
+                } else {
+                    eprintln!("--lang requires a language code");
+                    return;
+                }
+            }
+            "--template" => {
+                if let Some(template) = args.next() {
+                    custom = Some(template);
+                } else {
+                    eprintln!("--template requires a template string");
+                    return;
+                }
+            }
             _ => {
                 if arg.starts_with('-') {
                     eprintln!("error");
                     std::process::exit(2);
                 }
-                if name.is_none() {
-                    name = Some(arg);
-                }
+                names.push(arg);
             }
         }
     }
 
-    let name = name.as_deref().unwrap_or("World");
-    let greeting = format!("Hello, {}!", name);
+    if lang == "list" {
+        for (code, language, _, _) in GREETINGS {
+            println!("{}  {}", code, language);
+        }
+        return;
+    }
+    let Some((template, and)) = greeting_for(&lang) else {
+        eprintln!(
+            "error: unknown language '{}' (supported: {})",
+            lang,
+            language_codes()
+        );
+        std::process::exit(2);
+    };
 
-    for _ in 0..repeat {
+    let template = custom.as_deref().unwrap_or(template);
+    // Check the template before reading stdin, which may have no names
+    if let Err(e) = render(template, "") {
+        eprintln!("error: {}", e);
+        std::process::exit(2);
+    }
+
+    if from_stdin {
+        match read_names(io::stdin().lock()) {
+            Ok(read) => names.extend(read),
+            Err(e) => {
+                eprintln!("error: reading names from stdin: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if names.is_empty() {
+        names.push(String::from("World"));
+    }
+    let greetings = greetings(&names, template, and, joined).unwrap();
+    let lines = repeated(&greetings, repeat, from_stdin);
+    for greeting in &lines {
         if upper {
             println!("{}", greeting.to_uppercase());
         } else {
             println!("{}", greeting);
         }
     }
+    if verbose {
+        eprintln!("{} greeting(s)", lines.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn greet(lang: &str, name: &str) -> String {
+        let (template, _) = greeting_for(lang).unwrap();
+        render(template, name).unwrap()
+    }
+
+    #[test]
+    fn every_language_greets_by_name() {
+        let expected = [
+            ("en", "Hello, Alice!", "and"),
+            ("fr", "Bonjour, Alice!", "et"),
+            ("de", "Hallo, Alice!", "und"),
+            ("es", "¡Hola, Alice!", "y"),
+            ("it", "Ciao, Alice!", "e"),
+            ("pt", "Olá, Alice!", "e"),
+            ("ja", "Aliceさん、こんにちは！", "と"),
+        ];
+        assert_eq!(expected.len(), GREETINGS.len());
+        for (lang, greeting, and) in expected {
+            assert_eq!(greet(lang, "Alice"), greeting, "{}", lang);
+            assert_eq!(greeting_for(lang).unwrap().1, and, "{}", lang);
+        }
+    }
+
+    #[test]
+    fn japanese_puts_the_name_first() {
+        let (template, _) = greeting_for("ja").unwrap();
+        assert!(template.starts_with("{name}"));
+        assert_eq!(greet("ja", "Bob"), "Bobさん、こんにちは！");
+    }
+
+    #[test]
+    fn unknown_codes_are_rejected() {
+        assert_eq!(greeting_for("xx"), None);
+        assert_eq!(greeting_for(""), None);
+        assert_eq!(greeting_for("EN"), None);
+        assert_eq!(greeting_for("list"), None);
+        assert_eq!(language_codes(), "en, fr, de, es, it, pt, ja");
+    }
+
+    #[test]
+    fn upper_handles_non_ascii() {
+        assert_eq!(greet("es", "élodie").to_uppercase(), "¡HOLA, ÉLODIE!");
+        assert_eq!(greet("pt", "joão").to_uppercase(), "OLÁ, JOÃO!");
+        assert_eq!(greet("de", "straße").to_uppercase(), "HALLO, STRASSE!");
+        // Kana have no case and pass through untouched
+        assert_eq!(
+            greet("ja", "alice").to_uppercase(),
+            "ALICEさん、こんにちは！"
+        );
+    }
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|n| n.to_string()).collect()
+    }
+
+    const FIVE: [&str; 5] = ["Alice", "Bob", "Carol", "Dave", "Eve"];
+
+    #[test]
+    fn join_names_lists_without_an_oxford_comma() {
+        let join = |n: usize| join_names(&names(&FIVE[..n]), "and");
+        assert_eq!(join(0), "");
+        assert_eq!(join(1), "Alice");
+        assert_eq!(join(2), "Alice and Bob");
+        assert_eq!(join(3), "Alice, Bob and Carol");
+        assert_eq!(join(5), "Alice, Bob, Carol, Dave and Eve");
+        assert_eq!(join_names(&names(&FIVE[..3]), "et"), "Alice, Bob et Carol");
+    }
+
+    #[test]
+    fn joined_greets_everyone_at_once() {
+        let joined = |n: usize| greetings(&names(&FIVE[..n]), "Hello, {name}!", "and", true);
+        assert_eq!(joined(0).unwrap(), Vec::<String>::new());
+        assert_eq!(joined(1).unwrap(), ["Hello, Alice!"]);
+        assert_eq!(joined(2).unwrap(), ["Hello, Alice and Bob!"]);
+        assert_eq!(joined(3).unwrap(), ["Hello, Alice, Bob and Carol!"]);
+        assert_eq!(
+            joined(5).unwrap(),
+            ["Hello, Alice, Bob, Carol, Dave and Eve!"]
+        );
+    }
+
+    #[test]
+    fn each_name_gets_its_own_line_in_order() {
+        let lines = |n: usize| greetings(&names(&FIVE[..n]), "Hi {name}", "and", false);
+        assert_eq!(lines(1).unwrap(), ["Hi Alice"]);
+        assert_eq!(lines(2).unwrap(), ["Hi Alice", "Hi Bob"]);
+        assert_eq!(lines(3).unwrap(), ["Hi Alice", "Hi Bob", "Hi Carol"]);
+        assert_eq!(
+            lines(5).unwrap(),
+            ["Hi Alice", "Hi Bob", "Hi Carol", "Hi Dave", "Hi Eve"]
+        );
+    }
+
+    #[test]
+    fn duplicate_names_are_kept() {
+        let list = names(&["Bob", "Alice", "Bob"]);
+        assert_eq!(
+            greetings(&list, "Hi {name}", "and", false).unwrap(),
+            ["Hi Bob", "Hi Alice", "Hi Bob"]
+        );
+        assert_eq!(
+            greetings(&list, "Hi {name}", "and", true).unwrap(),
+            ["Hi Bob, Alice and Bob"]
+        );
+    }
+
+    #[test]
+    fn repeat_repeats_the_whole_block() {
+        let block = names(&["Hi Alice", "Hi Bob"]);
+        assert_eq!(
+            repeated(&block, 3, false),
+            [
+                "Hi Alice", "Hi Bob", "Hi Alice", "Hi Bob", "Hi Alice", "Hi Bob"
+            ]
+        );
+        assert_eq!(
+            repeated(&block, 2, true),
+            ["Hi Alice", "Hi Alice", "Hi Bob", "Hi Bob"]
+        );
+        assert!(repeated(&block, 0, false).is_empty());
+    }
+
+    #[test]
+    fn render_fills_placeholders_anywhere() {
+        assert_eq!(render("{name}, welcome", "Ann").unwrap(), "Ann, welcome");
+        assert_eq!(render("Welcome, {name}", "Ann").unwrap(), "Welcome, Ann");
+        assert_eq!(render("{name}", "Ann").unwrap(), "Ann");
+        assert_eq!(render("no name here", "Ann").unwrap(), "no name here");
+        assert_eq!(render("", "Ann").unwrap(), "");
+    }
+
+    #[test]
+    fn render_repeats_and_mixes_placeholders() {
+        assert_eq!(render("{name} {name}{name}", "Ann").unwrap(), "Ann AnnAnn");
+        assert_eq!(
+            render("{NAME}! {name_lower}? {name}.", "Ann").unwrap(),
+            "ANN! ann? Ann."
+        );
+    }
+
+    #[test]
+    fn render_escapes_braces() {
+        assert_eq!(render("{{name}}", "Ann").unwrap(), "{name}");
+        assert_eq!(render("{{{name}}}", "Ann").unwrap(), "{Ann}");
+        assert_eq!(render("a {{ b }} c", "Ann").unwrap(), "a { b } c");
+        // A lone closing brace is taken literally
+        assert_eq!(render("a } b", "Ann").unwrap(), "a } b");
+    }
+
+    #[test]
+    fn render_rejects_an_unclosed_brace() {
+        let err = render("Hello, {name", "Ann").unwrap_err();
+        assert!(err.starts_with("unclosed '{' in template"), "{}", err);
+        assert!(err.contains("{{ for a literal brace"), "{}", err);
+        assert!(render("{", "Ann").is_err());
+    }
+
+    #[test]
+    fn render_names_an_unknown_placeholder() {
+        assert_eq!(
+            render("Hi {nom}!", "Ann").unwrap_err(),
+            "unknown placeholder {nom} in template (valid: {name}, {NAME}, {name_lower})"
+        );
+        assert!(render("{}", "Ann").is_err());
+        assert!(render("{Name}", "Ann").is_err());
+    }
 }
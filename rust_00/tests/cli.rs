@@ -0,0 +1,67 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run hello with `args`, feeding `stdin` to it.
+fn hello(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_00"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("spawn hello");
+    let mut input = child.stdin.take().unwrap();
+    input.write_all(stdin.as_bytes()).unwrap();
+    drop(input);
+    child.wait_with_output().expect("wait for hello")
+}
+
+fn stdout(args: &[&str], stdin: &str) -> String {
+    let out = hello(args, stdin);
+    assert!(out.status.success(), "{:?} failed: {:?}", args, out);
+    String::from_utf8(out.stdout).unwrap()
+}
+
+const NAMES: &str = "Alice  \n\nBob\t\n   \nCarol\n";
+
+#[test]
+fn names_are_read_from_stdin() {
+    let expected = "Hello, Alice!\nHello, Bob!\nHello, Carol!\n";
+    assert_eq!(stdout(&["-"], NAMES), expected);
+    assert_eq!(stdout(&["--stdin"], NAMES), expected);
+}
+
+#[test]
+fn stdin_names_follow_the_arguments() {
+    assert_eq!(
+        stdout(&["Zed", "-"], "Alice\n"),
+        "Hello, Zed!\nHello, Alice!\n"
+    );
+}
+
+#[test]
+fn repeat_applies_per_name_from_stdin() {
+    assert_eq!(
+        stdout(&["-", "--repeat", "2"], NAMES),
+        "Hello, Alice!\nHello, Alice!\nHello, Bob!\nHello, Bob!\nHello, Carol!\nHello, Carol!\n"
+    );
+}
+
+#[test]
+fn stdin_composes_with_upper_and_template() {
+    assert_eq!(
+        stdout(&["-", "--upper", "--template", "Hey {name}"], NAMES),
+        "HEY ALICE\nHEY BOB\nHEY CAROL\n"
+    );
+}
+
+#[test]
+fn verbose_counts_greetings_on_stderr() {
+    let out = hello(&["-", "--verbose", "--repeat", "2"], NAMES);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap().lines().count(), 6);
+    assert_eq!(String::from_utf8(out.stderr).unwrap(), "6 greeting(s)\n");
+
+    let out = hello(&["-"], NAMES);
+    assert!(out.stderr.is_empty());
+}
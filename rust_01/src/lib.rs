@@ -0,0 +1,2135 @@
+//! Word frequency counting behind the `wordfreq` binary.
+//!
+//! `WordCounter` holds the counting options and accumulates `Counts` from
+//! text fed to it in pieces; `tokenize` is the tokenizer it uses. Output
+//! formatting and argument parsing live in `main.rs`.
+
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, Read};
+
+/// How text is turned into counted keys: tokenizing, normalization and
+/// the filters a token has to pass.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub tokenizer: Tokenizer,
+    pub ignore_case: bool,
+    /// Whose rules case folding follows.
+    pub locale: Locale,
+    /// Compose accents (NFC) and fold case.
+    pub normalize: bool,
+    pub stem: bool,
+    /// Shortest key counted, in characters.
+    pub min_length: usize,
+    /// Longest key counted, in characters.
+    pub max_length: usize,
+    pub numbers: Numbers,
+    /// Count runs of this many words instead of single words.
+    pub ngrams: usize,
+    /// Let n-grams run across sentence ends.
+    pub cross_sentences: bool,
+    /// Count pairs of different words at most this many words apart,
+    /// keyed `"a + b"`, instead of single words.
+    pub cooccur: Option<usize>,
+    /// Pairs tracked before the rarest are pruned.
+    pub max_pairs: usize,
+    /// Count characters instead of words.
+    pub chars: bool,
+    /// With `chars`, skip anything that isn't a letter.
+    pub letters_only: bool,
+    /// Stopwords, compared after the same folding as keys.
+    pub exclude: Vec<String>,
+    /// `PREFIX*`, `*SUFFIX` and `*PART*` patterns of keys to drop.
+    pub exclude_patterns: Vec<String>,
+    /// Phrases to count on their own, matched case-folded on the token
+    /// stream so punctuation and line breaks between words don't matter.
+    pub phrases: Vec<String>,
+    /// Only count keys starting with one of these, when there are any.
+    pub prefixes: Vec<String>,
+    /// Only count keys ending with one of these, when there are any.
+    pub suffixes: Vec<String>,
+    /// Fail on invalid UTF-8 instead of replacing it.
+    pub strict_utf8: bool,
+    /// Keep a snippet of each key's first occurrence.
+    pub context: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            tokenizer: Tokenizer::Words {
+                hyphens: Hyphens::Keep,
+                apostrophes: Apostrophes::Keep,
+            },
+            ignore_case: false,
+            locale: Locale::Default,
+            normalize: false,
+            stem: false,
+            min_length: 1,
+            max_length: usize::MAX,
+            numbers: Numbers::Include,
+            ngrams: 1,
+            cross_sentences: false,
+            cooccur: None,
+            max_pairs: MAX_PAIRS,
+            chars: false,
+            letters_only: false,
+            exclude: Vec::new(),
+            exclude_patterns: Vec::new(),
+            phrases: Vec::new(),
+            prefixes: Vec::new(),
+            suffixes: Vec::new(),
+            strict_utf8: false,
+            context: false,
+        }
+    }
+}
+
+impl Options {
+    /// Whether keys are case-folded.
+    pub fn folds_case(&self) -> bool {
+        self.ignore_case || self.normalize
+    }
+
+    /// Whether keys or phrases are built from several words, so counting
+    /// has to see an input in order.
+    pub fn spans_words(&self) -> bool {
+        self.ngrams > 1 || self.cooccur.is_some() || !self.phrases.is_empty()
+    }
+}
+
+/// Default for `Options::max_pairs`.
+pub const MAX_PAIRS: usize = 1_000_000;
+
+/// Case rules for folding. Turkish and Azerbaijani have a dotted and a
+/// dotless I, so "I" folds to "ı" and "İ" to "i".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Locale {
+    Default,
+    Turkish,
+    Azerbaijani,
+}
+
+/// A case-folding function.
+pub type Fold = fn(&str) -> String;
+
+impl Locale {
+    /// The folding function for this locale, picked once so counting
+    /// doesn't check the locale for every token.
+    pub fn fold(self) -> Fold {
+        match self {
+            Locale::Default => fold_case,
+            Locale::Turkish | Locale::Azerbaijani => fold_case_turkic,
+        }
+    }
+}
+
+/// What to do with purely numeric tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Numbers {
+    Include,
+    Exclude,
+    Only,
+}
+
+/// What counting an input produced.
+#[derive(Clone, Debug, Default)]
+pub struct Counts {
+    pub freq: HashMap<String, usize>,
+    /// How often each original spelling of a case-folded key was seen, so
+    /// `--ignore-case` can show "NASA" rather than "nasa".
+    pub spellings: HashMap<String, HashMap<String, usize>>,
+    /// Occurrences of each of `Options::phrases`, keyed by its folded
+    /// words joined with spaces; phrases never seen are there with 0.
+    pub phrases: HashMap<String, usize>,
+    /// A snippet around each key's first occurrence, for `--context`.
+    pub contexts: HashMap<String, String>,
+    /// Bytes of input read.
+    pub bytes: u64,
+    /// Files read (stdin and command-line text don't count).
+    pub files: usize,
+    /// Tokens dropped by `--exclude`/`--exclude-pattern`.
+    pub excluded: usize,
+    /// Tokens dropped by `--prefix`/`--suffix`.
+    pub filtered: usize,
+    /// Hashes of the distinct keys dropped for being shorter than
+    /// `min_length`; hashes rather than keys so long junk tokens don't
+    /// pile up in memory, but merged counts can still tell them apart.
+    pub too_short: HashSet<u64>,
+    /// Hashes of the distinct keys dropped for being over `max_length`.
+    pub too_long: HashSet<u64>,
+    /// Rare pairs dropped to stay under `Options::max_pairs`; when nonzero,
+    /// pair counts may be low.
+    pub pruned: usize,
+    /// Work done while counting, for `--stats`.
+    pub stats: Stats,
+}
+
+/// Counters kept while counting, cheap enough to always be on.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Tokens (or characters) read, whether counted or filtered out.
+    pub tokens: usize,
+    /// The most distinct keys held at once.
+    pub peak_distinct: usize,
+}
+
+impl Counts {
+    pub fn merge(&mut self, other: Counts) {
+        for (word, count) in other.freq {
+            *self.freq.entry(word).or_insert(0) += count;
+        }
+        for (key, forms) in other.spellings {
+            let entry = self.spellings.entry(key).or_default();
+            for (form, count) in forms {
+                *entry.entry(form).or_insert(0) += count;
+            }
+        }
+        for (phrase, count) in other.phrases {
+            *self.phrases.entry(phrase).or_insert(0) += count;
+        }
+        // `other` was counted after `self`, so a snippet here came first
+        for (key, snippet) in other.contexts {
+            self.contexts.entry(key).or_insert(snippet);
+        }
+        self.bytes += other.bytes;
+        self.files += other.files;
+        self.excluded += other.excluded;
+        self.filtered += other.filtered;
+        self.too_short.extend(other.too_short);
+        self.too_long.extend(other.too_long);
+        self.pruned += other.pruned;
+        self.stats.tokens += other.stats.tokens;
+        // The merged map may be bigger than either part ever was
+        self.stats.peak_distinct = self
+            .stats
+            .peak_distinct
+            .max(other.stats.peak_distinct)
+            .max(self.freq.len());
+    }
+
+    /// The most common original spelling of `key`, ties broken
+    /// alphabetically; `key` itself when no spellings were tracked.
+    pub fn display_form(&self, key: &str) -> String {
+        self.spellings
+            .get(key)
+            .and_then(|forms| {
+                forms
+                    .iter()
+                    .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            })
+            .map_or_else(|| key.to_string(), |(form, _)| form.clone())
+    }
+}
+
+/// Combining diacritical marks, which belong to the letter before them.
+fn is_combining_mark(c: char) -> bool {
+    matches!(c, '\u{0300}'..='\u{036f}' | '\u{1ab0}'..='\u{1aff}' | '\u{1dc0}'..='\u{1dff}' | '\u{20d0}'..='\u{20ff}' | '\u{fe20}'..='\u{fe2f}')
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || is_combining_mark(c)
+}
+
+/// Precomposed forms for a base letter followed by a combining mark: for
+/// each mark, the bases it combines with and the matching results.
+const COMPOSITIONS: &[(char, &str, &str)] = &[
+    ('\u{0300}', "AEIOUaeiou", "ÀÈÌÒÙàèìòù"),
+    ('\u{0301}', "AEIOUYCNSZaeiouycnsz", "ÁÉÍÓÚÝĆŃŚŹáéíóúýćńśź"),
+    ('\u{0302}', "AEIOUaeiou", "ÂÊÎÔÛâêîôû"),
+    ('\u{0303}', "ANOano", "ÃÑÕãñõ"),
+    ('\u{0308}', "AEIOUaeiouyЕе", "ÄËÏÖÜäëïöüÿЁё"),
+    ('\u{030a}', "AUau", "ÅŮåů"),
+    ('\u{030c}', "CDENRSZcdenrsz", "ČĎĚŇŘŠŽčďěňřšž"),
+    ('\u{0327}', "CSTcst", "ÇŞŢçşţ"),
+    ('\u{0328}', "AEae", "ĄĘąę"),
+    ('\u{0306}', "GUguИи", "ĞŬğŭЙй"),
+];
+
+/// NFC for the common Latin accents: fold a base letter and a following
+/// combining mark into the precomposed character when there is one. Other
+/// sequences are left as they are.
+fn nfc(word: &str) -> String {
+    let mut out = String::with_capacity(word.len());
+    for c in word.chars() {
+        if is_combining_mark(c) {
+            let composed = out.chars().last().and_then(|base| {
+                let (_, bases, results) = COMPOSITIONS.iter().find(|(m, _, _)| *m == c)?;
+                let i = bases.chars().position(|b| b == base)?;
+                results.chars().nth(i)
+            });
+            if let Some(composed) = composed {
+                out.pop();
+                out.push(composed);
+                continue;
+            }
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Full case folding: lowercase, plus the expansions `to_lowercase` leaves
+/// alone (ß → ss, ligatures, final sigma).
+fn fold_case(word: &str) -> String {
+    let mut out = String::with_capacity(word.len());
+    for c in word.chars() {
+        fold_char(c, &mut out);
+    }
+    out
+}
+
+fn fold_char(c: char, out: &mut String) {
+    match c {
+        'ß' | 'ẞ' => out.push_str("ss"),
+        'ς' => out.push('σ'),
+        'ﬀ' => out.push_str("ff"),
+        'ﬁ' => out.push_str("fi"),
+        'ﬂ' => out.push_str("fl"),
+        'ﬃ' => out.push_str("ffi"),
+        'ﬄ' => out.push_str("ffl"),
+        'ﬅ' | 'ﬆ' => out.push_str("st"),
+        c => out.extend(c.to_lowercase()),
+    }
+}
+
+/// `fold_case` with Turkish and Azerbaijani I's: "I" → "ı", and "İ" (or
+/// "I" plus a combining dot above) → "i" rather than "i̇".
+fn fold_case_turkic(word: &str) -> String {
+    let mut out = String::with_capacity(word.len());
+    let mut chars = word.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            'I' if chars.next_if_eq(&'\u{0307}').is_some() => out.push('i'),
+            'I' => out.push('ı'),
+            'İ' => out.push('i'),
+            c => fold_char(c, &mut out),
+        }
+    }
+    out
+}
+
+/// Whether `word` is a number: an optional sign, digits with optional
+/// thousands separators (`1,000`), and an optional decimal part (`3.14`).
+/// Mixed tokens like `v2` are words.
+fn is_number(word: &str) -> bool {
+    let word = word.strip_prefix(['-', '+']).unwrap_or(word);
+    let (int, frac) = match word.split_once('.') {
+        Some((int, frac)) => (int, Some(frac)),
+        None => (word, None),
+    };
+    let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let int_ok = if int.contains(',') {
+        let mut groups = int.split(',');
+        let head = groups.next().unwrap_or_default();
+        all_digits(head) && head.len() <= 3 && groups.all(|g| g.len() == 3 && all_digits(g))
+    } else {
+        int.is_empty() && frac.is_some() || all_digits(int)
+    };
+    int_ok && frac.is_none_or(all_digits)
+}
+
+fn is_consonant(w: &[u8], i: usize) -> bool {
+    match w[i] {
+        b'a' | b'e' | b'i' | b'o' | b'u' => false,
+        b'y' => i == 0 || !is_consonant(w, i - 1),
+        _ => true,
+    }
+}
+
+/// Porter's measure: the number of vowel-consonant sequences in `w`.
+fn measure(w: &[u8]) -> usize {
+    let mut m = 0;
+    let mut prev_vowel = false;
+    for i in 0..w.len() {
+        let vowel = !is_consonant(w, i);
+        if prev_vowel && !vowel {
+            m += 1;
+        }
+        prev_vowel = vowel;
+    }
+    m
+}
+
+fn has_vowel(w: &[u8]) -> bool {
+    (0..w.len()).any(|i| !is_consonant(w, i))
+}
+
+/// Ends consonant-vowel-consonant, the last not w, x or y (hop, but not hoop).
+fn ends_cvc(w: &[u8]) -> bool {
+    let n = w.len();
+    n >= 3
+        && is_consonant(w, n - 3)
+        && !is_consonant(w, n - 2)
+        && is_consonant(w, n - 1)
+        && !matches!(w[n - 1], b'w' | b'x' | b'y')
+}
+
+/// A light English stemmer: Porter's steps 1a and 1b, which strip plural
+/// `-s`/`-es` and `-ed`/`-ing` (hopping → hop, hoping → hope). Far from a
+/// full stemmer, but it merges the common inflections. Words with anything
+/// but ASCII letters are returned as they are; stems come back lowercase.
+fn stem(word: &str) -> String {
+    if word.len() <= 2 || !word.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return word.to_string();
+    }
+    let mut w = word.to_ascii_lowercase().into_bytes();
+
+    // Step 1a, keeping at least three letters and -us (ties → tie; was, bus stay)
+    if w.ends_with(b"sses") || (w.ends_with(b"ies") && w.len() > 4) {
+        w.truncate(w.len() - 2);
+    } else if w.ends_with(b"s") && !w.ends_with(b"ss") && !w.ends_with(b"us") && w.len() > 3 {
+        w.pop();
+    }
+
+    // Step 1b
+    let mut cleanup = false;
+    if w.ends_with(b"eed") {
+        if measure(&w[..w.len() - 3]) > 0 {
+            w.pop();
+        }
+    } else if let Some(n) = [&b"ed"[..], b"ing"].into_iter().find_map(|suffix| {
+        (w.ends_with(suffix) && has_vowel(&w[..w.len() - suffix.len()])).then_some(suffix.len())
+    }) {
+        w.truncate(w.len() - n);
+        cleanup = true;
+    }
+    if cleanup {
+        let n = w.len();
+        if w.ends_with(b"at") || w.ends_with(b"bl") || w.ends_with(b"iz") {
+            w.push(b'e');
+        } else if n >= 2
+            && w[n - 1] == w[n - 2]
+            && is_consonant(&w, n - 1)
+            && !matches!(w[n - 1], b'l' | b's' | b'z')
+        {
+            w.pop();
+        } else if measure(&w) == 1 && ends_cvc(&w) {
+            w.push(b'e');
+        }
+    }
+    String::from_utf8(w).unwrap_or_else(|_| word.to_string())
+}
+
+/// The key a word is counted under.
+pub fn word_key(word: &str, options: &Options, fold: Fold) -> String {
+    let word = if options.normalize {
+        nfc(word)
+    } else {
+        word.to_string()
+    };
+    let word = if options.folds_case() {
+        fold(&word)
+    } else {
+        word
+    };
+    if options.stem { stem(&word) } else { word }
+}
+
+/// How text is cut into tokens; only one strategy is active at a time.
+#[derive(Clone, Debug)]
+pub enum Tokenizer {
+    /// Whitespace, with surrounding punctuation and quotes trimmed; inner
+    /// hyphens and apostrophes are handled as configured.
+    Words {
+        hyphens: Hyphens,
+        apostrophes: Apostrophes,
+    },
+    /// Split on any of these characters and nothing else.
+    Delimiters(Vec<char>),
+    /// Every non-overlapping match of a `--token-pattern`.
+    Pattern(Pattern),
+    /// Whole lines for `--lines`, optionally with surrounding whitespace
+    /// trimmed. Blank lines are not counted.
+    Lines { trim: bool },
+}
+
+/// What the default tokenizer does with a hyphen inside a word.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hyphens {
+    /// `well-known` stays one word.
+    Keep,
+    /// `well-known` becomes `well` and `known`.
+    Split,
+    /// `well-known` becomes `wellknown`.
+    Strip,
+}
+
+/// What the default tokenizer does with an apostrophe inside a word.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Apostrophes {
+    /// `don't` stays as it is.
+    Keep,
+    /// `don't` becomes `dont`.
+    Strip,
+}
+
+fn is_apostrophe(c: char) -> bool {
+    matches!(c, '\'' | '\u{2018}' | '\u{2019}')
+}
+
+/// Trim everything that isn't part of a word (punctuation, ASCII and smart
+/// quotes) from both ends.
+fn trim_word(raw: &str) -> &str {
+    raw.trim_matches(|c: char| !is_word_char(c))
+}
+
+impl Tokenizer {
+    /// Whether no token can contain the byte `b`, making the spot after it
+    /// safe to cut the input. Only ASCII bytes qualify, as they are always
+    /// whole characters in UTF-8.
+    pub fn splits_at(&self, b: u8) -> bool {
+        if !b.is_ascii() {
+            return false;
+        }
+        match self {
+            Tokenizer::Words { .. } => b.is_ascii_whitespace(),
+            Tokenizer::Lines { .. } => b == b'\n',
+            Tokenizer::Delimiters(set) => set.contains(&(b as char)),
+            Tokenizer::Pattern(pattern) => pattern
+                .items
+                .iter()
+                .all(|(atom, _, _)| !atom.matches(b as char)),
+        }
+    }
+}
+
+/// One piece of a `--token-pattern`.
+#[derive(Clone, Debug)]
+enum Atom {
+    Any,
+    Char(char),
+    Class {
+        negated: bool,
+        items: Vec<ClassItem>,
+    },
+}
+
+#[derive(Clone, Debug)]
+enum ClassItem {
+    Range(char, char),
+    Word,
+    Digit,
+    Space,
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Range(lo, hi) => (lo..=hi).contains(&c),
+            ClassItem::Word => c.is_alphanumeric() || c == '_',
+            ClassItem::Digit => c.is_ascii_digit(),
+            ClassItem::Space => c.is_whitespace(),
+        }
+    }
+}
+
+impl Atom {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Atom::Any => true,
+            Atom::Char(x) => *x == c,
+            Atom::Class { negated, items } => items.iter().any(|i| i.matches(c)) != *negated,
+        }
+    }
+}
+
+/// A small regular-expression subset for `--token-pattern`: literals, `.`,
+/// `\w` `\d` `\s` and their negations, `[...]` classes with ranges and `^`,
+/// each optionally followed by `?`, `*` or `+`. Groups, alternation and
+/// counted repetition are not supported.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    items: Vec<(Atom, usize, usize)>,
+}
+
+impl Pattern {
+    pub fn parse(src: &str) -> Result<Pattern, String> {
+        let mut items = Vec::new();
+        let mut chars = src.chars().peekable();
+        while let Some(c) = chars.next() {
+            let atom = match c {
+                '.' => Atom::Any,
+                '\\' => {
+                    let e = chars.next().ok_or("pattern ends with '\\'")?;
+                    escape_atom(e)
+                }
+                '[' => {
+                    let negated = chars.next_if_eq(&'^').is_some();
+                    let mut class = Vec::new();
+                    loop {
+                        let c = chars.next().ok_or("unclosed '[' in pattern")?;
+                        let lo = match c {
+                            ']' if !class.is_empty() => break,
+                            '\\' => match escape_atom(chars.next().ok_or("unclosed '['")?) {
+                                Atom::Char(c) => c,
+                                Atom::Class { items, .. } => {
+                                    class.extend(items);
+                                    continue;
+                                }
+                                Atom::Any => unreachable!(),
+                            },
+                            c => c,
+                        };
+                        let hi = if chars.peek() == Some(&'-') {
+                            chars.next();
+                            match chars.next() {
+                                Some(']') => {
+                                    class.push(ClassItem::Range(lo, lo));
+                                    class.push(ClassItem::Range('-', '-'));
+                                    break;
+                                }
+                                Some(hi) if hi >= lo => hi,
+                                _ => return Err(format!("bad range starting at '{}'", lo)),
+                            }
+                        } else {
+                            lo
+                        };
+                        class.push(ClassItem::Range(lo, hi));
+                    }
+                    Atom::Class {
+                        negated,
+                        items: class,
+                    }
+                }
+                '(' | ')' | '|' | '{' | '}' | '^' | '$' => {
+                    return Err(format!("'{}' is not supported in --token-pattern", c));
+                }
+                '?' | '*' | '+' => return Err(format!("'{}' has nothing to repeat", c)),
+                c => Atom::Char(c),
+            };
+            let (min, max) = match chars.next_if(|c| matches!(c, '?' | '*' | '+')) {
+                Some('?') => (0, 1),
+                Some('*') => (0, usize::MAX),
+                Some('+') => (1, usize::MAX),
+                _ => (1, 1),
+            };
+            items.push((atom, min, max));
+        }
+        if items.is_empty() {
+            return Err("empty --token-pattern".to_string());
+        }
+        Ok(Pattern { items })
+    }
+
+    /// Longest match of items[i..] starting at `pos`, with greedy
+    /// repetition and backtracking. Returns the end position.
+    fn match_here(&self, chars: &[char], i: usize, pos: usize) -> Option<usize> {
+        let Some((atom, min, max)) = self.items.get(i) else {
+            return Some(pos);
+        };
+        let mut n = 0;
+        while n < *max && pos + n < chars.len() && atom.matches(chars[pos + n]) {
+            n += 1;
+        }
+        (*min..=n)
+            .rev()
+            .find_map(|k| self.match_here(chars, i + 1, pos + k))
+    }
+
+    /// Byte ranges of every non-empty, non-overlapping match, left to right.
+    pub fn find_all(&self, text: &str) -> Vec<(usize, usize)> {
+        let offsets: Vec<usize> = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .collect();
+        let chars: Vec<char> = text.chars().collect();
+        let mut spans = Vec::new();
+        let mut pos = 0;
+        while pos < chars.len() {
+            match self.match_here(&chars, 0, pos) {
+                Some(end) if end > pos => {
+                    spans.push((offsets[pos], offsets[end]));
+                    pos = end;
+                }
+                _ => pos += 1,
+            }
+        }
+        spans
+    }
+}
+
+fn escape_atom(e: char) -> Atom {
+    let class = |negated, item| Atom::Class {
+        negated,
+        items: vec![item],
+    };
+    match e {
+        'w' => class(false, ClassItem::Word),
+        'W' => class(true, ClassItem::Word),
+        'd' => class(false, ClassItem::Digit),
+        'D' => class(true, ClassItem::Digit),
+        's' => class(false, ClassItem::Space),
+        'S' => class(true, ClassItem::Space),
+        't' => Atom::Char('\t'),
+        'n' => Atom::Char('\n'),
+        c => Atom::Char(c),
+    }
+}
+
+/// Parse a `--delimiters` set, where `\t`, `\n` and `\\` are escapes.
+pub fn parse_delimiters(set: &str) -> Vec<char> {
+    let mut out = Vec::new();
+    let mut chars = set.chars();
+    while let Some(c) = chars.next() {
+        out.push(if c != '\\' {
+            c
+        } else {
+            match chars.next() {
+                Some('t') => '\t',
+                Some('n') => '\n',
+                Some(other) => other,
+                None => '\\',
+            }
+        });
+    }
+    out
+}
+
+/// Cut `text` into tokens. Each comes with a flag telling whether it ends a
+/// sentence. With the default tokenizer, pure punctuation yields an empty
+/// word so that `.`, `!` or `?` standing alone still ends one; the other
+/// strategies look for those in the text between two tokens, and report one
+/// before the first token as an empty word too.
+pub fn tokenize<'a>(text: &'a str, tokenizer: &Tokenizer) -> Vec<(Cow<'a, str>, bool)> {
+    let spans = match *tokenizer {
+        Tokenizer::Words {
+            hyphens,
+            apostrophes,
+        } => {
+            let mut tokens = Vec::new();
+            let breaks = |c: char| c.is_whitespace() || c == char::REPLACEMENT_CHARACTER;
+            for raw_word in text.split(breaks).filter(|w| !w.is_empty()) {
+                let tail = raw_word.trim_end_matches(|c: char| {
+                    matches!(c, '"' | ')' | ']' | '\u{201c}' | '\u{201d}') || is_apostrophe(c)
+                });
+                let sentence_end = tail.ends_with(['.', '!', '?']);
+                let word = trim_word(raw_word);
+                let pieces: Vec<&str> = if hyphens == Hyphens::Split {
+                    word.split('-')
+                        .map(trim_word)
+                        .filter(|p| !p.is_empty())
+                        .collect()
+                } else {
+                    vec![word]
+                };
+                if pieces.is_empty() {
+                    tokens.push((Cow::Borrowed(""), sentence_end));
+                }
+                let last = pieces.len().saturating_sub(1);
+                for (i, piece) in pieces.into_iter().enumerate() {
+                    let mut piece = Cow::Borrowed(piece);
+                    if hyphens == Hyphens::Strip && piece.contains('-') {
+                        piece = Cow::Owned(piece.replace('-', ""));
+                    }
+                    if apostrophes == Apostrophes::Strip && piece.contains(is_apostrophe) {
+                        piece = Cow::Owned(piece.replace(is_apostrophe, ""));
+                    }
+                    tokens.push((piece, sentence_end && i == last));
+                }
+            }
+            return tokens;
+        }
+        Tokenizer::Lines { trim } => {
+            return text
+                .lines()
+                .map(|line| (Cow::Borrowed(if trim { line.trim() } else { line }), false))
+                .collect();
+        }
+        Tokenizer::Delimiters(ref set) => {
+            let mut spans = Vec::new();
+            let mut start = 0;
+            for (i, c) in text.char_indices().chain([(text.len(), ' ')]) {
+                if i == text.len() || set.contains(&c) || c == char::REPLACEMENT_CHARACTER {
+                    if i > start {
+                        spans.push((start, i));
+                    }
+                    start = i + c.len_utf8();
+                }
+            }
+            spans
+        }
+        Tokenizer::Pattern(ref pattern) => pattern.find_all(text),
+    };
+    let mut tokens = Vec::with_capacity(spans.len() + 1);
+    // A sentence end before the first token (the previous chunk's last word)
+    let first = spans.first().map_or(text.len(), |s| s.0);
+    if text[..first].contains(['.', '!', '?']) {
+        tokens.push((Cow::Borrowed(""), true));
+    }
+    for (i, &(start, end)) in spans.iter().enumerate() {
+        let next = spans.get(i + 1).map_or(text.len(), |s| s.0);
+        let gap = &text[end..next];
+        tokens.push((
+            Cow::Borrowed(&text[start..end]),
+            gap.contains(['.', '!', '?']),
+        ));
+    }
+    tokens
+}
+
+/// Words dropped by `--exclude` and `--exclude-pattern`, and the
+/// `--prefix`/`--suffix` a word must have, folded the same way as the keys
+/// they are compared with.
+struct Exclusions {
+    words: HashSet<String>,
+    patterns: Vec<String>,
+    prefixes: Vec<String>,
+    suffixes: Vec<String>,
+}
+
+impl Exclusions {
+    fn new(options: &Options, fold: Fold) -> Exclusions {
+        let fold_all = |list: &[String]| -> Vec<String> {
+            list.iter()
+                .map(|s| {
+                    if options.folds_case() {
+                        fold(s)
+                    } else {
+                        s.clone()
+                    }
+                })
+                .collect()
+        };
+        Exclusions {
+            words: options
+                .exclude
+                .iter()
+                .map(|w| word_key(w, options, fold))
+                .collect(),
+            patterns: fold_all(&options.exclude_patterns),
+            prefixes: fold_all(&options.prefixes),
+            suffixes: fold_all(&options.suffixes),
+        }
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        self.words.contains(key) || self.patterns.iter().any(|p| glob_match(p, key))
+    }
+
+    /// Whether `key` has one of the prefixes and one of the suffixes; an
+    /// empty list lets anything through.
+    fn has_affixes(&self, key: &str) -> bool {
+        (self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p.as_str())))
+            && (self.suffixes.is_empty() || self.suffixes.iter().any(|s| key.ends_with(s.as_str())))
+    }
+}
+
+/// `PREFIX*`, `*SUFFIX` and `*PART*` patterns; anything else must match
+/// exactly.
+fn glob_match(pattern: &str, word: &str) -> bool {
+    match (pattern.strip_prefix('*'), pattern.strip_suffix('*')) {
+        (Some(_), Some(_)) if pattern.len() >= 2 => word.contains(&pattern[1..pattern.len() - 1]),
+        (Some(_), Some(_)) => true,
+        (Some(suffix), None) => word.ends_with(suffix),
+        (None, Some(prefix)) => word.starts_with(prefix),
+        (None, None) => word == pattern,
+    }
+}
+
+/// Incremental counts. Text can be fed in pieces as long as no token is
+/// split between two of them; n-gram windows carry over from one piece to
+/// the next.
+pub struct WordCounter {
+    options: Options,
+    counts: Counts,
+    exclusions: Exclusions,
+    /// The last n-gram's (or co-occurrence window's) worth of (key,
+    /// original spelling, token index).
+    window: VecDeque<(String, String, usize)>,
+    /// Case folding for `options.locale`.
+    fold: Fold,
+    /// Each phrase's folded words, grouped by its last word.
+    phrases: HashMap<String, Vec<Vec<String>>>,
+    /// Words in the longest phrase.
+    longest_phrase: usize,
+    /// The last `longest_phrase` folded tokens.
+    recent: VecDeque<String>,
+    /// Tokens fed so far; indexes in `window` count from the first one.
+    tokens_seen: usize,
+    /// Whether to record original spellings for the display form.
+    track_spellings: bool,
+}
+
+impl WordCounter {
+    pub fn new(options: Options) -> WordCounter {
+        let fold = options.locale.fold();
+        let mut counts = Counts::default();
+        let mut phrases: HashMap<String, Vec<Vec<String>>> = HashMap::new();
+        for phrase in &options.phrases {
+            let words = phrase_words(phrase, &options, fold);
+            let Some(last) = words.last() else {
+                continue;
+            };
+            if counts.phrases.insert(words.join(" "), 0).is_none() {
+                phrases.entry(last.clone()).or_default().push(words);
+            }
+        }
+        let longest_phrase = phrases.values().flatten().map(Vec::len).max();
+        WordCounter {
+            counts,
+            phrases,
+            longest_phrase: longest_phrase.unwrap_or(0),
+            recent: VecDeque::new(),
+            exclusions: Exclusions::new(&options, fold),
+            fold,
+            window: VecDeque::with_capacity(options.cooccur.unwrap_or(options.ngrams)),
+            tokens_seen: 0,
+            // Stemming merges different words, so no spelling is "the" one.
+            track_spellings: options.folds_case() && !options.stem,
+            options,
+        }
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn feed(&mut self, text: &str) {
+        self.counts.bytes += text.len() as u64;
+        if self.options.chars {
+            self.feed_chars(text);
+        } else {
+            self.feed_words(text);
+        }
+        self.note_distinct();
+    }
+
+    fn note_distinct(&mut self) {
+        let stats = &mut self.counts.stats;
+        stats.peak_distinct = stats.peak_distinct.max(self.counts.freq.len());
+    }
+
+    /// Count one more input file towards `Counts::files`.
+    pub fn add_file(&mut self) {
+        self.counts.files += 1;
+    }
+
+    pub fn counts(&self) -> &Counts {
+        &self.counts
+    }
+
+    pub fn into_counts(self) -> Counts {
+        self.counts
+    }
+
+    /// Every counted key, most frequent first, ties broken alphabetically.
+    pub fn results(&self) -> Vec<(String, usize)> {
+        let mut all: Vec<(String, usize)> = self
+            .counts
+            .freq
+            .iter()
+            .map(|(w, &c)| (w.clone(), c))
+            .collect();
+        all.sort_by(by_count);
+        all
+    }
+
+    /// Count the words of `text` that pass the filters, or with `ngrams > 1`
+    /// the runs of that many consecutive words that do, or with `cooccur`
+    /// the pairs of them close enough together.
+    fn feed_words(&mut self, text: &str) {
+        let tokens = tokenize(text, &self.options.tokenizer);
+        let ngrams = self.options.ngrams;
+        let context = self.options.context;
+        let base = self.tokens_seen;
+        self.tokens_seen += tokens.len();
+        for (i, (word, sentence_end)) in tokens.iter().enumerate() {
+            let word = word.as_ref();
+            // Words dropped by a filter don't break an n-gram
+            let word_key = word_key(word, &self.options, self.fold);
+            if !word.is_empty() {
+                self.counts.stats.tokens += 1;
+            }
+            if !self.phrases.is_empty() && !word.is_empty() {
+                self.match_phrases((self.fold)(&word_key));
+            }
+            let numbers_ok = match self.options.numbers {
+                Numbers::Include => true,
+                Numbers::Exclude => !is_number(word),
+                Numbers::Only => is_number(word),
+            };
+            let length = word_key.chars().count();
+            let mut passes = !word.is_empty() && numbers_ok;
+            if passes && length < self.options.min_length {
+                self.counts.too_short.insert(key_hash(&word_key));
+                passes = false;
+            } else if passes && length > self.options.max_length {
+                self.counts.too_long.insert(key_hash(&word_key));
+                passes = false;
+            }
+            let excluded = passes && self.exclusions.matches(&word_key);
+            let filtered = passes && !excluded && !self.exclusions.has_affixes(&word_key);
+            if excluded {
+                self.counts.excluded += 1;
+            } else if filtered {
+                self.counts.filtered += 1;
+            } else if passes {
+                if let Some(width) = self.options.cooccur {
+                    self.count_pairs(word_key, word, width, &tokens, base, i);
+                } else if ngrams <= 1 {
+                    if self.track_spellings {
+                        self.record_spelling(&word_key, word);
+                    }
+                    if context {
+                        self.record_context(&word_key, &tokens, i, i);
+                    }
+                    *self.counts.freq.entry(word_key).or_insert(0) += 1;
+                } else {
+                    if self.window.len() == ngrams {
+                        self.window.pop_front();
+                    }
+                    self.window
+                        .push_back((word_key, word.to_string(), base + i));
+                    if self.window.len() == ngrams {
+                        let (keys, forms): (Vec<&str>, Vec<&str>) = self
+                            .window
+                            .iter()
+                            .map(|(k, f, _)| (k.as_str(), f.as_str()))
+                            .unzip();
+                        let gram = keys.join(" ");
+                        if self.track_spellings {
+                            self.record_spelling(&gram, &forms.join(" "));
+                        }
+                        // A gram that started in an earlier chunk gets its
+                        // snippet from a later occurrence
+                        let first = self.window[0].2;
+                        if context && first >= base {
+                            self.record_context(&gram, &tokens, first - base, i);
+                        }
+                        *self.counts.freq.entry(gram).or_insert(0) += 1;
+                    }
+                }
+            }
+            if *sentence_end && !self.options.cross_sentences {
+                self.window.clear();
+            }
+        }
+    }
+
+    /// Count the phrases that end with `word`, the newest folded token.
+    /// Phrases see every token, filtered or not, and overlapping matches
+    /// all count.
+    fn match_phrases(&mut self, word: String) {
+        if self.recent.len() == self.longest_phrase {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(word);
+        let Some(candidates) = self.recent.back().and_then(|w| self.phrases.get(w)) else {
+            return;
+        };
+        for words in candidates {
+            if words.len() <= self.recent.len()
+                && self
+                    .recent
+                    .iter()
+                    .rev()
+                    .zip(words.iter().rev())
+                    .all(|(a, b)| a == b)
+            {
+                *self.counts.phrases.entry(words.join(" ")).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Pair the word at token `i` with each different word in the window,
+    /// then make it the newest of the last `width` words.
+    fn count_pairs(
+        &mut self,
+        key: String,
+        form: &str,
+        width: usize,
+        tokens: &[(Cow<str>, bool)],
+        base: usize,
+        i: usize,
+    ) {
+        let pairs: Vec<(String, String, usize)> = self
+            .window
+            .iter()
+            .filter(|(other, _, _)| *other != key)
+            .map(|(other, other_form, at)| {
+                if *other < key {
+                    (
+                        format!("{} + {}", other, key),
+                        format!("{} + {}", other_form, form),
+                        *at,
+                    )
+                } else {
+                    (
+                        format!("{} + {}", key, other),
+                        format!("{} + {}", form, other_form),
+                        *at,
+                    )
+                }
+            })
+            .collect();
+        for (pair, forms, at) in pairs {
+            if self.track_spellings {
+                self.record_spelling(&pair, &forms);
+            }
+            if self.options.context && at >= base {
+                self.record_context(&pair, tokens, at - base, i);
+            }
+            *self.counts.freq.entry(pair).or_insert(0) += 1;
+        }
+        if self.window.len() == width {
+            self.window.pop_front();
+        }
+        self.window.push_back((key, form.to_string(), base + i));
+        if self.counts.freq.len() > self.options.max_pairs {
+            self.prune_pairs();
+        }
+    }
+
+    /// Drop the rarest pairs, those seen once first, until at most half of
+    /// `max_pairs` are left. This is what keeps memory bounded on big
+    /// inputs, at a price: a dropped pair that shows up again is counted
+    /// from zero, so its final count is low by however often it had been
+    /// seen. Pairs that are frequent overall survive, so the top of the
+    /// list is rarely affected.
+    fn prune_pairs(&mut self) {
+        self.note_distinct();
+        let target = self.options.max_pairs / 2;
+        let mut floor = 1;
+        while self.counts.freq.len() > target {
+            let before = self.counts.freq.len();
+            self.counts.freq.retain(|_, count| *count > floor);
+            self.counts.pruned += before - self.counts.freq.len();
+            floor += 1;
+        }
+        let freq = &self.counts.freq;
+        self.counts
+            .spellings
+            .retain(|pair, _| freq.contains_key(pair));
+        self.counts
+            .contexts
+            .retain(|pair, _| freq.contains_key(pair));
+    }
+
+    fn record_context(
+        &mut self,
+        key: &str,
+        tokens: &[(Cow<str>, bool)],
+        first: usize,
+        last: usize,
+    ) {
+        if !self.counts.contexts.contains_key(key) {
+            let snippet = snippet(tokens, first, last);
+            self.counts.contexts.insert(key.to_string(), snippet);
+        }
+    }
+
+    fn record_spelling(&mut self, key: &str, form: &str) {
+        let forms = self.counts.spellings.entry(key.to_string()).or_default();
+        *forms.entry(form.to_string()).or_insert(0) += 1;
+    }
+
+    /// Count individual characters instead of words.
+    fn feed_chars(&mut self, text: &str) {
+        for c in text.chars() {
+            self.counts.stats.tokens += 1;
+            if self.options.letters_only && !c.is_alphabetic() {
+                continue;
+            }
+            if self.options.ignore_case {
+                for lower in c.to_lowercase() {
+                    *self.counts.freq.entry(lower.to_string()).or_insert(0) += 1;
+                }
+            } else {
+                *self.counts.freq.entry(c.to_string()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Read `input` in chunks and feed each one, cut after the last byte no
+    /// token can contain; the rest is carried over to the next chunk. Memory
+    /// stays bounded by the longest token, or by [`MAX_CARRY`] when there is
+    /// no such byte, not by the input. `start` is where `input` begins in
+    /// its file, for error offsets.
+    pub fn feed_reader(&mut self, input: &mut dyn Read, start: u64) -> io::Result<()> {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut carry: Vec<u8> = Vec::new();
+        let mut offset = start;
+        loop {
+            let n = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            carry.extend_from_slice(&buf[..n]);
+            let cut = carry
+                .iter()
+                .rposition(|&b| self.options.tokenizer.splits_at(b))
+                .map(|i| i + 1)
+                .or_else(|| (carry.len() > MAX_CARRY).then(|| forced_cut(&carry)));
+            if let Some(cut) = cut {
+                let rest = carry.split_off(cut);
+                self.feed(&decode(&carry, self.options.strict_utf8, offset)?);
+                offset += carry.len() as u64;
+                carry = rest;
+            }
+        }
+        self.feed(&decode(&carry, self.options.strict_utf8, offset)?);
+        Ok(())
+    }
+}
+
+/// A hash of `key` that is the same in every counter, so the sets of
+/// dropped keys can be merged.
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The folded words of `phrase`, tokenized and normalized like the text it
+/// is looked for in.
+fn phrase_words(phrase: &str, options: &Options, fold: Fold) -> Vec<String> {
+    tokenize(phrase, &options.tokenizer)
+        .iter()
+        .filter(|(w, _)| !w.is_empty())
+        .map(|(w, _)| fold(&word_key(w, options, fold)))
+        .collect()
+}
+
+/// Words shown on either side of a `--context` match.
+const CONTEXT_WORDS: usize = 5;
+
+/// The longest a `--context` snippet gets, in characters.
+const MAX_CONTEXT: usize = 120;
+
+/// Tokens `first..=last` in brackets, with up to `CONTEXT_WORDS` tokens on
+/// either side, trimmed from the outside in to fit `MAX_CONTEXT`.
+fn snippet(tokens: &[(Cow<str>, bool)], first: usize, last: usize) -> String {
+    let words = |from: usize, to: usize| -> Vec<&str> {
+        tokens[from..to.min(tokens.len())]
+            .iter()
+            .map(|(w, _)| w.as_ref())
+            .filter(|w| !w.is_empty())
+            .collect()
+    };
+    let mut before = words(first.saturating_sub(CONTEXT_WORDS), first);
+    let mut after = words(last + 1, last + 1 + CONTEXT_WORDS);
+    let hit = format!("[{}]", words(first, last + 1).join(" "));
+    loop {
+        let mut parts = before.clone();
+        parts.push(&hit);
+        parts.extend(&after);
+        let line = parts.join(" ");
+        if line.chars().count() <= MAX_CONTEXT {
+            return line;
+        }
+        if before.len() > after.len() {
+            before.remove(0);
+        } else if after.pop().is_none() {
+            return ellipsize(&line, MAX_CONTEXT);
+        }
+    }
+}
+
+/// Read buffer size for `WordCounter::feed_reader`.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How much `WordCounter::feed_reader` carries over while waiting for a
+/// byte no token can contain. A `--token-pattern` such as `.+` has none,
+/// so past this the input is cut anyway, and a token crossing that cut is
+/// counted as two.
+pub const MAX_CARRY: usize = 16 * CHUNK_SIZE;
+
+/// Where to cut `bytes` that have no safe byte: after the last whitespace,
+/// or else before the last character, so no character is split.
+fn forced_cut(bytes: &[u8]) -> usize {
+    match bytes.iter().rposition(|b| b.is_ascii_whitespace()) {
+        Some(i) => i + 1,
+        None => bytes.iter().rposition(|&b| b & 0xc0 != 0x80).unwrap_or(0),
+    }
+}
+
+/// Decode a chunk that starts `offset` bytes into its input. Invalid
+/// sequences become U+FFFD, which tokenizers treat as a word break, unless
+/// `strict` is set.
+fn decode(bytes: &[u8], strict: bool, offset: u64) -> io::Result<Cow<'_, str>> {
+    if !strict {
+        return Ok(String::from_utf8_lossy(bytes));
+    }
+    std::str::from_utf8(bytes).map(Cow::Borrowed).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid UTF-8 at byte {}", offset + e.valid_up_to() as u64),
+        )
+    })
+}
+
+/// Descending count, ties broken alphabetically so output is stable.
+pub fn by_count(a: &(String, usize), b: &(String, usize)) -> cmp::Ordering {
+    b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))
+}
+
+/// The `n` smallest items in ascending order. Keeps a bounded max-heap of
+/// the best so far instead of sorting everything, so this is O(M log n) for
+/// M items; with `n` covering every item it is a plain sort.
+pub fn smallest<K: Ord>(items: impl ExactSizeIterator<Item = K>, n: usize) -> Vec<K> {
+    if n >= items.len() {
+        let mut all: Vec<K> = items.collect();
+        all.sort_unstable();
+        return all;
+    }
+    let mut heap = BinaryHeap::with_capacity(n + 1);
+    for item in items {
+        if heap.len() < n {
+            heap.push(item);
+        } else if let Some(mut worst) = heap.peek_mut()
+            && item < *worst
+        {
+            *worst = item;
+        }
+    }
+    heap.into_sorted_vec()
+}
+
+/// Cut `s` to at most `width` characters, marking the cut with an ellipsis.
+pub fn ellipsize(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.to_string();
+    }
+    let mut cut: String = s.chars().take(width.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn words(text: &str, tokenizer: &Tokenizer) -> Vec<String> {
+        tokenize(text, tokenizer)
+            .into_iter()
+            .map(|(w, _)| w.into_owned())
+            .filter(|w| !w.is_empty())
+            .collect()
+    }
+
+    fn default_words(text: &str) -> Vec<String> {
+        words(text, &Options::default().tokenizer)
+    }
+
+    fn count(text: &str, options: Options) -> Vec<(String, usize)> {
+        let mut counter = WordCounter::new(options);
+        counter.feed(text);
+        counter.results()
+    }
+
+    fn pairs(expected: &[(&str, usize)]) -> Vec<(String, usize)> {
+        expected.iter().map(|&(w, c)| (w.to_string(), c)).collect()
+    }
+
+    fn word_tokenizer(hyphens: Hyphens, apostrophes: Apostrophes) -> Tokenizer {
+        Tokenizer::Words {
+            hyphens,
+            apostrophes,
+        }
+    }
+
+    #[test]
+    fn tokenize_trims_surrounding_punctuation() {
+        assert_eq!(
+            default_words("Hello, world! (yes) ...and... --dash"),
+            ["Hello", "world", "yes", "and", "dash"]
+        );
+    }
+
+    #[test]
+    fn tokenize_trims_ascii_and_smart_quotes() {
+        assert_eq!(
+            default_words("\"plain\" \u{201c}smart\u{201d} \u{2018}single\u{2019} 'ascii'"),
+            ["plain", "smart", "single", "ascii"]
+        );
+    }
+
+    #[test]
+    fn tokenize_keeps_inner_apostrophes_and_hyphens_by_default() {
+        assert_eq!(
+            default_words("don't it\u{2019}s well-known"),
+            ["don't", "it\u{2019}s", "well-known"]
+        );
+    }
+
+    #[test]
+    fn tokenize_marks_sentence_ends() {
+        let tokens: Vec<(String, bool)> = tokenize(
+            "One. Two!\" three (four?) five",
+            &Options::default().tokenizer,
+        )
+        .into_iter()
+        .map(|(w, end)| (w.into_owned(), end))
+        .collect();
+        let expected = [
+            ("One", true),
+            ("Two", true),
+            ("three", false),
+            ("four", true),
+            ("five", false),
+        ];
+        assert_eq!(
+            tokens,
+            expected.map(|(w, end)| (w.to_string(), end)).to_vec()
+        );
+    }
+
+    #[test]
+    fn hyphens_can_be_split_or_stripped() {
+        let text = "well-known co-op -dash-";
+        assert_eq!(
+            words(text, &word_tokenizer(Hyphens::Split, Apostrophes::Keep)),
+            ["well", "known", "co", "op", "dash"]
+        );
+        assert_eq!(
+            words(text, &word_tokenizer(Hyphens::Strip, Apostrophes::Keep)),
+            ["wellknown", "coop", "dash"]
+        );
+    }
+
+    #[test]
+    fn apostrophes_can_be_stripped() {
+        assert_eq!(
+            words(
+                "don't don\u{2019}t rock'n'roll",
+                &word_tokenizer(Hyphens::Keep, Apostrophes::Strip)
+            ),
+            ["dont", "dont", "rocknroll"]
+        );
+    }
+
+    #[test]
+    fn split_hyphen_keeps_sentence_end_on_last_piece() {
+        let tokens = tokenize(
+            "ice-cream. next",
+            &word_tokenizer(Hyphens::Split, Apostrophes::Keep),
+        );
+        let ends: Vec<bool> = tokens.iter().map(|(_, end)| *end).collect();
+        assert_eq!(ends, [false, true, false]);
+    }
+
+    #[test]
+    fn delimiters_split_only_on_the_given_characters() {
+        let tokenizer = Tokenizer::Delimiters(parse_delimiters(",;\\t"));
+        assert_eq!(words("a b,c;;d\te", &tokenizer), ["a b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn token_pattern_finds_every_match() {
+        let tokenizer = Tokenizer::Pattern(Pattern::parse(r"\d+").unwrap());
+        assert_eq!(words("a1 b22 c333 d", &tokenizer), ["1", "22", "333"]);
+        let tokenizer = Tokenizer::Pattern(Pattern::parse("[a-z]+'?[a-z]*").unwrap());
+        assert_eq!(words("Don't STOP me", &tokenizer), ["on't", "me"]);
+    }
+
+    #[test]
+    fn token_pattern_rejects_what_it_cannot_parse() {
+        for bad in ["", "*a", "[abc", "(a)", "a|b"] {
+            assert!(Pattern::parse(bad).is_err(), "{:?} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn lines_tokenizer_optionally_trims() {
+        let text = "  first  \r\n\nsecond\n";
+        assert_eq!(
+            words(text, &Tokenizer::Lines { trim: false }),
+            ["  first  ", "second"]
+        );
+        assert_eq!(
+            words(text, &Tokenizer::Lines { trim: true }),
+            ["first", "second"]
+        );
+    }
+
+    #[test]
+    fn replacement_character_breaks_words() {
+        assert_eq!(default_words("ab\u{fffd}cd"), ["ab", "cd"]);
+        let tokenizer = Tokenizer::Delimiters(vec![',']);
+        assert_eq!(words("ab\u{fffd}cd,ef", &tokenizer), ["ab", "cd", "ef"]);
+    }
+
+    #[test]
+    fn splits_at_only_accepts_bytes_no_token_contains() {
+        let words = Options::default().tokenizer;
+        assert!(words.splits_at(b' '));
+        assert!(words.splits_at(b'\n'));
+        assert!(!words.splits_at(b'a'));
+        assert!(!words.splits_at(0xc3));
+        let lines = Tokenizer::Lines { trim: true };
+        assert!(lines.splits_at(b'\n'));
+        assert!(!lines.splits_at(b' '));
+        let delimiters = Tokenizer::Delimiters(vec![',', '\u{e9}']);
+        assert!(delimiters.splits_at(b','));
+        assert!(!delimiters.splits_at(b' '));
+    }
+
+    #[test]
+    fn counter_sorts_by_count_then_alphabetically() {
+        assert_eq!(
+            count("b a c b a b", Options::default()),
+            pairs(&[("b", 3), ("a", 2), ("c", 1)])
+        );
+        assert_eq!(count("", Options::default()), pairs(&[]));
+    }
+
+    #[test]
+    fn min_length_filters_short_words() {
+        let options = Options {
+            min_length: 3,
+            ..Options::default()
+        };
+        assert_eq!(
+            count("a an ant ants a an", options),
+            pairs(&[("ant", 1), ("ants", 1)])
+        );
+    }
+
+    #[test]
+    fn min_length_counts_characters_not_bytes() {
+        let options = Options {
+            min_length: 3,
+            ..Options::default()
+        };
+        assert_eq!(count("été ét", options), pairs(&[("été", 1)]));
+    }
+
+    #[test]
+    fn max_length_drops_long_tokens() {
+        let options = Options {
+            max_length: 5,
+            ..Options::default()
+        };
+        assert_eq!(
+            count("see aGVsbG8gd29ybGQ= there", options),
+            pairs(&[("see", 1), ("there", 1)])
+        );
+    }
+
+    #[test]
+    fn length_filters_remember_distinct_dropped_words() {
+        let options = Options {
+            min_length: 2,
+            max_length: 3,
+            ..Options::default()
+        };
+        let count = |text: &str| {
+            let mut counter = WordCounter::new(options.clone());
+            counter.feed(text);
+            counter.into_counts()
+        };
+        let mut total = count("a a b ok long");
+        total.merge(count("b longer long"));
+        assert_eq!(total.too_short.len(), 2);
+        assert_eq!(total.too_long.len(), 2);
+        assert_eq!(total.freq, HashMap::from([("ok".to_string(), 1)]));
+    }
+
+    #[test]
+    fn case_is_kept_unless_folded() {
+        assert_eq!(
+            count("The the THE", Options::default()),
+            pairs(&[("THE", 1), ("The", 1), ("the", 1)])
+        );
+        let options = Options {
+            ignore_case: true,
+            ..Options::default()
+        };
+        assert_eq!(count("The the THE", options), pairs(&[("the", 3)]));
+    }
+
+    #[test]
+    fn case_folding_handles_special_cases() {
+        let options = Options {
+            ignore_case: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            count("Straße STRASSE strasse", options),
+            pairs(&[("strasse", 3)])
+        );
+    }
+
+    #[test]
+    fn folded_words_show_their_most_common_spelling() {
+        let options = Options {
+            ignore_case: true,
+            ..Options::default()
+        };
+        let mut counter = WordCounter::new(options);
+        counter.feed("NASA nasa NASA");
+        counter.feed("NASA Nasa nasa");
+        assert_eq!(counter.results(), pairs(&[("nasa", 6)]));
+        assert_eq!(counter.counts().display_form("nasa"), "NASA");
+        assert_eq!(counter.counts().display_form("missing"), "missing");
+    }
+
+    #[test]
+    fn display_form_ties_break_alphabetically() {
+        let mut counter = WordCounter::new(Options {
+            ignore_case: true,
+            ..Options::default()
+        });
+        counter.feed("nasa Nasa");
+        assert_eq!(counter.counts().display_form("nasa"), "Nasa");
+    }
+
+    #[test]
+    fn turkish_locale_folds_dotted_and_dotless_i() {
+        let options = Options {
+            ignore_case: true,
+            ..Options::default()
+        };
+        let turkish = Options {
+            locale: Locale::Turkish,
+            ..options.clone()
+        };
+        let text = "Istanbul ıstanbul İzmir izmir";
+        assert_eq!(
+            count(text, turkish),
+            pairs(&[("izmir", 2), ("ıstanbul", 2)])
+        );
+        assert_eq!(
+            count(text, options),
+            pairs(&[
+                ("istanbul", 1),
+                ("izmir", 1),
+                ("i\u{307}zmir", 1),
+                ("ıstanbul", 1)
+            ])
+        );
+    }
+
+    #[test]
+    fn turkic_folding_handles_a_combining_dot() {
+        assert_eq!(fold_case_turkic("I\u{307}STANBUL"), "istanbul");
+        assert_eq!(fold_case_turkic("DIŞ"), "dış");
+        assert_eq!(Locale::Azerbaijani.fold()("İŞ"), "iş");
+        assert_eq!(Locale::Default.fold()("Straße"), "strasse");
+    }
+
+    #[test]
+    fn normalize_composes_accents_and_folds_case() {
+        let options = Options {
+            normalize: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            count("cafe\u{301} CAF\u{c9} café", options),
+            pairs(&[("café", 3)])
+        );
+    }
+
+    #[test]
+    fn stemming_merges_inflections() {
+        let options = Options {
+            stem: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            count("cats cat running run was bus", options),
+            pairs(&[("cat", 2), ("run", 2), ("bus", 1), ("was", 1)])
+        );
+    }
+
+    #[test]
+    fn stem_table() {
+        let table = [
+            // Step 1a plurals
+            ("caresses", "caress"),
+            ("ponies", "poni"),
+            ("ties", "tie"),
+            ("caress", "caress"),
+            ("cats", "cat"),
+            ("dogs", "dog"),
+            ("runs", "run"),
+            ("boxes", "boxe"),
+            ("bus", "bus"),
+            ("was", "was"),
+            // Step 1b -eed, -ed and -ing
+            ("feed", "feed"),
+            ("agreed", "agree"),
+            ("plastered", "plaster"),
+            ("bled", "bled"),
+            ("motoring", "motor"),
+            ("sing", "sing"),
+            ("jumped", "jump"),
+            ("jumping", "jump"),
+            ("played", "play"),
+            // -at, -bl and -iz get their e back
+            ("conflated", "conflate"),
+            ("troubled", "trouble"),
+            ("sized", "size"),
+            // Doubled consonants are undone, except l, s and z
+            ("hopping", "hop"),
+            ("hopped", "hop"),
+            ("tanned", "tan"),
+            ("running", "run"),
+            ("falling", "fall"),
+            ("hissing", "hiss"),
+            ("fizzed", "fizz"),
+            // A short cvc stem gets its e back
+            ("hoping", "hope"),
+            ("filing", "file"),
+            ("failing", "fail"),
+            // Case, short words and anything not plain ASCII letters
+            ("Running", "run"),
+            ("is", "is"),
+            ("naïve", "naïve"),
+            ("x-rays", "x-rays"),
+            ("42s", "42s"),
+        ];
+        for (word, expected) in table {
+            assert_eq!(stem(word), expected, "stem({:?})", word);
+        }
+    }
+
+    #[test]
+    fn numbers_can_be_excluded_or_kept_alone() {
+        let text = "1 apples 2.5 1,000 -3 pears 1";
+        let exclude = Options {
+            numbers: Numbers::Exclude,
+            ..Options::default()
+        };
+        assert_eq!(count(text, exclude), pairs(&[("apples", 1), ("pears", 1)]));
+        let only = Options {
+            numbers: Numbers::Only,
+            ..Options::default()
+        };
+        assert_eq!(
+            count(text, only),
+            pairs(&[("1", 2), ("1,000", 1), ("2.5", 1), ("3", 1)])
+        );
+    }
+
+    #[test]
+    fn excluded_words_are_counted_separately() {
+        let mut counter = WordCounter::new(Options {
+            ignore_case: true,
+            exclude: vec!["The".to_string()],
+            exclude_patterns: vec!["un*".to_string(), "*ing".to_string()],
+            ..Options::default()
+        });
+        counter.feed("the cat THE unhappy singing dog");
+        assert_eq!(counter.results(), pairs(&[("cat", 1), ("dog", 1)]));
+        assert_eq!(counter.counts().excluded, 4);
+    }
+
+    #[test]
+    fn prefix_keeps_words_starting_with_any_of_them() {
+        let mut counter = WordCounter::new(Options {
+            tokenizer: Tokenizer::Pattern(Pattern::parse("[#@]?\\w+").unwrap()),
+            prefixes: vec!["#".to_string(), "@".to_string()],
+            ..Options::default()
+        });
+        counter.feed("#rust is #fun says @ferris #rust");
+        assert_eq!(
+            counter.results(),
+            pairs(&[("#rust", 2), ("#fun", 1), ("@ferris", 1)])
+        );
+        assert_eq!(counter.counts().filtered, 2);
+    }
+
+    #[test]
+    fn suffix_applies_after_case_folding() {
+        let options = Options {
+            ignore_case: true,
+            suffixes: vec!["ING".to_string(), "ed".to_string()],
+            ..Options::default()
+        };
+        assert_eq!(
+            count("Singing walked RUNNING run", options),
+            pairs(&[("running", 1), ("singing", 1), ("walked", 1)])
+        );
+    }
+
+    #[test]
+    fn prefix_and_suffix_must_both_match() {
+        let mut counter = WordCounter::new(Options {
+            prefixes: vec!["re".to_string(), "un".to_string()],
+            suffixes: vec!["ing".to_string()],
+            ..Options::default()
+        });
+        counter.feed("reading undoing singing rerun unread");
+        assert_eq!(counter.results(), pairs(&[("reading", 1), ("undoing", 1)]));
+        assert_eq!(counter.counts().filtered, 3);
+    }
+
+    #[test]
+    fn phrases_match_across_punctuation_and_line_breaks() {
+        let mut counter = WordCounter::new(Options {
+            phrases: vec!["Machine learning".to_string(), "et al".to_string()],
+            ..Options::default()
+        });
+        counter.feed("machine learning, as in Smith et\nal. Machine.\nLearning again");
+        assert_eq!(counter.counts().phrases["machine learning"], 2);
+        assert_eq!(counter.counts().phrases["et al"], 1);
+    }
+
+    #[test]
+    fn phrases_overlap_and_carry_across_pieces() {
+        let mut counter = WordCounter::new(Options {
+            phrases: vec!["ha ha".to_string(), "new york city".to_string()],
+            ..Options::default()
+        });
+        counter.feed("ha ha ha. New York");
+        counter.feed("city");
+        assert_eq!(counter.counts().phrases["ha ha"], 2);
+        assert_eq!(counter.counts().phrases["new york city"], 1);
+    }
+
+    #[test]
+    fn phrases_never_seen_are_counted_as_zero() {
+        let mut counter = WordCounter::new(Options {
+            phrases: vec!["not here".to_string(), String::new()],
+            ..Options::default()
+        });
+        counter.feed("nothing to see");
+        assert_eq!(
+            counter.counts().phrases,
+            HashMap::from([("not here".to_string(), 0)])
+        );
+    }
+
+    #[test]
+    fn glob_match_supports_prefix_suffix_and_infix() {
+        assert!(glob_match("pre*", "prefix"));
+        assert!(!glob_match("pre*", "spree"));
+        assert!(glob_match("*fix", "suffix"));
+        assert!(glob_match("*ff*", "suffix"));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[test]
+    fn ngrams_stop_at_sentence_ends_unless_crossing() {
+        let options = Options {
+            ngrams: 2,
+            ..Options::default()
+        };
+        assert_eq!(
+            count("a b. c d", options.clone()),
+            pairs(&[("a b", 1), ("c d", 1)])
+        );
+        let crossing = Options {
+            cross_sentences: true,
+            ..options
+        };
+        assert_eq!(
+            count("a b. c d", crossing),
+            pairs(&[("a b", 1), ("b c", 1), ("c d", 1)])
+        );
+    }
+
+    #[test]
+    fn filtered_words_do_not_break_ngrams() {
+        let options = Options {
+            ngrams: 2,
+            min_length: 2,
+            ..Options::default()
+        };
+        assert_eq!(count("big a dog", options), pairs(&[("big dog", 1)]));
+    }
+
+    #[test]
+    fn incremental_feed_equals_one_big_feed() {
+        let text = "It was the best of times, it was the worst of times. It was \
+                    the age of wisdom; it was the age of foolishness!";
+        for options in [
+            Options::default(),
+            Options {
+                ignore_case: true,
+                ngrams: 3,
+                ..Options::default()
+            },
+            Options {
+                chars: true,
+                ..Options::default()
+            },
+        ] {
+            let mut whole = WordCounter::new(options.clone());
+            whole.feed(text);
+            let mut pieces = WordCounter::new(options);
+            for piece in text.split_inclusive(' ') {
+                pieces.feed(piece);
+            }
+            assert_eq!(pieces.results(), whole.results());
+            assert_eq!(pieces.counts().bytes, whole.counts().bytes);
+        }
+    }
+
+    #[test]
+    fn feed_reader_matches_feed_across_chunks() {
+        let text = "alpha beta, gamma. delta-epsilon don't\n".repeat(CHUNK_SIZE / 10);
+        assert!(text.len() > 3 * CHUNK_SIZE);
+        let options = Options {
+            ngrams: 2,
+            ..Options::default()
+        };
+        let mut whole = WordCounter::new(options.clone());
+        whole.feed(&text);
+        let mut streamed = WordCounter::new(options);
+        streamed
+            .feed_reader(&mut Cursor::new(text.as_bytes()), 0)
+            .unwrap();
+        assert_eq!(streamed.results(), whole.results());
+    }
+
+    #[test]
+    fn feed_reader_bounds_the_carry_when_no_byte_is_safe() {
+        let pattern = |src| Options {
+            tokenizer: Tokenizer::Pattern(Pattern::parse(src).unwrap()),
+            ..Options::default()
+        };
+        let text = "ab cd\n".repeat(MAX_CARRY / 2);
+        let mut streamed = WordCounter::new(pattern(".+"));
+        streamed
+            .feed_reader(&mut Cursor::new(text.as_bytes()), 0)
+            .unwrap();
+        let results = streamed.results();
+        assert!(results.len() > 1);
+        assert!(
+            results
+                .iter()
+                .all(|(w, _)| w.len() <= MAX_CARRY + CHUNK_SIZE)
+        );
+        let total: usize = results.iter().map(|(w, n)| w.len() * n).sum();
+        assert_eq!(total, text.len());
+
+        // Without whitespace the cut still falls between characters
+        let text = "\u{e9}".repeat(MAX_CARRY);
+        let mut whole = WordCounter::new(pattern("."));
+        whole.feed(&text);
+        let mut streamed = WordCounter::new(pattern("."));
+        streamed
+            .feed_reader(&mut Cursor::new(text.as_bytes()), 0)
+            .unwrap();
+        assert_eq!(streamed.results(), whole.results());
+    }
+
+    #[test]
+    fn feed_reader_replaces_invalid_utf8_by_default() {
+        let mut counter = WordCounter::new(Options::default());
+        counter
+            .feed_reader(&mut Cursor::new(&b"ab\xffcd ab caf\xe9"[..]), 0)
+            .unwrap();
+        assert_eq!(
+            counter.results(),
+            pairs(&[("ab", 2), ("caf", 1), ("cd", 1)])
+        );
+    }
+
+    #[test]
+    fn feed_reader_reports_the_offset_of_invalid_utf8_when_strict() {
+        let options = Options {
+            strict_utf8: true,
+            ..Options::default()
+        };
+        let mut counter = WordCounter::new(options.clone());
+        let err = counter
+            .feed_reader(&mut Cursor::new(&b"ok ok\x80"[..]), 0)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "invalid UTF-8 at byte 5");
+
+        let mut counter = WordCounter::new(options);
+        let err = counter
+            .feed_reader(&mut Cursor::new(&b"\xc3("[..]), 100)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid UTF-8 at byte 100");
+    }
+
+    #[test]
+    fn chars_mode_counts_characters() {
+        let options = Options {
+            chars: true,
+            ignore_case: true,
+            letters_only: true,
+            ..Options::default()
+        };
+        assert_eq!(count("aA b!", options), pairs(&[("a", 2), ("b", 1)]));
+    }
+
+    #[test]
+    fn context_keeps_the_first_occurrence_with_five_words_each_side() {
+        let mut counter = WordCounter::new(Options {
+            context: true,
+            ..Options::default()
+        });
+        counter.feed("one two three four five six seven eight nine ten eleven twelve");
+        counter.feed("seven again");
+        assert_eq!(
+            counter.counts().contexts["seven"],
+            "two three four five six [seven] eight nine ten eleven twelve"
+        );
+        assert_eq!(counter.counts().contexts["again"], "seven [again]");
+    }
+
+    #[test]
+    fn context_brackets_whole_ngrams() {
+        let mut counter = WordCounter::new(Options {
+            context: true,
+            ngrams: 2,
+            ..Options::default()
+        });
+        counter.feed("a b c d");
+        assert_eq!(counter.counts().contexts["b c"], "a [b c] d");
+    }
+
+    #[test]
+    fn snippets_are_capped() {
+        let long = "x".repeat(100);
+        let text = format!("{long} {long} hit {long}");
+        let mut counter = WordCounter::new(Options {
+            context: true,
+            ..Options::default()
+        });
+        counter.feed(&text);
+        let snippet = &counter.counts().contexts["hit"];
+        assert!(snippet.chars().count() <= MAX_CONTEXT);
+        assert!(snippet.contains("[hit]"));
+    }
+
+    #[test]
+    fn merge_adds_counts_and_keeps_earlier_context() {
+        let options = Options {
+            context: true,
+            ignore_case: true,
+            ..Options::default()
+        };
+        let mut first = WordCounter::new(options.clone());
+        first.feed("first Word");
+        first.add_file();
+        let mut second = WordCounter::new(options);
+        second.feed("second word WORD");
+        second.add_file();
+
+        let mut total = first.into_counts();
+        total.merge(second.into_counts());
+        assert_eq!(total.freq["word"], 3);
+        assert_eq!(total.contexts["word"], "first [Word]");
+        assert_eq!(total.display_form("word"), "WORD");
+        assert_eq!(total.files, 2);
+        assert_eq!(total.bytes, 26);
+    }
+
+    #[test]
+    fn cooccur_counts_pairs_within_the_window() {
+        let options = Options {
+            cooccur: Some(2),
+            ..Options::default()
+        };
+        // "x" and "y" are at most two apart three times
+        assert_eq!(
+            count("x a y. x b y. y x", options),
+            pairs(&[
+                ("x + y", 3),
+                ("a + x", 1),
+                ("a + y", 1),
+                ("b + x", 1),
+                ("b + y", 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn cooccur_skips_stopwords_and_self_pairs() {
+        let options = Options {
+            cooccur: Some(1),
+            ignore_case: true,
+            exclude: vec!["the".to_string()],
+            ..Options::default()
+        };
+        assert_eq!(
+            count("Cat the dog dog THE cat", options),
+            pairs(&[("cat + dog", 2)])
+        );
+    }
+
+    #[test]
+    fn cooccur_prunes_rare_pairs_over_the_cap() {
+        let mut counter = WordCounter::new(Options {
+            cooccur: Some(1),
+            max_pairs: 4,
+            ..Options::default()
+        });
+        // The fifth pair goes over the cap and every pair seen once is
+        // dropped, so "i + j" starts again from zero
+        counter.feed("a b. a b. a b. c d. e f. g h. i j. i j");
+        assert_eq!(counter.results(), pairs(&[("a + b", 3), ("i + j", 1)]));
+        assert_eq!(counter.counts().pruned, 4);
+    }
+
+    #[test]
+    fn stats_count_every_token_and_the_largest_map() {
+        let mut counter = WordCounter::new(Options {
+            exclude: vec!["the".to_string()],
+            ..Options::default()
+        });
+        counter.feed("the cat and the dog. ");
+        counter.feed("a cat");
+        let counts = counter.into_counts();
+        // Excluded words are read too, so they count as tokens
+        assert_eq!(
+            counts.stats,
+            Stats {
+                tokens: 7,
+                peak_distinct: 4,
+            }
+        );
+        assert_eq!(counts.bytes, 26);
+    }
+
+    #[test]
+    fn stats_add_up_when_merged() {
+        let count = |text: &str| {
+            let mut counter = WordCounter::new(Options::default());
+            counter.feed(text);
+            counter.into_counts()
+        };
+        let mut total = count("a b c");
+        total.merge(count("c d"));
+        assert_eq!(total.stats.tokens, 5);
+        assert_eq!(total.stats.peak_distinct, 4);
+    }
+
+    #[test]
+    fn stats_see_pairs_before_pruning() {
+        let mut counter = WordCounter::new(Options {
+            cooccur: Some(1),
+            max_pairs: 2,
+            ..Options::default()
+        });
+        counter.feed("a b. c d. e f");
+        assert_eq!(counter.counts().stats.peak_distinct, 3);
+        assert!(counter.counts().freq.is_empty());
+    }
+
+    #[test]
+    fn smallest_matches_a_full_sort() {
+        let items = [5, 3, 9, 1, 7, 3, 8];
+        let mut sorted = items.to_vec();
+        sorted.sort();
+        for n in 0..=items.len() + 1 {
+            let expected: Vec<i32> = sorted.iter().copied().take(n).collect();
+            assert_eq!(smallest(items.iter().copied(), n), expected);
+        }
+        assert_eq!(smallest(items.iter().copied(), usize::MAX), sorted);
+    }
+
+    #[test]
+    fn ellipsize_marks_cuts() {
+        assert_eq!(ellipsize("short", 10), "short");
+        assert_eq!(ellipsize("exactly", 7), "exactly");
+        assert_eq!(ellipsize("truncated", 5), "trun…");
+        assert_eq!(ellipsize("äöüäöü", 4), "äöü…");
+    }
+}
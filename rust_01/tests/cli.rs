@@ -0,0 +1,1355 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run wordfreq with `args`, feeding `stdin` when given.
+fn wordfreq(args: &[&str], stdin: Option<&str>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust_01"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("spawn wordfreq");
+    let mut input = child.stdin.take().unwrap();
+    // wordfreq may exit without reading stdin it was not asked to read
+    if let Err(e) = input.write_all(stdin.unwrap_or("").as_bytes()) {
+        assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe, "{e}");
+    }
+    drop(input);
+    child.wait_with_output().expect("wait for wordfreq")
+}
+
+fn stdout(args: &[&str], stdin: Option<&str>) -> String {
+    let out = wordfreq(args, stdin);
+    assert!(out.status.success(), "{:?} failed: {:?}", args, out);
+    String::from_utf8(out.stdout).unwrap()
+}
+
+/// The same text counted from an argument and from stdin.
+fn both(flags: &[&str], text: &str) -> (String, String) {
+    let mut with_arg = flags.to_vec();
+    with_arg.push(text);
+    (stdout(&with_arg, None), stdout(flags, Some(text)))
+}
+
+#[test]
+fn one_word_per_line_by_default() {
+    let (arg, stdin) = both(&[], "the cat saw the dog");
+    assert_eq!(arg, "the: 2\ncat: 1\ndog: 1\nsaw: 1\n");
+    assert_eq!(stdin, arg);
+}
+
+#[test]
+fn inline_joins_words_on_one_line() {
+    let (arg, stdin) = both(&["--inline"], "the cat saw the dog");
+    assert_eq!(arg, "the: 2  cat: 1  dog: 1  saw: 1\n");
+    assert_eq!(stdin, arg);
+}
+
+#[test]
+fn separator_replaces_the_inline_joiner() {
+    let (arg, stdin) = both(&["--inline", "--separator", ", "], "b a b");
+    assert_eq!(arg, "b: 2, a: 1\n");
+    assert_eq!(stdin, arg);
+
+    let (arg, stdin) = both(&["--inline", "--separator", "\\t"], "b a b");
+    assert_eq!(arg, "b: 2\ta: 1\n");
+    assert_eq!(stdin, arg);
+}
+
+#[test]
+fn separator_requires_inline() {
+    let out = wordfreq(&["--separator", ", ", "a b"], None);
+    assert_eq!(out.status.code(), Some(2));
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        err.contains("--separator only applies with --inline"),
+        "{err}"
+    );
+}
+
+#[test]
+fn print0_output_parses_back_into_the_word_list() {
+    let out = wordfreq(
+        &["--lines", "--print0", "--summary"],
+        Some("new york\nparis\nnew york\n"),
+    );
+    assert!(out.status.success());
+    let fields: Vec<&[u8]> = out.stdout.split(|&b| b == 0).collect();
+    // Every field is NUL-terminated, so the split leaves an empty tail
+    let (tail, fields) = fields.split_last().unwrap();
+    assert!(tail.is_empty());
+    let words: Vec<(String, usize)> = fields
+        .chunks(2)
+        .map(|pair| {
+            let word = String::from_utf8(pair[0].to_vec()).unwrap();
+            let count = std::str::from_utf8(pair[1]).unwrap().parse().unwrap();
+            (word, count)
+        })
+        .collect();
+    assert_eq!(
+        words,
+        [("new york".to_string(), 2), ("paris".to_string(), 1)]
+    );
+}
+
+#[test]
+fn print0_rejects_format() {
+    let out = wordfreq(&["--print0", "--format", "json", "a"], None);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn stats_go_to_stderr_only() {
+    let plain = wordfreq(&["a b a"], None);
+    let out = wordfreq(&["--stats", "a b a"], None);
+    assert!(out.status.success());
+    assert_eq!(out.stdout, plain.stdout);
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(err.contains("tokens: 3\n"), "{err}");
+    assert!(err.contains("peak distinct: 2\n"), "{err}");
+    assert!(err.contains("bytes read: 5\n"), "{err}");
+}
+
+#[test]
+fn stdin_is_ignored_when_text_is_given() {
+    assert_eq!(stdout(&["a b a"], Some("ignored")), "a: 2\nb: 1\n");
+}
+
+#[test]
+fn stdin_alone_is_counted() {
+    assert_eq!(stdout(&[], Some("a b a")), "a: 2\nb: 1\n");
+}
+
+#[test]
+fn stdin_flag_adds_stdin_to_text() {
+    let combined = stdout(&["--stdin", "extra words"], Some("more words"));
+    assert_eq!(combined, "words: 2\nextra: 1\nmore: 1\n");
+    // Same result as giving all of it as arguments
+    assert_eq!(combined, stdout(&["extra words", "more words"], None));
+}
+
+#[test]
+fn length_and_count_filters_compose() {
+    let text = "a a b cc cc dd dd dd ee ee ee ee fffff fffff gg";
+    let out = stdout(
+        &[
+            "--summary",
+            "--min-length",
+            "2",
+            "--max-length",
+            "4",
+            "--min-count",
+            "2",
+            "--max-count",
+            "3",
+            text,
+        ],
+        None,
+    );
+    // a and b are too short, fffff too long, gg too rare, ee too common
+    let expected = "\
+dd: 3
+cc: 2
+-- summary --
+tokens: 10
+distinct: 4
+type/token ratio: 0.4000
+average length: 2.00
+hapax legomena: 1
+bytes: 47
+files: 0
+excluded: 0
+filtered: 0
+too short: 2
+too long: 1
+under min count: 1
+over max count: 1
+";
+    assert_eq!(out, expected);
+}
+
+/// Path of a fixture in tests/data.
+fn data(name: &str) -> String {
+    format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+#[test]
+fn files_are_read_and_counted() {
+    let cat = data("cat.txt");
+    assert_eq!(
+        stdout(&["--file", &cat, "--top", "3"], None),
+        "the: 4\ncat: 3\na: 2\n"
+    );
+    assert_eq!(
+        stdout(&["-f", &cat, "--top", "3"], None),
+        "the: 4\ncat: 3\na: 2\n"
+    );
+}
+
+#[test]
+fn repeated_files_are_concatenated() {
+    let cat = data("cat.txt");
+    let out = stdout(&["--file", &cat, "--file", &cat, "--top", "3"], None);
+    assert_eq!(out, "the: 8\ncat: 6\na: 4\n");
+}
+
+#[test]
+fn file_dash_reads_stdin() {
+    let cat = std::fs::read_to_string(data("cat.txt")).unwrap();
+    let from_stdin = stdout(&["--file", "-", "--top", "3"], Some(&cat));
+    assert_eq!(from_stdin, "the: 4\ncat: 3\na: 2\n");
+    // Alongside a real file
+    assert_eq!(
+        stdout(
+            &["--file", &data("cat.txt"), "--file", "-", "--top", "3"],
+            Some(&cat)
+        ),
+        "the: 8\ncat: 6\na: 4\n"
+    );
+}
+
+#[test]
+fn text_and_files_are_counted_together() {
+    let out = stdout(
+        &["cat cat fox", "--file", &data("cat.txt"), "--top", "2"],
+        None,
+    );
+    assert_eq!(out, "cat: 5\nthe: 4\n");
+}
+
+#[test]
+fn an_unreadable_file_is_named() {
+    let missing = data("missing.txt");
+    let out = wordfreq(&["--file", &data("cat.txt"), "--file", &missing], None);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        err.starts_with(&format!("wordfreq: cannot read {}: ", missing)),
+        "{err}"
+    );
+}
+
+#[test]
+fn per_file_lists_each_file_then_the_total() {
+    let cat = data("cat.txt");
+    let out = stdout(
+        &[
+            "--per-file",
+            "cat cat cat dog dog",
+            "-f",
+            &cat,
+            "--top",
+            "2",
+        ],
+        None,
+    );
+    let expected = format!(
+        "\
+== <args> (5 words) ==
+cat: 3
+dog: 2
+
+== {cat} (15 words) ==
+the: 4
+cat: 3
+
+== TOTAL (20 words) ==
+cat: 6
+the: 4
+"
+    );
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn per_file_sections_apply_the_filters() {
+    let cat = data("cat.txt");
+    let out = stdout(
+        &[
+            "--per-file",
+            "The THE the cat cat dog a",
+            "-f",
+            &cat,
+            "--top",
+            "2",
+            "--ignore-case",
+            "--min-length",
+            "3",
+        ],
+        None,
+    );
+    // "a" and "on" no longer count towards either input's words
+    let expected = format!(
+        "\
+== <args> (6 words) ==
+THE: 3
+cat: 2
+
+== {cat} (12 words) ==
+the: 4
+cat: 3
+
+== TOTAL (18 words) ==
+the: 7
+cat: 5
+"
+    );
+    assert_eq!(out, expected);
+}
+
+/// A parsed JSON value, enough to check wordfreq's `--format json` output.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Json {
+        let mut chars = text.trim_end().chars().peekable();
+        let value = Json::value(&mut chars);
+        assert_eq!(chars.next(), None, "trailing data after JSON value");
+        value
+    }
+
+    fn value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Json {
+        match chars.peek().copied() {
+            Some('{') => {
+                chars.next();
+                let mut fields = Vec::new();
+                while chars.next_if_eq(&'}').is_none() {
+                    if !fields.is_empty() {
+                        assert_eq!(chars.next(), Some(','));
+                    }
+                    let Json::Str(key) = Json::value(chars) else {
+                        panic!("object key is not a string");
+                    };
+                    assert_eq!(chars.next(), Some(':'));
+                    fields.push((key, Json::value(chars)));
+                }
+                Json::Obj(fields)
+            }
+            Some('[') => {
+                chars.next();
+                let mut items = Vec::new();
+                while chars.next_if_eq(&']').is_none() {
+                    if !items.is_empty() {
+                        assert_eq!(chars.next(), Some(','));
+                    }
+                    items.push(Json::value(chars));
+                }
+                Json::Arr(items)
+            }
+            Some('"') => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next().expect("unterminated string") {
+                        '"' => return Json::Str(s),
+                        '\\' => match chars.next().expect("dangling escape") {
+                            'n' => s.push('\n'),
+                            'r' => s.push('\r'),
+                            't' => s.push('\t'),
+                            'u' => {
+                                let hex: String = chars.by_ref().take(4).collect();
+                                let code = u32::from_str_radix(&hex, 16).unwrap();
+                                s.push(char::from_u32(code).unwrap());
+                            }
+                            c @ ('"' | '\\' | '/') => s.push(c),
+                            c => panic!("invalid escape \\{c}"),
+                        },
+                        c if (c as u32) < 0x20 => panic!("raw control character in string"),
+                        c => s.push(c),
+                    }
+                }
+            }
+            _ => {
+                let word: String =
+                    std::iter::from_fn(|| chars.next_if(|c| !",]}".contains(*c))).collect();
+                match word.as_str() {
+                    "null" => Json::Null,
+                    "true" => Json::Bool(true),
+                    "false" => Json::Bool(false),
+                    n => Json::Num(n.parse().unwrap_or_else(|_| panic!("bad value {n:?}"))),
+                }
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Obj(fields) => &fields.iter().find(|(k, _)| k == key).expect(key).1,
+            _ => panic!("not an object: {self:?}"),
+        }
+    }
+
+    fn items(&self) -> &[Json] {
+        match self {
+            Json::Arr(items) => items,
+            _ => panic!("not an array: {self:?}"),
+        }
+    }
+
+    fn str(&self) -> &str {
+        match self {
+            Json::Str(s) => s,
+            _ => panic!("not a string: {self:?}"),
+        }
+    }
+
+    fn num(&self) -> f64 {
+        match self {
+            Json::Num(n) => *n,
+            _ => panic!("not a number: {self:?}"),
+        }
+    }
+}
+
+/// (word, count, rank) of each object in a JSON word list.
+fn json_words(list: &Json) -> Vec<(String, f64, f64)> {
+    list.items()
+        .iter()
+        .map(|w| {
+            (
+                w.get("word").str().to_string(),
+                w.get("count").num(),
+                w.get("rank").num(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn json_output_parses_in_rank_order() {
+    let out = stdout(
+        &[
+            "--format",
+            "json",
+            "say a\"b c\\d a\"b say a\"b x say a\"b c\\d",
+        ],
+        None,
+    );
+    let words = json_words(&Json::parse(&out));
+    assert_eq!(
+        words,
+        [
+            ("a\"b".to_string(), 4.0, 1.0),
+            ("say".to_string(), 3.0, 2.0),
+            ("c\\d".to_string(), 2.0, 3.0),
+            ("x".to_string(), 1.0, 4.0),
+        ]
+    );
+    // The quote is escaped in the raw text, not cut off
+    assert!(out.contains(r#""word":"a\"b""#), "{out}");
+}
+
+#[test]
+fn json_output_is_the_only_stdout() {
+    let out = stdout(
+        &[
+            "--format",
+            "json",
+            "--delimiters",
+            " ",
+            "--top",
+            "1",
+            "\"q\" \"q\" z",
+        ],
+        None,
+    );
+    assert_eq!(out.lines().count(), 1);
+    let words = json_words(&Json::parse(&out));
+    assert_eq!(words, [("\"q\"".to_string(), 2.0, 1.0)]);
+}
+
+#[test]
+fn csv_and_tsv_match_the_golden_files() {
+    let corpus = data("quoting.txt");
+    for (format, golden) in [("csv", "quoting.csv"), ("tsv", "quoting.tsv")] {
+        let expected = std::fs::read_to_string(data(golden)).unwrap();
+        let args = ["-f", &corpus, "--delimiters", " \\n", "--format", format];
+        assert_eq!(stdout(&args, None), expected, "{format}");
+
+        // --output writes the same bytes and leaves stdout empty
+        let path =
+            std::env::temp_dir().join(format!("wordfreq-golden-{}.{}", std::process::id(), format));
+        let mut with_output = args.to_vec();
+        with_output.extend(["--output", path.to_str().unwrap()]);
+        assert_eq!(stdout(&with_output, None), "");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            expected,
+            "{format}"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn equal_counts_are_listed_alphabetically() {
+    let fox = data("fox.txt");
+    for _ in 0..3 {
+        assert_eq!(
+            stdout(&["--file", &fox, "--top", "4"], None),
+            "The: 2\ndog: 2\nfox: 2\nthe: 2\n"
+        );
+    }
+    assert_eq!(
+        stdout(
+            &["--file", &fox, "--file", &data("cat.txt"), "--top", "3"],
+            None
+        ),
+        "the: 6\ncat: 3\ndog: 3\n"
+    );
+}
+
+#[test]
+fn sort_orders_only_the_shown_words() {
+    let fox = data("fox.txt");
+    let list = |flags: &[&str]| {
+        let mut args = vec!["--file", &fox, "--top", "5"];
+        args.extend(flags);
+        stdout(&args, None)
+    };
+    assert_eq!(
+        list(&["--sort", "alpha"]),
+        "The: 2\nbrown: 1\ndog: 2\nfox: 2\nthe: 2\n"
+    );
+    assert_eq!(
+        list(&["--sort", "length"]),
+        "The: 2\ndog: 2\nfox: 2\nthe: 2\nbrown: 1\n"
+    );
+    assert_eq!(
+        list(&["--sort", "length", "--reverse"]),
+        "brown: 1\nthe: 2\nfox: 2\ndog: 2\nThe: 2\n"
+    );
+    let out = wordfreq(&["--sort", "size", "x"], None);
+    assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn bottom_lists_the_rarest_words_and_min_count_filters_first() {
+    let cat = data("cat.txt");
+    assert_eq!(
+        stdout(&["-f", &cat, "--bottom", "3"], None),
+        "and: 1\ndog: 1\nhat: 1\n"
+    );
+    assert_eq!(
+        stdout(&["-f", &cat, "--min-count", "2"], None),
+        "the: 4\ncat: 3\na: 2\n"
+    );
+    assert_eq!(
+        stdout(&["-f", &cat, "--bottom", "2", "--min-count", "2"], None),
+        "a: 2\ncat: 3\n"
+    );
+    let out = wordfreq(&["--top", "2", "--bottom", "2", "x"], None);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn ngrams_stop_at_sentence_ends_unless_crossing() {
+    let text = "the big cat. the big dog";
+    assert_eq!(
+        stdout(&["--ngrams", "2", text], None),
+        "the big: 2\nbig cat: 1\nbig dog: 1\n"
+    );
+    assert_eq!(
+        stdout(&["--ngrams", "2", "--cross-sentences", text], None),
+        "the big: 2\nbig cat: 1\nbig dog: 1\ncat the: 1\n"
+    );
+    // A filtered word is skipped rather than splitting the run
+    assert_eq!(
+        stdout(
+            &["--ngrams", "2", "--min-length", "2", "the a big cat"],
+            None
+        ),
+        "big cat: 1\nthe big: 1\n"
+    );
+}
+
+#[test]
+fn percent_is_each_words_share_of_all_tokens() {
+    // 10 tokens: a 4, b 3, c 2, d 1
+    let text = "a b c a d b a c b a";
+    assert_eq!(
+        stdout(&["--percent", "--top", "2", text], None),
+        "a: 4 (40.00%, cumulative 40.00%)\nb: 3 (30.00%, cumulative 70.00%)\n"
+    );
+}
+
+#[test]
+fn percent_totals_come_after_filtering() {
+    // "x" is too short, leaving 6 tokens
+    let text = "x x aa bb aa cc aa bb";
+    let expected = "\
+word,count,percent,cumulative
+aa,3,50.00,50.00
+bb,2,33.33,83.33
+cc,1,16.67,100.00
+";
+    assert_eq!(
+        stdout(
+            &["--percent", "--format", "csv", "--min-length", "2", text],
+            None
+        ),
+        expected
+    );
+}
+
+#[test]
+fn normalize_composes_accents_and_folds_case() {
+    // "Cafe" + U+0301 COMBINING ACUTE ACCENT next to the precomposed forms
+    assert_eq!(
+        stdout(&["--normalize", "Cafe\u{301} café CAFÉ café"], None),
+        "café: 4\n"
+    );
+    assert_eq!(
+        stdout(&["--normalize", "ΣΟΦΟΣ σοφος σοφοσ σοφος"], None),
+        "σοφος: 4\n"
+    );
+    assert_eq!(
+        stdout(
+            &["--ignore-case", "Straße STRASSE strasse Straße ﬁne fine"],
+            None
+        ),
+        "Straße: 4\nfine: 2\n"
+    );
+    // Characters of the composed word, not bytes, meet --min-length; the
+    // word is still shown as it was spelled
+    assert_eq!(
+        stdout(
+            &[
+                "--normalize",
+                "--min-length",
+                "4",
+                "cafe\u{301} e\u{301}te\u{301}"
+            ],
+            None
+        ),
+        "cafe\u{301}: 1\n"
+    );
+}
+
+#[test]
+fn chars_counts_characters_with_their_code_points() {
+    assert_eq!(
+        stdout(&["--chars", "aAb a"], None),
+        "a U+0061: 2\nspace U+0020: 1\nA U+0041: 1\nb U+0062: 1\n"
+    );
+    assert_eq!(
+        stdout(
+            &["--chars", "--ignore-case", "--letters-only", "aAb, a!"],
+            None
+        ),
+        "a U+0061: 3\nb U+0062: 1\n"
+    );
+    assert_eq!(
+        stdout(&["--chars", "--format", "csv", "--top", "2", "aAb a"], None),
+        "char,codepoint,count\na,U+0061,2\n ,U+0020,1\n"
+    );
+}
+
+#[test]
+fn delimiters_split_only_on_the_given_characters() {
+    assert_eq!(
+        stdout(&["--delimiters", " ", "end. end. a,b a,b"], None),
+        "a,b: 2\nend.: 2\n"
+    );
+    assert_eq!(
+        stdout(&["--delimiters", ",\\t", "x y,x y\tz"], None),
+        "x y: 2\nz: 1\n"
+    );
+}
+
+#[test]
+fn token_pattern_counts_each_match() {
+    assert_eq!(
+        stdout(
+            &["--token-pattern", "#\\w+", "see #rust and #go, #rust!"],
+            None
+        ),
+        "#rust: 2\n#go: 1\n"
+    );
+    for args in [
+        &["--token-pattern", "(a|b)", "x"][..],
+        &["--token-pattern", "a", "--delimiters", " ", "x"],
+    ] {
+        let out = wordfreq(args, None);
+        assert_eq!(out.status.code(), Some(2), "{args:?}");
+        assert!(out.stdout.is_empty());
+    }
+}
+
+#[test]
+fn numbers_can_be_dropped_or_kept_alone() {
+    let text = "42 1,000 v2 1,00 word 42";
+    assert_eq!(
+        stdout(&["--no-numbers", text], None),
+        "1,00: 1\nv2: 1\nword: 1\n"
+    );
+    assert_eq!(stdout(&["--numbers-only", text], None), "42: 2\n1,000: 1\n");
+    // Signs and a bare decimal part survive when punctuation isn't trimmed
+    assert_eq!(
+        stdout(
+            &["--delimiters", " ", "--numbers-only", "+7 -3.14 .5 1,00"],
+            None
+        ),
+        "+7: 1\n-3.14: 1\n.5: 1\n"
+    );
+    let out = wordfreq(&["--no-numbers", "--numbers-only", "x"], None);
+    assert_eq!(out.status.code(), Some(2));
+}
+
+/// A small xorshift generator, so generated inputs are the same on
+/// every run.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[test]
+fn streamed_input_matches_a_reference_count_on_megabytes() {
+    // wordfreq reads its input in chunks of this size
+    const CHUNK_SIZE: usize = 64 * 1024;
+    const SYLLABLES: [&str; 10] = ["ka", "lo", "mi", "tre", "su", "é", "日本", "x", "zo", "ñu"];
+    const SPACES: [&str; 4] = [" ", "  ", "\n", "\t"];
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut text = String::new();
+    let mut boundary = CHUNK_SIZE;
+    while text.len() < 4 << 20 {
+        if text.len() + 40 > boundary {
+            // Pad up to just before the read boundary, then put a word
+            // (and a two-byte character) across it
+            while text.len() < boundary - 3 {
+                text.push_str(SPACES[rng.below(2)]);
+            }
+            text.truncate(boundary - 3);
+            text.push_str(" zé");
+            assert!(!text.is_char_boundary(boundary));
+            text.push_str(SYLLABLES[rng.below(SYLLABLES.len())]);
+            boundary += CHUNK_SIZE;
+        }
+        for _ in 0..1 + rng.below(4) {
+            text.push_str(SYLLABLES[rng.below(SYLLABLES.len())]);
+        }
+        text.push_str(SPACES[rng.below(SPACES.len())]);
+    }
+
+    let mut reference: std::collections::HashMap<&str, usize> = Default::default();
+    for word in text.split_whitespace() {
+        *reference.entry(word).or_default() += 1;
+    }
+    let mut reference: Vec<(&str, usize)> = reference.into_iter().collect();
+    reference.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    assert!(reference.len() > 1000);
+    let expected: String = reference
+        .iter()
+        .map(|(word, count)| format!("{word}: {count}\n"))
+        .collect();
+
+    let path = std::env::temp_dir().join(format!("wordfreq-stream-{}.txt", std::process::id()));
+    std::fs::write(&path, &text).unwrap();
+    let top = reference.len().to_string();
+    assert_eq!(
+        stdout(&["-f", path.to_str().unwrap(), "--top", &top], None),
+        expected
+    );
+    assert_eq!(stdout(&["-f", "-", "--top", &top], Some(&text)), expected);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn jobs_count_the_same_as_one_thread() {
+    // Over a megabyte, so it is split, with long tokens every so often
+    let mut text = "lorem ipsum, dolor sit amet\n".repeat(60_000);
+    for at in (1000..text.len() - 1000).step_by(99_991) {
+        text.replace_range(at..at + 8, "zzzzzzzz");
+    }
+    let path = std::env::temp_dir().join(format!("wordfreq-jobs-{}.txt", std::process::id()));
+    std::fs::write(&path, &text).unwrap();
+    let path = path.to_str().unwrap();
+    for flags in [
+        &[][..],
+        &["--delimiters", ","],
+        &["--token-pattern", "[a-z]+"],
+    ] {
+        let count = |jobs: &str| {
+            let mut args = flags.to_vec();
+            args.extend(["--jobs", jobs, "--top", "1000", "-f", path]);
+            stdout(&args, None)
+        };
+        let sequential = count("1");
+        assert!(sequential.contains("zzzzzzzz"), "{flags:?}");
+        for jobs in ["2", "3", "7"] {
+            assert_eq!(count(jobs), sequential, "{flags:?} --jobs {jobs}");
+        }
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn summary_follows_the_list() {
+    let out = stdout(
+        &[
+            "--summary",
+            "--min-length",
+            "2",
+            "The cat saw the mouse, a mouse ran",
+        ],
+        None,
+    );
+    // "a" is too short; The and the stay apart
+    let expected = "\
+mouse: 2
+The: 1
+cat: 1
+ran: 1
+saw: 1
+the: 1
+-- summary --
+tokens: 7
+distinct: 6
+type/token ratio: 0.8571
+average length: 3.57
+hapax legomena: 5
+bytes: 34
+files: 0
+excluded: 0
+filtered: 0
+too short: 1
+too long: 0
+under min count: 0
+over max count: 0
+";
+    assert_eq!(out, expected);
+
+    let out = stdout(&["--summary-only", "a b a"], None);
+    assert!(out.starts_with("-- summary --\ntokens: 3\n"), "{out}");
+}
+
+#[test]
+fn json_summary_has_every_statistic() {
+    let out = stdout(
+        &[
+            "--summary",
+            "--format",
+            "json",
+            "--top",
+            "1",
+            "--min-length",
+            "2",
+            "I saw a cat, a big cat and a dog",
+        ],
+        None,
+    );
+    let json = Json::parse(&out);
+    assert_eq!(
+        json_words(json.get("words")),
+        [("cat".to_string(), 2.0, 1.0)]
+    );
+    let summary = json.get("summary");
+    // saw cat big cat and dog, once "I" and three "a" are dropped
+    for (field, value) in [
+        ("tokens", 6.0),
+        ("distinct", 5.0),
+        ("type_token_ratio", 0.8333),
+        ("average_length", 3.0),
+        ("hapax", 4.0),
+        ("bytes", 32.0),
+    ] {
+        assert_eq!(summary.get(field).num(), value, "{field}");
+    }
+}
+
+#[test]
+fn excluded_words_are_dropped_and_reported() {
+    let text = "The cat saw the mouse, a mouse ran";
+    assert_eq!(
+        stdout(&["--exclude", "the,a", "--exclude", "cat", text], None),
+        "mouse: 2\nThe: 1\nran: 1\nsaw: 1\n"
+    );
+    assert_eq!(
+        stdout(
+            &[
+                "--ignore-case",
+                "--exclude",
+                "the",
+                "--exclude-pattern",
+                "m*",
+                "--exclude-pattern",
+                "*an",
+                text,
+            ],
+            None
+        ),
+        "a: 1\ncat: 1\nsaw: 1\n"
+    );
+    let out = stdout(
+        &[
+            "--summary-only",
+            "--exclude",
+            "the",
+            "The cat saw the mouse",
+        ],
+        None,
+    );
+    assert!(out.starts_with("-- summary --\ntokens: 4\n"), "{out}");
+    assert!(out.contains("\nexcluded: 1\n"), "{out}");
+}
+
+#[test]
+fn compare_splits_a_heavy_and_b_heavy_words() {
+    let (sea, rail) = (data("sea.txt"), data("rail.txt"));
+    let out = stdout(&["--compare", &sea, &rail, "--top", "3"], None);
+    let expected = format!(
+        "\
+== more common in {sea} ==
+sailed: +1.53 (2 vs 0)
+sea: +1.53 (2 vs 0)
+ship: +1.53 (2 vs 0)
+
+== more common in {rail} ==
+train: -2.05 (0 vs 3)
+rails: -1.64 (0 vs 2)
+station: -1.64 (0 vs 2)
+"
+    );
+    assert_eq!(out, expected);
+
+    // Swapping the files swaps the sections and the signs
+    let swapped = stdout(&["--compare", &rail, &sea, "--top", "1"], None);
+    assert_eq!(
+        swapped,
+        format!(
+            "== more common in {rail} ==\ntrain: +2.05 (3 vs 0)\n\n\
+             == more common in {sea} ==\nsailed: -1.53 (0 vs 2)\n"
+        )
+    );
+}
+
+#[test]
+fn markdown_table_escapes_pipes() {
+    let corpus = ["--delimiters", " ", "a|b cat a|b dog cat a|b"];
+    let mut args = vec!["--format", "markdown"];
+    args.extend(corpus);
+    assert_eq!(
+        stdout(&args, None),
+        "\
+| Word | Count |
+| --- | ---: |
+| a\\|b | 3 |
+| cat | 2 |
+| dog | 1 |
+"
+    );
+
+    args.push("--percent");
+    assert_eq!(
+        stdout(&args, None),
+        "\
+| Word | Count | Share |
+| --- | ---: | ---: |
+| a\\|b | 3 | 50.00% |
+| cat | 2 | 33.33% |
+| dog | 1 | 16.67% |
+"
+    );
+}
+
+#[test]
+fn quotes_are_trimmed_and_inner_marks_are_configurable() {
+    assert_eq!(
+        stdout(&["\"hello\" hello “hi” don't well-known"], None),
+        "hello: 2\ndon't: 1\nhi: 1\nwell-known: 1\n"
+    );
+    assert_eq!(
+        stdout(
+            &[
+                "--hyphens",
+                "split",
+                "--apostrophes",
+                "strip",
+                "don't well-known well"
+            ],
+            None
+        ),
+        "well: 2\ndont: 1\nknown: 1\n"
+    );
+    assert_eq!(
+        stdout(&["--hyphens", "strip", "well-known wellknown"], None),
+        "wellknown: 2\n"
+    );
+    let out = wordfreq(&["--hyphens", "nope", "x"], None);
+    assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn ignore_case_shows_the_most_common_spelling() {
+    assert_eq!(
+        stdout(&["--ignore-case", "NASA NASA NASA nasa The the THE"], None),
+        "NASA: 4\nTHE: 3\n"
+    );
+    assert_eq!(
+        stdout(
+            &[
+                "--ignore-case",
+                "--ngrams",
+                "2",
+                "New York new york New York"
+            ],
+            None
+        ),
+        "New York: 3\nYork new: 2\n"
+    );
+    let out = stdout(
+        &[
+            "--ignore-case",
+            "--format",
+            "json",
+            "Hello hello HELLO Hello",
+        ],
+        None,
+    );
+    assert_eq!(
+        out,
+        "[{\"word\":\"Hello\",\"key\":\"hello\",\"count\":4,\"rank\":1}]\n"
+    );
+}
+
+/// Count the lines of the log fixture at a fixed terminal width.
+fn log_lines(flags: &[&str]) -> String {
+    let out = Command::new(env!("CARGO_BIN_EXE_rust_01"))
+        .args(["--lines", "-f", &data("server.log")])
+        .args(flags)
+        .env("COLUMNS", "40")
+        .output()
+        .expect("run wordfreq");
+    assert!(out.status.success(), "{:?} failed: {:?}", flags, out);
+    String::from_utf8(out.stdout).unwrap()
+}
+
+#[test]
+fn lines_counts_repeated_log_lines() {
+    assert_eq!(
+        log_lines(&[]),
+        "\
+INFO  request served: 3
+DEBUG a very long debug line that go…: 2
+ERROR connection reset by peer: 2
+WARN  disk usage at 91%: 2
+  INFO  request served: 1
+INFO  request served   : 1
+INFO  server started: 1
+info  request served: 1
+"
+    );
+}
+
+#[test]
+fn lines_trim_and_ignore_case_merge_variants() {
+    assert_eq!(
+        log_lines(&["--trim", "--ignore-case", "--top", "2"]),
+        "INFO  request served: 6\nDEBUG a very long debug line that go…: 2\n"
+    );
+    // The 20-character INFO lines fall under the minimum once trimmed
+    assert_eq!(
+        log_lines(&["--trim", "--min-length", "21"]),
+        "\
+DEBUG a very long debug line that go…: 2
+ERROR connection reset by peer: 2
+WARN  disk usage at 91%: 2
+"
+    );
+}
+
+#[test]
+fn long_lines_are_counted_in_full() {
+    let out = stdout(
+        &[
+            "--lines",
+            "--format",
+            "json",
+            "-f",
+            &data("server.log"),
+            "--top",
+            "2",
+        ],
+        None,
+    );
+    let words = json_words(&Json::parse(&out));
+    assert_eq!(
+        words[1].0,
+        "DEBUG a very long debug line that goes on and on with a payload of \
+         key=value pairs well past any sensible terminal width"
+    );
+    assert_eq!(words[1].1, 2.0);
+}
+
+#[test]
+fn invalid_utf8_breaks_words_unless_strict() {
+    let path = std::env::temp_dir().join(format!("wordfreq-latin1-{}.txt", std::process::id()));
+    std::fs::write(&path, b"ab\xffcd ok ab").unwrap();
+    let path_str = path.to_str().unwrap();
+    assert_eq!(stdout(&["-f", path_str], None), "ab: 2\ncd: 1\nok: 1\n");
+
+    let out = wordfreq(&["--strict-utf8", "-f", path_str], None);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        format!("wordfreq: cannot read {path_str}: invalid UTF-8 at byte 2\n")
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzipped_files_are_decompressed() {
+    let plain = stdout(&["-f", &data("fox.txt")], None);
+    assert_eq!(stdout(&["-f", &data("fox.txt.gz")], None), plain);
+    // With --gzip the name doesn't matter
+    let renamed = std::env::temp_dir().join(format!("wordfreq-fox-{}.dat", std::process::id()));
+    std::fs::copy(data("fox.txt.gz"), &renamed).unwrap();
+    assert_eq!(
+        stdout(&["--gzip", "-f", renamed.to_str().unwrap()], None),
+        plain
+    );
+    std::fs::remove_file(renamed).unwrap();
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn corrupt_gzip_names_the_file() {
+    let good = std::fs::read(data("fox.txt.gz")).unwrap();
+    let truncated = std::env::temp_dir().join(format!("wordfreq-cut-{}.gz", std::process::id()));
+    std::fs::write(&truncated, &good[..good.len() / 2]).unwrap();
+    let path = truncated.to_str().unwrap();
+    let out = wordfreq(&["-f", path], None);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        err.starts_with(&format!(
+            "wordfreq: cannot read {path}: corrupt gzip data: "
+        )),
+        "{err}"
+    );
+    std::fs::remove_file(truncated).unwrap();
+
+    // Plain text forced through the decoder is not gzip either
+    let plain = data("fox.txt");
+    let out = wordfreq(&["--gzip", "-f", &plain], None);
+    assert_eq!(out.status.code(), Some(1));
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        err.starts_with(&format!(
+            "wordfreq: cannot read {plain}: corrupt gzip data: "
+        )),
+        "{err}"
+    );
+}
+
+#[cfg(not(feature = "gzip"))]
+#[test]
+fn gzip_needs_the_feature() {
+    let out = wordfreq(&["-f", &data("fox.txt.gz")], None);
+    assert_eq!(out.status.code(), Some(1));
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        err.contains("needs wordfreq built with --features gzip"),
+        "{err}"
+    );
+}
+
+/// A fresh, empty directory in the temp directory for this test.
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("wordfreq-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(unix)]
+#[test]
+fn dir_walks_nested_folders_once_and_skips_other_extensions() {
+    let root = temp_dir("tree");
+    std::fs::create_dir_all(root.join("sub/deeper")).unwrap();
+    std::fs::write(root.join("a.txt"), "apple apple pear").unwrap();
+    std::fs::write(root.join("sub/b.TXT"), "apple plum").unwrap();
+    std::fs::write(root.join("sub/deeper/c.md"), "pear apple").unwrap();
+    std::fs::write(root.join("sub/skip.log"), "apple skipped skipped").unwrap();
+    std::fs::write(root.join("sub/deeper/notes.txt.bak"), "skipped").unwrap();
+    // Links back up the tree would be walked forever without the guard
+    std::os::unix::fs::symlink(&root, root.join("sub/deeper/loop")).unwrap();
+    std::os::unix::fs::symlink(root.join("sub"), root.join("sub-again")).unwrap();
+
+    let dir = root.to_str().unwrap();
+    let out = wordfreq(&["--dir", dir, "--ext", "txt,.md", "--summary"], None);
+    assert!(out.status.success(), "{:?}", out);
+    let text = String::from_utf8(out.stdout).unwrap();
+    let (list, summary) = text.split_once("-- summary --\n").unwrap();
+    assert_eq!(list, "apple: 4\npear: 2\nplum: 1\n");
+    assert!(summary.starts_with("tokens: 7\n"), "{summary}");
+    assert!(summary.contains("\nfiles: 3\n"), "{summary}");
+
+    // Without --ext every file is read, still once each
+    let out = stdout(&["--dir", dir, "--summary-only"], None);
+    assert!(out.contains("\nfiles: 5\n"), "{out}");
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn dir_ignores_dangling_links() {
+    let root = temp_dir("dangling");
+    std::fs::write(root.join("a.txt"), "kept").unwrap();
+    std::os::unix::fs::symlink(root.join("gone"), root.join("gone-dir")).unwrap();
+    let out = wordfreq(&["--dir", root.to_str().unwrap(), "--summary"], None);
+    assert!(out.status.success(), "{:?}", out);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.starts_with("kept: 1\n"), "{stdout}");
+    assert!(stdout.contains("\nfiles: 1\n"), "{stdout}");
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn context_shows_the_first_occurrence() {
+    assert_eq!(
+        stdout(&["--context", "-f", &data("cat.txt"), "--top", "2"], None),
+        "\
+the: 4
+    [the] cat and the dog the
+cat: 3
+    the [cat] and the dog the cat
+"
+    );
+    assert_eq!(
+        stdout(
+            &[
+                "--context",
+                "--ngrams",
+                "2",
+                "--top",
+                "1",
+                "one two three four five six seven eight one two",
+            ],
+            None
+        ),
+        "one two: 2\n    [one two] three four five six seven\n"
+    );
+    assert_eq!(
+        stdout(
+            &["--context", "--format", "csv", "--top", "1", "a b a"],
+            None
+        ),
+        "word,count,context\na,2,[a] b a\n"
+    );
+    let out = wordfreq(&["--context", "--chars", "x"], None);
+    assert_eq!(out.status.code(), Some(2));
+}
+
+/// Run wordfreq with bad arguments and return its stderr, checking that it
+/// exits with 2, prints nothing on stdout and ends with the help hint.
+fn usage_error(args: &[&str]) -> String {
+    let out = wordfreq(args, None);
+    assert_eq!(out.status.code(), Some(2), "{:?}", args);
+    assert!(out.stdout.is_empty(), "{:?}", args);
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        err.ends_with("\nTry 'wordfreq --help' for more information.\n"),
+        "{err}"
+    );
+    err
+}
+
+#[test]
+fn an_invalid_number_names_the_flag_and_value() {
+    assert!(
+        usage_error(&["--top", "abc", "text"])
+            .starts_with("wordfreq: invalid value 'abc' for --top: expected a whole number\n")
+    );
+    assert!(
+        usage_error(&["--min-count", "-3", "text"])
+            .starts_with("wordfreq: invalid value '-3' for --min-count: expected a whole number\n")
+    );
+    assert!(usage_error(&["--jobs", "2.5", "text"]).contains("'2.5' for --jobs"));
+}
+
+#[test]
+fn a_missing_value_is_reported() {
+    assert!(usage_error(&["text", "--top"]).starts_with("wordfreq: --top requires a value\n"));
+    // Another option is not taken as the value
+    assert!(
+        usage_error(&["--file", "--inline", "text"])
+            .starts_with("wordfreq: --file requires a value\n")
+    );
+}
+
+#[test]
+fn an_unknown_flag_is_reported() {
+    assert!(usage_error(&["--bogus", "text"]).starts_with("wordfreq: unknown option '--bogus'\n"));
+}
+
+#[test]
+fn min_length_zero_is_rejected() {
+    assert!(
+        usage_error(&["--min-length", "0", "text"])
+            .starts_with("wordfreq: --min-length must be at least 1\n")
+    );
+}
+
+#[test]
+fn all_lists_every_word_of_a_fixture() {
+    let book = format!("{}/book.txt", env!("CARGO_MANIFEST_DIR"));
+    let text = std::fs::read_to_string(&book).unwrap();
+    let mut reference: Vec<(String, usize)> = Vec::new();
+    for word in text.split_whitespace() {
+        match reference.iter_mut().find(|(w, _)| w == word) {
+            Some((_, count)) => *count += 1,
+            None => reference.push((word.to_string(), 1)),
+        }
+    }
+    reference.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    assert!(reference.len() > 100);
+    let expected: String = reference
+        .iter()
+        .map(|(w, c)| format!("{w}: {c}\n"))
+        .collect();
+
+    let flags = ["--delimiters", " \\n\\t", "-f", &book];
+    let all = stdout(&[&flags[..], &["--all"]].concat(), None);
+    assert_eq!(all, expected);
+    assert_eq!(
+        stdout(&[&flags[..], &["--top", "0"]].concat(), None),
+        expected
+    );
+    // The default is still the top ten
+    assert_eq!(stdout(&flags, None).lines().count(), 10);
+
+    // Over the whole list the running share reaches 100%
+    let percent = stdout(&[&flags[..], &["--all", "--percent"]].concat(), None);
+    assert_eq!(percent.lines().count(), reference.len());
+    assert!(
+        percent.ends_with(", cumulative 100.00%)\n"),
+        "{}",
+        percent.lines().last().unwrap()
+    );
+}
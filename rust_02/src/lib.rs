@@ -0,0 +1,1992 @@
+//! Hex Tool - Read & Write Binary Files
+//!
+//! Core operations behind the `hextool` binary. Everything here works on
+//! `Read + Seek` / `Write + Seek` trait objects and writes its report to a
+//! caller-supplied `Write`, so it can be driven from `std::io::Cursor` in tests.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Errors reported by hextool operations.
+#[derive(Debug)]
+pub enum HexToolError {
+    /// Underlying I/O failure.
+    Io(io::Error),
+    /// Malformed user input (offsets, hex strings, templates).
+    Parse(String),
+    /// Well-formed input that does not fit the data (short files, gaps).
+    Validation(String),
+}
+
+impl HexToolError {
+    /// Process exit code for this error: 2 for bad input, 1 otherwise.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            HexToolError::Parse(_) => 2,
+            HexToolError::Io(_) | HexToolError::Validation(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for HexToolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HexToolError::Io(e) => write!(f, "I/O error: {}", e),
+            HexToolError::Parse(msg) | HexToolError::Validation(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for HexToolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HexToolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HexToolError {
+    fn from(e: io::Error) -> Self {
+        HexToolError::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, HexToolError>;
+
+/// Object-safe combination of `Read` and `Seek`.
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Object-safe combination of `Write` and `Seek`.
+pub trait WriteSeek: Write + Seek {}
+impl<T: Write + Seek> WriteSeek for T {}
+
+/// Parse a decimal or `0x` hex number. Decimal values may carry a binary
+/// `k`, `M` or `G` suffix (`256k` = 262144).
+pub fn parse_offset(s: &str) -> Result<u64> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        return u64::from_str_radix(&s[2..], 16)
+            .map_err(|_| HexToolError::Parse(format!("Invalid hex offset: {}", s)));
+    }
+    let (digits, multiplier) = match s.as_bytes().last() {
+        Some(b'k' | b'K') => (&s[..s.len() - 1], 1u64 << 10),
+        Some(b'm' | b'M') => (&s[..s.len() - 1], 1u64 << 20),
+        Some(b'g' | b'G') => (&s[..s.len() - 1], 1u64 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| HexToolError::Parse(format!("Invalid decimal offset: {}", s)))
+}
+
+/// Decode a string of hex digit pairs such as `"deadBEEF"`.
+pub fn hex_to_bytes(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.trim();
+    let invalid = || HexToolError::Parse(format!("Invalid hex string: {}", hex));
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+pub fn byte_to_ascii(byte: u8) -> char {
+    if (0x20..=0x7E).contains(&byte) {
+        byte as char
+    } else {
+        '.'
+    }
+}
+
+/// Render the ASCII column for one line of bytes. With `utf8`, complete
+/// multi-byte sequences inside the line are shown as their character followed
+/// by one space per continuation byte, so the column keeps one cell per byte.
+/// Invalid or line-split sequences fall back to `.`.
+pub fn gutter(bytes: &[u8], utf8: bool) -> String {
+    if !utf8 {
+        return bytes.iter().map(|&b| byte_to_ascii(b)).collect();
+    }
+    let mut out = String::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let width = match bytes[i] {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => 1,
+        };
+        let decoded = bytes
+            .get(i..i + width)
+            .filter(|_| width > 1)
+            .and_then(|seq| std::str::from_utf8(seq).ok())
+            .and_then(|s| s.chars().next())
+            .filter(|c| !c.is_control());
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                out.extend(std::iter::repeat_n(' ', width - 1));
+                i += width;
+            }
+            None => {
+                out.push(byte_to_ascii(bytes[i]));
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Lowercase hex without separators, as used in JSON records.
+pub fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Escape a string for use inside a JSON string literal.
+pub fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// One NDJSON record describing `bytes` located at `offset`.
+pub fn json_record(offset: u64, bytes: &[u8], utf8: bool) -> String {
+    let ascii = gutter(bytes, utf8);
+    format!(
+        "{{\"offset\":{},\"bytes\":\"{}\",\"ascii\":\"{}\"}}",
+        offset,
+        bytes_to_hex(bytes),
+        json_escape(&ascii)
+    )
+}
+
+/// Output switches shared by the read-style modes.
+#[derive(Clone, Copy, Default)]
+pub struct DumpOptions {
+    pub json: bool,
+    pub utf8: bool,
+}
+
+/// Read up to `size` bytes starting at `offset`; fewer are returned at EOF.
+/// The buffer grows with what is actually read, so a huge `size` against a
+/// small input costs nothing.
+pub fn read_at(input: &mut dyn ReadSeek, offset: u64, size: usize) -> Result<Vec<u8>> {
+    input.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::new();
+    input.take(size as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Format one dump line: offset, hex bytes with an extra gap after every 8,
+/// padding up to `width` bytes, then the ASCII gutter and a newline. The
+/// whole line is built in memory so callers issue a single write per line.
+pub fn format_dump_line(offset: u64, bytes: &[u8], width: usize, utf8: bool) -> String {
+    let n = bytes.len();
+    let mut line = String::with_capacity(11 + width.max(n) * 4 + n / 8 + 3);
+    line.push_str(&format!("{:08x}: ", offset));
+    for (i, &b) in bytes.iter().enumerate() {
+        line.push(HEX_DIGITS[(b >> 4) as usize] as char);
+        line.push(HEX_DIGITS[(b & 0x0f) as usize] as char);
+        line.push(' ');
+        if (i + 1) % 8 == 0 && i + 1 != n {
+            line.push(' ');
+        }
+    }
+    // Fill with spaces if not enough bytes read
+    for _ in n..width {
+        line.push_str("   ");
+    }
+    line.push('|');
+    line.push_str(&gutter(bytes, utf8));
+    line.push_str("|\n");
+    line
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Dump `size` bytes at `offset` as a single hex line padded to `size`
+/// columns with an ASCII gutter, or as 16-byte NDJSON records.
+pub fn dump(
+    input: &mut dyn ReadSeek,
+    offset: u64,
+    size: usize,
+    opts: DumpOptions,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let buf = read_at(input, offset, size)?;
+
+    if opts.json {
+        for (i, chunk) in buf.chunks(16).enumerate() {
+            writeln!(
+                out,
+                "{}",
+                json_record(offset + (i * 16) as u64, chunk, opts.utf8)
+            )?;
+        }
+        return Ok(());
+    }
+
+    out.write_all(format_dump_line(offset, &buf, size, opts.utf8).as_bytes())?;
+    Ok(())
+}
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+mod seek_hole {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    const SEEK_DATA: i32 = 3;
+    const SEEK_HOLE: i32 = 4;
+    const ENXIO: i32 = 6;
+
+    unsafe extern "C" {
+        fn lseek(fd: i32, offset: i64, whence: i32) -> i64;
+    }
+
+    /// `lseek` with SEEK_DATA/SEEK_HOLE. `Ok(None)` means "no more data"
+    /// (ENXIO); any other failure means the filesystem can't tell us.
+    fn seek(file: &File, pos: u64, whence: i32) -> io::Result<Option<u64>> {
+        // SAFETY: lseek only inspects the descriptor, which `file` keeps open.
+        let r = unsafe { lseek(file.as_raw_fd(), pos as i64, whence) };
+        if r >= 0 {
+            return Ok(Some(r as u64));
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(ENXIO) => Ok(None),
+            _ => Err(err),
+        }
+    }
+
+    pub fn data_regions(file: &File, start: u64, end: u64) -> Option<Vec<(u64, u64)>> {
+        let mut regions = Vec::new();
+        let mut pos = start;
+        while pos < end {
+            let Some(data) = seek(file, pos, SEEK_DATA).ok()? else {
+                break;
+            };
+            if data >= end {
+                break;
+            }
+            let hole = seek(file, data, SEEK_HOLE).ok()??;
+            regions.push((data, hole.min(end)));
+            pos = hole;
+        }
+        Some(regions)
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+mod seek_hole {
+    pub fn data_regions(_: &std::fs::File, _: u64, _: u64) -> Option<Vec<(u64, u64)>> {
+        None
+    }
+}
+
+/// Data regions `[start, end)` of `file` between `start` and `end`, found
+/// with SEEK_DATA/SEEK_HOLE. `None` when the platform or filesystem does not
+/// support hole detection.
+pub fn data_regions(file: &File, start: u64, end: u64) -> Option<Vec<(u64, u64)>> {
+    seek_hole::data_regions(file, start, end)
+}
+
+fn write_hole(out: &mut dyn Write, offset: u64, len: u64, json: bool) -> io::Result<()> {
+    if json {
+        writeln!(out, "{{\"offset\":{},\"hole\":{}}}", offset, len)
+    } else {
+        writeln!(out, "... hole: {} bytes at 0x{:08x} ...", len, offset)
+    }
+}
+
+/// Like [`dump`], but holes in a sparse file are skipped and replaced by a
+/// `... hole: N bytes at OFFSET ...` marker, with each data region dumped on
+/// its own. Without hole support, or when the range has no holes, this is
+/// exactly [`dump`].
+pub fn dump_sparse(
+    file: &mut File,
+    offset: u64,
+    size: usize,
+    opts: DumpOptions,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let end = offset
+        .saturating_add(size as u64)
+        .min(file.metadata()?.len());
+    // A range starting at or past the end is left to dump to report.
+    let regions = match data_regions(file, offset, end) {
+        Some(r) if offset < end && r != [(offset, end)] => r,
+        _ => return dump(file, offset, size, opts, out),
+    };
+
+    let mut pos = offset;
+    for (start, stop) in regions {
+        if start > pos {
+            write_hole(out, pos, start - pos, opts.json)?;
+        }
+        let len = (stop - start) as usize;
+        dump(file, start, len, opts, out)?;
+        pos = stop;
+    }
+    if pos < end {
+        write_hole(out, pos, end - pos, opts.json)?;
+    }
+    Ok(())
+}
+
+/// Write `data` at `offset` and report what was written.
+pub fn write_at(
+    target: &mut dyn WriteSeek,
+    offset: u64,
+    data: &[u8],
+    opts: DumpOptions,
+    out: &mut dyn Write,
+) -> Result<()> {
+    target.seek(SeekFrom::Start(offset))?;
+    target.write_all(data)?;
+    target.flush()?;
+
+    if opts.json {
+        writeln!(out, "{}", json_record(offset, data, opts.utf8))?;
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "Writing {} bytes at offset 0x{:08x}",
+        data.len(),
+        offset
+    )?;
+    let hex: Vec<String> = data.iter().map(|b| format!("{:02x}", b)).collect();
+    writeln!(out, "Hex: {}", hex.join(" "))?;
+    writeln!(out, "ASCII: {}", gutter(data, false))?;
+    writeln!(out, "✓ Successfully written")?;
+    Ok(())
+}
+
+/// Largest range `--bits` will display.
+pub const MAX_BITS_BYTES: usize = 64;
+
+/// Byte order used to number bits across a multi-byte range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endian {
+    /// Bit 0 is the least significant bit of the first byte.
+    Little,
+    /// Bit 0 is the least significant bit of the last byte.
+    Big,
+}
+
+/// A named bitfield `NAME:HI:LO`, both bounds inclusive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BitField {
+    pub name: String,
+    pub hi: u32,
+    pub lo: u32,
+}
+
+impl BitField {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid =
+            |why: &str| HexToolError::Parse(format!("Invalid bitfield '{}': {}", spec, why));
+        let mut parts = spec.rsplitn(3, ':');
+        let (Some(lo), Some(hi), Some(name)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid("expected NAME:HI:LO"));
+        };
+        let hi: u32 = hi.parse().map_err(|_| invalid("HI is not a number"))?;
+        let lo: u32 = lo.parse().map_err(|_| invalid("LO is not a number"))?;
+        if name.is_empty() {
+            return Err(invalid("empty name"));
+        }
+        if hi < lo {
+            return Err(invalid("HI must not be below LO"));
+        }
+        if hi - lo >= 32 {
+            return Err(invalid("fields are limited to 32 bits"));
+        }
+        Ok(BitField {
+            name: name.to_string(),
+            hi,
+            lo,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.hi - self.lo + 1
+    }
+}
+
+/// Extract bits `hi..=lo` from `bytes`, numbering bits according to `endian`.
+pub fn extract_bits(bytes: &[u8], endian: Endian, hi: u32, lo: u32) -> Result<u32> {
+    let total = bytes.len() as u32 * 8;
+    if hi >= total {
+        return Err(HexToolError::Validation(format!(
+            "bit {} is out of range: the selection has {} bits (0..={})",
+            hi,
+            total,
+            total.saturating_sub(1)
+        )));
+    }
+    let bit = |k: u32| {
+        let index = match endian {
+            Endian::Little => (k / 8) as usize,
+            Endian::Big => bytes.len() - 1 - (k / 8) as usize,
+        };
+        ((bytes[index] >> (k % 8)) & 1) as u32
+    };
+    Ok((lo..=hi).rev().fold(0, |acc, k| (acc << 1) | bit(k)))
+}
+
+/// Show each byte of the range with its bits labelled 7..0, then every
+/// requested bitfield. Ranges are limited to [`MAX_BITS_BYTES`].
+pub fn dump_bits(
+    input: &mut dyn ReadSeek,
+    offset: u64,
+    size: usize,
+    fields: &[BitField],
+    endian: Endian,
+    out: &mut dyn Write,
+) -> Result<()> {
+    if size == 0 || size > MAX_BITS_BYTES {
+        return Err(HexToolError::Validation(format!(
+            "--bits works on 1 to {} bytes, got {}",
+            MAX_BITS_BYTES, size
+        )));
+    }
+    let bytes = read_at(input, offset, size)?;
+    if bytes.is_empty() {
+        return Err(HexToolError::Validation(format!(
+            "no data at offset 0x{:08x}",
+            offset
+        )));
+    }
+    // Validate every field before printing anything.
+    let values = fields
+        .iter()
+        .map(|f| {
+            extract_bits(&bytes, endian, f.hi, f.lo)
+                .map_err(|e| HexToolError::Validation(format!("bitfield '{}': {}", f.name, e)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    writeln!(out, "offset    hex  7 6 5 4 3 2 1 0")?;
+    for (i, &b) in bytes.iter().enumerate() {
+        let bits: Vec<String> = (0..8).rev().map(|k| ((b >> k) & 1).to_string()).collect();
+        writeln!(
+            out,
+            "{:08x}  {:02x}   {}",
+            offset + i as u64,
+            b,
+            bits.join(" ")
+        )?;
+    }
+
+    if !fields.is_empty() {
+        writeln!(out)?;
+    }
+    for (f, value) in fields.iter().zip(values) {
+        writeln!(
+            out,
+            "{}[{}:{}] = {} (0x{:x}, 0b{:0width$b})",
+            f.name,
+            f.hi,
+            f.lo,
+            value,
+            value,
+            value,
+            width = f.width() as usize
+        )?;
+    }
+    Ok(())
+}
+
+/// A single `--expect` check: `bytes` must appear at `offset`.
+pub struct Expect {
+    pub offset: u64,
+    pub bytes: Vec<u8>,
+}
+
+/// Result of comparing an [`Expect`] against the input.
+pub struct ExpectOutcome {
+    pub offset: u64,
+    pub expected: Vec<u8>,
+    pub actual: Vec<u8>,
+}
+
+impl ExpectOutcome {
+    pub fn passed(&self) -> bool {
+        self.actual == self.expected
+    }
+
+    /// Absolute offset of the first differing (or missing) byte.
+    pub fn mismatch_offset(&self) -> Option<u64> {
+        if self.passed() {
+            return None;
+        }
+        let i = self
+            .expected
+            .iter()
+            .zip(&self.actual)
+            .position(|(e, a)| e != a)
+            .unwrap_or(self.actual.len());
+        Some(self.offset + i as u64)
+    }
+}
+
+pub fn check_expect(input: &mut dyn ReadSeek, expect: &Expect) -> Result<ExpectOutcome> {
+    Ok(ExpectOutcome {
+        offset: expect.offset,
+        expected: expect.bytes.clone(),
+        actual: read_at(input, expect.offset, expect.bytes.len())?,
+    })
+}
+
+/// Write expected and actual bytes side by side, 8 per row, marking every
+/// differing position with `^^`. Bytes past the end of the input show as `--`.
+fn write_mismatch(out: &mut dyn Write, outcome: &ExpectOutcome) -> io::Result<()> {
+    writeln!(out, "  offset    expected                  actual")?;
+    for (row, chunk) in outcome.expected.chunks(8).enumerate() {
+        let start = row * 8;
+        let mut exp_col = String::new();
+        let mut act_col = String::new();
+        let mut marks = String::new();
+        for (i, &e) in chunk.iter().enumerate() {
+            exp_col.push_str(&format!("{:02x} ", e));
+            match outcome.actual.get(start + i) {
+                Some(&a) => {
+                    act_col.push_str(&format!("{:02x} ", a));
+                    marks.push_str(if a == e { "   " } else { "^^ " });
+                }
+                None => {
+                    act_col.push_str("-- ");
+                    marks.push_str("^^ ");
+                }
+            }
+        }
+        writeln!(
+            out,
+            "  {:08x}  {:<24}  {}",
+            outcome.offset + start as u64,
+            exp_col,
+            act_col.trim_end()
+        )?;
+        if marks.contains('^') {
+            writeln!(out, "  {:8}  {:24}  {}", "", "", marks.trim_end())?;
+        }
+    }
+    Ok(())
+}
+
+/// Run every check against `input`, which is only ever read. Returns whether
+/// all checks passed.
+pub fn run_expects(
+    input: &mut dyn ReadSeek,
+    expects: &[Expect],
+    json: bool,
+    out: &mut dyn Write,
+) -> Result<bool> {
+    let mut all_ok = true;
+
+    for expect in expects {
+        let outcome = check_expect(input, expect)?;
+        all_ok &= outcome.passed();
+
+        if json {
+            let mismatch = outcome
+                .mismatch_offset()
+                .map_or("null".to_string(), |o| o.to_string());
+            writeln!(
+                out,
+                "{{\"offset\":{},\"pass\":{},\"expected\":\"{}\",\"actual\":\"{}\",\"mismatch\":{}}}",
+                outcome.offset,
+                outcome.passed(),
+                bytes_to_hex(&outcome.expected),
+                bytes_to_hex(&outcome.actual),
+                mismatch
+            )?;
+            continue;
+        }
+
+        if outcome.passed() {
+            writeln!(
+                out,
+                "PASS 0x{:08x}: {} bytes match",
+                outcome.offset,
+                outcome.expected.len()
+            )?;
+            continue;
+        }
+
+        if outcome.actual.len() < outcome.expected.len()
+            && outcome.expected.starts_with(&outcome.actual)
+        {
+            writeln!(
+                out,
+                "FAIL 0x{:08x}: file ends after {} of {} expected bytes",
+                outcome.offset,
+                outcome.actual.len(),
+                outcome.expected.len()
+            )?;
+        } else {
+            writeln!(
+                out,
+                "FAIL 0x{:08x}: first mismatch at offset 0x{:08x}",
+                outcome.offset,
+                outcome.mismatch_offset().unwrap_or(outcome.offset)
+            )?;
+        }
+        write_mismatch(out, &outcome)?;
+    }
+
+    Ok(all_ok)
+}
+
+/// Built-in templates usable as `--template @name`.
+const BUILTIN_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "elf-header",
+        "ei_magic:bytes[4]\nei_class:u8\nei_data:u8\nei_version:u8\nei_osabi:u8\n\
+         ei_abiversion:u8\nei_pad:bytes[7]\ne_type:u16le\ne_machine:u16le\ne_version:u32le\n\
+         e_entry:u64le\ne_phoff:u64le\ne_shoff:u64le\ne_flags:u32le\ne_ehsize:u16le\n\
+         e_phentsize:u16le\ne_phnum:u16le\ne_shentsize:u16le\ne_shnum:u16le\ne_shstrndx:u16le",
+    ),
+    (
+        "bmp-header",
+        "signature:str[2]\nfile_size:u32le\nreserved1:u16le\nreserved2:u16le\n\
+         data_offset:u32le\ndib_size:u32le\nwidth:u32le\nheight:u32le\nplanes:u16le\n\
+         bpp:u16le\ncompression:u32le\nimage_size:u32le\nx_ppm:u32le\ny_ppm:u32le\n\
+         colors_used:u32le\ncolors_important:u32le",
+    ),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldType {
+    U8,
+    U16Le,
+    U16Be,
+    U32Le,
+    U32Be,
+    U64Le,
+    Bytes(usize),
+    Str(usize),
+}
+
+impl FieldType {
+    pub fn parse(s: &str) -> Option<Self> {
+        let sized = |prefix: &str| -> Option<usize> {
+            s.strip_prefix(prefix)?
+                .strip_suffix(']')?
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+        };
+        match s {
+            "u8" => Some(Self::U8),
+            "u16le" => Some(Self::U16Le),
+            "u16be" => Some(Self::U16Be),
+            "u32le" => Some(Self::U32Le),
+            "u32be" => Some(Self::U32Be),
+            "u64le" => Some(Self::U64Le),
+            _ => sized("bytes[")
+                .map(Self::Bytes)
+                .or_else(|| sized("str[").map(Self::Str)),
+        }
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U16Le | Self::U16Be => 2,
+            Self::U32Le | Self::U32Be => 4,
+            Self::U64Le => 8,
+            Self::Bytes(n) | Self::Str(n) => n,
+        }
+    }
+
+    pub fn decode(self, raw: &[u8]) -> String {
+        let uint = |bytes: &mut dyn Iterator<Item = &u8>| {
+            bytes.fold(0u64, |acc, &b| (acc << 8) | b as u64)
+        };
+        let num = match self {
+            Self::U8 | Self::U16Be | Self::U32Be => uint(&mut raw.iter()),
+            Self::U16Le | Self::U32Le | Self::U64Le => uint(&mut raw.iter().rev()),
+            Self::Bytes(_) => return bytes_to_hex(raw),
+            Self::Str(_) => {
+                let end = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+                return format!("\"{}\"", gutter(&raw[..end], false));
+            }
+        };
+        format!("{} (0x{:0width$x})", num, num, width = self.size() * 2)
+    }
+}
+
+pub struct Field {
+    pub name: String,
+    pub kind: FieldType,
+}
+
+/// Parse a template made of `name:type` lines. Blank lines and `#` comments
+/// are ignored; errors name the offending line.
+pub fn parse_template(text: &str) -> Result<Vec<Field>> {
+    let mut fields = Vec::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (name, kind) = line.split_once(':').ok_or_else(|| {
+            HexToolError::Parse(format!("template line {}: expected name:type", lineno + 1))
+        })?;
+        let kind = FieldType::parse(kind.trim()).ok_or_else(|| {
+            HexToolError::Parse(format!(
+                "template line {}: unknown type '{}' (expected u8, u16le, u16be, u32le, u32be, u64le, bytes[N] or str[N])",
+                lineno + 1,
+                kind.trim()
+            ))
+        })?;
+        fields.push(Field {
+            name: name.trim().to_string(),
+            kind,
+        });
+    }
+    if fields.is_empty() {
+        return Err(HexToolError::Parse(
+            "template defines no fields".to_string(),
+        ));
+    }
+    Ok(fields)
+}
+
+/// Load a template from a file, or a built-in one when `spec` is `@name`.
+pub fn load_template(spec: &str) -> Result<Vec<Field>> {
+    let text = match spec.strip_prefix('@') {
+        Some(name) => BUILTIN_TEMPLATES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, t)| t.to_string())
+            .ok_or_else(|| {
+                let names: Vec<_> = BUILTIN_TEMPLATES.iter().map(|(n, _)| *n).collect();
+                HexToolError::Parse(format!(
+                    "unknown built-in template '@{}' (available: @{})",
+                    name,
+                    names.join(", @")
+                ))
+            })?,
+        None => std::fs::read_to_string(spec)
+            .map_err(|e| HexToolError::Parse(format!("cannot read template {}: {}", spec, e)))?,
+    };
+    parse_template(&text)
+}
+
+/// Decode every field of the template starting at `offset`.
+pub fn decode_template(
+    input: &mut dyn ReadSeek,
+    offset: u64,
+    fields: &[Field],
+    out: &mut dyn Write,
+) -> Result<()> {
+    // Check every field against the bytes left in the input before reading,
+    // so an oversized template fails here instead of allocating for it.
+    let total = fields
+        .iter()
+        .try_fold(0u64, |sum, f| sum.checked_add(f.kind.size() as u64));
+    let available = input.seek(SeekFrom::End(0))?.saturating_sub(offset);
+    let mut pos = 0u64;
+    for f in fields {
+        let size = f.kind.size() as u64;
+        match pos.checked_add(size) {
+            Some(end) if end <= available => pos = end,
+            _ => {
+                let template = match total {
+                    Some(total) => format!("template is {} bytes", total),
+                    None => "template size overflows 64 bits".to_string(),
+                };
+                return Err(HexToolError::Validation(format!(
+                    "Field '{}' at offset 0x{:08x} needs {} bytes but only {} are available ({}, file has {} from 0x{:08x})",
+                    f.name,
+                    offset.saturating_add(pos),
+                    size,
+                    available - pos,
+                    template,
+                    available,
+                    offset
+                )));
+            }
+        }
+    }
+
+    let data = read_at(input, offset, pos as usize)?;
+    if (data.len() as u64) < pos {
+        return Err(HexToolError::Validation(format!(
+            "file ended after {} of {} template bytes from 0x{:08x}",
+            data.len(),
+            pos,
+            offset
+        )));
+    }
+
+    let name_width = fields
+        .iter()
+        .map(|f| f.name.len())
+        .max()
+        .unwrap_or(4)
+        .max(4);
+    writeln!(
+        out,
+        "{:8}  {:name_width$}  {:23}  value",
+        "offset", "name", "raw"
+    )?;
+    let mut pos = 0;
+    for f in fields {
+        let raw = &data[pos..pos + f.kind.size()];
+        let mut raw_hex: Vec<String> = raw.iter().take(8).map(|b| format!("{:02x}", b)).collect();
+        if raw.len() > 8 {
+            raw_hex.push("..".to_string());
+        }
+        writeln!(
+            out,
+            "{:08x}  {:name_width$}  {:23}  {}",
+            offset + pos as u64,
+            f.name,
+            raw_hex.join(" "),
+            f.kind.decode(raw)
+        )?;
+        pos += f.kind.size();
+    }
+    Ok(())
+}
+
+pub fn chunk_name(prefix: &str, index: usize) -> String {
+    format!("{}.{:03}", prefix, index)
+}
+
+/// Split `input` into `chunk_size`-byte files named `PREFIX.NNN`. An empty
+/// input still produces one (empty) chunk so that joining can restore it.
+pub fn split(
+    input: &mut dyn Read,
+    chunk_size: u64,
+    prefix: &str,
+    out: &mut dyn Write,
+) -> Result<()> {
+    if chunk_size == 0 {
+        return Err(HexToolError::Validation(
+            "split SIZE must be greater than 0".to_string(),
+        ));
+    }
+    let mut input = io::BufReader::new(input);
+    let mut written = 0u64;
+    let mut index = 0;
+
+    loop {
+        // A full chunk may still be the last one; don't emit an empty follower.
+        if index > 0 && input.fill_buf()?.is_empty() {
+            break;
+        }
+        let name = chunk_name(prefix, index);
+        let mut chunk = File::create(&name)?;
+        let n = io::copy(&mut (&mut input).take(chunk_size), &mut chunk)?;
+        writeln!(out, "{}  {} bytes", name, n)?;
+        written += n;
+        index += 1;
+        if n < chunk_size {
+            break;
+        }
+    }
+
+    writeln!(out, "Total: {} bytes in {} chunks", written, index)?;
+    Ok(())
+}
+
+/// Find the chunk indices present for `prefix`, in numeric order, failing
+/// when there are none or the sequence has a gap.
+pub fn chunk_indices(prefix: &str) -> Result<Vec<usize>> {
+    let prefix_path = Path::new(prefix);
+    let dir = match prefix_path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let stem = prefix_path
+        .file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut indices: Vec<usize> = std::fs::read_dir(&dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            let suffix = name.strip_prefix(&stem)?.strip_prefix('.')?;
+            if suffix.is_empty() || !suffix.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            suffix.parse().ok()
+        })
+        .collect();
+    indices.sort_unstable();
+
+    if indices.is_empty() {
+        return Err(HexToolError::Validation(format!(
+            "No chunks found for prefix {}",
+            prefix
+        )));
+    }
+    if let Some(missing) = (0..).zip(&indices).find(|(i, n)| i != *n).map(|(i, _)| i) {
+        return Err(HexToolError::Validation(format!(
+            "Missing chunk {}",
+            chunk_name(prefix, missing)
+        )));
+    }
+    Ok(indices)
+}
+
+/// Concatenate the `PREFIX.NNN` chunks in numeric order into `output`.
+pub fn join(prefix: &str, output: &mut dyn Write, out: &mut dyn Write) -> Result<u64> {
+    let indices = chunk_indices(prefix)?;
+    let mut total = 0u64;
+    for &i in &indices {
+        let name = chunk_name(prefix, i);
+        let n = io::copy(&mut File::open(&name)?, output)?;
+        writeln!(out, "{}  {} bytes", name, n)?;
+        total += n;
+    }
+    output.flush()?;
+    writeln!(out, "Total: {} bytes from {} chunks", total, indices.len())?;
+    Ok(total)
+}
+
+/// Build `target` with `fill` in a new file beside it, renamed over `target`
+/// only once `fill` succeeds. On failure the partial file is removed and an
+/// existing `target` is left as it was.
+pub fn replace_with<T>(target: &Path, fill: impl FnOnce(&mut File) -> Result<T>) -> Result<T> {
+    let dir = match target.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let name = target
+        .file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp = dir.join(format!(".{}.{}.tmp", name, std::process::id()));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)?;
+    // Keep the mode of the file being replaced.
+    let result = std::fs::metadata(target)
+        .map_or(Ok(()), |meta| file.set_permissions(meta.permissions()))
+        .map_err(HexToolError::from)
+        .and_then(|()| fill(&mut file))
+        .and_then(|value| {
+            file.sync_all()?;
+            std::fs::rename(&temp, target)?;
+            Ok(value)
+        });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Where one input lands inside a concatenated output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConcatEntry {
+    pub path: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Lay out `inputs` back to back, each starting at a multiple of `align`.
+/// Fails if any input is missing or not a regular file, so nothing gets
+/// written for a bad command line.
+pub fn plan_concat(inputs: &[String], align: u64) -> Result<Vec<ConcatEntry>> {
+    if align == 0 {
+        return Err(HexToolError::Validation(
+            "alignment must be greater than 0".to_string(),
+        ));
+    }
+    let mut plan = Vec::with_capacity(inputs.len());
+    let mut pos = 0u64;
+    for path in inputs {
+        let meta = std::fs::metadata(path)
+            .map_err(|e| HexToolError::Validation(format!("cannot concat {}: {}", path, e)))?;
+        if !meta.is_file() {
+            return Err(HexToolError::Validation(format!(
+                "cannot concat {}: not a regular file",
+                path
+            )));
+        }
+        let offset = pos.div_ceil(align) * align;
+        plan.push(ConcatEntry {
+            path: path.clone(),
+            offset,
+            size: meta.len(),
+        });
+        pos = offset + meta.len();
+    }
+    Ok(plan)
+}
+
+/// Write every planned input into `output`, filling alignment gaps with
+/// `pad`, and print each input's size and offset.
+pub fn concat(
+    plan: &[ConcatEntry],
+    output: &mut dyn Write,
+    pad: u8,
+    out: &mut dyn Write,
+) -> Result<u64> {
+    let mut pos = 0u64;
+    writeln!(out, "{:10}  {:>10}  file", "offset", "size")?;
+    for entry in plan {
+        io::copy(&mut io::repeat(pad).take(entry.offset - pos), output)?;
+        let n = io::copy(&mut File::open(&entry.path)?, output)?;
+        if n != entry.size {
+            return Err(HexToolError::Validation(format!(
+                "{} changed size while concatenating ({} bytes, expected {})",
+                entry.path, n, entry.size
+            )));
+        }
+        writeln!(out, "0x{:08x}  {:>10}  {}", entry.offset, n, entry.path)?;
+        pos = entry.offset + n;
+    }
+    output.flush()?;
+    writeln!(out, "Total: {} bytes from {} files", pos, plan.len())?;
+    Ok(pos)
+}
+
+/// How many leading bytes `--identify` inspects (tar keeps its magic at 257).
+pub const IDENTIFY_LEN: usize = 512;
+
+/// A known file signature: `magic` must appear at `offset`, and `details`
+/// decodes a few follow-up fields from the header when there are any.
+pub struct Signature {
+    pub name: &'static str,
+    pub offset: usize,
+    pub magic: &'static [u8],
+    pub details: fn(&[u8]) -> Vec<(&'static str, String)>,
+}
+
+fn be16(h: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(h.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn le16(h: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(h.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be32(h: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(h.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn le32(h: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(h.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn no_details(_: &[u8]) -> Vec<(&'static str, String)> {
+    Vec::new()
+}
+
+fn elf_details(h: &[u8]) -> Vec<(&'static str, String)> {
+    let class = match h.get(4) {
+        Some(1) => "32-bit",
+        Some(2) => "64-bit",
+        _ => "invalid",
+    };
+    let data = match h.get(5) {
+        Some(1) => "little-endian",
+        Some(2) => "big-endian",
+        _ => "invalid",
+    };
+    let read16 = if h.get(5) == Some(&2) { be16 } else { le16 };
+    let mut out = vec![
+        ("class", class.to_string()),
+        ("endianness", data.to_string()),
+    ];
+    if let Some(kind) = read16(h, 16) {
+        let kind = match kind {
+            1 => "relocatable",
+            2 => "executable",
+            3 => "shared object",
+            4 => "core",
+            _ => "other",
+        };
+        out.push(("type", kind.to_string()));
+    }
+    if let Some(machine) = read16(h, 18) {
+        out.push(("machine", format!("0x{:04x}", machine)));
+    }
+    out
+}
+
+fn mz_details(h: &[u8]) -> Vec<(&'static str, String)> {
+    le32(h, 0x3c)
+        .map(|pe| vec![("pe_header_offset", format!("0x{:x}", pe))])
+        .unwrap_or_default()
+}
+
+fn png_details(h: &[u8]) -> Vec<(&'static str, String)> {
+    if h.get(12..16) != Some(b"IHDR") {
+        return Vec::new();
+    }
+    let mut out = Vec::new();
+    if let (Some(w), Some(hgt)) = (be32(h, 16), be32(h, 20)) {
+        out.push(("dimensions", format!("{}x{}", w, hgt)));
+    }
+    if let Some(depth) = h.get(24) {
+        out.push(("bit_depth", depth.to_string()));
+    }
+    out
+}
+
+fn jpeg_details(h: &[u8]) -> Vec<(&'static str, String)> {
+    match h.get(6..10) {
+        Some(b"JFIF") => vec![("variant", "JFIF".to_string())],
+        Some(b"Exif") => vec![("variant", "Exif".to_string())],
+        _ => Vec::new(),
+    }
+}
+
+fn gif_details(h: &[u8]) -> Vec<(&'static str, String)> {
+    let mut out = Vec::new();
+    if let Some(v) = h.get(3..6) {
+        out.push(("version", String::from_utf8_lossy(v).into_owned()));
+    }
+    if let (Some(w), Some(hgt)) = (le16(h, 6), le16(h, 8)) {
+        out.push(("dimensions", format!("{}x{}", w, hgt)));
+    }
+    out
+}
+
+fn zip_details(h: &[u8]) -> Vec<(&'static str, String)> {
+    let Some(name_len) = le16(h, 26) else {
+        return Vec::new();
+    };
+    h.get(30..30 + name_len as usize)
+        .map(|name| vec![("first_entry", String::from_utf8_lossy(name).into_owned())])
+        .unwrap_or_default()
+}
+
+fn gzip_details(h: &[u8]) -> Vec<(&'static str, String)> {
+    let mut out = Vec::new();
+    if let Some(&method) = h.get(2) {
+        let method = if method == 8 { "deflate" } else { "unknown" };
+        out.push(("method", method.to_string()));
+    }
+    if let Some(mtime) = le32(h, 4) {
+        out.push(("mtime", mtime.to_string()));
+    }
+    out
+}
+
+fn pdf_details(h: &[u8]) -> Vec<(&'static str, String)> {
+    let version: String = h
+        .get(5..)
+        .unwrap_or_default()
+        .iter()
+        .take_while(|b| b.is_ascii_digit() || **b == b'.')
+        .map(|&b| b as char)
+        .collect();
+    vec![("version", version)]
+}
+
+fn sqlite_details(h: &[u8]) -> Vec<(&'static str, String)> {
+    be16(h, 16)
+        .map(|size| {
+            // A stored page size of 1 means 65536.
+            let size = if size == 1 { 65536 } else { size as u32 };
+            vec![("page_size", size.to_string())]
+        })
+        .unwrap_or_default()
+}
+
+fn macho_details(h: &[u8]) -> Vec<(&'static str, String)> {
+    let little = matches!(h.first(), Some(0xce | 0xcf));
+    let cpu = if little { le32(h, 4) } else { be32(h, 4) };
+    let bits = if h.first() == Some(&0xcf) || h.get(3) == Some(&0xcf) {
+        "64-bit"
+    } else {
+        "32-bit"
+    };
+    let mut out = vec![("class", bits.to_string())];
+    if let Some(cpu) = cpu {
+        out.push(("cputype", format!("0x{:08x}", cpu)));
+    }
+    out
+}
+
+fn riff_details(h: &[u8]) -> Vec<(&'static str, String)> {
+    let mut out = Vec::new();
+    if let Some(form) = h.get(8..12) {
+        out.push(("form", String::from_utf8_lossy(form).into_owned()));
+    }
+    if h.get(8..16) == Some(b"WAVEfmt ") {
+        if let Some(ch) = le16(h, 22) {
+            out.push(("channels", ch.to_string()));
+        }
+        if let Some(rate) = le32(h, 24) {
+            out.push(("sample_rate", rate.to_string()));
+        }
+    }
+    out
+}
+
+const fn sig(
+    name: &'static str,
+    offset: usize,
+    magic: &'static [u8],
+    details: fn(&[u8]) -> Vec<(&'static str, String)>,
+) -> Signature {
+    Signature {
+        name,
+        offset,
+        magic,
+        details,
+    }
+}
+
+pub const SIGNATURES: &[Signature] = &[
+    sig("ELF", 0, b"\x7fELF", elf_details),
+    sig("PE/MZ executable", 0, b"MZ", mz_details),
+    sig("PNG image", 0, b"\x89PNG\r\n\x1a\n", png_details),
+    sig("JPEG image", 0, b"\xff\xd8\xff", jpeg_details),
+    sig("GIF image", 0, b"GIF87a", gif_details),
+    sig("GIF image", 0, b"GIF89a", gif_details),
+    sig("ZIP archive", 0, b"PK\x03\x04", zip_details),
+    sig("GZIP data", 0, b"\x1f\x8b", gzip_details),
+    sig("PDF document", 0, b"%PDF-", pdf_details),
+    sig("SQLite 3 database", 0, b"SQLite format 3\0", sqlite_details),
+    sig("tar archive", 257, b"ustar", no_details),
+    sig("Mach-O binary", 0, b"\xfe\xed\xfa\xce", macho_details),
+    sig("Mach-O binary", 0, b"\xfe\xed\xfa\xcf", macho_details),
+    sig("Mach-O binary", 0, b"\xce\xfa\xed\xfe", macho_details),
+    sig("Mach-O binary", 0, b"\xcf\xfa\xed\xfe", macho_details),
+    sig("Mach-O fat binary", 0, b"\xca\xfe\xba\xbe", no_details),
+    sig("RIFF container", 0, b"RIFF", riff_details),
+];
+
+/// Return the first signature whose magic matches `header`.
+pub fn identify(header: &[u8]) -> Option<&'static Signature> {
+    SIGNATURES.iter().find(|sig| {
+        header
+            .get(sig.offset..sig.offset + sig.magic.len())
+            .is_some_and(|b| b == sig.magic)
+    })
+}
+
+/// Identify the type of `input` from its leading bytes.
+pub fn describe(input: &mut dyn Read, out: &mut dyn Write) -> Result<()> {
+    let mut header = Vec::with_capacity(IDENTIFY_LEN);
+    input.take(IDENTIFY_LEN as u64).read_to_end(&mut header)?;
+
+    match identify(&header) {
+        Some(sig) => {
+            let magic: Vec<String> = sig.magic.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "Type: {}", sig.name)?;
+            writeln!(
+                out,
+                "Magic: {} at offset 0x{:x}",
+                magic.join(" "),
+                sig.offset
+            )?;
+            for (key, value) in (sig.details)(&header) {
+                writeln!(out, "  {}: {}", key, value)?;
+            }
+        }
+        None => {
+            let first: Vec<String> = header
+                .iter()
+                .take(16)
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            writeln!(out, "Type: unknown")?;
+            writeln!(out, "First bytes: {}", first.join(" "))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// The per-byte `print!` formatter this crate used before dump lines were
+    /// built in memory; kept to prove the output did not change.
+    fn legacy_dump_line(offset: u64, buf: &[u8], size: usize) -> String {
+        let n = buf.len();
+        let mut s = format!("{:08x}: ", offset);
+        for (i, b) in buf.iter().enumerate() {
+            s += &format!("{:02x} ", b);
+            if (i + 1) % 8 == 0 && i + 1 != n {
+                s += " ";
+            }
+        }
+        for _ in n..size {
+            s += "   ";
+        }
+        s += "|";
+        for &b in buf {
+            s.push(byte_to_ascii(b));
+        }
+        s + "|\n"
+    }
+
+    #[test]
+    fn dump_line_matches_legacy_formatter() {
+        let data: Vec<u8> = (0..=255u8).chain(0..=255u8).collect();
+        for (offset, len, size) in [
+            (0, 0, 16),
+            (3, 5, 16),
+            (0, 16, 16),
+            (7, 8, 8),
+            (0, 512, 512),
+            (9, 17, 40),
+        ] {
+            let buf = &data[..len];
+            assert_eq!(
+                format_dump_line(offset, buf, size, false),
+                legacy_dump_line(offset, buf, size)
+            );
+        }
+    }
+
+    fn dump_to_string(data: &[u8], offset: u64, size: usize, opts: DumpOptions) -> String {
+        let mut out = Vec::new();
+        dump(&mut Cursor::new(data), offset, size, opts, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parse_offset_accepts_decimal_hex_and_suffixes() {
+        assert_eq!(parse_offset("0").unwrap(), 0);
+        assert_eq!(parse_offset("1234").unwrap(), 1234);
+        assert_eq!(parse_offset("0x10").unwrap(), 16);
+        assert_eq!(parse_offset("0XfF").unwrap(), 255);
+        assert_eq!(parse_offset("256k").unwrap(), 256 * 1024);
+        assert_eq!(parse_offset("2M").unwrap(), 2 << 20);
+        assert_eq!(parse_offset("1G").unwrap(), 1 << 30);
+    }
+
+    #[test]
+    fn parse_offset_rejects_garbage() {
+        for bad in ["", "0x", "0xZZ", "12a", "k", "-1", "99999999999G"] {
+            assert!(
+                matches!(parse_offset(bad), Err(HexToolError::Parse(_))),
+                "{:?} should not parse",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn hex_to_bytes_decodes_pairs() {
+        assert_eq!(hex_to_bytes("").unwrap(), Vec::<u8>::new());
+        assert_eq!(hex_to_bytes("00ff7F").unwrap(), vec![0x00, 0xff, 0x7f]);
+        assert_eq!(hex_to_bytes("  48656c6c6f\n").unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn hex_to_bytes_rejects_odd_length_and_non_hex() {
+        assert!(matches!(hex_to_bytes("abc"), Err(HexToolError::Parse(_))));
+        assert!(matches!(hex_to_bytes("zz"), Err(HexToolError::Parse(_))));
+        assert!(matches!(hex_to_bytes("é0"), Err(HexToolError::Parse(_))));
+    }
+
+    #[test]
+    fn dump_formats_a_full_line() {
+        let out = dump_to_string(b"Hello, World!\x00\x01\x7f", 0, 16, DumpOptions::default());
+        assert_eq!(
+            out,
+            "00000000: 48 65 6c 6c 6f 2c 20 57  6f 72 6c 64 21 00 01 7f |Hello, World!...|\n"
+        );
+    }
+
+    #[test]
+    fn dump_pads_short_reads_and_honours_offset() {
+        let out = dump_to_string(b"0123456789", 6, 8, DumpOptions::default());
+        assert_eq!(out, "00000006: 36 37 38 39             |6789|\n");
+    }
+
+    /// A JSON value as found in hextool's flat NDJSON records.
+    #[derive(Debug, PartialEq)]
+    enum Json {
+        Null,
+        Bool(bool),
+        Num(u64),
+        Str(String),
+    }
+
+    /// Parse one flat JSON object, as a strict JSON parser would read it.
+    fn parse_record(line: &str) -> Vec<(String, Json)> {
+        fn string(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+            assert_eq!(chars.next(), Some('"'));
+            let mut s = String::new();
+            loop {
+                match chars.next().expect("unterminated string") {
+                    '"' => return s,
+                    '\\' => match chars.next().expect("dangling escape") {
+                        '"' => s.push('"'),
+                        '\\' => s.push('\\'),
+                        '/' => s.push('/'),
+                        'n' => s.push('\n'),
+                        'r' => s.push('\r'),
+                        't' => s.push('\t'),
+                        'b' => s.push('\u{8}'),
+                        'f' => s.push('\u{c}'),
+                        'u' => {
+                            let hex: String = chars.by_ref().take(4).collect();
+                            let code = u32::from_str_radix(&hex, 16).expect("bad \\u escape");
+                            s.push(char::from_u32(code).expect("surrogate"));
+                        }
+                        c => panic!("invalid escape \\{}", c),
+                    },
+                    c if (c as u32) < 0x20 => panic!("raw control character {:?}", c),
+                    c => s.push(c),
+                }
+            }
+        }
+
+        let mut chars = line.chars().peekable();
+        let mut fields = Vec::new();
+        assert_eq!(chars.next(), Some('{'), "{}", line);
+        while chars.peek() != Some(&'}') {
+            if !fields.is_empty() {
+                assert_eq!(chars.next(), Some(','), "{}", line);
+            }
+            let key = string(&mut chars);
+            assert_eq!(chars.next(), Some(':'), "{}", line);
+            let value = match chars.peek() {
+                Some('"') => Json::Str(string(&mut chars)),
+                Some(c) if c.is_ascii_digit() => {
+                    let mut n = String::new();
+                    while let Some(d) = chars.next_if(|c| c.is_ascii_digit()) {
+                        n.push(d);
+                    }
+                    Json::Num(n.parse().unwrap())
+                }
+                _ => {
+                    let word: String =
+                        std::iter::from_fn(|| chars.next_if(char::is_ascii_lowercase)).collect();
+                    match word.as_str() {
+                        "true" => Json::Bool(true),
+                        "false" => Json::Bool(false),
+                        "null" => Json::Null,
+                        _ => panic!("unexpected value in {}", line),
+                    }
+                }
+            };
+            fields.push((key, value));
+        }
+        assert_eq!(chars.next(), Some('}'));
+        assert_eq!(chars.next(), None, "trailing data in {}", line);
+        fields
+    }
+
+    fn field<'a>(record: &'a [(String, Json)], key: &str) -> &'a Json {
+        &record.iter().find(|(k, _)| k == key).expect(key).1
+    }
+
+    fn str_field(s: &str) -> Json {
+        Json::Str(s.to_string())
+    }
+
+    #[test]
+    fn dump_json_emits_one_record_per_16_bytes() {
+        let data: Vec<u8> = (0u8..20).collect();
+        let opts = DumpOptions {
+            json: true,
+            utf8: false,
+        };
+        let out = dump_to_string(&data, 3, 20, opts);
+        let records: Vec<_> = out.lines().map(parse_record).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(*field(&records[0], "offset"), Json::Num(3));
+        assert_eq!(
+            *field(&records[0], "bytes"),
+            str_field("030405060708090a0b0c0d0e0f101112")
+        );
+        assert_eq!(*field(&records[0], "ascii"), str_field(&".".repeat(16)));
+        assert_eq!(*field(&records[1], "offset"), Json::Num(19));
+        assert_eq!(*field(&records[1], "bytes"), str_field("13"));
+        assert_eq!(*field(&records[1], "ascii"), str_field("."));
+    }
+
+    #[test]
+    fn dump_json_ascii_survives_quotes_controls_and_utf8() {
+        let data = b"\"a\\\x00\n\x1f\x7f\xff\xc3\xa9\xe2\x82\xac";
+        let record = |utf8| {
+            let out = dump_to_string(data, 0, 16, DumpOptions { json: true, utf8 });
+            parse_record(out.trim_end())
+        };
+
+        let plain = record(false);
+        assert_eq!(
+            *field(&plain, "bytes"),
+            str_field("22615c000a1f7fffc3a9e282ac")
+        );
+        assert_eq!(*field(&plain, "ascii"), str_field("\"a\\.........."));
+
+        let utf8 = record(true);
+        assert_eq!(*field(&utf8, "ascii"), str_field("\"a\\.....é €  "));
+
+        // Control characters that do reach the escaper come back intact.
+        let raw = "\u{0}\u{1}\t\r\n\u{1f}\u{7f}é€😀";
+        let line = format!("{{\"s\":\"{}\"}}", json_escape(raw));
+        assert_eq!(parse_record(&line), [("s".to_string(), str_field(raw))]);
+    }
+
+    #[test]
+    fn expect_json_reports_each_check() {
+        let mut input = Cursor::new(b"\x7fELF\x02\x01".to_vec());
+        let expects = [
+            Expect {
+                offset: 0,
+                bytes: b"\x7fELF".to_vec(),
+            },
+            Expect {
+                offset: 4,
+                bytes: vec![0x02, 0x02, 0x00],
+            },
+        ];
+        let mut out = Vec::new();
+        assert!(!run_expects(&mut input, &expects, true, &mut out).unwrap());
+        let text = String::from_utf8(out).unwrap();
+        let records: Vec<_> = text.lines().map(parse_record).collect();
+        assert_eq!(records.len(), 2);
+
+        assert_eq!(*field(&records[0], "offset"), Json::Num(0));
+        assert_eq!(*field(&records[0], "pass"), Json::Bool(true));
+        assert_eq!(*field(&records[0], "expected"), str_field("7f454c46"));
+        assert_eq!(*field(&records[0], "actual"), str_field("7f454c46"));
+        assert_eq!(*field(&records[0], "mismatch"), Json::Null);
+
+        assert_eq!(*field(&records[1], "offset"), Json::Num(4));
+        assert_eq!(*field(&records[1], "pass"), Json::Bool(false));
+        assert_eq!(*field(&records[1], "expected"), str_field("020200"));
+        assert_eq!(*field(&records[1], "actual"), str_field("0201"));
+        assert_eq!(*field(&records[1], "mismatch"), Json::Num(5));
+    }
+
+    #[test]
+    fn json_escape_handles_quotes_controls_and_unicode() {
+        assert_eq!(json_escape("a\"b\\c"), "a\\\"b\\\\c");
+        assert_eq!(json_escape("\n\u{1}\u{7f}"), "\\n\\u0001\\u007f");
+        assert_eq!(json_escape("é€"), "é€");
+    }
+
+    #[test]
+    fn utf8_gutter_keeps_one_cell_per_byte() {
+        let bytes = b"a\xc3\xa9\xe2\x82\xac\xf0\x9f\x98\x80\xff";
+        assert_eq!(gutter(bytes, true), "aé €  😀   .");
+        assert_eq!(gutter(bytes, false), "a..........");
+        // A sequence cut off by the end of the line is not decoded.
+        assert_eq!(gutter(b"z\xe2\x82", true), "z..");
+    }
+
+    #[test]
+    fn expect_pass_and_mismatch_offset() {
+        let mut input = Cursor::new(b"\x7fELF\x02\x01\x01".to_vec());
+        let pass = check_expect(
+            &mut input,
+            &Expect {
+                offset: 0,
+                bytes: b"\x7fELF".to_vec(),
+            },
+        )
+        .unwrap();
+        assert!(pass.passed());
+
+        let fail = check_expect(
+            &mut input,
+            &Expect {
+                offset: 4,
+                bytes: vec![0x02, 0x02],
+            },
+        )
+        .unwrap();
+        assert!(!fail.passed());
+        assert_eq!(fail.mismatch_offset(), Some(5));
+
+        let mut out = Vec::new();
+        let ok = run_expects(
+            &mut input,
+            &[Expect {
+                offset: 4,
+                bytes: vec![0x02, 0x02],
+            }],
+            false,
+            &mut out,
+        )
+        .unwrap();
+        assert!(!ok);
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("FAIL 0x00000004: first mismatch at offset 0x00000005"));
+        assert!(text.contains("   ^^"));
+    }
+
+    #[test]
+    fn expect_reports_short_input() {
+        let mut input = Cursor::new(b"abc".to_vec());
+        let mut out = Vec::new();
+        let expects = [Expect {
+            offset: 1,
+            bytes: b"bcde".to_vec(),
+        }];
+        assert!(!run_expects(&mut input, &expects, false, &mut out).unwrap());
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("file ends after 2 of 4 expected bytes"));
+        assert!(text.contains("62 63 -- --"));
+    }
+
+    #[test]
+    fn template_decodes_every_field() {
+        let fields =
+            parse_template("# demo\nmagic:str[4]\nver:u8\nlen:u16le\nid:u32be\nblob:bytes[2]\n")
+                .unwrap();
+        let mut data = b"XXHDR\0".to_vec();
+        data.extend([0x03, 0x34, 0x12, 0xde, 0xad, 0xbe, 0xef, 0xca, 0xfe]);
+        let mut out = Vec::new();
+        decode_template(&mut Cursor::new(data), 2, &fields, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[1].starts_with("00000002  magic") && lines[1].ends_with("\"HDR\""));
+        assert!(lines[2].ends_with("3 (0x03)"));
+        assert!(lines[3].ends_with("4660 (0x1234)"));
+        assert!(lines[4].ends_with("3735928559 (0xdeadbeef)"));
+        assert!(lines[5].ends_with("cafe"));
+    }
+
+    #[test]
+    fn template_errors_are_precise() {
+        let err = parse_template("a:u8\nb:u24\n").err().unwrap();
+        assert!(
+            err.to_string()
+                .starts_with("template line 2: unknown type 'u24'")
+        );
+
+        let fields = parse_template("a:u8\nb:u32le").unwrap();
+        let err = decode_template(&mut Cursor::new(vec![1, 2, 3]), 0, &fields, &mut Vec::new())
+            .err()
+            .unwrap();
+        assert!(matches!(err, HexToolError::Validation(_)));
+        assert!(
+            err.to_string()
+                .starts_with("Field 'b' at offset 0x00000001 needs 4 bytes")
+        );
+    }
+
+    #[test]
+    fn oversized_template_fields_fail_without_allocating() {
+        let data = vec![0xaa; 32];
+        let fields = parse_template("magic:u32le\nblob:bytes[99999999999999]\n").unwrap();
+        let err = decode_template(&mut Cursor::new(&data), 0, &fields, &mut Vec::new())
+            .err()
+            .unwrap();
+        assert!(matches!(err, HexToolError::Validation(_)));
+        assert_eq!(
+            err.to_string(),
+            "Field 'blob' at offset 0x00000004 needs 99999999999999 bytes but only 28 are available (template is 100000000000003 bytes, file has 32 from 0x00000000)"
+        );
+
+        // A huge read request only holds what the input really has.
+        let buf = read_at(&mut Cursor::new(&data), 30, usize::MAX).unwrap();
+        assert_eq!(buf, [0xaa, 0xaa]);
+    }
+
+    #[test]
+    fn template_sizes_that_overflow_are_reported() {
+        let fields = parse_template(&format!("blob:bytes[{}]\nb:u8\n", u64::MAX)).unwrap();
+        let err = decode_template(&mut Cursor::new(vec![1, 2, 3]), 1, &fields, &mut Vec::new())
+            .err()
+            .unwrap();
+        assert!(matches!(err, HexToolError::Validation(_)));
+        assert_eq!(
+            err.to_string(),
+            "Field 'blob' at offset 0x00000001 needs 18446744073709551615 bytes but only 2 are available (template size overflows 64 bits, file has 2 from 0x00000001)"
+        );
+
+        // Past the end of the file nothing is available at all.
+        let fields = parse_template("a:u8").unwrap();
+        let err = decode_template(&mut Cursor::new(vec![1]), 5, &fields, &mut Vec::new())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("but only 0 are available"));
+    }
+
+    #[test]
+    fn builtin_templates_parse() {
+        assert_eq!(
+            load_template("@elf-header")
+                .unwrap()
+                .iter()
+                .map(|f| f.kind.size())
+                .sum::<usize>(),
+            64
+        );
+        assert_eq!(
+            load_template("@bmp-header")
+                .unwrap()
+                .iter()
+                .map(|f| f.kind.size())
+                .sum::<usize>(),
+            54
+        );
+        assert!(load_template("@nope").is_err());
+    }
+
+    fn details_of(header: &[u8]) -> (&'static str, Vec<(&'static str, String)>) {
+        let sig = identify(header).expect("signature");
+        (sig.name, (sig.details)(header))
+    }
+
+    #[test]
+    fn identify_handcrafted_headers() {
+        let mut elf = b"\x7fELF\x01\x02\x01".to_vec();
+        elf.resize(16, 0);
+        elf.extend([0x00, 0x02, 0x00, 0x28]);
+        let (name, details) = details_of(&elf);
+        assert_eq!(name, "ELF");
+        assert_eq!(details[0], ("class", "32-bit".to_string()));
+        assert_eq!(details[1], ("endianness", "big-endian".to_string()));
+        assert_eq!(details[2], ("type", "executable".to_string()));
+
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend(640u32.to_be_bytes());
+        png.extend(480u32.to_be_bytes());
+        png.push(8);
+        let (name, details) = details_of(&png);
+        assert_eq!(name, "PNG image");
+        assert_eq!(details[0], ("dimensions", "640x480".to_string()));
+
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend(10u16.to_le_bytes());
+        gif.extend(20u16.to_le_bytes());
+        assert_eq!(details_of(&gif).1[1], ("dimensions", "10x20".to_string()));
+
+        assert_eq!(
+            details_of(b"%PDF-1.7\n").1[0],
+            ("version", "1.7".to_string())
+        );
+        assert_eq!(details_of(b"\x1f\x8b\x08\0\0\0\0\0").0, "GZIP data");
+
+        let mut tar = vec![0u8; 257];
+        tar.extend(b"ustar\x0000");
+        assert_eq!(details_of(&tar).0, "tar archive");
+
+        assert!(identify(b"plain text").is_none());
+    }
+
+    #[test]
+    fn sparse_dump_near_the_top_of_the_offset_range() {
+        let path = std::env::temp_dir().join(format!("hextool-sparse-top-{}", std::process::id()));
+        std::fs::write(&path, b"data").unwrap();
+        let mut file = File::open(&path).unwrap();
+        // Offset plus size would overflow; this behaves like a plain dump
+        // (which the OS refuses to seek for) rather than panicking.
+        for offset in [u64::MAX - 3, u64::MAX] {
+            let mut sparse = Vec::new();
+            let sparse_result =
+                dump_sparse(&mut file, offset, 16, DumpOptions::default(), &mut sparse);
+            let mut plain = Vec::new();
+            let plain_result = dump(&mut file, offset, 16, DumpOptions::default(), &mut plain);
+            assert_eq!(sparse_result.is_ok(), plain_result.is_ok());
+            assert_eq!(sparse, plain);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sparse_dump_marks_holes_and_keeps_data() {
+        let path = std::env::temp_dir().join(format!("hextool-sparse-{}", std::process::id()));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let size = 4 << 20;
+        file.set_len(size).unwrap();
+        write_at(
+            &mut file,
+            0,
+            b"head",
+            DumpOptions::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+        write_at(
+            &mut file,
+            2 << 20,
+            b"middle",
+            DumpOptions::default(),
+            &mut Vec::new(),
+        )
+        .unwrap();
+
+        let mut sparse = Vec::new();
+        dump_sparse(
+            &mut file,
+            0,
+            size as usize,
+            DumpOptions::default(),
+            &mut sparse,
+        )
+        .unwrap();
+        let sparse = String::from_utf8(sparse).unwrap();
+
+        if data_regions(&file, 0, size).is_some_and(|r| r.len() > 1) {
+            assert!(sparse.contains("... hole: "));
+            let data_lines: Vec<&str> = sparse.lines().filter(|l| !l.starts_with("...")).collect();
+            assert_eq!(data_lines.len(), 2);
+            assert!(data_lines[0].starts_with("00000000: 68 65 61 64 00"));
+            assert!(data_lines[1].starts_with("00200000: 6d 69 64 64 6c 65 00"));
+        } else {
+            // No hole support here: the output must match a plain dump.
+            let mut plain = Vec::new();
+            dump(
+                &mut file,
+                0,
+                size as usize,
+                DumpOptions::default(),
+                &mut plain,
+            )
+            .unwrap();
+            assert_eq!(sparse.as_bytes(), plain);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bitfield_straddling_a_byte_boundary() {
+        // Bits 9..7 take bit 7 of the low byte and bits 1..0 of the next one.
+        let bytes = [0b1000_0000, 0b0000_0010];
+        assert_eq!(extract_bits(&bytes, Endian::Little, 9, 7).unwrap(), 0b101);
+        // Big endian numbers from the last byte instead.
+        assert_eq!(extract_bits(&bytes, Endian::Big, 9, 7).unwrap(), 0b000);
+        assert_eq!(
+            extract_bits(&[0b0000_0010, 0b1000_0000], Endian::Big, 9, 7).unwrap(),
+            0b101
+        );
+        assert_eq!(extract_bits(&[0xa5], Endian::Little, 7, 0).unwrap(), 0xa5);
+        assert!(matches!(
+            extract_bits(&bytes, Endian::Little, 16, 14),
+            Err(HexToolError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn bitfield_specs_are_validated() {
+        let f = BitField::parse("mode:9:7").unwrap();
+        assert_eq!((f.name.as_str(), f.hi, f.lo, f.width()), ("mode", 9, 7, 3));
+        for bad in [
+            "mode",
+            "mode:1",
+            "mode:x:0",
+            ":3:1",
+            "mode:1:3",
+            "wide:40:0",
+        ] {
+            assert!(
+                BitField::parse(bad).is_err(),
+                "{:?} should be rejected",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn bits_view_prints_each_bit_and_fields() {
+        let mut out = Vec::new();
+        let fields = [BitField::parse("mode:9:7").unwrap()];
+        dump_bits(
+            &mut Cursor::new(vec![0x80, 0x02]),
+            0,
+            2,
+            &fields,
+            Endian::Little,
+            &mut out,
+        )
+        .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("00000000  80   1 0 0 0 0 0 0 0\n"));
+        assert!(text.contains("00000001  02   0 0 0 0 0 0 1 0\n"));
+        assert!(text.ends_with("mode[9:7] = 5 (0x5, 0b101)\n"));
+    }
+
+    #[test]
+    fn concat_with_alignment_round_trips() {
+        let dir = std::env::temp_dir().join(format!("hextool-concat-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sources: Vec<(String, Vec<u8>)> = [5usize, 16, 3]
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| {
+                let path = dir.join(format!("in{}", i)).to_string_lossy().into_owned();
+                let data: Vec<u8> = (0..len).map(|b| (b + i * 50) as u8).collect();
+                std::fs::write(&path, &data).unwrap();
+                (path, data)
+            })
+            .collect();
+        let inputs: Vec<String> = sources.iter().map(|(p, _)| p.clone()).collect();
+
+        let plan = plan_concat(&inputs, 8).unwrap();
+        let offsets: Vec<u64> = plan.iter().map(|e| e.offset).collect();
+        assert_eq!(offsets, vec![0, 8, 24]);
+
+        let mut joined = Cursor::new(Vec::new());
+        let total = concat(&plan, &mut joined, 0xee, &mut Vec::new()).unwrap();
+        assert_eq!(total, 27);
+        assert_eq!(&joined.get_ref()[5..8], &[0xee; 3]);
+        for (entry, (_, data)) in plan.iter().zip(&sources) {
+            assert_eq!(
+                &read_at(&mut joined, entry.offset, data.len()).unwrap(),
+                data
+            );
+        }
+
+        let mut missing = inputs.clone();
+        missing.push(dir.join("nope").to_string_lossy().into_owned());
+        assert!(matches!(
+            plan_concat(&missing, 1),
+            Err(HexToolError::Validation(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn split_and_join_round_trip() {
+        let dir = std::env::temp_dir().join(format!("hextool-split-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("part").to_string_lossy().into_owned();
+        let data: Vec<u8> = (0..(1 << 20) + 37).map(|i| (i * 31 % 251) as u8).collect();
+
+        split(&mut Cursor::new(&data), 256 << 10, &prefix, &mut Vec::new()).unwrap();
+        assert_eq!(chunk_indices(&prefix).unwrap(), vec![0, 1, 2, 3, 4]);
+
+        let mut joined = Vec::new();
+        let total = join(&prefix, &mut joined, &mut Vec::new()).unwrap();
+        assert_eq!(total as usize, data.len());
+        assert_eq!(joined, data);
+
+        std::fs::remove_file(chunk_name(&prefix, 2)).unwrap();
+        let err = chunk_indices(&prefix).err().unwrap();
+        assert!(err.to_string().starts_with("Missing chunk"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,13 @@
+use std::env;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 
-use std::env;
+use rust_02::{
+    BitField, DumpOptions, Endian, Expect, HexToolError, concat, decode_template, describe, dump,
+    dump_bits, dump_sparse, hex_to_bytes, join, load_template, parse_offset, plan_concat,
+    replace_with, run_expects, split, write_at,
+};
 
 /// Hex Tool - Read & Write Binary Files
 struct Args {
@@ -10,14 +16,68 @@ struct Args {
     write: Option<String>,
     offset: u64,
     size: Option<usize>,
+    expects: Vec<ExpectArg>,
+    json: bool,
+    template: Option<String>,
+    utf8: bool,
+    split: Option<u64>,
+    out_prefix: Option<String>,
+    join: Option<String>,
+    out: Option<String>,
+    identify: bool,
+    sparse: bool,
+    bits: bool,
+    bitfields: Vec<BitField>,
+    endian: Endian,
+    concat: Option<(String, Vec<String>)>,
+    align: u64,
+    pad_byte: u8,
+}
+
+/// A single `--expect`/`--expect-file` check, bound to the offset in effect
+/// when it appeared on the command line.
+struct ExpectArg {
+    offset: u64,
+    source: ExpectSource,
+}
+
+enum ExpectSource {
+    Hex(String),
+    File(String),
 }
 
 fn print_help() {
     println!("Hex Tool - Read & Write Binary Files\n");
-    println!("Usage: hextool --file <PATH> [--read | --write <HEX>] [--offset <N>] [--size <N>]\n");
     println!(
-        "Options:\n  -f, --file PATH      Target file (required)\n      --read           Read mode (display hex)\n      --write HEX      Write mode (hex string to write)\n      --offset N       Offset in bytes (decimal or 0x hex) [default: 0]\n      --size N         Number of bytes to read\n  -h, --help           Print help"
+        "Usage: hextool --file <PATH> [--read | --write <HEX> | --expect <HEX>...] [--offset <N>] [--size <N>]\n"
+    );
+    println!("Options:");
+    println!("  -f, --file PATH      Target file (required)");
+    println!("      --read           Read mode (display hex)");
+    println!("      --write HEX      Write mode (hex string to write)");
+    println!("      --expect HEX     Check that the bytes at --offset equal HEX (repeatable)");
+    println!("      --expect-file P  Check that the bytes at --offset equal the contents of P");
+    println!("      --offset N       Offset in bytes (decimal or 0x hex) [default: 0]");
+    println!("      --size N         Number of bytes to read (k/M/G suffixes allowed)");
+    println!("      --json           Emit one JSON object per line (read, write, expect)");
+    println!("      --utf8           Show UTF-8 characters in the ASCII column");
+    println!("      --split SIZE     Split --file into SIZE-byte chunks (k/M/G suffixes allowed)");
+    println!("      --out-prefix P   Chunk name prefix for --split (P.000, P.001, ...)");
+    println!("      --join PREFIX    Concatenate PREFIX.000, PREFIX.001, ... back together");
+    println!("      --out FILE       Output file for --join");
+    println!("      --identify       Detect the file type from its magic number");
+    println!("      --sparse         Skip holes in sparse files when reading");
+    println!("      --bits           Show each byte of the range (max 64) bit by bit");
+    println!("      --bitfield F     Extract NAME:HI:LO from the range (repeatable, max 32 bits)");
+    println!("      --endian E       Byte order for --bitfield: little or big [default: little]");
+    println!("      --concat OUT A.. Write the input files back to back into OUT");
+    println!("      --align N        Start each --concat input at a multiple of N [default: 1]");
+    println!("      --pad-byte B     Byte used for --align padding [default: 0]");
+    println!(
+        "      --template FILE  Decode fields described in FILE (or @elf-header, @bmp-header)"
     );
+    println!("  -h, --help           Print help");
+    println!("\nEach --expect uses the most recent --offset given before it.");
 }
 
 fn parse_args() -> Result<Args, String> {
@@ -26,6 +86,22 @@ fn parse_args() -> Result<Args, String> {
     let mut write: Option<String> = None;
     let mut offset: u64 = 0;
     let mut size: Option<usize> = None;
+    let mut expects: Vec<ExpectArg> = Vec::new();
+    let mut json = false;
+    let mut template: Option<String> = None;
+    let mut utf8 = false;
+    let mut split: Option<u64> = None;
+    let mut out_prefix: Option<String> = None;
+    let mut join: Option<String> = None;
+    let mut out: Option<String> = None;
+    let mut identify = false;
+    let mut sparse = false;
+    let mut bits = false;
+    let mut bitfields: Vec<BitField> = Vec::new();
+    let mut endian = Endian::Little;
+    let mut concat: Option<(String, Vec<String>)> = None;
+    let mut align: u64 = 1;
+    let mut pad_byte: u8 = 0;
 
     let mut it = env::args().skip(1).peekable();
     while let Some(arg) = it.next() {
@@ -41,12 +117,96 @@ fn parse_args() -> Result<Args, String> {
             "-w" | "--write" => write = it.next(),
             "-o" | "--offset" => {
                 if let Some(v) = it.next() {
-                    offset = parse_offset(&v)?;
+                    offset = parse_offset(&v).map_err(|e| e.to_string())?;
+                }
+            }
+            "-e" | "--expect" => {
+                let hex = it
+                    .next()
+                    .ok_or_else(|| "--expect requires HEX".to_string())?;
+                expects.push(ExpectArg {
+                    offset,
+                    source: ExpectSource::Hex(hex),
+                });
+            }
+            "--expect-file" => {
+                let path = it
+                    .next()
+                    .ok_or_else(|| "--expect-file requires PATH".to_string())?;
+                expects.push(ExpectArg {
+                    offset,
+                    source: ExpectSource::File(path),
+                });
+            }
+            "--json" => json = true,
+            "--utf8" => utf8 = true,
+            "-t" | "--template" => {
+                template = Some(
+                    it.next()
+                        .ok_or_else(|| "--template requires FILE".to_string())?,
+                );
+            }
+            "--split" => {
+                let v = it
+                    .next()
+                    .ok_or_else(|| "--split requires SIZE".to_string())?;
+                let n = parse_offset(&v).map_err(|e| e.to_string())?;
+                if n == 0 {
+                    return Err("--split SIZE must be greater than 0".to_string());
+                }
+                split = Some(n);
+            }
+            "--out-prefix" => out_prefix = it.next(),
+            "--join" => join = it.next(),
+            "--out" => out = it.next(),
+            "-i" | "--identify" => identify = true,
+            "--sparse" => sparse = true,
+            "--bits" => bits = true,
+            "--bitfield" => {
+                let spec = it
+                    .next()
+                    .ok_or_else(|| "--bitfield requires NAME:HI:LO".to_string())?;
+                bitfields.push(BitField::parse(&spec).map_err(|e| e.to_string())?);
+            }
+            "--endian" => {
+                endian = match it.next().as_deref() {
+                    Some("little" | "le") => Endian::Little,
+                    Some("big" | "be") => Endian::Big,
+                    _ => return Err("--endian requires 'little' or 'big'".to_string()),
+                };
+            }
+            "--concat" => {
+                let target = it
+                    .next()
+                    .ok_or_else(|| "--concat requires OUT and input files".to_string())?;
+                let mut inputs = Vec::new();
+                while let Some(input) = it.next_if(|a| !a.starts_with('-') || a == "-") {
+                    inputs.push(input);
+                }
+                if inputs.is_empty() {
+                    return Err("--concat requires at least one input file".to_string());
+                }
+                concat = Some((target, inputs));
+            }
+            "--align" => {
+                let v = it.next().ok_or_else(|| "--align requires N".to_string())?;
+                align = parse_offset(&v).map_err(|e| e.to_string())?;
+                if align == 0 {
+                    return Err("--align N must be greater than 0".to_string());
                 }
             }
+            "--pad-byte" => {
+                let v = it
+                    .next()
+                    .ok_or_else(|| "--pad-byte requires a value".to_string())?;
+                pad_byte = parse_offset(&v)
+                    .ok()
+                    .and_then(|n| u8::try_from(n).ok())
+                    .ok_or_else(|| format!("Invalid pad byte: {}", v))?;
+            }
             "-s" | "--size" => {
                 if let Some(v) = it.next() {
-                    size = Some(v.parse().unwrap_or(16));
+                    size = Some(parse_offset(&v).map_or(16, |n| n as usize));
                 }
             }
             _ => {
@@ -56,118 +216,231 @@ fn parse_args() -> Result<Args, String> {
         }
     }
 
-    let file = file.ok_or_else(|| "--file is required".to_string())?;
+    if split.is_some() && out_prefix.is_none() {
+        return Err("--split requires --out-prefix PREFIX".to_string());
+    }
+    if join.is_some() && out.is_none() {
+        return Err("--join requires --out FILE".to_string());
+    }
+    // Only reading, writing and --expect have a JSON form.
+    let plain_only = [
+        (split.is_some(), "--split"),
+        (join.is_some(), "--join"),
+        (concat.is_some(), "--concat"),
+        (template.is_some(), "--template"),
+        (identify, "--identify"),
+        (bits || !bitfields.is_empty(), "--bits"),
+    ];
+    if let Some((_, mode)) = plain_only.iter().find(|(used, _)| json && *used) {
+        return Err(format!("--json cannot be used with {}", mode));
+    }
+    // Joining and concatenating name their own inputs, so need no --file.
+    let file = match file {
+        Some(f) => f,
+        None if join.is_some() || concat.is_some() => String::new(),
+        None => return Err("--file is required".to_string()),
+    };
     Ok(Args {
         file,
         read,
         write,
         offset,
         size,
+        expects,
+        json,
+        template,
+        utf8,
+        split,
+        out_prefix,
+        join,
+        out,
+        identify,
+        sparse,
+        bits,
+        bitfields,
+        endian,
+        concat,
+        align,
+        pad_byte,
     })
 }
 
-fn parse_offset(s: &str) -> Result<u64, String> {
-    if s.starts_with("0x") || s.starts_with("0X") {
-        u64::from_str_radix(&s[2..], 16).map_err(|_| format!("Invalid hex offset: {}", s))
-    } else {
-        s.parse::<u64>()
-            .map_err(|_| format!("Invalid decimal offset: {}", s))
+/// Carry out the requested mode and return the process exit status.
+fn run(args: &Args, out: &mut dyn Write) -> Result<i32, HexToolError> {
+    let opts = DumpOptions {
+        json: args.json,
+        utf8: args.utf8,
+    };
+
+    if args.identify {
+        describe(&mut File::open(&args.file)?, out)?;
+        return Ok(0);
     }
-}
 
-fn hex_to_bytes(hex: &str) -> Option<Vec<u8>> {
-    let hex = hex.trim();
-    if !hex.len().is_multiple_of(2) {
-        return None;
+    // Bit Mode
+    if args.bits || !args.bitfields.is_empty() {
+        // Without --size, cover exactly the bytes the bitfields need.
+        let needed = args.bitfields.iter().map(|f| f.hi as usize / 8 + 1).max();
+        let size = args.size.or(needed).unwrap_or(1);
+        let mut file = File::open(&args.file)?;
+        dump_bits(
+            &mut file,
+            args.offset,
+            size,
+            &args.bitfields,
+            args.endian,
+            out,
+        )?;
+        return Ok(0);
     }
-    (0..hex.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
-        .collect()
-}
 
-fn byte_to_ascii(byte: u8) -> char {
-    if (0x20..=0x7E).contains(&byte) {
-        byte as char
-    } else {
-        '.'
+    // Concat Mode
+    if let Some((target, inputs)) = &args.concat {
+        // Check every input first, and build OUT beside the original so
+        // that OUT may also be one of the inputs.
+        let plan = plan_concat(inputs, args.align)?;
+        replace_with(Path::new(target), |file| {
+            concat(&plan, file, args.pad_byte, out)
+        })?;
+        return Ok(0);
     }
-}
 
-fn main() -> io::Result<()> {
-    let args = match parse_args() {
-        Ok(a) => a,
-        Err(e) => {
-            eprintln!("{}", e);
-            print_help();
-            std::process::exit(2);
-        }
-    };
+    // Split / Join Mode
+    if let (Some(size), Some(prefix)) = (args.split, &args.out_prefix) {
+        split(&mut File::open(&args.file)?, size, prefix, out)?;
+        return Ok(0);
+    }
+    if let (Some(prefix), Some(target)) = (&args.join, &args.out) {
+        // The chunk list is checked before anything is written.
+        replace_with(Path::new(target), |file| join(prefix, file, out))?;
+        return Ok(0);
+    }
+
+    // Template Mode
+    if let Some(spec) = &args.template {
+        let fields = load_template(spec)?;
+        decode_template(&mut File::open(&args.file)?, args.offset, &fields, out)?;
+        return Ok(0);
+    }
+
+    // Expect Mode
+    if !args.expects.is_empty() {
+        let expects = args
+            .expects
+            .iter()
+            .map(|e| {
+                let bytes = match &e.source {
+                    ExpectSource::Hex(hex) => hex_to_bytes(hex)?,
+                    ExpectSource::File(path) => std::fs::read(path)?,
+                };
+                Ok(Expect {
+                    offset: e.offset,
+                    bytes,
+                })
+            })
+            .collect::<Result<Vec<_>, HexToolError>>()?;
+        let ok = run_expects(&mut File::open(&args.file)?, &expects, args.json, out)?;
+        return Ok(if ok { 0 } else { 1 });
+    }
 
     // Write Mode
     if let Some(hexstr) = &args.write {
-        let bytes = hex_to_bytes(hexstr);
-        if bytes.is_none() {
-            eprintln!("Invalid hex string.");
-            std::process::exit(1);
-        }
-        let data = bytes.unwrap();
+        let data = hex_to_bytes(hexstr)?;
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(false)
             .open(&args.file)?;
-        file.seek(SeekFrom::Start(args.offset))?;
-        file.write_all(&data)?;
-
-        println!(
-            "Writing {} bytes at offset 0x{:08x}",
-            data.len(),
-            args.offset
-        );
-        print!("Hex: ");
-        for (i, &b) in data.iter().enumerate() {
-            if i > 0 {
-                print!(" ");
-            }
-            print!("{:02x}", b);
-        }
-        println!();
-        print!("ASCII: ");
-        for &b in &data {
-            print!("{}", byte_to_ascii(b));
-        }
-        println!("\n✓ Successfully written");
-        return Ok(());
+        write_at(&mut file, args.offset, &data, opts, out)?;
+        return Ok(0);
     }
 
     // Read Mode
     if args.read {
         let size = args.size.unwrap_or(16);
         let mut file = File::open(&args.file)?;
-        file.seek(SeekFrom::Start(args.offset))?;
-        let mut buf = vec![0u8; size];
-        let n = file.read(&mut buf)?;
-
-        print!("{:08x}: ", args.offset);
-        for (i, b) in buf[..n].iter().enumerate() {
-            print!("{:02x} ", b);
-            if (i + 1) % 8 == 0 && i + 1 != n {
-                print!(" ");
-            }
+        if args.sparse {
+            dump_sparse(&mut file, args.offset, size, opts, out)?;
+        } else {
+            dump(&mut file, args.offset, size, opts, out)?;
+        }
+        return Ok(0);
+    }
+
+    eprintln!("Please specify either --read or --write option. Use --help for usage.");
+    Ok(0)
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}", e);
+            print_help();
+            std::process::exit(2);
         }
-        // Fill with spaces if not enough bytes read
-        for _ in n..size {
-            print!("   ");
+    };
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let result = run(&args, &mut out);
+    // Flush whatever was produced before exiting, since process::exit
+    // skips the BufWriter's destructor.
+    let flushed = out.flush().map_err(HexToolError::from);
+    let code = match result.and_then(|code| flushed.map(|()| code)) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            e.exit_code()
         }
-        print!("|");
-        for &b in &buf[..n] {
-            print!("{}", byte_to_ascii(b));
+    };
+    std::process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expect_args(file: &str, hex: &str) -> Args {
+        Args {
+            file: file.to_string(),
+            read: false,
+            write: None,
+            offset: 0,
+            size: None,
+            expects: vec![ExpectArg {
+                offset: 1,
+                source: ExpectSource::Hex(hex.to_string()),
+            }],
+            json: false,
+            template: None,
+            utf8: false,
+            split: None,
+            out_prefix: None,
+            join: None,
+            out: None,
+            identify: false,
+            sparse: false,
+            bits: false,
+            bitfields: Vec::new(),
+            endian: Endian::Little,
+            concat: None,
+            align: 1,
+            pad_byte: 0,
         }
-        println!("|");
-        return Ok(());
     }
 
-    println!("Please specify either --read or --write option. Use --help for usage.");
-    Ok(())
+    #[test]
+    fn expect_mode_returns_its_status() {
+        let path = std::env::temp_dir().join(format!("hextool-run-{}", std::process::id()));
+        std::fs::write(&path, b"abcd").unwrap();
+        let file = path.to_str().unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(run(&expect_args(file, "6263"), &mut out).unwrap(), 0);
+        assert_eq!(out, b"PASS 0x00000001: 2 bytes match\n");
+        let mut out = Vec::new();
+        assert_eq!(run(&expect_args(file, "6264"), &mut out).unwrap(), 1);
+        assert!(out.starts_with(b"FAIL 0x00000001: "));
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,351 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Run hextool with `args`.
+fn hextool(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust_02"))
+        .args(args)
+        .output()
+        .expect("run hextool")
+}
+
+/// A file in the temp directory holding `data`, unique to this test.
+fn fixture(name: &str, data: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("hextool-cli-{}-{}", name, std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+const HEADER: &[u8] = b"\x7fELF\x02\x01\x01\x00";
+
+#[test]
+fn expect_passes_and_exits_zero() {
+    let file = fixture("expect-pass", HEADER);
+    let path = file.to_str().unwrap();
+    let out = hextool(&["-f", path, "--expect", "7f454c46"]);
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    assert_eq!(text(&out.stdout), "PASS 0x00000000: 4 bytes match\n");
+
+    // Each --expect takes the --offset given before it.
+    let out = hextool(&[
+        "-f", path, "--expect", "7f45", "--offset", "4", "--expect", "020101",
+    ]);
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    assert_eq!(
+        text(&out.stdout),
+        "PASS 0x00000000: 2 bytes match\nPASS 0x00000004: 3 bytes match\n"
+    );
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn expect_mismatch_marks_the_offset_and_exits_one() {
+    let file = fixture("expect-fail", HEADER);
+    let path = file.to_str().unwrap();
+    let out = hextool(&[
+        "-f", path, "--expect", "7f454c46", "--offset", "4", "--expect", "0202",
+    ]);
+    assert_eq!(out.status.code(), Some(1), "{:?}", out);
+    let lines: Vec<String> = text(&out.stdout).lines().map(String::from).collect();
+    assert_eq!(lines[0], "PASS 0x00000000: 4 bytes match");
+    assert_eq!(
+        lines[1],
+        "FAIL 0x00000004: first mismatch at offset 0x00000005"
+    );
+    assert!(lines[3].starts_with("  00000004  02 02 "), "{}", lines[3]);
+    assert!(lines[3].ends_with("02 01"), "{}", lines[3]);
+    // The marker sits under the second byte only
+    assert_eq!(lines[4].trim(), "^^");
+    assert_eq!(lines[4].find('^'), lines[3].rfind("01"));
+    // The target is left as it was
+    assert_eq!(std::fs::read(&file).unwrap(), HEADER);
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn expect_past_the_end_of_a_short_file_fails() {
+    let file = fixture("expect-short", b"abc");
+    let path = file.to_str().unwrap();
+    let out = hextool(&["-f", path, "--offset", "1", "--expect", "62636465"]);
+    assert_eq!(out.status.code(), Some(1), "{:?}", out);
+    let stdout = text(&out.stdout);
+    assert!(stdout.starts_with("FAIL 0x00000001: file ends after 2 of 4 expected bytes\n"));
+    assert!(stdout.contains("62 63 -- --"), "{}", stdout);
+
+    // Starting beyond the end reads nothing at all
+    let out = hextool(&["-f", path, "--offset", "10", "--expect", "00"]);
+    assert_eq!(out.status.code(), Some(1), "{:?}", out);
+    assert!(text(&out.stdout).contains("file ends after 0 of 1 expected bytes"));
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn expect_file_compares_against_another_file() {
+    let file = fixture("expect-target", HEADER);
+    let same = fixture("expect-same", b"\x02\x01\x01");
+    let other = fixture("expect-other", b"\x02\x02\x01");
+    let path = file.to_str().unwrap();
+    let out = hextool(&[
+        "-f",
+        path,
+        "--offset",
+        "4",
+        "--expect-file",
+        same.to_str().unwrap(),
+    ]);
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    let out = hextool(&[
+        "-f",
+        path,
+        "--offset",
+        "4",
+        "--expect-file",
+        other.to_str().unwrap(),
+    ]);
+    assert_eq!(out.status.code(), Some(1), "{:?}", out);
+    for f in [file, same, other] {
+        std::fs::remove_file(f).unwrap();
+    }
+}
+
+#[test]
+fn json_records_for_read_write_and_expect() {
+    // Quotes, backslashes and control bytes must still give valid JSON
+    let file = fixture("json", b"\"a\\\x00\n\x1f\x7f\xff\xc3\xa9");
+    let path = file.to_str().unwrap();
+    let out = hextool(&["-f", path, "--read", "--json"]);
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    assert_eq!(
+        text(&out.stdout),
+        "{\"offset\":0,\"bytes\":\"22615c000a1f7fffc3a9\",\"ascii\":\"\\\"a\\\\.......\"}\n"
+    );
+
+    let out = hextool(&["-f", path, "--write", "41", "--offset", "1", "--json"]);
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    assert_eq!(
+        text(&out.stdout),
+        "{\"offset\":1,\"bytes\":\"41\",\"ascii\":\"A\"}\n"
+    );
+
+    let out = hextool(&[
+        "-f", path, "--json", "--expect", "22415c", "--offset", "8", "--expect", "c3a900",
+    ]);
+    assert_eq!(out.status.code(), Some(1), "{:?}", out);
+    assert_eq!(
+        text(&out.stdout),
+        "{\"offset\":0,\"pass\":true,\"expected\":\"22415c\",\"actual\":\"22415c\",\"mismatch\":null}\n\
+         {\"offset\":8,\"pass\":false,\"expected\":\"c3a900\",\"actual\":\"c3a9\",\"mismatch\":10}\n"
+    );
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn oversized_templates_fail_before_reading() {
+    let file = fixture("template-big", &[0xaa; 32]);
+    let path = file.to_str().unwrap();
+    let template = fixture(
+        "template-big.tpl",
+        b"magic:u32le\nblob:bytes[99999999999999]\n",
+    );
+    let out = hextool(&["-f", path, "--template", template.to_str().unwrap()]);
+    assert_eq!(out.status.code(), Some(1), "{:?}", out);
+    assert_eq!(
+        text(&out.stderr),
+        "Field 'blob' at offset 0x00000004 needs 99999999999999 bytes but only 28 are available (template is 100000000000003 bytes, file has 32 from 0x00000000)\n"
+    );
+
+    // A template whose size does not fit in 64 bits
+    let overflow = format!("blob:bytes[{}]\nb:u8\n", u64::MAX);
+    std::fs::write(&template, overflow).unwrap();
+    let out = hextool(&[
+        "-f",
+        path,
+        "--offset",
+        "30",
+        "--template",
+        template.to_str().unwrap(),
+    ]);
+    assert_eq!(out.status.code(), Some(1), "{:?}", out);
+    assert_eq!(
+        text(&out.stderr),
+        "Field 'blob' at offset 0x0000001e needs 18446744073709551615 bytes but only 2 are available (template size overflows 64 bits, file has 2 from 0x0000001e)\n"
+    );
+    for f in [file, template] {
+        std::fs::remove_file(f).unwrap();
+    }
+}
+
+#[test]
+fn utf8_gutter_keeps_one_column_per_byte() {
+    let file = fixture("utf8", b"caf\xc3\xa9 \xe2\x82\xac!\xff");
+    let path = file.to_str().unwrap();
+    let out = hextool(&["-f", path, "--read"]);
+    assert_eq!(
+        text(&out.stdout),
+        "00000000: 63 61 66 c3 a9 20 e2 82  ac 21 ff                |caf.. ...!.|\n"
+    );
+    // Each character sits on its first byte, with its other bytes blank
+    let out = hextool(&["-f", path, "--read", "--utf8"]);
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    assert_eq!(
+        text(&out.stdout),
+        "00000000: 63 61 66 c3 a9 20 e2 82  ac 21 ff                |café  €  !.|\n"
+    );
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn split_and_join_round_trip() {
+    let data: Vec<u8> = (0..=255).cycle().take(2500).collect();
+    let file = fixture("split", &data);
+    let path = file.to_str().unwrap();
+    let prefix = format!("{}-part", path);
+    let out = hextool(&["-f", path, "--split", "1k", "--out-prefix", &prefix]);
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    let sizes: Vec<u64> = ["000", "001", "002"]
+        .iter()
+        .map(|n| {
+            std::fs::metadata(format!("{}.{}", prefix, n))
+                .unwrap()
+                .len()
+        })
+        .collect();
+    assert_eq!(sizes, [1024, 1024, 452]);
+    assert!(!std::path::Path::new(&format!("{}.003", prefix)).exists());
+
+    let joined = format!("{}-joined", path);
+    let out = hextool(&["--join", &prefix, "--out", &joined]);
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    assert_eq!(std::fs::read(&joined).unwrap(), data);
+    for n in ["000", "001", "002"] {
+        std::fs::remove_file(format!("{}.{}", prefix, n)).unwrap();
+    }
+    std::fs::remove_file(joined).unwrap();
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn identify_names_the_type_from_its_magic() {
+    let png = fixture("identify.png", b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR");
+    let out = hextool(&["-f", png.to_str().unwrap(), "--identify"]);
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    assert_eq!(
+        text(&out.stdout),
+        "Type: PNG image\nMagic: 89 50 4e 47 0d 0a 1a 0a at offset 0x0\n"
+    );
+
+    let plain = fixture("identify.txt", b"hello");
+    let out = hextool(&["-f", plain.to_str().unwrap(), "--identify"]);
+    assert_eq!(
+        text(&out.stdout),
+        "Type: unknown\nFirst bytes: 68 65 6c 6c 6f\n"
+    );
+    for f in [png, plain] {
+        std::fs::remove_file(f).unwrap();
+    }
+}
+
+#[test]
+fn json_is_refused_by_modes_without_a_json_form() {
+    let file = fixture("json-modes", HEADER);
+    let path = file.to_str().unwrap();
+    let prefix = format!("{}-part", path);
+    for (args, mode) in [
+        (vec!["--split", "4", "--out-prefix", &prefix], "--split"),
+        (vec!["--identify"], "--identify"),
+        (vec!["--bits"], "--bits"),
+        (vec!["--template", "@elf-header"], "--template"),
+        (vec!["--join", &prefix, "--out", &prefix], "--join"),
+        (vec!["--concat", &prefix, path], "--concat"),
+    ] {
+        let out = hextool(&[&["-f", path, "--json"], &args[..]].concat());
+        assert_eq!(out.status.code(), Some(2), "{:?}", out);
+        let stderr = text(&out.stderr);
+        assert!(
+            stderr.starts_with(&format!("--json cannot be used with {}\n", mode)),
+            "{}",
+            stderr
+        );
+    }
+    // Nothing was split before the refusal
+    assert!(!std::path::Path::new(&format!("{}.000", prefix)).exists());
+
+    // Sparse reads are dumps, so they keep their JSON records
+    let out = hextool(&["-f", path, "--read", "--sparse", "--json", "--size", "8"]);
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    assert_eq!(
+        text(&out.stdout),
+        "{\"offset\":0,\"bytes\":\"7f454c4602010100\",\"ascii\":\".ELF....\"}\n"
+    );
+    std::fs::remove_file(file).unwrap();
+}
+
+#[test]
+fn join_with_missing_chunks_keeps_the_existing_output() {
+    let target = fixture("join-target", b"keep me");
+    let path = target.to_str().unwrap();
+    let prefix = format!("{}-part", path);
+    let chunk = |n: &str| format!("{}.{}", prefix, n);
+    let join = || hextool(&["--join", &prefix, "--out", path]);
+    // replace_with's partial file is named after the target
+    let temp = format!(".{}.", target.file_name().unwrap().to_string_lossy());
+    let no_temp_left = || {
+        !std::fs::read_dir(target.parent().unwrap())
+            .unwrap()
+            .any(|e| e.unwrap().file_name().to_string_lossy().starts_with(&temp))
+    };
+
+    // No chunks, a gap after .000, then no .000 at all
+    let out = join();
+    assert_eq!(out.status.code(), Some(1), "{:?}", out);
+    std::fs::write(chunk("000"), b"ab").unwrap();
+    std::fs::write(chunk("002"), b"ef").unwrap();
+    let out = join();
+    assert_eq!(out.status.code(), Some(1), "{:?}", out);
+    assert!(text(&out.stderr).contains(".001"), "{:?}", out);
+    std::fs::rename(chunk("000"), chunk("001")).unwrap();
+    let out = join();
+    assert_eq!(out.status.code(), Some(1), "{:?}", out);
+    assert!(text(&out.stderr).contains(".000"), "{:?}", out);
+    assert_eq!(std::fs::read(&target).unwrap(), b"keep me");
+    assert!(no_temp_left());
+
+    // A complete sequence replaces it
+    std::fs::write(chunk("000"), b"cd").unwrap();
+    let out = join();
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    assert_eq!(std::fs::read(&target).unwrap(), b"cdabef");
+    assert!(no_temp_left());
+    for n in ["000", "001", "002"] {
+        std::fs::remove_file(chunk(n)).unwrap();
+    }
+    std::fs::remove_file(target).unwrap();
+}
+
+#[test]
+fn concat_can_take_its_output_as_an_input() {
+    let target = fixture("concat-self", b"head");
+    let tail = fixture("concat-tail", b"tail");
+    let path = target.to_str().unwrap();
+    let out = hextool(&[
+        "--concat",
+        path,
+        path,
+        tail.to_str().unwrap(),
+        path,
+        "--align",
+        "8",
+    ]);
+    assert_eq!(out.status.code(), Some(0), "{:?}", out);
+    assert_eq!(
+        std::fs::read(&target).unwrap(),
+        b"head\0\0\0\0tail\0\0\0\0head"
+    );
+    for f in [target, tail] {
+        std::fs::remove_file(f).unwrap();
+    }
+}
@@ -0,0 +1,1966 @@
+//! The chat once the channel is up: a thread reading frames and printing
+//! what arrives, one sending what is typed, and one for the chores that
+//! come due on their own, such as rekeys and Ctrl+C. Frames go out
+//! through a `Sender` over any writer and come in over any `Transport`,
+//! so a session can run over a socket or a pipe alike. `udp` is the same
+//! chat over datagrams.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cipher::{CipherKind, CipherPair, StreamCipher};
+use crate::command::{self, Action};
+use crate::dh::{DhGroup, U2048, keyed_secret};
+use crate::proto::{
+    FRAME_ACK, FRAME_BYE, FRAME_FILE_CHUNK, FRAME_FILE_META, FRAME_FORMAT, FRAME_HELLO, FRAME_PING,
+    FRAME_PONG, FRAME_PRESENCE, FRAME_REKEY, FRAME_TEXT, FRAME_TYPING, FRAME_VERSION,
+    FRAME_VERSION_NONCE, Frame, MIN_MESSAGE, NONCE_LEN, RecvKeys, SEQ_LEN, check_nick,
+    decode_presence, decode_text, encode_presence, encode_text, frame_name, pad, random_nonce,
+    seal_frame, test_nonce, text_limit, unpad, write_frame,
+};
+use crate::session::{Ending, SessionStats, SessionSummary};
+use crate::transcript::{
+    Direction, History, Transcript, latency_text, local_clock, millis_between,
+};
+use crate::transfer::{Incoming, RECEIVED_DIR, receive_chunk, send_file, start_incoming};
+use crate::{
+    Level, Transport, duration_text, enabled, is_timeout, log, log_to, say, say_to, style,
+    waiting_for,
+};
+
+pub mod udp;
+
+/// The first `count` bytes of `cipher`'s keystream, at `-vv`. They come
+/// from a copy, so the bytes shown are the ones the first frame uses.
+fn print_keystream(cipher: &StreamCipher, count: usize) {
+    if !enabled(Level::Detail) {
+        return;
+    }
+    let mut preview = cipher.clone();
+    let bytes: String = (0..count)
+        .map(|_| format!("{:02X} ", preview.next_byte()))
+        .collect();
+    log!(Level::Detail, "Keystream: {}...", bytes);
+}
+
+/// Ctrl+C presses seen since the chat began.
+pub static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Count Ctrl+C presses in `INTERRUPTS` instead of dying at once, so the
+/// chat can say BYE first. A second press exits on the spot.
+#[cfg(unix)]
+pub fn catch_interrupts() -> io::Result<()> {
+    use std::ffi::c_int;
+
+    const SIGINT: c_int = 2;
+    const SIG_ERR: usize = usize::MAX;
+    unsafe extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+        fn _exit(status: c_int) -> !;
+    }
+
+    extern "C" fn on_interrupt(_: c_int) {
+        // Only async-signal-safe calls in here: an atomic and _exit
+        if INTERRUPTS.fetch_add(1, Ordering::SeqCst) > 0 {
+            // SAFETY: _exit ends the process without running any code
+            // that could be in an inconsistent state
+            unsafe { _exit(130) }
+        }
+    }
+
+    // SAFETY: the handler only touches an atomic before possibly exiting
+    let previous = unsafe { signal(SIGINT, on_interrupt as extern "C" fn(c_int) as usize) };
+    if previous == SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Count Ctrl+C presses in `INTERRUPTS` instead of dying at once, so the
+/// chat can say BYE first. A second press exits on the spot.
+#[cfg(windows)]
+pub fn catch_interrupts() -> io::Result<()> {
+    const CTRL_C_EVENT: u32 = 0;
+    unsafe extern "system" {
+        fn SetConsoleCtrlHandler(
+            handler: Option<unsafe extern "system" fn(u32) -> i32>,
+            add: i32,
+        ) -> i32;
+    }
+
+    unsafe extern "system" fn on_interrupt(event: u32) -> i32 {
+        // Returning 0 lets the default handler end the process
+        (event == CTRL_C_EVENT && INTERRUPTS.fetch_add(1, Ordering::SeqCst) == 0) as i32
+    }
+
+    // SAFETY: the handler is a plain function that only touches an atomic
+    if unsafe { SetConsoleCtrlHandler(Some(on_interrupt), 1) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// How often the housekeeping thread checks for Ctrl+C.
+pub const TICK: Duration = Duration::from_millis(100);
+
+/// How long to wait for the peer to acknowledge our BYE.
+const BYE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The sending half of a chat. Both threads send through it: this one
+/// what is typed, the reader to acknowledge a BYE.
+pub struct Sender<'a, W> {
+    writer: Mutex<W>,
+    keys: Mutex<SendKeys>,
+    rekey: Rekey,
+    /// Largest payload either side puts in a frame, from `--max-message`.
+    pub max_message: usize,
+    /// Set while a `/send` is in progress; one file goes at a time.
+    sending_file: AtomicBool,
+    /// Counts the bytes of every frame written.
+    stats: &'a SessionStats,
+    /// `--test-seed`, which picks the frames' nonces in place of the OS.
+    test_seed: Option<u64>,
+}
+
+/// Our current sending keys.
+struct SendKeys {
+    cipher: StreamCipher,
+    /// Frame format our frames go in, `FRAME_VERSION` until the peer
+    /// agrees to another.
+    format: u8,
+    epoch: u8,
+    /// Frames sent under this epoch.
+    sent: u64,
+    /// Sequence number for the next frame, whatever its epoch.
+    seq: u64,
+    /// When this epoch began.
+    since: Instant,
+    /// Our private key while a REKEY we sent awaits the peer's.
+    pending: Option<U2048>,
+}
+
+/// What it takes to run a new key exchange mid-chat, and when to.
+pub struct Rekey {
+    pub group: DhGroup,
+    pub cipher: CipherKind,
+    pub is_server: bool,
+    pub seed: Option<u64>,
+    /// Start one after sending this many frames under one key.
+    pub messages: Option<u64>,
+    /// Start one after a key has been in use this long.
+    pub interval: Option<Duration>,
+    /// `--psk`, mixed into every new secret as into the first.
+    pub psk: Option<Vec<u8>>,
+}
+
+impl<'a, W: Write> Sender<'a, W> {
+    /// Frames are written to `writer`, which may be a second handle on a
+    /// connection someone else reads from.
+    pub fn new(
+        writer: W,
+        stats: &'a SessionStats,
+        cipher: StreamCipher,
+        rekey: Rekey,
+        max_message: usize,
+        test_seed: Option<u64>,
+    ) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+            keys: Mutex::new(SendKeys {
+                cipher,
+                format: FRAME_VERSION,
+                epoch: 0,
+                sent: 0,
+                seq: 0,
+                since: Instant::now(),
+                pending: None,
+            }),
+            rekey,
+            max_message,
+            sending_file: AtomicBool::new(false),
+            stats,
+            test_seed,
+        }
+    }
+
+    /// Encrypt `plain` and send it as a tagged `kind` frame. Returns the
+    /// keystream position it started at and the ciphertext.
+    pub fn send(&self, kind: u8, plain: &[u8]) -> io::Result<(u64, Vec<u8>)> {
+        if plain.len() > self.max_message {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "message too long: {} bytes, limit is {}",
+                    plain.len(),
+                    self.max_message
+                ),
+            ));
+        }
+        let mut keys = self.keys.lock().unwrap();
+        let sent = self.write(&mut keys, kind, plain)?;
+        let due = self.rekey.messages.is_some_and(|limit| keys.sent >= limit);
+        let started = if due && kind != FRAME_BYE {
+            self.start_rekey(&mut keys)?
+        } else {
+            None
+        };
+        // Print only once the keys are free: the other thread may be
+        // holding stdout while it waits for them
+        drop(keys);
+        if let Some(epoch) = started {
+            log!(
+                Level::Summary,
+                "\r[REKEY] Offering new keys for epoch {}...",
+                epoch
+            );
+        }
+        Ok(sent)
+    }
+
+    /// Seal and write one frame. The keys stay locked through the write,
+    /// so frames leave in keystream order.
+    fn write(&self, keys: &mut SendKeys, kind: u8, plain: &[u8]) -> io::Result<(u64, Vec<u8>)> {
+        let nonce = match keys.format {
+            FRAME_VERSION_NONCE => Some(self.nonce(keys.seq)?),
+            _ => None,
+        };
+        let (start, encrypted, frame) = seal_frame(
+            &mut keys.cipher,
+            nonce.as_ref(),
+            kind,
+            keys.epoch,
+            keys.seq,
+            plain,
+        );
+        write_frame(&mut *self.writer.lock().unwrap(), &frame)?;
+        self.stats.frame_sent(frame.len());
+        keys.sent += 1;
+        keys.seq += 1;
+        Ok((start, encrypted))
+    }
+
+    /// The nonce for frame `seq`: random, or the test seed's for it.
+    fn nonce(&self, seq: u64) -> io::Result<[u8; NONCE_LEN]> {
+        match self.test_seed {
+            Some(seed) => Ok(test_nonce(seed, seq)),
+            None => random_nonce(),
+        }
+    }
+
+    /// Send a REKEY frame carrying a fresh public key, unless one is
+    /// already waiting for an answer. Returns the epoch it is for.
+    fn start_rekey(&self, keys: &mut SendKeys) -> io::Result<Option<u8>> {
+        if keys.pending.is_some() {
+            return Ok(None);
+        }
+        let epoch = keys.epoch.wrapping_add(1);
+        let private = self.rekey.private_key(epoch)?;
+        self.write(keys, FRAME_REKEY, &self.rekey.group.public_key(&private))?;
+        keys.pending = Some(private);
+        Ok(Some(epoch))
+    }
+
+    /// Start a rekey if the current key is older than `--rekey-minutes`.
+    /// Returns how long to wait before asking again.
+    fn rekey_if_stale(&self, interval: Duration) -> io::Result<Duration> {
+        let mut keys = self.keys.lock().unwrap();
+        let age = keys.since.elapsed();
+        if age < interval {
+            return Ok(interval - age);
+        }
+        let started = self.start_rekey(&mut keys)?;
+        drop(keys);
+        if let Some(epoch) = started
+            && enabled(Level::Summary)
+        {
+            say!("\r[REKEY] Offering new keys for epoch {}...", epoch);
+            prompt(&mut io::stdout().lock())?;
+        }
+        Ok(interval)
+    }
+
+    /// Act on the peer's REKEY: answer it with our own public key unless
+    /// it is the answer to ours, then switch to keys from the new secret.
+    /// Returns the new epoch and the cipher for the peer's frames in it.
+    pub fn finish_rekey(&self, their_public: &[u8]) -> io::Result<(u8, StreamCipher)> {
+        let mut keys = self.keys.lock().unwrap();
+        let epoch = keys.epoch.wrapping_add(1);
+        // If both sides offered at once, each takes the other's offer as
+        // the answer, and both arrive at the same secret
+        let private = match keys.pending.take() {
+            Some(private) => private,
+            None => {
+                let private = self.rekey.private_key(epoch)?;
+                self.write(
+                    &mut keys,
+                    FRAME_REKEY,
+                    &self.rekey.group.public_key(&private),
+                )?;
+                private
+            }
+        };
+        let secret = self.rekey.group.shared_secret(their_public, &private)?;
+        let secret = keyed_secret(&secret, self.rekey.psk.as_deref());
+        let pair = CipherPair::derive(&secret, self.rekey.cipher, self.rekey.is_server);
+        keys.cipher = pair.send;
+        keys.epoch = epoch;
+        keys.sent = 0;
+        keys.since = Instant::now();
+        Ok((epoch, pair.recv))
+    }
+
+    /// Put every frame from here on in `format`, without asking the peer.
+    pub fn set_format(&self, format: u8) {
+        self.keys.lock().unwrap().format = format;
+    }
+
+    /// The key epoch our frames are sent under.
+    pub fn epoch(&self) -> u8 {
+        self.keys.lock().unwrap().epoch
+    }
+}
+
+impl Rekey {
+    /// A private key for `epoch`. With a seed each epoch still gets a
+    /// different one, so no two epochs share a keystream.
+    fn private_key(&self, epoch: u8) -> io::Result<U2048> {
+        let seed = self.seed.map(|seed| seed.wrapping_add(epoch as u64));
+        self.group.private_key(seed)
+    }
+}
+
+/// Command-line settings for the chat once the channel is up.
+pub struct ChatOptions {
+    pub nick: String,
+    pub keepalive: Option<Duration>,
+    pub ack_timeout: Option<Duration>,
+    pub timeout: Option<Duration>,
+    pub max_message: usize,
+    /// Pad each message to a multiple of this many bytes.
+    pub pad: usize,
+    pub timestamps: bool,
+    pub show_latency: bool,
+    pub raw: bool,
+    pub stats_on_exit: bool,
+    /// `--history`.
+    pub history: usize,
+    /// `--frame-format`.
+    pub frame_format: u8,
+    /// `--test-seed`.
+    pub test_seed: Option<u64>,
+    /// Set when sending a script.
+    pub pace: Option<Pace>,
+}
+
+/// When a script sends its next line.
+#[derive(Clone, Copy)]
+pub enum Pace {
+    /// Once the peer has sent a message since our last one.
+    Reply,
+    /// After a fixed wait.
+    Delay(Duration),
+}
+
+/// What the two chat threads share besides the socket.
+struct Session<'a> {
+    nick: String,
+    peer: String,
+    /// Set once we start leaving, so the peer's BYE is taken as a reply.
+    quitting: AtomicBool,
+    /// Set when the peer leaves first, so we stop waiting for input.
+    peer_left: AtomicBool,
+    transcript: Option<&'a Transcript>,
+    stats: &'a SessionStats,
+    /// `--stats-on-exit`.
+    stats_on_exit: bool,
+    history: History,
+    /// `--timestamps`.
+    timestamps: bool,
+    /// `--show-latency`.
+    show_latency: bool,
+    /// `--raw`.
+    raw: bool,
+    pace: Option<Pace>,
+    /// `--pad`, 1 when off.
+    pad: usize,
+    /// When the peer's typing indicator was drawn, while it is showing.
+    typing: Mutex<Option<Instant>>,
+    /// `--ack-timeout`.
+    ack_timeout: Option<Duration>,
+    /// Messages sent that the peer has not acknowledged, by sequence
+    /// number.
+    unacked: Mutex<BTreeMap<u64, Unacked>>,
+    /// The read timeout between frames.
+    keepalive: Option<Duration>,
+    /// `--timeout`: the read timeout within one.
+    timeout: Option<Duration>,
+}
+
+/// A message sent and not yet acknowledged.
+struct Unacked {
+    /// The start of its text, to show with its status.
+    preview: String,
+    sent: Instant,
+    /// Set once we have warned that it is overdue.
+    overdue: bool,
+}
+
+/// Longest piece of a message shown next to its delivery status, in
+/// characters.
+const PREVIEW_CHARS: usize = 30;
+
+/// The start of `text`, cut at `PREVIEW_CHARS` with an ellipsis.
+fn preview(text: &str) -> String {
+    match text.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+impl<'a> Session<'a> {
+    /// A session between `nick` and `peer`, set up as `options` say.
+    fn new(
+        nick: String,
+        peer: String,
+        options: &ChatOptions,
+        transcript: Option<&'a Transcript>,
+        stats: &'a SessionStats,
+    ) -> Self {
+        Self {
+            nick,
+            peer,
+            quitting: AtomicBool::new(false),
+            peer_left: AtomicBool::new(false),
+            transcript,
+            stats,
+            stats_on_exit: options.stats_on_exit,
+            history: History::new(options.history),
+            timestamps: options.timestamps,
+            show_latency: options.show_latency,
+            raw: options.raw,
+            pace: options.pace,
+            pad: options.pad,
+            typing: Mutex::new(None),
+            ack_timeout: options.ack_timeout,
+            unacked: Mutex::new(BTreeMap::new()),
+            keepalive: options.keepalive,
+            timeout: options.timeout,
+        }
+    }
+
+    /// Message text as it is shown and logged: exact but escaped with
+    /// `--raw`, else trimmed, with anything not UTF-8 replaced.
+    fn shown(&self, text: &[u8]) -> String {
+        if self.raw {
+            style::visible(text)
+        } else {
+            String::from_utf8_lossy(text).trim().to_string()
+        }
+    }
+
+    /// Count a message and add it to the history, and to the transcript
+    /// if there is one. A failed write is reported but does not end the
+    /// chat.
+    fn record(&self, direction: Direction, text: &str) {
+        match direction {
+            Direction::Sent => self.stats.message_sent(),
+            Direction::Received => self.stats.message_received(),
+        }
+        let nick = match direction {
+            Direction::Sent => &self.nick,
+            Direction::Received => &self.peer,
+        };
+        self.history.push(direction, SystemTime::now(), nick, text);
+        let Some(transcript) = &self.transcript else {
+            return;
+        };
+        if let Err(e) = transcript.record(direction, nick, text) {
+            say!("\r[LOG] Writing the transcript failed: {}", e);
+        }
+    }
+}
+
+/// Print the keystream preview, trade nicknames, then chat over `stream`
+/// until either side leaves: a reader thread reads `reader`, a second
+/// handle on the same connection, and prints what arrives while this
+/// thread sends what is typed. Each direction has its own cipher,
+/// continued across messages.
+///
+/// Leaving with `/quit` or end of input sends BYE and waits briefly for the
+/// peer's, once any file being sent has gone. When the peer leaves first
+/// the chat ends without waiting for another line of input. Returns the
+/// exit code for how it ended.
+///
+/// Ctrl+C leaves the same way; pressed again, it exits at once.
+///
+/// Keys are replaced as `rekey` says, by a DH exchange in REKEY frames.
+/// A peer silent for `keepalive` is sent a PING, and one that stays
+/// silent through `MAX_UNANSWERED` of them is given up on.
+pub fn chat<'s, T>(
+    stream: &'s T,
+    reader: T,
+    ciphers: CipherPair,
+    rekey: Rekey,
+    options: ChatOptions,
+    transcript: Option<&Transcript>,
+    input: &Input,
+) -> io::Result<i32>
+where
+    T: Transport + Send + Sync,
+    &'s T: Write,
+{
+    let CipherPair { send, recv } = ciphers;
+    print_keystream(&send, 12);
+    log!(Level::Summary);
+    println!("✓ Secure channel established!");
+    println!();
+
+    let mut reader = BufReader::new(reader);
+    let stats = SessionStats::new();
+    let sender = Sender::new(
+        stream,
+        &stats,
+        send,
+        rekey,
+        options.max_message,
+        options.test_seed,
+    );
+    let mut recv = RecvKeys::new(recv);
+    let Hello {
+        nick: peer,
+        format,
+        presence,
+    } = exchange_hello(
+        &sender,
+        &mut reader,
+        &mut recv,
+        &options.nick,
+        options.frame_format,
+    )
+    .map_err(waiting_for(stream, "the peer's hello"))?;
+    log!(
+        Level::Summary,
+        "[STREAM] Frame format {:#04x}: {}",
+        format,
+        if format == FRAME_VERSION_NONCE {
+            "a keystream per message, from random bytes in front of it"
+        } else {
+            "one running keystream per direction"
+        }
+    );
+    say!("[CHAT] You are <{}>, talking to <{}>", options.nick, peer);
+    say!("[CHAT] Type messages and press Enter; /quit or Ctrl+D to leave.");
+    say!(
+        "[CHAT] /send PATH sends a file; files you receive go to ./{}/",
+        RECEIVED_DIR
+    );
+    say!("[CHAT] /help lists the other commands.");
+
+    // The reader wakes after this long without data to send a PING
+    reader.get_ref().set_read_timeout(options.keepalive)?;
+    let session = Session::new(options.nick.clone(), peer, &options, transcript, &stats);
+    sender.send(FRAME_PRESENCE, &encode_presence(true, &session.nick))?;
+    if let Some(presence) = presence {
+        show_presence(&mut io::stdout().lock(), &presence)?;
+    }
+    catch_interrupts()?;
+    let (done_tx, done_rx) = mpsc::channel();
+    let done_rx = Mutex::new(done_rx);
+    // Dropped when we leave, which stops the housekeeping thread
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    thread::scope(|scope| {
+        // Gives how the session ended if the peer left first
+        let receiver = scope.spawn(|| {
+            let ending = receive_loop(reader, recv, &sender, &session);
+            if session.quitting.load(Ordering::SeqCst) {
+                let _ = done_tx.send(ending);
+                None
+            } else {
+                report_ending(&ending);
+                session.peer_left.store(true, Ordering::SeqCst);
+                Some(ending)
+            }
+        });
+        scope.spawn(|| housekeeping(&sender, &session, &done_rx, stop_rx));
+        let mut result = send_loop(scope, &sender, &session, input);
+        if !session.peer_left.load(Ordering::SeqCst) {
+            wait_for_file(&sender, &session);
+        }
+        drop(stop_tx);
+        if !session.peer_left.load(Ordering::SeqCst) {
+            result = result.and_then(|()| leave(&sender, &session, &done_rx).map(drop));
+        }
+        // The reader is about to see the connection close under it; that
+        // is not news from the peer
+        session.quitting.store(true, Ordering::SeqCst);
+        // Unblocks the reader if it is still waiting
+        stream.hang_up();
+        let peer_ending = receiver
+            .join()
+            .unwrap_or_else(|_| Some(Ending::Failed(io::Error::other("the reader panicked"))));
+        // A file still going fails at once on the closed connection; let
+        // it say so before the summary
+        while sender.sending_file.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let ending = match (peer_ending, result) {
+            // Whatever failed after the peer left was only the fallout
+            (Some(ending), _) => ending,
+            (None, Ok(())) => Ending::Left,
+            (None, Err(e)) => {
+                let ending = Ending::from_error(e);
+                report_ending(&ending);
+                ending
+            }
+        };
+        report_session(&sender, &session, &ending);
+        Ok(ending.exit_code())
+    })
+}
+
+/// Lines typed on stdin. A thread of its own reads them, so a chat can
+/// end while nothing is being typed.
+pub struct Input {
+    lines: mpsc::Receiver<io::Result<Typed>>,
+    /// Set once stdin has ended, perhaps with lines still to take.
+    ended: Arc<AtomicBool>,
+    /// When to tell the peer we are typing, while part of a line waits.
+    typing_at: Cell<Option<Instant>>,
+}
+
+/// What comes in from the keyboard.
+enum Typed {
+    /// A whole line as typed, newline included when there was one.
+    Line(Vec<u8>),
+    /// Part of a line, with the rest still to come.
+    Partial,
+}
+
+/// How long part of a line waits before the peer hears we are typing.
+const TYPING_AFTER: Duration = Duration::from_secs(1);
+
+/// How often to tell the peer again while the line is still unfinished.
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+
+/// How long the peer's typing indicator shows without news.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Input {
+    /// Read stdin as it comes, so part of a line can be noticed. A
+    /// terminal in line mode passes nothing on before Enter, so there only
+    /// piped input ever shows as typing.
+    pub fn spawn() -> Self {
+        let (tx, lines) = mpsc::channel();
+        let ended = Arc::new(AtomicBool::new(false));
+        let reader_ended = Arc::clone(&ended);
+        thread::spawn(move || {
+            let mut stdin = io::stdin().lock();
+            let mut pending = Vec::new();
+            let send = |typed| tx.send(Ok(typed)).is_ok();
+            loop {
+                let chunk = match stdin.fill_buf() {
+                    Ok(chunk) => chunk.to_vec(),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        break;
+                    }
+                };
+                if chunk.is_empty() {
+                    if !pending.is_empty() {
+                        send(Typed::Line(pending));
+                    }
+                    break;
+                }
+                stdin.consume(chunk.len());
+                pending.extend_from_slice(&chunk);
+                let mut open = true;
+                while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                    open = send(Typed::Line(pending.drain(..=end).collect()));
+                }
+                if !open || (!pending.is_empty() && !send(Typed::Partial)) {
+                    break;
+                }
+            }
+            reader_ended.store(true, Ordering::SeqCst);
+        });
+        Self {
+            lines,
+            ended,
+            typing_at: Cell::new(None),
+        }
+    }
+
+    /// The lines of a `--script` file, handed over one at a time. It
+    /// counts as ended once the last has been taken.
+    pub fn script(lines: Vec<String>) -> Self {
+        let (tx, lines_rx) = mpsc::sync_channel(0);
+        let ended = Arc::new(AtomicBool::new(false));
+        let reader_ended = Arc::clone(&ended);
+        thread::spawn(move || {
+            for line in lines {
+                if tx.send(Ok(Typed::Line(line.into_bytes()))).is_err() {
+                    break;
+                }
+            }
+            reader_ended.store(true, Ordering::SeqCst);
+        });
+        Self {
+            lines: lines_rx,
+            ended,
+            typing_at: Cell::new(None),
+        }
+    }
+
+    pub fn ended(&self) -> bool {
+        self.ended.load(Ordering::SeqCst)
+    }
+
+    /// The next line typed, or `None` at the end of input or once `stop`
+    /// says so; it is asked every `TICK`. `Typed::Partial` means part of
+    /// a line has waited `TYPING_AFTER`, and comes again every
+    /// `TYPING_REFRESH` while it still waits.
+    fn next_line(&self, stop: impl Fn() -> bool) -> io::Result<Option<Typed>> {
+        loop {
+            match self.lines.recv_timeout(TICK) {
+                Ok(Ok(Typed::Partial)) => {
+                    if self.typing_at.get().is_none() {
+                        self.typing_at.set(Some(Instant::now() + TYPING_AFTER));
+                    }
+                }
+                Ok(typed) => {
+                    self.typing_at.set(None);
+                    return typed.map(Some);
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if stop() {
+                        return Ok(None);
+                    }
+                    if let Some(at) = self.typing_at.get()
+                        && Instant::now() >= at
+                    {
+                        self.typing_at.set(Some(at + TYPING_REFRESH));
+                        return Ok(Some(Typed::Partial));
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Runs beside the chat until `stop` closes: starts rekeys once a key is
+/// `--rekey-minutes` old, and turns Ctrl+C into a clean exit. The other
+/// threads may be blocked reading, so this one ends the process.
+fn housekeeping(
+    sender: &Sender<impl Write>,
+    session: &Session,
+    done: &Mutex<mpsc::Receiver<Ending>>,
+    stop: mpsc::Receiver<()>,
+) {
+    let mut next_rekey = sender
+        .rekey
+        .interval
+        .map(|interval| Instant::now() + interval);
+    while let Err(mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(TICK) {
+        let stale = session
+            .typing
+            .lock()
+            .unwrap()
+            .is_some_and(|since| since.elapsed() >= TYPING_TIMEOUT);
+        if stale {
+            let mut out = io::stdout().lock();
+            if clear_typing(&mut out, session) {
+                let _ = prompt(&mut out);
+            }
+        }
+        warn_undelivered(session);
+        if INTERRUPTS.load(Ordering::SeqCst) > 0 {
+            match leave(sender, session, done) {
+                Ok(true) => {
+                    report_session(sender, session, &Ending::Left);
+                    std::process::exit(0);
+                }
+                // Already leaving; the main thread finishes up
+                Ok(false) => return,
+                Err(e) => {
+                    say!("\r[NETWORK] Sending BYE failed: {}", e);
+                    report_session(sender, session, &Ending::from_error(e));
+                    std::process::exit(1);
+                }
+            }
+        }
+        if let (Some(interval), Some(at)) = (sender.rekey.interval, next_rekey)
+            && Instant::now() >= at
+        {
+            match sender.rekey_if_stale(interval) {
+                Ok(wait) => next_rekey = Some(Instant::now() + wait),
+                Err(e) => {
+                    say!("\r[REKEY] Sending new keys failed: {}", e);
+                    next_rekey = None;
+                }
+            }
+        }
+    }
+}
+
+/// Hold off leaving while a `/send` is still going, so the file arrives
+/// whole, unless the peer leaves first. Ctrl+C still leaves at once,
+/// cancelling the transfer.
+fn wait_for_file(sender: &Sender<impl Write>, session: &Session) {
+    if !sender.sending_file.load(Ordering::SeqCst) {
+        return;
+    }
+    say!("\r[FILE] Waiting for the file to finish sending before leaving...");
+    while sender.sending_file.load(Ordering::SeqCst) && !session.peer_left.load(Ordering::SeqCst) {
+        thread::sleep(TICK);
+    }
+}
+
+/// Send BYE and wait briefly for the peer's. `quitting` is set first so
+/// the reader takes the peer's BYE as the reply to ours. Returns false,
+/// doing nothing, if we are already leaving.
+fn leave(
+    sender: &Sender<impl Write>,
+    session: &Session,
+    done: &Mutex<mpsc::Receiver<Ending>>,
+) -> io::Result<bool> {
+    if session.quitting.swap(true, Ordering::SeqCst) {
+        return Ok(false);
+    }
+    say!("\r[NETWORK] Leaving, sending BYE...");
+    // Only to be polite; BYE is what counts
+    let _ = sender.send(FRAME_PRESENCE, &encode_presence(false, &session.nick));
+    sender.send(FRAME_BYE, &[])?;
+    match done.lock().unwrap().recv_timeout(BYE_TIMEOUT) {
+        Ok(Ending::Bye) => say!("\r[NETWORK] Peer acknowledged, disconnected."),
+        _ => say!("\r[NETWORK] No reply from peer, closing anyway."),
+    }
+    Ok(true)
+}
+
+/// What the session amounted to, printed however it ends; with
+/// `--stats-on-exit`, what `/stats` shows as well.
+fn report_session(sender: &Sender<impl Write>, session: &Session, ending: &Ending) {
+    if session.stats_on_exit {
+        show_stats(sender, session);
+    }
+    let summary = SessionSummary {
+        stats: session.stats.snapshot(),
+        unacked: session.unacked.lock().unwrap().len(),
+        ending: ending.to_string(),
+    };
+    for line in summary.lines() {
+        say!("{}", line);
+    }
+}
+
+/// `/stats`: the traffic so far, then the keys and frame format in use.
+fn show_stats(sender: &Sender<impl Write>, session: &Session) {
+    let (epoch, format) = {
+        let keys = sender.keys.lock().unwrap();
+        (keys.epoch, keys.format)
+    };
+    let mut out = io::stdout().lock();
+    for line in session.stats.snapshot().lines() {
+        let _ = say_to!(out, "\r{}", line);
+    }
+    let _ = say_to!(
+        out,
+        "[STATS] Cipher: {}, key epoch {}; key exchange: {}",
+        sender.rekey.cipher.name(),
+        epoch,
+        sender.rekey.group.name()
+    );
+    let padding = match session.pad {
+        1 => "off".to_string(),
+        block => format!("to {} bytes", block),
+    };
+    let _ = say_to!(
+        out,
+        "[STATS] Frame format {:#04x}; padding {}; messages up to {} bytes",
+        format,
+        padding,
+        sender.max_message
+    );
+}
+
+/// Tell the user why the session ended, unless we ended it.
+fn report_ending(ending: &Ending) {
+    match ending {
+        Ending::Left => {}
+        Ending::Bye => say!("\r[NETWORK] Peer disconnected."),
+        Ending::Closed => {
+            say!("\r[NETWORK] Peer closed the connection without saying goodbye.")
+        }
+        Ending::Truncated => say!(
+            "\r[NETWORK] Protocol error: short frame; the connection closed partway through one, so the stream is corrupt."
+        ),
+        Ending::Reset => say!("\r[NETWORK] Connection reset by peer."),
+        Ending::Unreachable => say!(
+            "\r[NETWORK] Peer unreachable: no answer to {} keepalive pings, closing.",
+            MAX_UNANSWERED
+        ),
+        Ending::Failed(e) if e.kind() == io::ErrorKind::InvalidData => {
+            say!("\r[NETWORK] Protocol error: {}", e)
+        }
+        Ending::Failed(e) => say!("\r[NETWORK] Connection failed: {}", e),
+    }
+}
+
+/// What the peer's hello told us.
+struct Hello {
+    nick: String,
+    /// The frame format both sides use from here on.
+    format: u8,
+    /// The PRESENCE an older build sends where its FORMAT would be.
+    presence: Option<Vec<u8>>,
+}
+
+/// Send our nickname in a HELLO frame, then in a FORMAT frame the newest
+/// frame format we offer, and read the same from the peer: its HELLO
+/// must be the first frame it sends. Both sides then use the older of
+/// the two formats for every later frame. A build from before FORMAT
+/// frames skips ours as one it does not know, and sends a PRESENCE in
+/// place of its own; it gets `FRAME_VERSION`, the format it speaks.
+fn exchange_hello(
+    sender: &Sender<impl Write>,
+    reader: &mut impl Read,
+    recv: &mut RecvKeys,
+    nick: &str,
+    newest: u8,
+) -> io::Result<Hello> {
+    {
+        let mut keys = sender.keys.lock().unwrap();
+        sender.write(&mut keys, FRAME_HELLO, nick.as_bytes())?;
+        sender.write(&mut keys, FRAME_FORMAT, &[newest])?;
+    }
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut read = |what: &str| -> io::Result<(u8, Vec<u8>)> {
+        let frame = recv.read_frame(reader, MIN_MESSAGE)?;
+        match recv.open(&frame) {
+            Some((_, plain)) => Ok((frame.kind, plain)),
+            None => Err(invalid(format!(
+                "peer's {} failed authentication; do both sides use the same --cipher?",
+                what
+            ))),
+        }
+    };
+    let (kind, nick) = read("hello")?;
+    if kind != FRAME_HELLO {
+        return Err(invalid(format!(
+            "expected a hello frame, got type {}",
+            kind
+        )));
+    }
+    let nick =
+        check_nick(&nick).map_err(|e| invalid(format!("peer sent a bad nickname: {}", e)))?;
+    let (format, presence) = match read("frame format")? {
+        (FRAME_FORMAT, offer) => match offer[..] {
+            [theirs] if theirs >= FRAME_VERSION => (newest.min(theirs), None),
+            _ => {
+                return Err(invalid(format!(
+                    "peer offered frame format {:02x?}, which this build does not speak",
+                    offer
+                )));
+            }
+        },
+        (FRAME_PRESENCE, presence) => (FRAME_VERSION, Some(presence)),
+        (kind, _) => {
+            return Err(invalid(format!(
+                "expected the peer's frame format, got type {}",
+                kind
+            )));
+        }
+    };
+    sender.keys.lock().unwrap().format = format;
+    recv.format = format;
+    Ok(Hello {
+        nick,
+        format,
+        presence,
+    })
+}
+
+pub fn prompt(out: &mut impl Write) -> io::Result<()> {
+    write!(out, "> ")?;
+    out.flush()
+}
+
+/// Send each line typed until `/quit`, the end of stdin, or the peer
+/// leaving. `/send PATH` sends a file from a thread of its own so
+/// chatting can go on meanwhile.
+fn send_loop<'scope, 'env, W: Write + Send>(
+    scope: &'scope thread::Scope<'scope, 'env>,
+    sender: &'env Sender<'env, W>,
+    session: &'env Session,
+    input: &Input,
+) -> io::Result<()> {
+    loop {
+        prompt(&mut io::stdout().lock())?;
+        let line = loop {
+            match input.next_line(|| session.peer_left.load(Ordering::SeqCst))? {
+                Some(Typed::Line(line)) => break line,
+                // Only a hint for the peer, so a failure is left to the
+                // next real message to report
+                Some(Typed::Partial) => {
+                    let _ = sender.send(FRAME_TYPING, &[]);
+                }
+                None => return Ok(()),
+            }
+        };
+        let text = String::from_utf8_lossy(&line);
+        let typed = typed_text(&text, session.raw);
+        let message = match command::parse(typed) {
+            Action::Message(message) => message_bytes(&line, typed, message, session.raw),
+            Action::Quit => return Ok(()),
+            Action::Stats => {
+                show_stats(sender, session);
+                continue;
+            }
+            Action::Help => {
+                say!("[CHAT] Commands:");
+                show_help();
+                continue;
+            }
+            Action::History(count) => {
+                show_history(session, count)?;
+                continue;
+            }
+            Action::Save(path) => {
+                save_history(session, &path);
+                continue;
+            }
+            Action::SendFile(path) => {
+                if sender.sending_file.swap(true, Ordering::SeqCst) {
+                    say!("[FILE] Already sending a file; wait for it to finish");
+                    continue;
+                }
+                let received = session.stats.received();
+                scope.spawn(move || {
+                    if let Err(e) = send_file(sender, &path, &session.quitting) {
+                        if session.quitting.load(Ordering::SeqCst)
+                            || session.peer_left.load(Ordering::SeqCst)
+                        {
+                            say!(
+                                "\r[FILE] Sending {} cancelled: the chat is over",
+                                path.display()
+                            );
+                        } else {
+                            say!("\r[FILE] Sending {} failed: {}", path.display(), e);
+                            let _ = prompt(&mut io::stdout().lock());
+                        }
+                    }
+                    sender.sending_file.store(false, Ordering::SeqCst);
+                });
+                // The peer does not reply to a file, so only a delay holds
+                // a script back
+                if let Some(pace @ Pace::Delay(_)) = session.pace {
+                    wait_turn(session, pace, received);
+                }
+                continue;
+            }
+            Action::Unknown(name) => {
+                say!("[ERROR] Unknown command {}; the commands are:", name);
+                show_help();
+                continue;
+            }
+            Action::Invalid(e) => {
+                say!("[ERROR] {}", e);
+                continue;
+            }
+        };
+        let limit = text_limit(sender.max_message, session.pad);
+        send_text(session, message, limit, |payload| {
+            sender.send(FRAME_TEXT, payload)
+        })?;
+    }
+}
+
+/// The text of a typed line for `command::parse`: `--raw` takes it as
+/// typed, less only its newline, and otherwise it is trimmed.
+fn typed_text(text: &str, raw: bool) -> &str {
+    if raw {
+        text.strip_suffix('\n').unwrap_or(text)
+    } else {
+        text.trim()
+    }
+}
+
+/// The bytes to send for `message`, which `command::parse` found in
+/// `typed`, the text of `line`. `--raw` sends them exactly as typed, so
+/// bytes that are not UTF-8 go out unchanged rather than as U+FFFD.
+fn message_bytes<'a>(line: &'a [u8], typed: &str, message: &'a str, raw: bool) -> &'a [u8] {
+    if !raw {
+        return message.as_bytes();
+    }
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    // Only a leading `/` can have been taken off, and it is one byte in
+    // both the text and the line
+    &line[typed.len() - message.len()..]
+}
+
+/// Send `message` as typed, unless it is empty or over `limit` bytes,
+/// through `send`, which seals a TEXT payload and returns the keystream
+/// position it started at and the ciphertext. It is listed as awaiting
+/// its ACK, and a script then waits for its turn to send again.
+fn send_text(
+    session: &Session,
+    message: &[u8],
+    limit: usize,
+    send: impl FnOnce(&[u8]) -> io::Result<(u64, Vec<u8>)>,
+) -> io::Result<()> {
+    if message.is_empty() {
+        if session.raw {
+            say!("[CHAT] Empty message not sent");
+        }
+        return Ok(());
+    }
+    if message.len() > limit {
+        say!(
+            "[ERROR] Message too long: {} bytes, limit is {}; not sent",
+            message.len(),
+            limit
+        );
+        return Ok(());
+    }
+
+    let shown = session.shown(message);
+    // A reply may come in before the send returns
+    let received = session.stats.received();
+    // Hold stdout so an incoming message can't land in the middle
+    let mut out = io::stdout().lock();
+    let seq = session.stats.sent() as u64 + 1;
+    let payload = pad(&encode_text(seq, SystemTime::now(), message), session.pad)?;
+    // Listed before it goes, since the ACK may beat us back
+    session.unacked.lock().unwrap().insert(
+        seq,
+        Unacked {
+            preview: preview(&shown),
+            sent: Instant::now(),
+            overdue: false,
+        },
+    );
+    let (start, encrypted) = send(&payload)?;
+    session.record(Direction::Sent, &shown);
+    say_to!(out, "[…] #{} {}", seq, preview(&shown))?;
+    log_to!(out, Level::Detail)?;
+    log_to!(out, Level::Detail, "[ENCRYPT]")?;
+    log_to!(
+        out,
+        Level::Detail,
+        "Plain: {}({:?})",
+        hex(&payload),
+        String::from_utf8_lossy(message)
+    )?;
+    log_to!(
+        out,
+        Level::Detail,
+        "{}",
+        key_line(start, &payload, &encrypted)
+    )?;
+    log_to!(out, Level::Detail, "Cipher: {}", hex(&encrypted))?;
+    log_to!(out, Level::Detail)?;
+    log_to!(out, Level::Summary)?;
+
+    log_to!(
+        out,
+        Level::Summary,
+        "[NETWORK] Sending encrypted message ({} bytes)...",
+        encrypted.len()
+    )?;
+    log_to!(out, Level::Summary, "[→] Sent {} bytes", encrypted.len())?;
+    log_to!(out, Level::Summary)?;
+    drop(out);
+
+    if let Some(pace) = session.pace {
+        wait_turn(session, pace, received);
+    }
+    Ok(())
+}
+
+/// `/history`: the last `count` messages again, with their local times.
+fn show_history(session: &Session, count: usize) -> io::Result<()> {
+    let lines = session.history.replay(count, local_clock);
+    if lines.is_empty() {
+        say!("[HISTORY] No messages yet");
+    }
+    let mut out = io::stdout().lock();
+    for line in lines {
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// `/save`: the messages kept, written to `path` as `--log` would.
+fn save_history(session: &Session, path: &Path) {
+    match session.history.save(path) {
+        Ok(count) => say!("[HISTORY] Saved {} message(s) to {}", count, path.display()),
+        Err(e) => say!("[ERROR] Cannot save to {}: {}", path.display(), e),
+    }
+}
+
+/// The commands, for `/help` and after one we do not know.
+fn show_help() {
+    for line in command::help_lines() {
+        say!("{}", line);
+    }
+}
+
+/// Hold a script back until `pace` lets it send again: the peer has sent
+/// more than `received` messages, or the delay is up. Either way, stop
+/// waiting if the peer leaves.
+fn wait_turn(session: &Session, pace: Pace, received: usize) {
+    let started = Instant::now();
+    while !session.peer_left.load(Ordering::SeqCst) {
+        let done = match pace {
+            Pace::Reply => session.stats.received() > received,
+            Pace::Delay(delay) => started.elapsed() >= delay,
+        };
+        if done {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// The keystream bytes a message was XORed with, and where they sit in
+/// the stream.
+fn key_line(start: u64, plain: &[u8], encrypted: &[u8]) -> String {
+    let end = start + plain.len() as u64;
+    let key: Vec<u8> = plain.iter().zip(encrypted).map(|(p, c)| p ^ c).collect();
+    format!("Key [{}..{}]: {}", start, end, hex(&key))
+}
+
+/// Bytes as lowercase hex pairs, each followed by a space.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x} ", b)).collect()
+}
+
+/// Keepalive pings that may go unanswered before the peer is given up on.
+const MAX_UNANSWERED: u32 = 2;
+
+/// Print every message that arrives until the peer says BYE or the
+/// connection ends. A BYE we did not ask for is answered with our own.
+/// Frames failing authentication are dropped and counted.
+/// Files the peer sends are saved under `RECEIVED_DIR`.
+///
+/// Whenever the socket's read timeout passes without data, a PING goes
+/// out; any frame from the peer counts as the answer.
+fn receive_loop<T: Transport>(
+    mut reader: BufReader<T>,
+    mut keys: RecvKeys,
+    sender: &Sender<impl Write>,
+    session: &Session,
+) -> Ending {
+    let mut incoming: Option<Incoming> = None;
+    // Pings sent since the peer was last heard from
+    let mut unanswered = 0;
+    let ending = loop {
+        // Wait for the start of a frame here, where a timeout loses
+        // nothing, rather than inside read_frame
+        match reader.fill_buf() {
+            Ok([]) => break Ending::Closed,
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) => {
+                if unanswered == MAX_UNANSWERED {
+                    break Ending::Unreachable;
+                }
+                unanswered += 1;
+                if let Err(e) = sender.send(FRAME_PING, &[]) {
+                    break Ending::Failed(e);
+                }
+                log!(
+                    Level::Detail,
+                    "\r[NETWORK] Peer quiet, sent PING ({} unanswered)",
+                    unanswered
+                );
+                continue;
+            }
+            // A signal such as SIGCONT after a pause; nothing was lost
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Ending::from_error(e),
+        }
+        // The peer has begun a frame, so it has no reason to pause before
+        // the end of it
+        if let Err(e) = reader.get_ref().set_read_timeout(session.timeout) {
+            break Ending::Failed(e);
+        }
+        let timed_out = waiting_for(reader.get_ref(), "the rest of a frame");
+        let frame = keys
+            .read_frame(&mut reader, sender.max_message)
+            .map_err(timed_out);
+        if let Err(e) = reader.get_ref().set_read_timeout(session.keepalive) {
+            break Ending::Failed(e);
+        }
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => break Ending::within_frame(e),
+        };
+        session.stats.frame_received(frame.wire_len());
+        unanswered = 0;
+        // Every frame is encrypted, so even one we skip moves the stream on
+        let Some((start, decrypted)) = keys.open(&frame) else {
+            session.stats.frame_dropped();
+            let mut out = io::stdout().lock();
+            let _ = say_to!(
+                out,
+                "\r[SECURITY] !!! {}-byte message failed authentication: dropped, it may have been tampered with !!!",
+                frame.payload.len()
+            );
+            let _ = prompt(&mut out);
+            continue;
+        };
+        let Frame {
+            kind,
+            payload: encrypted,
+            ..
+        } = frame;
+        if kind == FRAME_BYE {
+            if !session.quitting.load(Ordering::SeqCst) {
+                let _ = sender.send(FRAME_BYE, &[]);
+            }
+            break Ending::Bye;
+        }
+        if kind == FRAME_PING {
+            if let Err(e) = sender.send(FRAME_PONG, &[]) {
+                break Ending::Failed(e);
+            }
+            continue;
+        }
+        if kind == FRAME_PONG {
+            continue;
+        }
+        if kind == FRAME_ACK {
+            acknowledged(session, &decrypted);
+            continue;
+        }
+        if kind == FRAME_REKEY {
+            match sender.finish_rekey(&decrypted) {
+                Ok((epoch, cipher)) => {
+                    keys.next = Some((epoch, cipher));
+                    if enabled(Level::Summary) {
+                        say!("\r[REKEY] Switched to new keys, epoch {} ✓", epoch);
+                        let _ = prompt(&mut io::stdout().lock());
+                    }
+                }
+                Err(e) => break Ending::Failed(e),
+            }
+            continue;
+        }
+        let mut out = io::stdout().lock();
+        if kind == FRAME_TYPING {
+            let _ = show_typing(&mut out, session);
+            continue;
+        }
+        // Whatever the peer sends next, it is no longer just typing
+        clear_typing(&mut out, session);
+        if kind == FRAME_PRESENCE {
+            let _ = show_presence(&mut out, &decrypted);
+            continue;
+        }
+        if kind == FRAME_FILE_META {
+            incoming = start_incoming(&mut out, Path::new(RECEIVED_DIR), &decrypted);
+            let _ = prompt(&mut out);
+            continue;
+        }
+        if kind == FRAME_FILE_CHUNK {
+            if receive_chunk(&mut out, &mut incoming, &decrypted) {
+                let _ = prompt(&mut out);
+            }
+            continue;
+        }
+        if kind != FRAME_TEXT {
+            match frame_name(kind) {
+                Some(_) if !enabled(Level::Summary) => continue,
+                Some(name) => {
+                    let _ = say_to!(out, "\r[NETWORK] Ignoring {} frame", name);
+                }
+                None => {
+                    let _ = say_to!(
+                        out,
+                        "\r[WARNING] Skipped {} bytes of unknown frame type {}",
+                        encrypted.len(),
+                        kind
+                    );
+                }
+            }
+            let _ = prompt(&mut out);
+            continue;
+        }
+        let Some((seq, sent_at, text)) = unpad(&decrypted).and_then(decode_text) else {
+            break Ending::Failed(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer sent a message with a bad length or send time",
+            ));
+        };
+        // Padding can hide an empty message from `read_frame`
+        if text.is_empty() {
+            break Ending::Failed(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer sent an empty message",
+            ));
+        }
+        let text = session.shown(text);
+        let received = Received {
+            start,
+            encrypted: &encrypted,
+            decrypted: &decrypted,
+            sent_at,
+            text: &text,
+        };
+        if let Err(e) = show_received(&mut out, &received, session) {
+            break Ending::Failed(e);
+        }
+        session.record(Direction::Received, &text);
+        drop(out);
+        if let Err(e) = sender.send(FRAME_ACK, &seq.to_be_bytes()) {
+            break Ending::Failed(e);
+        }
+    };
+    if let Some(file) = incoming {
+        file.interrupted();
+    }
+    ending
+}
+
+/// Say who joined or left, from a PRESENCE payload.
+fn show_presence(out: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    match decode_presence(payload) {
+        Some((true, nick)) => say_to!(out, "\r[CHAT] <{}> joined", nick)?,
+        Some((false, nick)) => say_to!(out, "\r[CHAT] <{}> left", nick)?,
+        None => say_to!(out, "\r[WARNING] Ignoring a malformed PRESENCE frame")?,
+    }
+    prompt(out)
+}
+
+/// Mark the message an ACK names as delivered. An ACK for nothing we
+/// are waiting on, such as a repeat, is ignored.
+fn acknowledged(session: &Session, payload: &[u8]) {
+    let mut out = io::stdout().lock();
+    let Ok(seq) = <[u8; SEQ_LEN]>::try_from(payload).map(u64::from_be_bytes) else {
+        let _ = say_to!(out, "\r[WARNING] Ignoring a malformed ACK frame");
+        let _ = prompt(&mut out);
+        return;
+    };
+    let Some(message) = session.unacked.lock().unwrap().remove(&seq) else {
+        return;
+    };
+    session.stats.round_trip(message.sent.elapsed());
+    let late = if message.overdue { " (late)" } else { "" };
+    let _ = say_to!(out, "\r[✓] #{} {}{}", seq, message.preview, late);
+    let _ = prompt(&mut out);
+}
+
+/// Warn once about each message that has waited longer than
+/// `--ack-timeout` for its ACK.
+fn warn_undelivered(session: &Session) {
+    let Some(timeout) = session.ack_timeout else {
+        return;
+    };
+    let mut out = io::stdout().lock();
+    let mut unacked = session.unacked.lock().unwrap();
+    let mut warned = false;
+    for (seq, message) in unacked.iter_mut() {
+        if !message.overdue && message.sent.elapsed() >= timeout {
+            message.overdue = true;
+            warned = true;
+            let _ = say_to!(
+                out,
+                "\r[WARNING] #{} not delivered after {}: {}",
+                seq,
+                duration_text(timeout),
+                message.preview
+            );
+        }
+    }
+    if warned {
+        let _ = prompt(&mut out);
+    }
+}
+
+/// What shows in place of the prompt while the peer is typing.
+fn typing_text(peer: &str) -> String {
+    format!("<{}> is typing… > ", peer)
+}
+
+/// Draw the peer's typing indicator over the prompt line, or refresh its
+/// time if it is already there.
+fn show_typing(out: &mut impl Write, session: &Session) -> io::Result<()> {
+    let mut typing = session.typing.lock().unwrap();
+    let shown = typing.replace(Instant::now()).is_some();
+    if !shown {
+        write!(out, "\r{}", typing_text(&session.peer))?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Blank out the typing indicator, leaving the cursor at the start of the
+/// line. Returns whether it was showing.
+fn clear_typing(out: &mut impl Write, session: &Session) -> bool {
+    if session.typing.lock().unwrap().take().is_none() {
+        return false;
+    }
+    let width = typing_text(&session.peer).chars().count();
+    let _ = write!(out, "\r{}\r", " ".repeat(width));
+    true
+}
+
+/// A TEXT frame once opened.
+struct Received<'a> {
+    /// Keystream position the payload was encrypted at.
+    start: u64,
+    encrypted: &'a [u8],
+    /// The whole payload, send time included.
+    decrypted: &'a [u8],
+    /// When the peer sent it, by the peer's clock.
+    sent_at: SystemTime,
+    text: &'a str,
+}
+
+/// Print a received message over the prompt line, then draw the prompt
+/// again below it.
+fn show_received(out: &mut impl Write, message: &Received, session: &Session) -> io::Result<()> {
+    let Received {
+        start,
+        encrypted,
+        decrypted,
+        sent_at,
+        text,
+    } = *message;
+    let now = SystemTime::now();
+    write!(out, "\r")?;
+    log_to!(
+        out,
+        Level::Summary,
+        "[NETWORK] Received encrypted message ({} bytes)",
+        encrypted.len()
+    )?;
+    log_to!(
+        out,
+        Level::Summary,
+        "[~] Received {} bytes",
+        encrypted.len()
+    )?;
+    log_to!(out, Level::Summary)?;
+
+    log_to!(out, Level::Detail, "[DECRYPT]")?;
+    log_to!(
+        out,
+        Level::Detail,
+        "Cipher: {}",
+        hex(&encrypted[..encrypted.len().min(10)])
+    )?;
+    log_to!(
+        out,
+        Level::Detail,
+        "{}",
+        key_line(start, decrypted, encrypted)
+    )?;
+    log_to!(out, Level::Detail, "Plain: {}→ {:?}", hex(decrypted), text)?;
+    log_to!(out, Level::Detail)?;
+
+    if session.timestamps {
+        write!(out, "[{}] ", local_clock(now))?;
+    }
+    write!(out, "{} {}", style::nick(&session.peer), text)?;
+    if session.show_latency {
+        write!(
+            out,
+            " (sent {}, latency {})",
+            local_clock(sent_at),
+            latency_text(millis_between(sent_at, now))
+        )?;
+    }
+    writeln!(out)?;
+    log_to!(out, Level::Summary)?;
+    prompt(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VERBOSITY;
+    use crate::dh::{DhParams, test_secret};
+    use crate::proto::MAX_MESSAGE;
+    use std::io::Cursor;
+
+    /// A connection the peer has already written `incoming` to, which
+    /// keeps what we write back.
+    struct Wire {
+        incoming: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Wire {
+        fn new(incoming: Vec<u8>) -> Self {
+            Self {
+                incoming: Cursor::new(incoming),
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Wire {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for Wire {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Wire {
+        fn read_timeout(&self) -> Option<Duration> {
+            None
+        }
+
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn hang_up(&self) {}
+    }
+
+    fn rekey(is_server: bool, messages: Option<u64>) -> Rekey {
+        Rekey {
+            group: DhGroup::Small(DhParams::DEFAULT),
+            cipher: CipherKind::ChaCha20,
+            is_server,
+            seed: Some(if is_server { 7 } else { 8 }),
+            messages,
+            interval: None,
+            psk: None,
+        }
+    }
+
+    /// Keys for one end of a chat over the shared test secret.
+    fn keys(is_server: bool) -> CipherPair {
+        CipherPair::derive(&test_secret(1), CipherKind::ChaCha20, is_server)
+    }
+
+    /// A sender for one end that writes to memory.
+    fn sender(stats: &SessionStats, is_server: bool, messages: Option<u64>) -> Sender<'_, Vec<u8>> {
+        Sender::new(
+            Vec::new(),
+            stats,
+            keys(is_server).send,
+            rekey(is_server, messages),
+            MAX_MESSAGE,
+            None,
+        )
+    }
+
+    fn written(sender: &Sender<Vec<u8>>) -> Vec<u8> {
+        sender.writer.lock().unwrap().clone()
+    }
+
+    /// Every frame in `bytes`, opened with `recv`.
+    fn frames(bytes: &[u8], recv: &mut RecvKeys) -> Vec<(u8, Vec<u8>)> {
+        let mut reader = bytes;
+        let mut frames = Vec::new();
+        while !reader.is_empty() {
+            let frame = recv.read_frame(&mut reader, MAX_MESSAGE).unwrap();
+            let (_, plain) = recv.open(&frame).expect("frame should authenticate");
+            frames.push((frame.kind, plain));
+        }
+        frames
+    }
+
+    fn options() -> ChatOptions {
+        ChatOptions {
+            nick: "alice".to_string(),
+            keepalive: None,
+            ack_timeout: None,
+            timeout: None,
+            max_message: MAX_MESSAGE,
+            pad: 1,
+            timestamps: false,
+            show_latency: false,
+            raw: false,
+            stats_on_exit: false,
+            history: 10,
+            frame_format: FRAME_VERSION,
+            test_seed: None,
+            pace: None,
+        }
+    }
+
+    fn session(stats: &SessionStats) -> Session<'_> {
+        Session::new(
+            "alice".to_string(),
+            "bob".to_string(),
+            &options(),
+            None,
+            stats,
+        )
+    }
+
+    /// A TEXT payload as `send_text` builds it.
+    fn text(seq: u64, text: &str) -> Vec<u8> {
+        pad(&encode_text(seq, SystemTime::now(), text.as_bytes()), 1).unwrap()
+    }
+
+    #[test]
+    fn the_keystream_preview_leaves_the_keys_alone() {
+        // The preview only runs at -vv
+        VERBOSITY.store(Level::Detail as u8, Ordering::Relaxed);
+        for kind in [CipherKind::ChaCha20, CipherKind::Lcg] {
+            let send = CipherPair::derive(&test_secret(1), kind, true).send;
+            let mut untouched = send.clone();
+            let mut previewed = send;
+            print_keystream(&previewed, 12);
+            assert_eq!(
+                previewed.seal(FRAME_HELLO, 0, 0, b"alice").2,
+                untouched.seal(FRAME_HELLO, 0, 0, b"alice").2
+            );
+        }
+    }
+
+    #[test]
+    fn previews_are_cut_at_a_character_limit() {
+        assert_eq!(preview("short"), "short");
+        assert_eq!(preview(&"é".repeat(40)), format!("{}…", "é".repeat(30)));
+    }
+
+    #[test]
+    fn sent_frames_open_on_the_other_side() {
+        let stats = SessionStats::new();
+        let sender = sender(&stats, true, None);
+        let hello = text(1, "hello");
+        sender.send(FRAME_TEXT, &hello).unwrap();
+        sender.send(FRAME_PING, &[]).unwrap();
+        let mut recv = RecvKeys::new(keys(false).recv);
+        let frames = frames(&written(&sender), &mut recv);
+        assert_eq!(frames, vec![(FRAME_TEXT, hello), (FRAME_PING, vec![])]);
+        assert_eq!(stats.snapshot().bytes_sent, written(&sender).len() as u64);
+
+        let too_long = vec![b'x'; MAX_MESSAGE + 1];
+        let err = sender.send(FRAME_TEXT, &too_long).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn a_rekey_moves_both_sides_to_new_keys() {
+        let stats = SessionStats::new();
+        let server = sender(&stats, true, Some(2));
+        let client = sender(&stats, false, None);
+        let mut client_recv = RecvKeys::new(keys(false).recv);
+        let mut server_recv = RecvKeys::new(keys(true).recv);
+
+        // Two frames under one key, then the server asks for new keys
+        server.send(FRAME_PING, &[]).unwrap();
+        server.send(FRAME_PING, &[]).unwrap();
+        let sent = frames(&written(&server), &mut client_recv);
+        assert_eq!(sent.len(), 3);
+        let (kind, server_public) = &sent[2];
+        assert_eq!(*kind, FRAME_REKEY);
+
+        // The client answers with its own half and both switch over
+        client_recv.next = Some(client.finish_rekey(server_public).unwrap());
+        let answer = frames(&written(&client), &mut server_recv);
+        assert_eq!(answer[0].0, FRAME_REKEY);
+        server_recv.next = Some(server.finish_rekey(&answer[0].1).unwrap());
+        assert_eq!((server.epoch(), client.epoch()), (1, 1));
+
+        server.writer.lock().unwrap().clear();
+        client.writer.lock().unwrap().clear();
+        let (down, up) = (text(3, "from the server"), text(1, "from the client"));
+        server.send(FRAME_TEXT, &down).unwrap();
+        client.send(FRAME_TEXT, &up).unwrap();
+        assert_eq!(
+            frames(&written(&server), &mut client_recv),
+            vec![(FRAME_TEXT, down)]
+        );
+        assert_eq!(
+            frames(&written(&client), &mut server_recv),
+            vec![(FRAME_TEXT, up)]
+        );
+    }
+
+    #[test]
+    fn hellos_settle_on_the_older_frame_format() {
+        let stats = SessionStats::new();
+        let client = sender(&stats, false, None);
+        {
+            let mut keys = client.keys.lock().unwrap();
+            client.write(&mut keys, FRAME_HELLO, b"bob").unwrap();
+            client
+                .write(&mut keys, FRAME_FORMAT, &[FRAME_VERSION])
+                .unwrap();
+        }
+        let server = sender(&stats, true, None);
+        let mut recv = RecvKeys::new(keys(true).recv);
+        let hello = exchange_hello(
+            &server,
+            &mut &written(&client)[..],
+            &mut recv,
+            "alice",
+            FRAME_VERSION_NONCE,
+        )
+        .unwrap();
+        assert_eq!(hello.nick, "bob");
+        assert_eq!(hello.format, FRAME_VERSION);
+        assert!(hello.presence.is_none());
+        assert_eq!(server.keys.lock().unwrap().format, FRAME_VERSION);
+        assert_eq!(recv.format, FRAME_VERSION);
+
+        // Ours went out first: our nickname, then the newest format we speak
+        let mut client_recv = RecvKeys::new(keys(false).recv);
+        assert_eq!(
+            frames(&written(&server), &mut client_recv),
+            vec![
+                (FRAME_HELLO, b"alice".to_vec()),
+                (FRAME_FORMAT, vec![FRAME_VERSION_NONCE])
+            ]
+        );
+    }
+
+    #[test]
+    fn a_hello_from_an_older_build_keeps_its_presence() {
+        let stats = SessionStats::new();
+        let client = sender(&stats, false, None);
+        client.send(FRAME_HELLO, b"bob").unwrap();
+        client
+            .send(FRAME_PRESENCE, &encode_presence(true, "bob"))
+            .unwrap();
+        let server = sender(&stats, true, None);
+        let mut recv = RecvKeys::new(keys(true).recv);
+        let hello = exchange_hello(
+            &server,
+            &mut &written(&client)[..],
+            &mut recv,
+            "alice",
+            FRAME_VERSION_NONCE,
+        )
+        .unwrap();
+        assert_eq!(hello.format, FRAME_VERSION);
+        assert_eq!(hello.presence, Some(encode_presence(true, "bob")));
+    }
+
+    #[test]
+    fn a_hello_with_a_bad_nickname_is_refused() {
+        let stats = SessionStats::new();
+        let client = sender(&stats, false, None);
+        client.send(FRAME_HELLO, b"bad\nnick").unwrap();
+        client.send(FRAME_FORMAT, &[FRAME_VERSION]).unwrap();
+        let server = sender(&stats, true, None);
+        let mut recv = RecvKeys::new(keys(true).recv);
+        let Err(err) = exchange_hello(
+            &server,
+            &mut &written(&client)[..],
+            &mut recv,
+            "alice",
+            FRAME_VERSION,
+        ) else {
+            panic!("a bad nickname was accepted");
+        };
+        assert!(err.to_string().contains("bad nickname"), "{}", err);
+    }
+
+    #[test]
+    fn messages_received_are_recorded_and_acknowledged() {
+        let stats = SessionStats::new();
+        let client = sender(&stats, false, None);
+        client.send(FRAME_TEXT, &text(1, "hi alice")).unwrap();
+        client.send(FRAME_BYE, &[]).unwrap();
+
+        let server_stats = SessionStats::new();
+        let server = sender(&server_stats, true, None);
+        let session = session(&server_stats);
+        let reader = BufReader::new(Wire::new(written(&client)));
+        let ending = receive_loop(reader, RecvKeys::new(keys(true).recv), &server, &session);
+        assert!(matches!(ending, Ending::Bye), "{:?}", ending);
+        assert_eq!(server_stats.received(), 1);
+        let history = session.history.replay(10, |_| String::new());
+        assert!(
+            history.iter().any(|line| line.contains("hi alice")),
+            "{:?}",
+            history
+        );
+
+        // An ACK for the message, then our BYE in answer to theirs
+        let mut client_recv = RecvKeys::new(keys(false).recv);
+        assert_eq!(
+            frames(&written(&server), &mut client_recv),
+            vec![
+                (FRAME_ACK, 1u64.to_be_bytes().to_vec()),
+                (FRAME_BYE, vec![])
+            ]
+        );
+    }
+
+    #[test]
+    fn the_receive_loop_tells_a_close_from_a_cut() {
+        let stats = SessionStats::new();
+        let client = sender(&stats, false, None);
+        client.send(FRAME_TEXT, &text(1, "cut short")).unwrap();
+        let mut bytes = written(&client);
+        bytes.pop();
+
+        let server = sender(&stats, true, None);
+        let session = session(&stats);
+        let recv = || RecvKeys::new(keys(true).recv);
+        let cut = receive_loop(BufReader::new(Wire::new(bytes)), recv(), &server, &session);
+        assert!(matches!(cut, Ending::Truncated), "{:?}", cut);
+        let closed = receive_loop(BufReader::new(Wire::new(vec![])), recv(), &server, &session);
+        assert!(matches!(closed, Ending::Closed), "{:?}", closed);
+    }
+
+    #[test]
+    fn the_typing_indicator_is_drawn_once_and_cleared() {
+        let stats = SessionStats::new();
+        let session = session(&stats);
+        let mut out = Vec::new();
+        assert!(!clear_typing(&mut out, &session));
+        assert!(out.is_empty());
+        show_typing(&mut out, &session).unwrap();
+        // A refresh while it shows draws nothing more
+        show_typing(&mut out, &session).unwrap();
+        let shown = format!("\r{}", typing_text("bob"));
+        assert_eq!(String::from_utf8(out).unwrap(), shown);
+
+        let mut out = Vec::new();
+        assert!(clear_typing(&mut out, &session));
+        let blank = " ".repeat(typing_text("bob").chars().count());
+        assert_eq!(String::from_utf8(out).unwrap(), format!("\r{}\r", blank));
+        assert!(session.typing.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn a_message_arriving_clears_the_typing_indicator() {
+        let stats = SessionStats::new();
+        let server = sender(&stats, true, None);
+        let client = sender(&stats, false, None);
+        client.send(FRAME_TYPING, &[]).unwrap();
+        let typing_only = written(&client);
+        client.send(FRAME_TEXT, &text(1, "done typing")).unwrap();
+        let then_text = written(&client);
+
+        // The same frames with and without the message after them
+        let typing_after = |bytes| {
+            let session = session(&stats);
+            let reader = BufReader::new(Wire::new(bytes));
+            let ending = receive_loop(reader, RecvKeys::new(keys(true).recv), &server, &session);
+            assert!(matches!(ending, Ending::Closed), "{:?}", ending);
+            session.typing.lock().unwrap().is_some()
+        };
+        assert!(typing_after(typing_only));
+        assert!(!typing_after(then_text));
+    }
+}
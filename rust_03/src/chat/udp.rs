@@ -0,0 +1,396 @@
+//! `chat` over UDP, one datagram per frame.
+
+use std::io::{self, Write};
+use std::net::UdpSocket;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use super::{
+    BYE_TIMEOUT, ChatOptions, INTERRUPTS, Input, MAX_UNANSWERED, Received, Session, TICK, Typed,
+    acknowledged, catch_interrupts, message_bytes, prompt, report_ending, save_history, send_text,
+    show_help, show_history, show_presence, show_received, typed_text, warn_undelivered,
+};
+use crate::cipher::TAG_LEN;
+use crate::command::{self, Action};
+use crate::datagram::{
+    Arrival, DATAGRAM_HEADER, DATAGRAM_OVERHEAD, DatagramCipher, MAX_DATAGRAM, Opened, UdpLink,
+    Window,
+};
+use crate::proto::{
+    FRAME_ACK, FRAME_BYE, FRAME_HELLO, FRAME_PING, FRAME_PONG, FRAME_PRESENCE, FRAME_TEXT,
+    check_nick, decode_text, encode_presence, text_limit, unpad,
+};
+use crate::session::{Ending, SessionStats, SessionSummary};
+use crate::transcript::{Direction, Transcript};
+use crate::{Level, is_timeout, log, say, say_to, waiting_for};
+
+/// The sending half of a chat over UDP.
+struct UdpSender<'a> {
+    socket: &'a UdpSocket,
+    cipher: DatagramCipher,
+    /// Sequence number for the next datagram.
+    seq: Mutex<u64>,
+    stats: &'a SessionStats,
+}
+
+impl UdpSender<'_> {
+    /// Seal `plain` in the next datagram as a `kind` frame and send it.
+    /// Returns where its keystream started, always 0, and the ciphertext.
+    fn send(&self, kind: u8, plain: &[u8]) -> io::Result<(u64, Vec<u8>)> {
+        let mut seq = self.seq.lock().unwrap();
+        let datagram = self.cipher.seal(kind, *seq, plain);
+        self.socket.send(&datagram)?;
+        self.stats.frame_sent(datagram.len());
+        *seq += 1;
+        let encrypted = &datagram[DATAGRAM_HEADER..datagram.len() - TAG_LEN];
+        Ok((0, encrypted.to_vec()))
+    }
+}
+
+/// `chat` over UDP, once `udp_handshake` is done. Each frame goes in a
+/// datagram of its own, with no keystream running from one to the next,
+/// so losing one costs only that message. Messages are still
+/// acknowledged, and datagrams that come out of order, twice, or not at
+/// all are reported as they are noticed. There are no file transfers or
+/// rekeys over UDP.
+pub fn udp_chat(
+    link: UdpLink,
+    secret: &[u8],
+    is_server: bool,
+    options: ChatOptions,
+    transcript: Option<&Transcript>,
+    input: &Input,
+) -> io::Result<i32> {
+    log!(
+        Level::Summary,
+        "[STREAM] ChaCha20 per datagram, one key per direction derived from the secret; each datagram's nonce is its sequence number"
+    );
+    log!(Level::Summary);
+    println!("✓ Secure channel established!");
+    println!();
+
+    let timed_out = waiting_for(&link, "the peer's hello");
+    let socket = link.into_socket();
+    let stats = SessionStats::new();
+    let (send, recv) = DatagramCipher::pair(secret, is_server);
+    let sender = UdpSender {
+        socket: &socket,
+        cipher: send,
+        seq: Mutex::new(0),
+        stats: &stats,
+    };
+    sender.send(FRAME_HELLO, options.nick.as_bytes())?;
+    let mut window = Window::new();
+    let peer = udp_hello(&socket, &recv, &mut window).map_err(timed_out)?;
+    say!(
+        "[CHAT] You are <{}>, talking to <{}> over UDP",
+        options.nick,
+        peer
+    );
+    say!("[CHAT] Type messages and press Enter; /quit or Ctrl+D to leave.");
+    say!("[CHAT] /help lists the commands; /send and /stats need TCP.");
+
+    socket.set_read_timeout(Some(TICK))?;
+    let session = Session::new(options.nick.clone(), peer, &options, transcript, &stats);
+    let limit = text_limit(
+        options.max_message.min(MAX_DATAGRAM - DATAGRAM_OVERHEAD),
+        options.pad,
+    );
+    sender.send(FRAME_PRESENCE, &encode_presence(true, &session.nick))?;
+    catch_interrupts()?;
+    let (done_tx, done_rx) = mpsc::channel();
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let receiver = scope.spawn(|| {
+            let (ending, window) =
+                udp_receive_loop(&socket, &recv, window, &sender, &session, &stop);
+            if session.quitting.load(Ordering::SeqCst) {
+                let _ = done_tx.send(ending);
+                (None, window)
+            } else {
+                report_ending(&ending);
+                session.peer_left.store(true, Ordering::SeqCst);
+                (Some(ending), window)
+            }
+        });
+        let mut result = udp_send_loop(&sender, &session, input, limit);
+        if !session.peer_left.load(Ordering::SeqCst)
+            && !session.quitting.swap(true, Ordering::SeqCst)
+        {
+            say!("\r[NETWORK] Leaving, sending BYE...");
+            let _ = sender.send(FRAME_PRESENCE, &encode_presence(false, &session.nick));
+            result = result.and_then(|()| sender.send(FRAME_BYE, &[]).map(drop));
+            match done_rx.recv_timeout(BYE_TIMEOUT) {
+                Ok(Ending::Bye) => say!("\r[NETWORK] Peer acknowledged, disconnected."),
+                _ => say!("\r[NETWORK] No reply from peer, closing anyway."),
+            }
+        }
+        stop.store(true, Ordering::SeqCst);
+        let (peer_ending, window) = match receiver.join() {
+            Ok(joined) => joined,
+            Err(_) => (
+                Some(Ending::Failed(io::Error::other("the reader panicked"))),
+                Window::new(),
+            ),
+        };
+        let ending = match (peer_ending, result) {
+            (Some(ending), _) => ending,
+            (None, Ok(())) => Ending::Left,
+            (None, Err(e)) => {
+                let ending = Ending::from_error(e);
+                report_ending(&ending);
+                ending
+            }
+        };
+        report_udp_session(&session, &window, &ending);
+        Ok(ending.exit_code())
+    })
+}
+
+/// Wait for the peer's HELLO datagram, which must be the first to arrive,
+/// and return the nickname in it.
+fn udp_hello(socket: &UdpSocket, recv: &DatagramCipher, window: &mut Window) -> io::Result<String> {
+    let mut datagram = vec![0u8; MAX_DATAGRAM];
+    let len = socket.recv(&mut datagram)?;
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let Some(opened) = recv.open(&datagram[..len]) else {
+        return Err(invalid(
+            "peer's hello failed authentication; is it using --transport udp too?".to_string(),
+        ));
+    };
+    if opened.kind != FRAME_HELLO {
+        return Err(invalid(format!(
+            "expected a hello datagram, got type {}",
+            opened.kind
+        )));
+    }
+    window.arrive(opened.seq);
+    check_nick(&opened.payload).map_err(|e| invalid(format!("peer sent a bad nickname: {}", e)))
+}
+
+/// `send_loop` over UDP: messages, `/quit`, `/help` and the history
+/// commands, until input ends or the peer leaves.
+fn udp_send_loop(
+    sender: &UdpSender,
+    session: &Session,
+    input: &Input,
+    limit: usize,
+) -> io::Result<()> {
+    loop {
+        prompt(&mut io::stdout().lock())?;
+        let line = loop {
+            match input.next_line(|| session.peer_left.load(Ordering::SeqCst))? {
+                Some(Typed::Line(line)) => break line,
+                // No typing indicator over UDP
+                Some(Typed::Partial) => {}
+                None => return Ok(()),
+            }
+        };
+        let text = String::from_utf8_lossy(&line);
+        let typed = typed_text(&text, session.raw);
+        let message = match command::parse(typed) {
+            Action::Message(message) => message_bytes(&line, typed, message, session.raw),
+            Action::Quit => return Ok(()),
+            Action::Stats | Action::SendFile(_) => {
+                say!("[ERROR] /send and /stats need --transport tcp");
+                continue;
+            }
+            Action::Help => {
+                say!("[CHAT] Commands:");
+                show_help();
+                continue;
+            }
+            Action::History(count) => {
+                show_history(session, count)?;
+                continue;
+            }
+            Action::Save(path) => {
+                save_history(session, &path);
+                continue;
+            }
+            Action::Unknown(name) => {
+                say!("[ERROR] Unknown command {}; the commands are:", name);
+                show_help();
+                continue;
+            }
+            Action::Invalid(e) => {
+                say!("[ERROR] {}", e);
+                continue;
+            }
+        };
+        send_text(session, message, limit, |payload| {
+            sender.send(FRAME_TEXT, payload)
+        })?;
+    }
+}
+
+/// `receive_loop` over UDP: print each message that arrives, until the
+/// peer says BYE or stops answering keepalive pings, or `stop` is set.
+/// Each datagram is checked against `window` before it is acted on, and
+/// the window comes back with the ending for the summary.
+fn udp_receive_loop(
+    socket: &UdpSocket,
+    recv: &DatagramCipher,
+    mut window: Window,
+    sender: &UdpSender,
+    session: &Session,
+    stop: &AtomicBool,
+) -> (Ending, Window) {
+    let mut datagram = vec![0u8; MAX_DATAGRAM];
+    let mut heard = Instant::now();
+    // Pings sent since the peer was last heard from
+    let mut unanswered = 0;
+    let ending = loop {
+        let len = match socket.recv(&mut datagram) {
+            Ok(len) => len,
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::Interrupted => {
+                if stop.load(Ordering::SeqCst) {
+                    break Ending::Left;
+                }
+                if INTERRUPTS.load(Ordering::SeqCst) > 0
+                    && !session.quitting.swap(true, Ordering::SeqCst)
+                {
+                    say!("\r[NETWORK] Leaving, sending BYE...");
+                    let ending = match sender.send(FRAME_BYE, &[]) {
+                        Ok(_) => Ending::Left,
+                        Err(e) => Ending::from_error(e),
+                    };
+                    report_udp_session(session, &window, &ending);
+                    std::process::exit(ending.exit_code());
+                }
+                warn_undelivered(session);
+                if let Some(keepalive) = session.keepalive
+                    && heard.elapsed() >= keepalive * (unanswered + 1)
+                {
+                    if unanswered == MAX_UNANSWERED {
+                        break Ending::Unreachable;
+                    }
+                    unanswered += 1;
+                    if let Err(e) = sender.send(FRAME_PING, &[]) {
+                        break Ending::Failed(e);
+                    }
+                }
+                continue;
+            }
+            // What an ICMP "port unreachable" turns into
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => break Ending::Unreachable,
+            Err(e) => break Ending::from_error(e),
+        };
+        session.stats.frame_received(len);
+        let Some(Opened { kind, seq, payload }) = recv.open(&datagram[..len]) else {
+            session.stats.frame_dropped();
+            let mut out = io::stdout().lock();
+            let _ = say_to!(
+                out,
+                "\r[SECURITY] !!! {}-byte datagram failed authentication: dropped, it may have been tampered with !!!",
+                len
+            );
+            let _ = prompt(&mut out);
+            continue;
+        };
+        heard = Instant::now();
+        unanswered = 0;
+        let arrival = window.arrive(seq);
+        let note = match arrival {
+            Arrival::InOrder => None,
+            Arrival::Ahead(skipped) => Some(format!(
+                "[UDP] {} datagram(s) missing before #{}",
+                skipped, seq
+            )),
+            Arrival::Late => Some(format!("[UDP] Datagram #{} arrived out of order", seq)),
+            Arrival::Repeat => Some(format!("[UDP] Dropped a repeat of datagram #{}", seq)),
+            Arrival::TooOld => Some(format!(
+                "[UDP] Dropped datagram #{}: too old to tell from a repeat",
+                seq
+            )),
+        };
+        if let Some(note) = &note {
+            let mut out = io::stdout().lock();
+            let _ = say_to!(out, "\r{}", note);
+            let _ = prompt(&mut out);
+        }
+        if matches!(arrival, Arrival::Repeat | Arrival::TooOld) {
+            continue;
+        }
+        match kind {
+            FRAME_BYE => {
+                if !session.quitting.load(Ordering::SeqCst) {
+                    let _ = sender.send(FRAME_BYE, &[]);
+                }
+                break Ending::Bye;
+            }
+            FRAME_PING => {
+                if let Err(e) = sender.send(FRAME_PONG, &[]) {
+                    break Ending::Failed(e);
+                }
+                continue;
+            }
+            FRAME_ACK => {
+                acknowledged(session, &payload);
+                continue;
+            }
+            FRAME_PRESENCE => {
+                let _ = show_presence(&mut io::stdout().lock(), &payload);
+                continue;
+            }
+            FRAME_TEXT => {}
+            _ => continue,
+        }
+        let Some((message_seq, sent_at, text)) = unpad(&payload).and_then(decode_text) else {
+            break Ending::Failed(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer sent a message with a bad length or send time",
+            ));
+        };
+        if text.is_empty() {
+            break Ending::Failed(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer sent an empty message",
+            ));
+        }
+        let text = session.shown(text);
+        let received = Received {
+            start: 0,
+            encrypted: &datagram[DATAGRAM_HEADER..len - TAG_LEN],
+            decrypted: &payload,
+            sent_at,
+            text: &text,
+        };
+        let mut out = io::stdout().lock();
+        if let Err(e) = show_received(&mut out, &received, session) {
+            break Ending::Failed(e);
+        }
+        session.record(Direction::Received, &text);
+        drop(out);
+        if let Err(e) = sender.send(FRAME_ACK, &message_seq.to_be_bytes()) {
+            break Ending::Failed(e);
+        }
+    };
+    (ending, window)
+}
+
+/// `report_session` over UDP, with what became of the peer's datagrams.
+fn report_udp_session(session: &Session, window: &Window, ending: &Ending) {
+    if session.stats_on_exit {
+        for line in session.stats.snapshot().lines() {
+            say!("{}", line);
+        }
+    }
+    let summary = SessionSummary {
+        stats: session.stats.snapshot(),
+        unacked: session.unacked.lock().unwrap().len(),
+        ending: ending.to_string(),
+    };
+    for line in summary.lines() {
+        say!("{}", line);
+    }
+    say!(
+        "[UDP] Peer's datagrams: {} out of order, {} repeated, {} missing",
+        window.late(),
+        window.repeats(),
+        window.missing()
+    );
+}
@@ -0,0 +1,713 @@
+//! Keystreams and tags for the chat: ChaCha20, or the LCG kept for
+//! teaching, for the bytes, and HMAC-SHA256 for the tags, each keyed per
+//! direction from the DH secret.
+
+use std::io::{self, Read};
+
+use crate::proto::{
+    FRAME_OVERHEAD, FRAME_VERSION, FRAME_VERSION_NONCE, Frame, HEADER_LEN, Header, NONCE_LEN,
+};
+use crate::{Level, log, say};
+
+// LCG parameters for stream cipher
+const A: u64 = 1103515245;
+const C: u64 = 12345;
+const M: u64 = 1u64 << 32;
+
+/// Which keystream generator to use. Both peers must pick the same one.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CipherKind {
+    ChaCha20,
+    /// The original LCG, kept for teaching: a few known plaintext bytes
+    /// give away the whole keystream.
+    Lcg,
+}
+
+impl CipherKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherKind::ChaCha20 => "chacha20",
+            CipherKind::Lcg => "lcg",
+        }
+    }
+}
+
+// ChaCha20 nonces, one per direction; the keys differ as well
+const NONCE_SERVER: [u8; 12] = *b"server->peer";
+const NONCE_CLIENT: [u8; 12] = *b"client->peer";
+
+/// SHA-256 round constants (FIPS 180-4, 4.2.2).
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 (FIPS 180-4), fed in pieces so a file need not fit in memory.
+pub struct Sha256 {
+    state: [u32; 8],
+    /// Bytes waiting for a full 64-byte block.
+    pending: Vec<u8>,
+    length: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            pending: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if !self.pending.is_empty() {
+            let take = (64 - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            sha256_compress(&mut self.state, &self.pending);
+            self.pending.clear();
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            sha256_compress(&mut self.state, block);
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        // Pad with 0x80, zeros, then the bit length, to a multiple of 64 bytes
+        let bits = self.length * 8;
+        let mut tail = vec![0x80];
+        while (self.pending.len() + tail.len()) % 64 != 56 {
+            tail.push(0);
+        }
+        tail.extend_from_slice(&bits.to_be_bytes());
+        self.pending.extend_from_slice(&tail);
+        for block in self.pending.chunks(64) {
+            sha256_compress(&mut self.state, block);
+        }
+
+        let mut digest = [0u8; 32];
+        for (out, word) in digest.chunks_mut(4).zip(self.state) {
+            out.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run the SHA-256 compression function over one 64-byte block.
+fn sha256_compress(h: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
+    for (i, word) in block.chunks(4).enumerate() {
+        w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = *h;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = hh
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(SHA256_K[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        hh = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+    for (state, v) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+        *state = state.wrapping_add(v);
+    }
+}
+
+/// The SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// HMAC-SHA256 of `message` under `key` (RFC 2104).
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(message);
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+/// Length of the HMAC-SHA256 tags that end a frame's header and payload.
+pub const TAG_LEN: usize = 32;
+
+/// The tag for an encrypted frame header: HMAC-SHA256 over the keystream
+/// position it starts at and the header. Checked before the header is
+/// decrypted, so a forged length is never acted on.
+fn header_tag(mac_key: &[u8; 32], position: u64, header: &[u8]) -> [u8; TAG_LEN] {
+    let mut message = Vec::with_capacity(15 + header.len());
+    message.extend_from_slice(b"header ");
+    message.extend_from_slice(&position.to_be_bytes());
+    message.extend_from_slice(header);
+    hmac_sha256(mac_key, &message)
+}
+
+/// The tag for a frame: HMAC-SHA256 over its header, the keystream
+/// position its payload starts at, and the payload. Covering the position
+/// means a replayed or reordered frame fails too.
+fn frame_tag(
+    mac_key: &[u8; 32],
+    version: u8,
+    kind: u8,
+    epoch: u8,
+    position: u64,
+    payload: &[u8],
+) -> [u8; TAG_LEN] {
+    let mut message = Vec::with_capacity(15 + payload.len());
+    message.extend_from_slice(&[version, kind, epoch]);
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(&position.to_be_bytes());
+    message.extend_from_slice(payload);
+    hmac_sha256(mac_key, &message)
+}
+
+/// Compare two tags without stopping at the first difference, so the time
+/// taken says nothing about where they differ.
+pub fn tags_equal(a: &[u8; TAG_LEN], b: &[u8; TAG_LEN]) -> bool {
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Labels for the per-direction MAC keys
+const MAC_SERVER: &[u8] = b"chat mac server->peer ";
+const MAC_CLIENT: &[u8] = b"chat mac client->peer ";
+
+/// The ChaCha20 quarter round on four words of `state` (RFC 8439, 2.1).
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// One 64-byte ChaCha20 keystream block (RFC 8439, 2.3).
+fn chacha20_block(key: &[u8; 32], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let word = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for (i, chunk) in key.chunks(4).enumerate() {
+        initial[4 + i] = word(chunk);
+    }
+    initial[12] = counter;
+    for (i, chunk) in nonce.chunks(4).enumerate() {
+        initial[13 + i] = word(chunk);
+    }
+
+    let mut state = initial;
+    for _ in 0..10 {
+        // Column rounds, then diagonal rounds
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut block = [0u8; 64];
+    for (i, out) in block.chunks_mut(4).enumerate() {
+        out.copy_from_slice(&state[i].wrapping_add(initial[i]).to_le_bytes());
+    }
+    block
+}
+
+/// `data` XORed with the ChaCha20 keystream for `key` and `nonce`, from
+/// its first block: for a datagram, which starts a keystream of its own.
+pub fn chacha20_xor(key: &[u8; 32], nonce: &[u8; 12], data: &[u8]) -> Vec<u8> {
+    StreamCipher::chacha20(*key, *nonce, [0; 32]).encrypt(data)
+}
+
+// Direction labels for the keystream keys, so the two sides never
+// encrypt with the same keystream
+const CLIENT_TO_SERVER: &[u8] = b"c2s";
+const SERVER_TO_CLIENT: &[u8] = b"s2c";
+
+/// A 32-byte key for one direction from the DH secret, whatever its size:
+/// SHA-256 over a fixed label, the direction and the secret.
+fn derive_key(shared_secret: &[u8], direction: &[u8]) -> [u8; 32] {
+    sha256(&[b"chat key ", direction, b" ", shared_secret].concat())
+}
+
+#[derive(Clone)]
+enum Keystream {
+    Lcg {
+        state: u64,
+    },
+    ChaCha20 {
+        key: [u8; 32],
+        nonce: [u8; 12],
+        /// Number of the next block to generate.
+        counter: u32,
+        block: [u8; 64],
+    },
+}
+
+/// One direction's keystream. It is never re-seeded: each message picks
+/// up where the previous one stopped, at byte `position`. In frame
+/// format `FRAME_VERSION_NONCE` it is never used itself, only copied by
+/// `for_message` into a keystream for each frame.
+#[derive(Clone)]
+pub struct StreamCipher {
+    keystream: Keystream,
+    /// Keystream bytes used so far.
+    position: u64,
+    /// Key for the tags on this direction's frames.
+    mac_key: [u8; 32],
+    /// Frame format the headers it seals say, and its tags cover.
+    version: u8,
+}
+
+impl StreamCipher {
+    fn lcg(seed: u64, mac_key: [u8; 32]) -> Self {
+        Self {
+            keystream: Keystream::Lcg { state: seed },
+            position: 0,
+            mac_key,
+            version: FRAME_VERSION,
+        }
+    }
+
+    fn chacha20(key: [u8; 32], nonce: [u8; 12], mac_key: [u8; 32]) -> Self {
+        Self {
+            keystream: Keystream::ChaCha20 {
+                key,
+                nonce,
+                counter: 0,
+                block: [0; 64],
+            },
+            position: 0,
+            mac_key,
+            version: FRAME_VERSION,
+        }
+    }
+
+    /// The keystream for one `FRAME_VERSION_NONCE` frame: this one
+    /// restarted from the frame's random `nonce` as well as the key, with
+    /// the nonce mixed into the tag key too, so no part of one frame
+    /// passes for part of another. `self` must not have been used: its
+    /// state is then the direction's key alone.
+    pub fn for_message(&self, nonce: &[u8; NONCE_LEN]) -> StreamCipher {
+        let mac_key = hmac_sha256(&self.mac_key, &[b"frame nonce ", &nonce[..]].concat());
+        let mut cipher = match &self.keystream {
+            Keystream::Lcg { state } => {
+                let seed = sha256(&[&state.to_be_bytes()[..], nonce].concat());
+                Self::lcg(lcg_seed(&seed), mac_key)
+            }
+            // The first 4 bytes of the direction's nonce, then the frame's
+            Keystream::ChaCha20 {
+                key, nonce: ours, ..
+            } => {
+                let mut frame_nonce = [0u8; 12];
+                frame_nonce[..4].copy_from_slice(&ours[..4]);
+                frame_nonce[4..].copy_from_slice(nonce);
+                Self::chacha20(*key, frame_nonce, mac_key)
+            }
+        };
+        cipher.version = FRAME_VERSION_NONCE;
+        cipher
+    }
+
+    /// Move the keystream on `count` bytes without using them.
+    pub fn skip(&mut self, count: u64) {
+        for _ in 0..count {
+            self.next_byte();
+        }
+    }
+
+    pub fn next_byte(&mut self) -> u8 {
+        let byte = match &mut self.keystream {
+            Keystream::Lcg { state } => {
+                *state = (A.wrapping_mul(*state).wrapping_add(C)) % M;
+                (*state & 0xFF) as u8
+            }
+            Keystream::ChaCha20 {
+                key,
+                nonce,
+                counter,
+                block,
+            } => {
+                let offset = (self.position % 64) as usize;
+                if offset == 0 {
+                    *block = chacha20_block(key, *counter, nonce);
+                    *counter = counter
+                        .checked_add(1)
+                        .expect("ChaCha20 keystream exhausted");
+                }
+                block[offset]
+            }
+        };
+        self.position += 1;
+        byte
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        plaintext.iter().map(|&b| b ^ self.next_byte()).collect()
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Vec<u8> {
+        self.encrypt(ciphertext) // XOR is symmetric
+    }
+
+    /// Encrypt and tag a header for frame `seq`, a `kind` frame in key
+    /// `epoch`, then `plain` as its payload. Returns the keystream
+    /// position the payload starts at, its ciphertext, and the whole frame
+    /// as `write_frame` sends it.
+    pub fn seal(&mut self, kind: u8, epoch: u8, seq: u64, plain: &[u8]) -> (u64, Vec<u8>, Vec<u8>) {
+        let header = Header {
+            version: self.version,
+            kind,
+            epoch,
+            len: plain.len() as u32,
+            seq,
+        };
+        let header_start = self.position;
+        let header = self.encrypt(&header.encode());
+        let start = self.position;
+        let encrypted = self.encrypt(plain);
+        let mut frame = Vec::with_capacity(FRAME_OVERHEAD + plain.len());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&header_tag(&self.mac_key, header_start, &header));
+        frame.extend_from_slice(&encrypted);
+        frame.extend_from_slice(&frame_tag(
+            &self.mac_key,
+            self.version,
+            kind,
+            epoch,
+            start,
+            &encrypted,
+        ));
+        (start, encrypted, frame)
+    }
+
+    /// Check the tag on an encrypted header, `HEADER_LEN` bytes followed
+    /// by `TAG_LEN`, and decrypt it. A header that fails leaves the
+    /// keystream where it was, so other keys can be tried on it.
+    pub fn open_header(&mut self, block: &[u8; HEADER_LEN + TAG_LEN]) -> Option<Header> {
+        let (header, tag) = block.split_at(HEADER_LEN);
+        let expected = header_tag(&self.mac_key, self.position, header);
+        if !tags_equal(&expected, tag.try_into().unwrap()) {
+            return None;
+        }
+        Some(Header::decode(&self.decrypt(header).try_into().unwrap()))
+    }
+
+    /// Check `frame`'s tag, then decrypt its payload. A frame that fails
+    /// is not decrypted, but its length still moves the keystream on so
+    /// that the frames after it stay readable.
+    pub fn open(&mut self, frame: &Frame) -> Option<(u64, Vec<u8>)> {
+        let start = self.position;
+        let expected = frame_tag(
+            &self.mac_key,
+            self.version,
+            frame.kind,
+            frame.epoch,
+            start,
+            &frame.payload,
+        );
+        if !tags_equal(&expected, &frame.tag) {
+            self.skip(frame.payload.len() as u64);
+            return None;
+        }
+        Some((start, self.decrypt(&frame.payload)))
+    }
+}
+
+/// Our send and receive keystreams. Both start from the shared secret, and
+/// the peer's pair mirrors ours, so each side's `send` stays in step with
+/// the other's `recv` however the messages interleave.
+pub struct CipherPair {
+    pub send: StreamCipher,
+    pub recv: StreamCipher,
+}
+
+impl CipherPair {
+    pub fn new(shared_secret: &[u8], kind: CipherKind, is_server: bool) -> Self {
+        log!(
+            Level::Summary,
+            "[STREAM] Generating keystream from secret..."
+        );
+        match kind {
+            CipherKind::Lcg => {
+                log!(Level::Summary, "Algorithm: LCG (a={}, c={}, m=2^32)", A, C);
+                say!("[WARNING] The LCG keystream is predictable: NOT SECURE");
+                let (send, recv) = directions(is_server);
+                log!(
+                    Level::Detail,
+                    "Seeds: send {:X}, receive {:X}",
+                    lcg_seed(&derive_key(shared_secret, send)),
+                    lcg_seed(&derive_key(shared_secret, recv))
+                );
+            }
+            CipherKind::ChaCha20 => log!(
+                Level::Summary,
+                "Algorithm: ChaCha20 (RFC 8439), one key per direction derived from the secret"
+            ),
+        }
+        Self::derive(shared_secret, kind, is_server)
+    }
+
+    /// `new` without the commentary, for rekeying mid-chat.
+    pub fn derive(shared_secret: &[u8], kind: CipherKind, is_server: bool) -> Self {
+        let (send_mac, recv_mac) = if is_server {
+            (MAC_SERVER, MAC_CLIENT)
+        } else {
+            (MAC_CLIENT, MAC_SERVER)
+        };
+        // Naming the cipher in the label makes a mismatch fail the hello
+        let send_mac = hmac_sha256(shared_secret, &[send_mac, kind.name().as_bytes()].concat());
+        let recv_mac = hmac_sha256(shared_secret, &[recv_mac, kind.name().as_bytes()].concat());
+        // What we send, the peer receives: our send key is its receive key
+        let (send, recv) = directions(is_server);
+        let send_key = derive_key(shared_secret, send);
+        let recv_key = derive_key(shared_secret, recv);
+        match kind {
+            CipherKind::Lcg => Self {
+                send: StreamCipher::lcg(lcg_seed(&send_key), send_mac),
+                recv: StreamCipher::lcg(lcg_seed(&recv_key), recv_mac),
+            },
+            CipherKind::ChaCha20 => {
+                let (ours, theirs) = if is_server {
+                    (NONCE_SERVER, NONCE_CLIENT)
+                } else {
+                    (NONCE_CLIENT, NONCE_SERVER)
+                };
+                Self {
+                    send: StreamCipher::chacha20(send_key, ours, send_mac),
+                    recv: StreamCipher::chacha20(recv_key, theirs, recv_mac),
+                }
+            }
+        }
+    }
+}
+
+/// The direction labels for what we send and what we receive.
+fn directions(is_server: bool) -> (&'static [u8], &'static [u8]) {
+    if is_server {
+        (SERVER_TO_CLIENT, CLIENT_TO_SERVER)
+    } else {
+        (CLIENT_TO_SERVER, SERVER_TO_CLIENT)
+    }
+}
+
+/// The LCG seed: the low 64 bits of a direction's key.
+fn lcg_seed(key: &[u8; 32]) -> u64 {
+    let mut seed = [0u8; 8];
+    seed.copy_from_slice(&key[24..]);
+    u64::from_be_bytes(seed)
+}
+
+/// Fill `buf` from the operating system's random number generator.
+#[cfg(unix)]
+pub fn os_random(buf: &mut [u8]) -> io::Result<()> {
+    std::fs::File::open("/dev/urandom")?.read_exact(buf)
+}
+
+/// Fill `buf` from the operating system's random number generator.
+#[cfg(windows)]
+pub fn os_random(buf: &mut [u8]) -> io::Result<()> {
+    use std::ffi::c_void;
+
+    const BCRYPT_USE_SYSTEM_PREFERRED_RNG: u32 = 2;
+    #[link(name = "bcrypt")]
+    unsafe extern "system" {
+        fn BCryptGenRandom(algorithm: *mut c_void, buf: *mut u8, len: u32, flags: u32) -> i32;
+    }
+
+    for chunk in buf.chunks_mut(u32::MAX as usize) {
+        // SAFETY: the pointer and length describe `chunk`, which is valid
+        // for writes, and a null algorithm is allowed with this flag.
+        let status = unsafe {
+            BCryptGenRandom(
+                std::ptr::null_mut(),
+                chunk.as_mut_ptr(),
+                chunk.len() as u32,
+                BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+            )
+        };
+        if status != 0 {
+            return Err(io::Error::other(format!(
+                "BCryptGenRandom failed with status {:#x}",
+                status
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn rfc_key() -> [u8; 32] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    #[test]
+    fn sha256_matches_fips_180_example() {
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Fed in pieces that straddle a block
+        let mut hasher = Sha256::new();
+        let data = [b'x'; 200];
+        hasher.update(&data[..63]);
+        hasher.update(&data[63..]);
+        assert_eq!(hasher.finish(), sha256(&data));
+    }
+
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        let cases: [(&[u8], &[u8], &str); 3] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            // A key longer than a block is hashed first
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+        ];
+        for (key, message, expected) in cases {
+            assert_eq!(hex(&hmac_sha256(key, message)), expected);
+        }
+    }
+
+    #[test]
+    fn chacha20_block_matches_rfc_8439() {
+        // Section 2.3.2
+        let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        assert_eq!(
+            hex(&chacha20_block(&rfc_key(), 1, &nonce)),
+            "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e\
+             d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e"
+        );
+    }
+
+    #[test]
+    fn chacha20_keystream_matches_rfc_8439() {
+        // Section 2.4.2, which starts at block 1: skip block 0 first
+        let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let mut cipher = StreamCipher::chacha20(rfc_key(), nonce, [0; 32]);
+        cipher.encrypt(&[0; 64]);
+        let plain = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+                      only one tip for the future, sunscreen would be it.";
+        assert_eq!(
+            hex(&cipher.encrypt(plain)),
+            "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b\
+             f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8\
+             07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736\
+             5af90bbf74a35be6b40b8eedf2785e42874d"
+        );
+    }
+
+    #[test]
+    fn each_side_sends_what_the_other_receives() {
+        for kind in [CipherKind::ChaCha20, CipherKind::Lcg] {
+            let server = CipherPair::derive(b"secret", kind, true);
+            let client = CipherPair::derive(b"secret", kind, false);
+            for (mut send, mut recv) in [(server.send, client.recv), (client.send, server.recv)] {
+                // Several messages, so the keystream carries across them
+                for message in [&b"hello"[..], &[7; 100], b"x"] {
+                    let encrypted = send.encrypt(message);
+                    assert_ne!(encrypted, message);
+                    assert_eq!(recv.decrypt(&encrypted), message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn directions_and_ciphers_get_different_keystreams() {
+        let server = CipherPair::derive(b"secret", CipherKind::ChaCha20, true);
+        let mut lcg = CipherPair::derive(b"secret", CipherKind::Lcg, true).send;
+        let (mut send, mut recv) = (server.send, server.recv);
+        let zeros = [0; 32];
+        let ours = send.encrypt(&zeros);
+        assert_ne!(ours, recv.decrypt(&zeros));
+        assert_ne!(ours, lcg.encrypt(&zeros));
+    }
+
+    #[test]
+    fn tags_compare_in_full() {
+        let tag = [9; TAG_LEN];
+        let mut other = tag;
+        assert!(tags_equal(&tag, &other));
+        other[TAG_LEN - 1] = 0;
+        assert!(!tags_equal(&tag, &other));
+    }
+
+    #[test]
+    fn os_random_draws_differ() {
+        let (mut a, mut b) = ([0; 32], [0; 32]);
+        os_random(&mut a).unwrap();
+        os_random(&mut b).unwrap();
+        assert_ne!(a, b);
+    }
+}
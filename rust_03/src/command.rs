@@ -0,0 +1,261 @@
+//! What a typed line asks for: a message to send, or one of the slash
+//! commands. Parsing is kept apart from acting on it, so every command
+//! can be tested without a connection.
+
+use std::path::PathBuf;
+
+/// What to do with one line of input.
+#[derive(Debug, PartialEq)]
+pub enum Action<'a> {
+    /// Send the text as a message.
+    Message(&'a str),
+    Quit,
+    /// Send the file at the path.
+    SendFile(PathBuf),
+    Stats,
+    Help,
+    /// Show this many of the latest messages again.
+    History(usize),
+    /// Write the history to the path.
+    Save(PathBuf),
+    /// A command that is not one of ours.
+    Unknown(String),
+    /// One of ours, used wrongly; says how.
+    Invalid(String),
+}
+
+/// The commands, as `/help` lists them.
+pub const COMMANDS: [(&str, &str); 7] = [
+    ("/send PATH", "Send a file; quote a PATH with spaces in it"),
+    (
+        "/history [N]",
+        "Show the last N messages again, 20 by default",
+    ),
+    ("/save PATH", "Write the messages kept to PATH"),
+    ("/stats", "Show the traffic so far and the keys in use"),
+    ("/help", "List these commands"),
+    ("/quit", "Leave the chat, as Ctrl+D does"),
+    ("//TEXT", "Send /TEXT, leading slash and all"),
+];
+
+/// How many messages `/history` shows when not told.
+pub const HISTORY_SHOWN: usize = 20;
+
+/// What `line` asks for. A line not starting with `/` is a message, and
+/// so is one starting with `//`, less the first slash.
+pub fn parse(line: &str) -> Action<'_> {
+    let Some(command) = line.strip_prefix('/') else {
+        return Action::Message(line);
+    };
+    if command.starts_with('/') {
+        return Action::Message(command);
+    }
+    let (name, rest) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    let args = match split_args(rest) {
+        Ok(args) => args,
+        Err(e) => return Action::Invalid(format!("/{}: {}", name, e)),
+    };
+    let no_args = |action| match args.len() {
+        0 => action,
+        _ => Action::Invalid(format!("/{} takes no arguments", name)),
+    };
+    match name {
+        "quit" => no_args(Action::Quit),
+        "stats" => no_args(Action::Stats),
+        "help" => no_args(Action::Help),
+        "send" => one_path(name, args).map_or_else(Action::Invalid, Action::SendFile),
+        "save" => one_path(name, args).map_or_else(Action::Invalid, Action::Save),
+        "history" => match args.as_slice() {
+            [] => Action::History(HISTORY_SHOWN),
+            [count] => match count.parse() {
+                Ok(count) if count > 0 => Action::History(count),
+                _ => Action::Invalid(format!(
+                    "/history takes a number of messages, got '{}'",
+                    count
+                )),
+            },
+            _ => Action::Invalid("usage: /history [N]".to_string()),
+        },
+        _ => Action::Unknown(format!("/{}", name)),
+    }
+}
+
+/// The single PATH argument of `/send` or `/save`.
+fn one_path(name: &str, args: Vec<String>) -> Result<PathBuf, String> {
+    match <[String; 1]>::try_from(args) {
+        Ok([path]) => Ok(PathBuf::from(path)),
+        Err(args) if args.is_empty() => Err(format!("usage: /{} PATH", name)),
+        Err(_) => Err(format!(
+            "/{} takes one PATH; put quotes around a path with spaces",
+            name
+        )),
+    }
+}
+
+/// Split a command's arguments at whitespace. Double quotes keep spaces
+/// in an argument, with `\"` and `\\` inside them for a quote and a
+/// backslash; single quotes keep everything up to the next one as is.
+pub fn split_args(text: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(args);
+        }
+        let mut arg = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            match c {
+                '"' => loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if chars.peek().is_some_and(|&c| c == '"' || c == '\\') => {
+                            arg.push(chars.next().unwrap())
+                        }
+                        Some(c) => arg.push(c),
+                        None => return Err("missing closing \"".to_string()),
+                    }
+                },
+                '\'' => loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err("missing closing '".to_string()),
+                    }
+                },
+                c => arg.push(c),
+            }
+        }
+        args.push(arg);
+    }
+}
+
+/// The `/help` text, one `[CHAT]` line per command.
+pub fn help_lines() -> Vec<String> {
+    let width = COMMANDS
+        .iter()
+        .map(|(usage, _)| usage.len())
+        .max()
+        .unwrap_or(0);
+    COMMANDS
+        .iter()
+        .map(|(usage, about)| format!("[CHAT]   {:width$}  {}", usage, about, width = width))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_lines_are_messages() {
+        assert_eq!(parse("hello"), Action::Message("hello"));
+        assert_eq!(parse("a/b /c"), Action::Message("a/b /c"));
+        assert_eq!(parse(" /quit"), Action::Message(" /quit"));
+        assert_eq!(parse("//quit"), Action::Message("/quit"));
+        assert_eq!(parse("// two"), Action::Message("/ two"));
+    }
+
+    #[test]
+    fn commands_without_arguments() {
+        assert_eq!(parse("/quit"), Action::Quit);
+        assert_eq!(parse("/stats"), Action::Stats);
+        assert_eq!(parse("/help"), Action::Help);
+        assert_eq!(parse("/help  "), Action::Help);
+        assert_eq!(
+            parse("/quit now"),
+            Action::Invalid("/quit takes no arguments".to_string())
+        );
+    }
+
+    #[test]
+    fn send_takes_one_path() {
+        assert_eq!(
+            parse("/send notes.txt"),
+            Action::SendFile(PathBuf::from("notes.txt"))
+        );
+        assert_eq!(
+            parse("/send   \"file with spaces.txt\"  "),
+            Action::SendFile(PathBuf::from("file with spaces.txt"))
+        );
+        assert_eq!(
+            parse("/send 'it''s here'"),
+            Action::SendFile(PathBuf::from("its here"))
+        );
+        assert_eq!(
+            parse("/send"),
+            Action::Invalid("usage: /send PATH".to_string())
+        );
+        assert!(matches!(parse("/send two words"), Action::Invalid(e) if e.contains("quotes")));
+        assert_eq!(
+            parse("/send \"open"),
+            Action::Invalid("/send: missing closing \"".to_string())
+        );
+    }
+
+    #[test]
+    fn history_and_save() {
+        assert_eq!(parse("/history"), Action::History(HISTORY_SHOWN));
+        assert_eq!(parse("/history 5"), Action::History(5));
+        assert_eq!(
+            parse("/history 0"),
+            Action::Invalid("/history takes a number of messages, got '0'".to_string())
+        );
+        assert!(matches!(parse("/history lots"), Action::Invalid(_)));
+        assert_eq!(
+            parse("/history 1 2"),
+            Action::Invalid("usage: /history [N]".to_string())
+        );
+        assert_eq!(
+            parse("/save \"chat log.txt\""),
+            Action::Save(PathBuf::from("chat log.txt"))
+        );
+        assert_eq!(
+            parse("/save"),
+            Action::Invalid("usage: /save PATH".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_commands_are_named() {
+        assert_eq!(parse("/dance"), Action::Unknown("/dance".to_string()));
+        assert_eq!(parse("/QUIT"), Action::Unknown("/QUIT".to_string()));
+        assert_eq!(parse("/"), Action::Unknown("/".to_string()));
+    }
+
+    #[test]
+    fn quoting_rules() {
+        assert_eq!(split_args("").unwrap(), Vec::<String>::new());
+        assert_eq!(split_args("  a  b ").unwrap(), ["a", "b"]);
+        assert_eq!(
+            split_args(r#"pre"mid dle"post x"#).unwrap(),
+            ["premid dlepost", "x"]
+        );
+        assert_eq!(
+            split_args(r#""say \"hi\" \\ \n""#).unwrap(),
+            [r#"say "hi" \ \n"#]
+        );
+        assert_eq!(
+            split_args(r#"'no \"escapes\"'"#).unwrap(),
+            [r#"no \"escapes\""#]
+        );
+        assert_eq!(split_args(r#""""#).unwrap(), [""]);
+        assert!(split_args("'open").is_err());
+    }
+
+    #[test]
+    fn help_lists_every_command_aligned() {
+        let lines = help_lines();
+        assert_eq!(lines.len(), COMMANDS.len());
+        assert_eq!(
+            lines[0],
+            "[CHAT]   /send PATH    Send a file; quote a PATH with spaces in it"
+        );
+        assert_eq!(
+            lines[3],
+            "[CHAT]   /stats        Show the traffic so far and the keys in use"
+        );
+    }
+}
@@ -0,0 +1,405 @@
+//! The chat over UDP, where datagrams may be lost, repeated or reordered.
+//! Each frame is one datagram, sealed under a nonce made from its
+//! sequence number rather than at a point in a running keystream, so it
+//! opens whatever came before it. A `Window` over the sequence numbers
+//! says which datagrams came late, twice, or not at all.
+
+use std::io::{self, Read, Write};
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use crate::Transport;
+use crate::cipher::{TAG_LEN, chacha20_xor, hmac_sha256, sha256, tags_equal};
+
+/// Datagram format version, the first byte of every datagram.
+pub const DATAGRAM_VERSION: u8 = 0x80 | 0x40 | 1;
+
+/// Length of a datagram's header: version, frame type and 8-byte
+/// sequence number. It travels in the clear, since the receiver needs the
+/// sequence number to find the nonce, but the tag covers it.
+pub const DATAGRAM_HEADER: usize = 10;
+
+/// Bytes a datagram adds to its payload: the header and the tag.
+pub const DATAGRAM_OVERHEAD: usize = DATAGRAM_HEADER + TAG_LEN;
+
+/// Largest datagram sent or read: the most an IPv4 UDP datagram holds.
+pub const MAX_DATAGRAM: usize = 65_507;
+
+// Direction labels for the keys, as for the stream cipher's
+const CLIENT_TO_SERVER: &[u8] = b"c2s";
+const SERVER_TO_CLIENT: &[u8] = b"s2c";
+
+/// One direction's keys for datagrams: a ChaCha20 key, used with a
+/// fresh nonce per sequence number, and a key for the tags.
+pub struct DatagramCipher {
+    key: [u8; 32],
+    mac_key: [u8; 32],
+}
+
+/// A datagram once opened.
+#[derive(Debug, PartialEq)]
+pub struct Opened {
+    pub kind: u8,
+    pub seq: u64,
+    pub payload: Vec<u8>,
+}
+
+impl DatagramCipher {
+    /// Our sending and receiving keys from the DH secret. The labels
+    /// differ from the stream cipher's, so the two never share a key.
+    pub fn pair(shared_secret: &[u8], is_server: bool) -> (Self, Self) {
+        let (send, recv) = if is_server {
+            (SERVER_TO_CLIENT, CLIENT_TO_SERVER)
+        } else {
+            (CLIENT_TO_SERVER, SERVER_TO_CLIENT)
+        };
+        let keys = |direction: &[u8]| Self {
+            key: sha256(&[b"chat udp key ", direction, b" ", shared_secret].concat()),
+            mac_key: hmac_sha256(shared_secret, &[b"chat udp mac ", direction].concat()),
+        };
+        (keys(send), keys(recv))
+    }
+
+    /// The nonce for datagram `seq`. Each direction has its own key, so
+    /// the sequence number alone keeps nonces from repeating.
+    fn nonce(seq: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&seq.to_be_bytes());
+        nonce
+    }
+
+    /// Datagram `seq` carrying `plain` as a `kind` frame: the header, the
+    /// encrypted payload, then a tag over both.
+    pub fn seal(&self, kind: u8, seq: u64, plain: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(DATAGRAM_OVERHEAD + plain.len());
+        datagram.extend_from_slice(&[DATAGRAM_VERSION, kind]);
+        datagram.extend_from_slice(&seq.to_be_bytes());
+        datagram.extend_from_slice(&chacha20_xor(&self.key, &Self::nonce(seq), plain));
+        let tag = hmac_sha256(&self.mac_key, &datagram);
+        datagram.extend_from_slice(&tag);
+        datagram
+    }
+
+    /// Check a datagram's tag and decrypt it, or `None` if it is too
+    /// short, another format, or fails authentication.
+    pub fn open(&self, datagram: &[u8]) -> Option<Opened> {
+        let (sealed, tag) = datagram.split_last_chunk::<TAG_LEN>()?;
+        let (header, encrypted) = sealed.split_first_chunk::<DATAGRAM_HEADER>()?;
+        if header[0] != DATAGRAM_VERSION || !tags_equal(&hmac_sha256(&self.mac_key, sealed), tag) {
+            return None;
+        }
+        let seq = u64::from_be_bytes(header[2..].try_into().unwrap());
+        Some(Opened {
+            kind: header[1],
+            seq,
+            payload: chacha20_xor(&self.key, &Self::nonce(seq), encrypted),
+        })
+    }
+}
+
+/// How far behind the newest datagram a `Window` still tells a late one
+/// from a repeat.
+pub const WINDOW: u64 = 64;
+
+/// How a datagram's sequence number fits with those seen before it.
+#[derive(Debug, PartialEq)]
+pub enum Arrival {
+    /// The next one due.
+    InOrder,
+    /// Past the next one due, skipping this many, which may yet come.
+    Ahead(u64),
+    /// One skipped earlier, turning up now.
+    Late,
+    /// One seen already.
+    Repeat,
+    /// Too far behind the newest to tell; dropped.
+    TooOld,
+}
+
+/// The sequence numbers seen lately: the newest, and a bit for each of
+/// the `WINDOW` below it.
+pub struct Window {
+    /// One past the newest sequence number seen.
+    next: u64,
+    /// Bit `i` is set once `next - 1 - i` has arrived.
+    seen: u64,
+    /// Sequence numbers left behind the window without arriving.
+    lost: u64,
+    late: u64,
+    repeats: u64,
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Window {
+    pub fn new() -> Self {
+        Self {
+            next: 0,
+            seen: 0,
+            lost: 0,
+            late: 0,
+            repeats: 0,
+        }
+    }
+
+    /// Note datagram `seq`, which has passed authentication, and say how
+    /// it fits. Only `InOrder`, `Ahead` and `Late` ones should be acted
+    /// on.
+    pub fn arrive(&mut self, seq: u64) -> Arrival {
+        if seq >= self.next {
+            let shift = seq - self.next + 1;
+            let tracked = self.next.min(WINDOW);
+            if shift >= WINDOW {
+                // Everything tracked leaves the window, and so do the
+                // skipped numbers that never fit in it
+                self.lost += tracked - self.seen.count_ones() as u64;
+                self.lost += shift - WINDOW;
+                self.seen = 1;
+            } else {
+                let kept = WINDOW - shift;
+                let leaving = tracked.saturating_sub(kept);
+                let arrived = (self.seen >> kept).count_ones() as u64;
+                self.lost += leaving - arrived;
+                self.seen = (self.seen << shift) | 1;
+            }
+            self.next = seq + 1;
+            return match shift - 1 {
+                0 => Arrival::InOrder,
+                skipped => Arrival::Ahead(skipped),
+            };
+        }
+        let age = self.next - 1 - seq;
+        if age >= WINDOW {
+            return Arrival::TooOld;
+        }
+        let bit = 1 << age;
+        if self.seen & bit != 0 {
+            self.repeats += 1;
+            return Arrival::Repeat;
+        }
+        self.seen |= bit;
+        self.late += 1;
+        Arrival::Late
+    }
+
+    /// Datagrams that arrived late so far.
+    pub fn late(&self) -> u64 {
+        self.late
+    }
+
+    /// Datagrams that arrived again so far.
+    pub fn repeats(&self) -> u64 {
+        self.repeats
+    }
+
+    /// Sequence numbers below the newest that have not arrived, counting
+    /// those dropped as too old.
+    pub fn missing(&self) -> u64 {
+        let tracked = self.next.min(WINDOW);
+        self.lost + tracked - self.seen.count_ones() as u64
+    }
+}
+
+/// A UDP socket connected to the peer, read and written as a stream so
+/// the handshake can run over it: a flush sends what was written since
+/// the last one as one datagram, and reads take from one datagram until
+/// it is used up. That relies on the handshake's few datagrams arriving,
+/// in order; the chat after it does not.
+pub struct UdpLink {
+    socket: UdpSocket,
+    /// The datagram being read, and how much of it has been.
+    incoming: Vec<u8>,
+    read: usize,
+    outgoing: Vec<u8>,
+}
+
+impl UdpLink {
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            incoming: Vec::new(),
+            read: 0,
+            outgoing: Vec::new(),
+        }
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// The socket back, for the chat. Anything written and not flushed
+    /// is dropped.
+    pub fn into_socket(self) -> UdpSocket {
+        self.socket
+    }
+}
+
+impl Read for UdpLink {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // An empty datagram would read as the end of the stream
+        while self.read == self.incoming.len() {
+            self.incoming.resize(MAX_DATAGRAM, 0);
+            let len = self.socket.recv(&mut self.incoming)?;
+            self.incoming.truncate(len);
+            self.read = 0;
+        }
+        let len = buf.len().min(self.incoming.len() - self.read);
+        buf[..len].copy_from_slice(&self.incoming[self.read..self.read + len]);
+        self.read += len;
+        Ok(len)
+    }
+}
+
+impl Write for UdpLink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.outgoing.is_empty() {
+            self.socket.send(&self.outgoing)?;
+            self.outgoing.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Transport for UdpLink {
+    fn read_timeout(&self) -> Option<Duration> {
+        self.socket.read_timeout().ok().flatten()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// There is no connection to close: the peer finds out when its
+    /// reads time out.
+    fn hang_up(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{FRAME_HELLO, FRAME_TEXT};
+
+    fn keys() -> (DatagramCipher, DatagramCipher) {
+        let (server_send, _) = DatagramCipher::pair(b"secret", true);
+        let (_, client_recv) = DatagramCipher::pair(b"secret", false);
+        (server_send, client_recv)
+    }
+
+    #[test]
+    fn datagrams_open_in_any_order() {
+        let (send, recv) = keys();
+        let datagrams: Vec<Vec<u8>> = (0..5u64)
+            .map(|seq| send.seal(FRAME_TEXT, seq, format!("message {seq}").as_bytes()))
+            .collect();
+        for seq in [3, 0, 4, 1, 2, 3] {
+            let opened = recv.open(&datagrams[seq]).unwrap();
+            assert_eq!(opened.seq, seq as u64);
+            assert_eq!(opened.kind, FRAME_TEXT);
+            assert_eq!(opened.payload, format!("message {seq}").as_bytes());
+        }
+    }
+
+    #[test]
+    fn each_sequence_number_gets_its_own_keystream() {
+        let (send, _) = keys();
+        let (a, b) = (
+            send.seal(FRAME_TEXT, 1, b"same"),
+            send.seal(FRAME_TEXT, 2, b"same"),
+        );
+        assert_ne!(a[DATAGRAM_HEADER..], b[DATAGRAM_HEADER..]);
+        assert_ne!(&a[DATAGRAM_HEADER..DATAGRAM_HEADER + 4], b"same");
+    }
+
+    #[test]
+    fn tampered_or_misdirected_datagrams_fail() {
+        let (send, recv) = keys();
+        let datagram = send.seal(FRAME_HELLO, 7, b"alice");
+        // Every byte is covered, the clear header included
+        for i in 0..datagram.len() {
+            let mut bad = datagram.clone();
+            bad[i] ^= 1;
+            assert_eq!(recv.open(&bad), None, "byte {i}");
+        }
+        assert_eq!(recv.open(&datagram[..DATAGRAM_OVERHEAD - 1]), None);
+        // Our own keys are not the peer's, so a reflected datagram fails
+        let (_, our_recv) = DatagramCipher::pair(b"secret", true);
+        assert_eq!(our_recv.open(&datagram), None);
+        // Another secret fails too
+        let (_, other) = DatagramCipher::pair(b"other", false);
+        assert_eq!(other.open(&datagram), None);
+    }
+
+    #[test]
+    fn the_window_sorts_arrivals() {
+        let mut window = Window::new();
+        let arrivals: Vec<Arrival> = [0, 1, 3, 2, 2, 6, 4, 1]
+            .into_iter()
+            .map(|seq| window.arrive(seq))
+            .collect();
+        assert_eq!(
+            arrivals,
+            [
+                Arrival::InOrder,
+                Arrival::InOrder,
+                Arrival::Ahead(1),
+                Arrival::Late,
+                Arrival::Repeat,
+                Arrival::Ahead(2),
+                Arrival::Late,
+                Arrival::Repeat,
+            ]
+        );
+        // 5 is still to come
+        assert_eq!(window.missing(), 1);
+        assert_eq!((window.late(), window.repeats()), (2, 2));
+    }
+
+    #[test]
+    fn the_window_forgets_what_falls_behind() {
+        let mut window = Window::new();
+        window.arrive(0);
+        window.arrive(2);
+        assert_eq!(window.arrive(WINDOW + 1), Arrival::Ahead(WINDOW - 2));
+        // 1 has left the window, so it can no longer be told from a repeat
+        assert_eq!(window.arrive(1), Arrival::TooOld);
+        assert_eq!(window.arrive(3), Arrival::Late);
+        assert_eq!(window.missing(), WINDOW - 2);
+
+        // A jump past the whole window
+        let mut window = Window::new();
+        window.arrive(0);
+        assert_eq!(window.arrive(1000), Arrival::Ahead(999));
+        assert_eq!(window.missing(), 999);
+        assert_eq!(window.arrive(999), Arrival::Late);
+        assert_eq!(window.missing(), 998);
+    }
+
+    #[test]
+    fn the_handshake_link_reads_across_datagrams() {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        let (mut a, mut b) = (UdpLink::new(a), UdpLink::new(b));
+        a.write_all(&[0, 5]).unwrap();
+        a.write_all(b"hello").unwrap();
+        a.flush().unwrap();
+        a.write_all(b"again").unwrap();
+        a.flush().unwrap();
+        let mut read = [0u8; 9];
+        b.read_exact(&mut read).unwrap();
+        assert_eq!(&read, b"\0\x05helloag");
+        let mut rest = [0u8; 3];
+        b.read_exact(&mut rest).unwrap();
+        assert_eq!(&rest, b"ain");
+    }
+}
@@ -0,0 +1,1123 @@
+//! Diffie-Hellman key agreement: the groups, private and public keys,
+//! and the handshake that trades public keys, confirms the secret and
+//! checks any pre-shared key.
+
+use std::fs;
+use std::io::{self, Read};
+
+use crate::cipher::{hmac_sha256, os_random, sha256, tags_equal};
+use crate::{Level, Transport, log, say, waiting_for};
+
+// Hardcoded Diffie-Hellman parameters
+const P: u64 = 0xD87FA3E291B4C613; // 64-bit safe prime, (p-1)/2 is prime too
+const G: u64 = 2; // Generator
+
+/// A Diffie-Hellman group: prime modulus `p` and generator `g`.
+#[derive(Clone, Copy)]
+pub struct DhParams {
+    pub p: u64,
+    pub g: u64,
+}
+
+impl DhParams {
+    pub const DEFAULT: DhParams = DhParams { p: P, g: G };
+
+    /// Read p and g from `path`: two hex numbers, `0x` optional, separated
+    /// by whitespace. Lines starting with `#` are comments.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("cannot read '{}': {}", path, e))?;
+        let values = text
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .flat_map(str::split_whitespace)
+            .map(|word| {
+                let digits = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X"));
+                u64::from_str_radix(digits.unwrap_or(word), 16)
+                    .map_err(|_| format!("'{}' in {} is not a 64-bit hex number", word, path))
+            })
+            .collect::<Result<Vec<u64>, String>>()?;
+        match values[..] {
+            [p, g] => Ok(Self { p, g }),
+            _ => Err(format!(
+                "{} must hold exactly two hex values, p and g; found {}",
+                path,
+                values.len()
+            )),
+        }
+    }
+
+    /// Check that p is prime and g lies strictly between 1 and p-1.
+    pub fn validate(&self) -> Result<(), String> {
+        if !is_prime(self.p) {
+            return Err(format!("p = {:X} is not prime", self.p));
+        }
+        if self.g <= 1 || self.g >= self.p - 1 {
+            return Err(format!("g = {:X} must satisfy 1 < g < p-1", self.g));
+        }
+        Ok(())
+    }
+
+    /// Reject a peer's public key that is out of range or one of the
+    /// degenerate values 0, 1 and p-1, which would make the secret
+    /// guessable.
+    fn check_public(&self, key: u64) -> io::Result<()> {
+        if key <= 1 || key >= self.p - 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("peer sent a degenerate public key {:X}", key),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Miller-Rabin primality test. With these witnesses it is exact for
+/// every 64-bit number.
+fn is_prime(n: u64) -> bool {
+    const WITNESSES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+    if n < 2 {
+        return false;
+    }
+    for &w in &WITNESSES {
+        if n.is_multiple_of(w) {
+            return n == w;
+        }
+    }
+    // n - 1 = d * 2^r with d odd
+    let r = (n - 1).trailing_zeros();
+    let d = (n - 1) >> r;
+    'witness: for &a in &WITNESSES {
+        let mut x = modular_pow(a, d, n);
+        if x == 1 || x == n - 1 {
+            continue;
+        }
+        for _ in 1..r {
+            x = ((x as u128 * x as u128) % n as u128) as u64;
+            if x == n - 1 {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+/// Number of 64-bit limbs in a `U2048`.
+const LIMBS: usize = 32;
+
+/// A 2048-bit unsigned integer, least significant limb first. Just enough
+/// arithmetic for Diffie-Hellman over the MODP group.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct U2048([u64; LIMBS]);
+
+impl U2048 {
+    const ZERO: U2048 = U2048([0; LIMBS]);
+
+    /// Build from limbs written most significant first, the way numbers
+    /// are printed in the RFCs.
+    const fn from_be_limbs(limbs: [u64; LIMBS]) -> Self {
+        let mut out = [0u64; LIMBS];
+        let mut i = 0;
+        while i < LIMBS {
+            out[i] = limbs[LIMBS - 1 - i];
+            i += 1;
+        }
+        U2048(out)
+    }
+
+    pub fn from_u64(n: u64) -> Self {
+        let mut out = Self::ZERO;
+        out.0[0] = n;
+        out
+    }
+
+    /// Parse a big-endian byte string of at most 256 bytes.
+    pub fn from_be_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() > LIMBS * 8 {
+            return None;
+        }
+        let mut out = Self::ZERO;
+        for (i, &byte) in bytes.iter().rev().enumerate() {
+            out.0[i / 8] |= (byte as u64) << (8 * (i % 8));
+        }
+        Some(out)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; LIMBS * 8] {
+        let mut out = [0u8; LIMBS * 8];
+        for (chunk, limb) in out.chunks_mut(8).zip(self.0.iter().rev()) {
+            chunk.copy_from_slice(&limb.to_be_bytes());
+        }
+        out
+    }
+
+    fn overflowing_add(&self, other: &Self) -> (Self, bool) {
+        let mut out = Self::ZERO;
+        let mut carry = false;
+        for i in 0..LIMBS {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            out.0[i] = sum;
+            carry = c1 || c2;
+        }
+        (out, carry)
+    }
+
+    fn overflowing_sub(&self, other: &Self) -> (Self, bool) {
+        let mut out = Self::ZERO;
+        let mut borrow = false;
+        for i in 0..LIMBS {
+            let (diff, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            out.0[i] = diff;
+            borrow = b1 || b2;
+        }
+        (out, borrow)
+    }
+
+    /// Number of significant bits.
+    fn bits(&self) -> usize {
+        match self.0.iter().rposition(|&limb| limb != 0) {
+            Some(i) => i * 64 + 64 - self.0[i].leading_zeros() as usize,
+            None => 0,
+        }
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        self.0[i / 64] >> (i % 64) & 1 == 1
+    }
+
+    /// The full 4096-bit product.
+    fn mul_wide(&self, other: &Self) -> [u64; 2 * LIMBS] {
+        let mut out = [0u64; 2 * LIMBS];
+        for i in 0..LIMBS {
+            let mut carry = 0u64;
+            for j in 0..LIMBS {
+                let t = self.0[i] as u128 * other.0[j] as u128 + out[i + j] as u128 + carry as u128;
+                out[i + j] = t as u64;
+                carry = (t >> 64) as u64;
+            }
+            out[i + LIMBS] = carry;
+        }
+        out
+    }
+
+    /// `wide mod m` by shift-and-subtract long division, one bit at a
+    /// time. Slow, but only used outside the exponentiation loop.
+    fn rem_wide(wide: &[u64], m: &Self) -> Self {
+        assert!(*m != Self::ZERO, "division by zero");
+        let mut r = Self::ZERO;
+        for i in (0..wide.len() * 64).rev() {
+            // r < m, so r * 2 + bit fits in 2049 bits, the top one
+            // coming back as `carry`
+            let (doubled, carry) = r.overflowing_add(&r);
+            r = doubled;
+            r.0[0] |= wide[i / 64] >> (i % 64) & 1;
+            if carry || r >= *m {
+                r = r.overflowing_sub(m).0;
+            }
+        }
+        r
+    }
+
+    fn rem(&self, m: &Self) -> Self {
+        Self::rem_wide(&self.0, m)
+    }
+
+    fn mul_mod(&self, other: &Self, m: &Self) -> Self {
+        Self::rem_wide(&self.mul_wide(other), m)
+    }
+
+    /// `self^exp mod m` by square-and-multiply in Montgomery form. `m`
+    /// must be odd, which every prime modulus but 2 is.
+    fn pow_mod(&self, exp: &Self, m: &Self) -> Self {
+        assert!(m.0[0] & 1 == 1, "Montgomery reduction needs an odd modulus");
+        let mont = Montgomery::new(m);
+        let base = mont.to_form(&self.rem(m));
+        let mut x = mont.one;
+        for i in (0..exp.bits()).rev() {
+            x = mont.mul(&x, &x);
+            if exp.bit(i) {
+                x = mont.mul(&x, &base);
+            }
+        }
+        mont.mul(&x, &Self::from_u64(1))
+    }
+}
+
+impl Ord for U2048 {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U2048 {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Montgomery multiplication modulo an odd `m`, with R = 2^2048: numbers
+/// are kept as `x * R mod m`, which turns each reduction into shifts
+/// instead of a division.
+struct Montgomery {
+    m: U2048,
+    /// `-m^-1 mod 2^64`.
+    m_inv: u64,
+    /// `R^2 mod m`, for converting into Montgomery form.
+    r2: U2048,
+    /// 1 in Montgomery form, `R mod m`.
+    one: U2048,
+}
+
+impl Montgomery {
+    fn new(m: &U2048) -> Self {
+        // Newton's iteration doubles the correct low bits each round:
+        // 1, 2, 4, ... 64
+        let mut inv = 1u64;
+        for _ in 0..6 {
+            inv = inv.wrapping_mul(2u64.wrapping_sub(m.0[0].wrapping_mul(inv)));
+        }
+        let mut r = [0u64; LIMBS + 1];
+        r[LIMBS] = 1;
+        let one = U2048::rem_wide(&r, m);
+        Montgomery {
+            m: *m,
+            m_inv: inv.wrapping_neg(),
+            r2: one.mul_mod(&one, m),
+            one,
+        }
+    }
+
+    fn to_form(&self, x: &U2048) -> U2048 {
+        self.mul(x, &self.r2)
+    }
+
+    /// `a * b / R mod m`, interleaving the multiplication and the
+    /// reduction limb by limb (CIOS).
+    fn mul(&self, a: &U2048, b: &U2048) -> U2048 {
+        let m = &self.m.0;
+        let mut t = [0u64; LIMBS + 2];
+        for &b_i in &b.0 {
+            let mut carry = 0u64;
+            for (t_j, &a_j) in t.iter_mut().zip(&a.0) {
+                let s = *t_j as u128 + a_j as u128 * b_i as u128 + carry as u128;
+                *t_j = s as u64;
+                carry = (s >> 64) as u64;
+            }
+            let s = t[LIMBS] as u128 + carry as u128;
+            t[LIMBS] = s as u64;
+            t[LIMBS + 1] = (s >> 64) as u64;
+
+            // Add u * m so the lowest limb becomes zero, then drop it
+            let u = t[0].wrapping_mul(self.m_inv);
+            let s = t[0] as u128 + u as u128 * m[0] as u128;
+            let mut carry = (s >> 64) as u64;
+            for j in 1..LIMBS {
+                let s = t[j] as u128 + u as u128 * m[j] as u128 + carry as u128;
+                t[j - 1] = s as u64;
+                carry = (s >> 64) as u64;
+            }
+            let s = t[LIMBS] as u128 + carry as u128;
+            t[LIMBS - 1] = s as u64;
+            t[LIMBS] = t[LIMBS + 1] + (s >> 64) as u64;
+        }
+        let mut out = U2048::ZERO;
+        out.0.copy_from_slice(&t[..LIMBS]);
+        if t[LIMBS] != 0 || out >= self.m {
+            out = out.overflowing_sub(&self.m).0;
+        }
+        out
+    }
+}
+
+/// The 2048-bit MODP prime from RFC 3526, section 3 (group 14):
+/// p = 2^2048 - 2^1984 - 1 + 2^64 * ( [2^1918 pi] + 124476 ), generator 2.
+#[rustfmt::skip]
+const MODP_2048_P: U2048 = U2048::from_be_limbs([
+    0xFFFFFFFFFFFFFFFF, 0xC90FDAA22168C234, 0xC4C6628B80DC1CD1, 0x29024E088A67CC74,
+    0x020BBEA63B139B22, 0x514A08798E3404DD, 0xEF9519B3CD3A431B, 0x302B0A6DF25F1437,
+    0x4FE1356D6D51C245, 0xE485B576625E7EC6, 0xF44C42E9A637ED6B, 0x0BFF5CB6F406B7ED,
+    0xEE386BFB5A899FA5, 0xAE9F24117C4B1FE6, 0x49286651ECE45B3D, 0xC2007CB8A163BF05,
+    0x98DA48361C55D39A, 0x69163FA8FD24CF5F, 0x83655D23DCA3AD96, 0x1C62F356208552BB,
+    0x9ED529077096966D, 0x670C354E4ABC9804, 0xF1746C08CA18217C, 0x32905E462E36CE3B,
+    0xE39E772C180E8603, 0x9B2783A2EC07A28F, 0xB5C55DF06F4C52C9, 0xDE2BCBF695581718,
+    0x3995497CEA956AE5, 0x15D2261898FA0510, 0x15728E5A8AACAA68, 0xFFFFFFFFFFFFFFFF,
+]);
+const MODP_2048_G: u64 = 2;
+
+/// Bytes of private exponent drawn for the MODP group. 256 bits is
+/// twice the group's ~112-bit strength, the usual choice.
+pub const MODP_PRIVATE_LEN: usize = 32;
+
+/// The Diffie-Hellman group a session runs over.
+#[derive(Clone, Copy)]
+pub enum DhGroup {
+    /// The 64-bit classroom group, or one read with `--dh-params`.
+    /// Small enough to follow by hand, and to break.
+    Small(DhParams),
+    /// RFC 3526 group 14.
+    Modp2048,
+}
+
+impl DhGroup {
+    /// The `--dh` name of the group.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DhGroup::Small(_) => "small",
+            DhGroup::Modp2048 => "modp2048",
+        }
+    }
+
+    /// Length of a public key on the wire.
+    fn public_len(&self) -> usize {
+        match self {
+            DhGroup::Small(_) => 8,
+            DhGroup::Modp2048 => LIMBS * 8,
+        }
+    }
+
+    /// A fresh private key: from the OS generator, or, for reproducible
+    /// test runs only, derived from `seed`.
+    pub fn private_key(&self, seed: Option<u64>) -> io::Result<U2048> {
+        match self {
+            DhGroup::Small(dh) => Ok(U2048::from_u64(generate_private_key(dh.p, seed)?)),
+            DhGroup::Modp2048 => {
+                let mut bytes = [0u8; MODP_PRIVATE_LEN];
+                match seed {
+                    Some(seed) => {
+                        bytes = sha256(&[b"chat dh seed ", &seed.to_be_bytes()[..]].concat())
+                    }
+                    None => os_random(&mut bytes)?,
+                }
+                Ok(U2048::from_be_bytes(&bytes).expect("32 bytes fit"))
+            }
+        }
+    }
+
+    /// `g^private mod p`, big-endian and `public_len()` bytes long.
+    pub fn public_key(&self, private: &U2048) -> Vec<u8> {
+        match self {
+            DhGroup::Small(dh) => modular_pow(dh.g, private.0[0], dh.p).to_be_bytes().to_vec(),
+            DhGroup::Modp2048 => U2048::from_u64(MODP_2048_G)
+                .pow_mod(private, &MODP_2048_P)
+                .to_be_bytes()
+                .to_vec(),
+        }
+    }
+
+    /// `their_public^private mod p`, after checking the peer's key.
+    pub fn shared_secret(&self, their_public: &[u8], private: &U2048) -> io::Result<Vec<u8>> {
+        if their_public.len() != self.public_len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "peer's public key is {} bytes, expected {}; do both sides use the same --dh?",
+                    their_public.len(),
+                    self.public_len()
+                ),
+            ));
+        }
+        match self {
+            DhGroup::Small(dh) => {
+                let key = u64::from_be_bytes(their_public.try_into().expect("length checked"));
+                dh.check_public(key)?;
+                Ok(modular_pow(key, private.0[0], dh.p).to_be_bytes().to_vec())
+            }
+            DhGroup::Modp2048 => {
+                let key = U2048::from_be_bytes(their_public).expect("length checked");
+                let (p_minus_1, _) = MODP_2048_P.overflowing_sub(&U2048::from_u64(1));
+                if key <= U2048::from_u64(1) || key >= p_minus_1 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "peer sent a degenerate public key {}",
+                            short_hex(their_public)
+                        ),
+                    ));
+                }
+                Ok(key.pow_mod(private, &MODP_2048_P).to_be_bytes().to_vec())
+            }
+        }
+    }
+
+    /// A key or secret for the verbose output: in full for the small
+    /// group, shortened for the 2048-bit one.
+    fn show(&self, bytes: &[u8]) -> String {
+        match self {
+            DhGroup::Small(_) => {
+                let mut value = [0u8; 8];
+                value[8 - bytes.len().min(8)..]
+                    .copy_from_slice(&bytes[bytes.len().saturating_sub(8)..]);
+                format!("{:X}", u64::from_be_bytes(value))
+            }
+            DhGroup::Modp2048 => short_hex(bytes),
+        }
+    }
+}
+
+/// The first and last few bytes of a long number in hex.
+fn short_hex(bytes: &[u8]) -> String {
+    let hex = |b: &[u8]| b.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+    if bytes.len() <= 16 {
+        return hex(bytes);
+    }
+    format!(
+        "{}...{} ({} bytes)",
+        hex(&bytes[..8]),
+        hex(&bytes[bytes.len() - 4..]),
+        bytes.len()
+    )
+}
+
+fn modular_pow(mut base: u64, mut exp: u64, modulus: u64) -> u64 {
+    if modulus == 1 {
+        return 0;
+    }
+    let mut result = 1u128;
+    base %= modulus;
+    let mut base_128 = base as u128;
+    let modulus_128 = modulus as u128;
+
+    while exp > 0 {
+        if exp % 2 == 1 {
+            result = (result * base_128) % modulus_128;
+        }
+        exp >>= 1;
+        if exp > 0 {
+            base_128 = (base_128 * base_128) % modulus_128;
+        }
+    }
+    result as u64
+}
+
+/// A DH private key in `2..p-1`: from the OS generator, or, for
+/// reproducible test runs only, mixed from `seed`.
+fn generate_private_key(p: u64, seed: Option<u64>) -> io::Result<u64> {
+    if let Some(seed) = seed {
+        let mut x = seed | 1; // ensure non-zero odd
+        for _ in 0..5 {
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+        }
+        return Ok(2 + (x % (p - 3)));
+    }
+    // Draw again rather than reduce modulo p, which would favour small keys
+    loop {
+        let mut buf = [0u8; 8];
+        os_random(&mut buf)?;
+        let x = u64::from_be_bytes(buf);
+        if (2..p - 1).contains(&x) {
+            return Ok(x);
+        }
+    }
+}
+
+/// What the opening key exchange leaves both sides with.
+pub struct Exchange {
+    pub secret: Vec<u8>,
+    /// Hash of both public keys, the same on both sides.
+    pub fingerprint: [u8; 32],
+    /// Hash of the peer's public key, for `--known-peers`.
+    pub peer_key: [u8; 32],
+}
+
+/// Agree on a secret with the peer at the other end of `stream`, using a
+/// fresh private key, or `long_term` if one was loaded from `--key-file`.
+pub fn perform_dh_exchange(
+    stream: &mut impl Transport,
+    is_server: bool,
+    group: &DhGroup,
+    seed: Option<u64>,
+    long_term: Option<U2048>,
+) -> io::Result<Exchange> {
+    log!(Level::Summary, "[DH] Starting key exchange...");
+    log!(Level::Detail, "[DH] Using DH parameters:");
+    match group {
+        DhGroup::Small(dh) => {
+            log!(Level::Detail, "p = {:X} (64-bit prime - public)", dh.p);
+            log!(Level::Detail, "g = {} (generator - public)", dh.g);
+        }
+        DhGroup::Modp2048 => {
+            log!(
+                Level::Detail,
+                "p = {} (RFC 3526 2048-bit MODP prime - public)",
+                short_hex(&MODP_2048_P.to_be_bytes())
+            );
+            log!(Level::Detail, "g = {} (generator - public)", MODP_2048_G);
+        }
+    }
+    log!(Level::Detail);
+
+    let from_file = long_term.is_some();
+    let private_key = match long_term {
+        Some(key) => key,
+        None => group.private_key(seed)?,
+    };
+    let private_bytes = private_key.to_be_bytes();
+    let private_shown = group.show(&private_bytes[private_bytes.len() - MODP_PRIVATE_LEN..]);
+    log!(Level::Detail, "[DH] Generating our keypair...");
+    match seed {
+        _ if from_file => log!(
+            Level::Detail,
+            "private_key = {} (long-term, from the key file)",
+            private_shown
+        ),
+        Some(seed) => {
+            say!(
+                "[WARNING] Private key derived from seed {}: NOT SECURE",
+                seed
+            );
+            log!(
+                Level::Detail,
+                "private_key = {} (deterministic, testing only)",
+                private_shown
+            );
+        }
+        None => log!(Level::Detail, "private_key = {} (random)", private_shown),
+    }
+
+    // Compute public key: g^private mod p
+    let public_key = group.public_key(&private_key);
+    log!(Level::Detail, "public_key = g^private mod p");
+    log!(Level::Detail, "= {}", group.show(&public_key));
+    log!(Level::Detail);
+
+    log!(Level::Summary, "[DH] Exchanging keys...");
+    let their_public_key = exchange_public(stream, is_server, &public_key)?;
+    log!(
+        Level::Detail,
+        "← Receive their public: {}",
+        group.show(&their_public_key)
+    );
+
+    log!(Level::Detail);
+    log!(Level::Detail, "[DH] Computing shared secret...");
+    log!(
+        Level::Detail,
+        "Formula: secret = (their_public)^(our_private) mod p"
+    );
+    log!(Level::Detail);
+
+    // Compute shared secret: their_public^private mod p
+    let shared_secret = group.shared_secret(&their_public_key, &private_key)?;
+    log!(
+        Level::Detail,
+        "secret = ({})^({}) mod p",
+        group.show(&their_public_key),
+        private_shown
+    );
+    log!(Level::Detail, "= {}", group.show(&shared_secret));
+    log!(Level::Detail);
+
+    confirm_secret(stream, &shared_secret, is_server)?;
+    Ok(Exchange {
+        secret: shared_secret,
+        fingerprint: fingerprint(&public_key, &their_public_key),
+        peer_key: sha256(&[b"chat peer key ", &their_public_key[..]].concat()),
+    })
+}
+
+/// SHA-256 over both public keys, each with a 2-byte length, smaller one
+/// first so both sides get the same hash. The secret is left out: the
+/// fingerprint is for reading aloud.
+pub fn fingerprint(ours: &[u8], theirs: &[u8]) -> [u8; 32] {
+    let (first, second) = if ours <= theirs {
+        (ours, theirs)
+    } else {
+        (theirs, ours)
+    };
+    let mut message = b"chat fingerprint ".to_vec();
+    for key in [first, second] {
+        message.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        message.extend_from_slice(key);
+    }
+    sha256(&message)
+}
+
+/// Largest public key we accept from the peer, in bytes.
+const MAX_PUBLIC_KEY: usize = 512;
+
+/// Send our public key and read the peer's, each as a 2-byte big-endian
+/// length followed by the key. The server reads first.
+fn exchange_public<T: Transport>(
+    stream: &mut T,
+    is_server: bool,
+    ours: &[u8],
+) -> io::Result<Vec<u8>> {
+    let send = |stream: &mut T| -> io::Result<()> {
+        log!(
+            Level::Detail,
+            "[NETWORK] Sending public key ({} bytes)...",
+            ours.len()
+        );
+        stream.write_all(&(ours.len() as u16).to_be_bytes())?;
+        stream.write_all(ours)?;
+        stream.flush()
+    };
+    if !is_server {
+        send(stream)?;
+    }
+    let timed_out = waiting_for(stream, "the peer's public key");
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).map_err(&timed_out)?;
+    let len = u16::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_PUBLIC_KEY {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("peer announced a {}-byte public key", len),
+        ));
+    }
+    let mut theirs = vec![0u8; len];
+    stream.read_exact(&mut theirs).map_err(timed_out)?;
+    log!(
+        Level::Detail,
+        "[NETWORK] Received public key ({} bytes) ✓",
+        len
+    );
+    if is_server {
+        send(stream)?;
+    }
+    Ok(theirs)
+}
+
+/// What a side sends to prove it holds `shared_secret`: SHA-256 over the
+/// secret and the side's role, so the two values differ and one cannot
+/// just be echoed back.
+fn confirmation(shared_secret: &[u8], is_server: bool) -> [u8; 32] {
+    let role: &[u8] = if is_server {
+        b"key confirm server"
+    } else {
+        b"key confirm client"
+    };
+    sha256(&[shared_secret, role].concat())
+}
+
+/// Trade confirmations with the peer and check theirs against the secret
+/// we computed. On a mismatch the connection is closed.
+fn confirm_secret(
+    stream: &mut impl Transport,
+    shared_secret: &[u8],
+    is_server: bool,
+) -> io::Result<()> {
+    log!(Level::Detail, "[VERIFY] Exchanging key confirmations...");
+    stream.write_all(&confirmation(shared_secret, is_server))?;
+    stream.flush()?;
+    let mut theirs = [0u8; 32];
+    stream
+        .read_exact(&mut theirs)
+        .map_err(waiting_for(stream, "the peer's key confirmation"))?;
+
+    if !tags_equal(&theirs, &confirmation(shared_secret, !is_server)) {
+        say!("[VERIFY] ✗ Peer computed a different secret, closing the connection");
+        stream.hang_up();
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "key confirmation failed: the two sides do not share a secret",
+        ));
+    }
+    log!(
+        Level::Summary,
+        "[VERIFY] Both sides computed the same secret ✓"
+    );
+    log!(Level::Summary);
+    Ok(())
+}
+
+// First byte of a pre-shared key message: what follows, if anything
+const PSK_NONE: u8 = 0;
+const PSK_PROOF: u8 = 1;
+const PSK_REJECTED: u8 = 2;
+
+/// What a side sends to prove it knows the pre-shared key: HMAC-SHA256,
+/// keyed by the PSK, over the fingerprint of both public keys and the
+/// side's role. The fingerprint ties the proof to this exchange, so a
+/// proof seen once is no use on another connection.
+fn psk_proof(psk: &[u8], fingerprint: &[u8; 32], is_server: bool) -> [u8; 32] {
+    let role: &[u8] = if is_server {
+        b"psk proof server "
+    } else {
+        b"psk proof client "
+    };
+    hmac_sha256(psk, &[role, &fingerprint[..]].concat())
+}
+
+/// The secret the chat keys come from: the DH secret alone, or with a
+/// PSK mixed in, so that a man in the middle who somehow passed the
+/// proofs still holds the wrong keys.
+pub fn keyed_secret(shared_secret: &[u8], psk: Option<&[u8]>) -> Vec<u8> {
+    match psk {
+        Some(psk) => hmac_sha256(psk, &[b"chat psk secret ", shared_secret].concat()).to_vec(),
+        None => shared_secret.to_vec(),
+    }
+}
+
+/// The secret `--test-seed` uses in place of a key exchange: the same for
+/// a seed on both sides and in every run, so the same messages, sent at
+/// the same times, make the same frames byte for byte. Anyone who knows
+/// the seed can read them.
+pub fn test_secret(seed: u64) -> Vec<u8> {
+    sha256(&[b"chat test secret ", &seed.to_be_bytes()[..]].concat()).to_vec()
+}
+
+/// Prove to the peer that we know `psk` and check that it does, or agree
+/// that neither side has one. The client goes first and the server only
+/// answers with its proof once the client's checks out, so a stranger
+/// connecting to a server learns nothing to guess the key from.
+pub fn authenticate(
+    stream: &mut impl Transport,
+    fingerprint: &[u8; 32],
+    psk: Option<&[u8]>,
+    is_server: bool,
+) -> io::Result<()> {
+    let proof = |psk, is_server| psk_proof(psk, fingerprint, is_server);
+    let timed_out = waiting_for(stream, "the peer's pre-shared key proof");
+    let outcome = if is_server {
+        let theirs = read_psk_message(stream).map_err(&timed_out)?;
+        let (reply, outcome) = match (psk, theirs) {
+            (None, None) => (vec![PSK_NONE], Ok(false)),
+            (None, Some(_)) => (
+                vec![PSK_NONE],
+                Err("the client has a pre-shared key and we have none; use --psk"),
+            ),
+            (Some(_), None) => (
+                vec![PSK_REJECTED],
+                Err("the client has no pre-shared key (--psk)"),
+            ),
+            (Some(psk), Some(theirs)) if tags_equal(&theirs, &proof(psk, false)) => {
+                ([&[PSK_PROOF][..], &proof(psk, true)].concat(), Ok(true))
+            }
+            (Some(_), Some(_)) => (vec![PSK_REJECTED], Err("the pre-shared keys differ")),
+        };
+        stream.write_all(&reply)?;
+        stream.flush()?;
+        outcome
+    } else {
+        let message = match psk {
+            Some(psk) => [&[PSK_PROOF][..], &proof(psk, false)].concat(),
+            None => vec![PSK_NONE],
+        };
+        stream.write_all(&message)?;
+        stream.flush()?;
+        let mut kind = [0u8; 1];
+        stream.read_exact(&mut kind).map_err(&timed_out)?;
+        match (psk, kind[0]) {
+            (None, PSK_NONE) => Ok(false),
+            (None, PSK_REJECTED) => Err("the server requires a pre-shared key; use --psk"),
+            (Some(_), PSK_NONE) => Err("the server has no pre-shared key (--psk)"),
+            (Some(_), PSK_REJECTED) => Err("the server rejected our pre-shared key"),
+            (Some(psk), PSK_PROOF) => {
+                let mut theirs = [0u8; 32];
+                stream.read_exact(&mut theirs).map_err(&timed_out)?;
+                if tags_equal(&theirs, &proof(psk, true)) {
+                    Ok(true)
+                } else {
+                    Err("the pre-shared keys differ")
+                }
+            }
+            _ => Err("the server sent a malformed pre-shared key message"),
+        }
+    };
+    match outcome {
+        Ok(true) => {
+            log!(
+                Level::Summary,
+                "[AUTH] Both sides know the pre-shared key ✓"
+            );
+            Ok(())
+        }
+        Ok(false) => Ok(()),
+        Err(why) => {
+            say!(
+                "[AUTH] ✗ Authentication failed: {}; closing the connection",
+                why
+            );
+            stream.hang_up();
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("authentication failed: {}", why),
+            ))
+        }
+    }
+}
+
+/// The client's pre-shared key message: its proof, or `None` if it has
+/// no key.
+fn read_psk_message(stream: &mut impl Read) -> io::Result<Option<[u8; 32]>> {
+    let mut kind = [0u8; 1];
+    stream.read_exact(&mut kind)?;
+    match kind[0] {
+        PSK_NONE => Ok(None),
+        PSK_PROOF => {
+            let mut proof = [0u8; 32];
+            stream.read_exact(&mut proof)?;
+            Ok(Some(proof))
+        }
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("peer sent pre-shared key message type {}", other),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// One end of an in-memory connection.
+    struct Pipe {
+        tx: mpsc::Sender<Vec<u8>>,
+        rx: mpsc::Receiver<Vec<u8>>,
+        pending: Vec<u8>,
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        let end = |tx, rx| Pipe {
+            tx,
+            rx,
+            pending: Vec::new(),
+        };
+        (end(a_tx, a_rx), end(b_tx, b_rx))
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pending.is_empty() {
+                match self.rx.recv() {
+                    Ok(bytes) => self.pending = bytes,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.tx
+                .send(buf.to_vec())
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Pipe {
+        fn read_timeout(&self) -> Option<Duration> {
+            None
+        }
+
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn hang_up(&self) {}
+    }
+
+    /// Run `server` and `client` against each other over a pipe.
+    fn both<S, C, T, U>(server: S, client: C) -> (T, U)
+    where
+        S: FnOnce(&mut Pipe) -> T + Send,
+        C: FnOnce(&mut Pipe) -> U,
+        T: Send,
+    {
+        let (mut a, mut b) = pipe();
+        thread::scope(|scope| {
+            let server = scope.spawn(move || server(&mut a));
+            let client = client(&mut b);
+            (server.join().unwrap(), client)
+        })
+    }
+
+    #[test]
+    fn modular_pow_known_values() {
+        assert_eq!(modular_pow(2, 10, 1000), 24);
+        assert_eq!(modular_pow(3, 200, 1_000_000_007), 136_318_165);
+        assert_eq!(
+            modular_pow(0xDEADBEEF, 0x12345, P),
+            2_991_972_431_772_745_938
+        );
+        assert_eq!(modular_pow(5, 0, 7), 1);
+        assert_eq!(modular_pow(5, 3, 1), 0);
+    }
+
+    #[test]
+    fn miller_rabin_is_exact() {
+        assert!(is_prime(P));
+        assert!(is_prime((P - 1) / 2));
+        assert!(is_prime((1 << 61) - 1));
+        for composite in [0, 1, 561, 1_000_000_007 * 3, u64::MAX] {
+            assert!(!is_prime(composite), "{}", composite);
+        }
+    }
+
+    #[test]
+    fn default_params_validate_and_reject_degenerate_keys() {
+        let params = DhParams::DEFAULT;
+        assert!(params.validate().is_ok());
+        assert!(DhParams { p: P - 2, g: G }.validate().is_err());
+        assert!(DhParams { p: P, g: 1 }.validate().is_err());
+        for key in [0, 1, P - 1, P] {
+            assert!(params.check_public(key).is_err(), "{:X}", key);
+        }
+        assert!(params.check_public(2).is_ok());
+    }
+
+    #[test]
+    fn seeded_private_keys_repeat_and_random_ones_do_not() {
+        assert_eq!(
+            generate_private_key(P, Some(7)).unwrap(),
+            generate_private_key(P, Some(7)).unwrap()
+        );
+        assert_ne!(
+            generate_private_key(P, Some(7)).unwrap(),
+            generate_private_key(P, Some(8)).unwrap()
+        );
+        assert_ne!(
+            generate_private_key(P, None).unwrap(),
+            generate_private_key(P, None).unwrap()
+        );
+        let key = generate_private_key(P, Some(7)).unwrap();
+        assert!((2..P - 1).contains(&key));
+    }
+
+    #[test]
+    fn u2048_arithmetic() {
+        let bytes: Vec<u8> = (1..=40).collect();
+        let n = U2048::from_be_bytes(&bytes).unwrap();
+        assert_eq!(n.to_be_bytes()[LIMBS * 8 - 40..], bytes[..]);
+        assert!(U2048::from_be_bytes(&[1; LIMBS * 8 + 1]).is_none());
+
+        let (diff, borrow) = U2048::from_u64(1).overflowing_sub(&U2048::from_u64(2));
+        assert!(borrow);
+        assert_eq!(diff.to_be_bytes(), [0xff; LIMBS * 8]);
+        assert!(U2048::from_u64(2) > U2048::from_u64(1));
+
+        // Small exponents agree with the u64 version
+        let m = U2048::from_u64(P);
+        let x = U2048::from_u64(0xDEADBEEF).pow_mod(&U2048::from_u64(0x12345), &m);
+        assert_eq!(x, U2048::from_u64(2_991_972_431_772_745_938));
+
+        // 2^1024 is below p, and 2^2048 mod p is 2^2048 - p
+        let group = DhGroup::Modp2048;
+        let mut power = [0u8; LIMBS * 8];
+        power[LIMBS * 4 - 1] = 1;
+        assert_eq!(group.public_key(&U2048::from_u64(1024)), power);
+        let (wrapped, _) = U2048::ZERO.overflowing_sub(&MODP_2048_P);
+        assert_eq!(
+            group.public_key(&U2048::from_u64(2048)),
+            wrapped.to_be_bytes()
+        );
+    }
+
+    #[test]
+    fn dh_exchange_over_a_pipe_agrees() {
+        for group in [DhGroup::Small(DhParams::DEFAULT), DhGroup::Modp2048] {
+            let (server, client) = both(
+                |pipe| perform_dh_exchange(pipe, true, &group, None, None).unwrap(),
+                |pipe| perform_dh_exchange(pipe, false, &group, None, None).unwrap(),
+            );
+            assert_eq!(server.secret, client.secret);
+            assert_eq!(server.fingerprint, client.fingerprint);
+            assert_ne!(server.peer_key, client.peer_key);
+        }
+    }
+
+    #[test]
+    fn mismatched_secrets_fail_confirmation() {
+        let (server, client) = both(
+            |pipe| confirm_secret(pipe, b"one", true),
+            |pipe| confirm_secret(pipe, b"two", false),
+        );
+        for result in [server, client] {
+            let e = result.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(e.to_string().contains("key confirmation failed"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn fingerprint_is_order_independent() {
+        let hex: String = fingerprint(&[1, 2], &[3])
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(
+            hex,
+            "5468eddae1f8e4003626279ecb79707beadea2b61452a96326d4fa98ae89a66b"
+        );
+        assert_eq!(fingerprint(&[1, 2], &[3]), fingerprint(&[3], &[1, 2]));
+    }
+
+    #[test]
+    fn psk_proofs_depend_on_role_and_key() {
+        let fingerprint = [7; 32];
+        assert_ne!(
+            psk_proof(b"k", &fingerprint, true),
+            psk_proof(b"k", &fingerprint, false)
+        );
+        assert_ne!(
+            psk_proof(b"k", &fingerprint, true),
+            psk_proof(b"j", &fingerprint, true)
+        );
+        assert_eq!(keyed_secret(b"secret", None), b"secret");
+        assert_ne!(keyed_secret(b"secret", Some(b"k")), b"secret");
+    }
+
+    #[test]
+    fn authentication_needs_the_same_psk_on_both_sides() {
+        let fingerprint = [7; 32];
+        let run = |server: Option<&'static [u8]>, client: Option<&'static [u8]>| {
+            both(
+                move |pipe| authenticate(pipe, &fingerprint, server, true),
+                move |pipe| authenticate(pipe, &fingerprint, client, false),
+            )
+        };
+        let (server, client) = run(Some(b"k"), Some(b"k"));
+        assert!(server.is_ok() && client.is_ok());
+        let (server, client) = run(None, None);
+        assert!(server.is_ok() && client.is_ok());
+
+        for (server_psk, client_psk, server_why, client_why) in [
+            (
+                Some(&b"k"[..]),
+                Some(&b"j"[..]),
+                "the pre-shared keys differ",
+                "the server rejected our pre-shared key",
+            ),
+            (
+                None,
+                Some(&b"k"[..]),
+                "the client has a pre-shared key and we have none",
+                "the server has no pre-shared key",
+            ),
+            (
+                Some(&b"k"[..]),
+                None,
+                "the client has no pre-shared key",
+                "the server requires a pre-shared key",
+            ),
+        ] {
+            let (server, client) = run(server_psk, client_psk);
+            for (result, why) in [(server, server_why), (client, client_why)] {
+                let e = result.unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+                assert!(e.to_string().contains(why), "{}", e);
+            }
+        }
+    }
+}
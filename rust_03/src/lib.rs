@@ -0,0 +1,248 @@
+//! Stream cipher chat over TCP or UDP, behind the `streamchat` binary.
+//!
+//! `dh` agrees on a shared secret, `cipher` turns it into a keystream and
+//! tags for each direction, and `proto` frames the messages sent under
+//! them; `datagram` seals them one datagram at a time instead. The
+//! handshake runs over any `Transport`, so tests can drive it through an
+//! in-memory pipe, and UDP through a connected socket. `command` parses
+//! what is typed, and `session` counts the traffic and says how a chat
+//! ended.
+//!
+//! `chat` runs a session once the keys are agreed, over the same kind of
+//! `Transport`, with `transfer` for the files sent in it and `transcript`
+//! for what is kept of it. `relay` pairs up clients that cannot reach each
+//! other, and `selftest` chats with itself. Argument parsing, the
+//! handshake's checks on the peer and the sockets live in `main.rs`.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+pub mod chat;
+pub mod cipher;
+pub mod command;
+pub mod datagram;
+pub mod dh;
+pub mod proto;
+pub mod relay;
+pub mod selftest;
+pub mod session;
+pub mod style;
+pub mod transcript;
+pub mod transfer;
+
+/// How much to print, from `-v` flags: 0 shows chat lines and connection
+/// status, 1 adds network and crypto summaries, 2 adds every key, keystream
+/// and hex dump.
+pub static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+/// The verbosity a line needs before it is printed.
+#[derive(Clone, Copy)]
+pub enum Level {
+    Summary = 1,
+    Detail = 2,
+}
+
+/// Whether a `level` line shows at `verbosity`.
+pub fn shows(verbosity: u8, level: Level) -> bool {
+    verbosity >= level as u8
+}
+
+pub fn enabled(level: Level) -> bool {
+    shows(VERBOSITY.load(Ordering::Relaxed), level)
+}
+
+/// Print a line on stdout, colored for its `[TAG]`.
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        println!("{}", $crate::style::line(&format!($($arg)*)))
+    };
+}
+
+/// Like `say!`, but writes to `out` and evaluates to the write's result.
+#[macro_export]
+macro_rules! say_to {
+    ($out:expr, $($arg:tt)*) => {
+        writeln!($out, "{}", $crate::style::line(&format!($($arg)*)))
+    };
+}
+
+/// Print a line on stdout if `level` is enabled.
+#[macro_export]
+macro_rules! log {
+    ($level:expr) => {
+        if $crate::enabled($level) {
+            println!()
+        }
+    };
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::enabled($level) {
+            $crate::say!($($arg)*)
+        }
+    };
+}
+
+/// Like `log!`, but writes to `out` and evaluates to the write's result.
+#[macro_export]
+macro_rules! log_to {
+    ($out:expr, $level:expr) => {
+        if $crate::enabled($level) {
+            writeln!($out)
+        } else {
+            Ok(())
+        }
+    };
+    ($out:expr, $level:expr, $($arg:tt)*) => {
+        if $crate::enabled($level) {
+            $crate::say_to!($out, $($arg)*)
+        } else {
+            Ok(())
+        }
+    };
+}
+
+/// Whether `e` is a read timing out, which is `WouldBlock` on Unix and
+/// `TimedOut` on Windows.
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// What the handshake and the chat need from a connection besides its
+/// bytes: how long a read may wait, to say so when one times out, a way
+/// to change that between frames, and a way to hang up on a peer that
+/// fails a check or that we are done with.
+pub trait Transport: Read + Write {
+    fn read_timeout(&self) -> Option<Duration>;
+
+    /// `None` lets a read wait for ever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn hang_up(&self);
+}
+
+impl Transport for TcpStream {
+    fn read_timeout(&self) -> Option<Duration> {
+        TcpStream::read_timeout(self).ok().flatten()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn hang_up(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+/// Turns a read that timed out on `stream` into an error saying what we
+/// were waiting for. Other errors pass through as they are.
+pub fn waiting_for<T: Transport>(
+    stream: &T,
+    what: &'static str,
+) -> impl Fn(io::Error) -> io::Error + use<T> {
+    let limit = stream.read_timeout();
+    move |e| {
+        if !is_timeout(&e) {
+            return e;
+        }
+        let after = limit.map_or(String::new(), |limit| {
+            format!(" after {}", duration_text(limit))
+        });
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("timed out{} waiting for {}", after, what),
+        )
+    }
+}
+
+/// A duration to the second, like `1h 02m 03s` or `45s`.
+pub fn duration_text(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, s) => format!("{}h {:02}m {:02}s", h, m, s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A connection whose peer never sends anything.
+    struct Silent(Option<Duration>);
+
+    impl Read for Silent {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Silent {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Silent {
+        fn read_timeout(&self) -> Option<Duration> {
+            self.0
+        }
+
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn hang_up(&self) {}
+    }
+
+    #[test]
+    fn levels_show_from_their_verbosity_up() {
+        assert!(!shows(0, Level::Summary));
+        assert!(shows(1, Level::Summary));
+        assert!(!shows(1, Level::Detail));
+        assert!(shows(2, Level::Detail));
+        assert!(shows(3, Level::Summary));
+    }
+
+    #[test]
+    fn waiting_for_names_what_timed_out() {
+        let mut stream = Silent(Some(Duration::from_secs(2)));
+        let e = stream
+            .read(&mut [0; 1])
+            .map_err(waiting_for(&stream, "the peer's hello"))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            e.to_string(),
+            "timed out after 2s waiting for the peer's hello"
+        );
+
+        let unlimited = Silent(None);
+        let e = waiting_for(&unlimited, "a frame")(io::ErrorKind::TimedOut.into());
+        assert_eq!(e.to_string(), "timed out waiting for a frame");
+    }
+
+    #[test]
+    fn waiting_for_passes_other_errors_through() {
+        let stream = Silent(Some(Duration::from_secs(2)));
+        let e = waiting_for(&stream, "a frame")(io::ErrorKind::UnexpectedEof.into());
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn durations_read_as_hours_minutes_seconds() {
+        assert_eq!(duration_text(Duration::from_millis(4_900)), "4s");
+        assert_eq!(duration_text(Duration::from_secs(65)), "1m 05s");
+        assert_eq!(duration_text(Duration::from_secs(3_725)), "1h 02m 05s");
+    }
+}
@@ -1,466 +1,1843 @@
-use std::io::{self, BufReader, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs;
+use std::io::{self, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use rust_03::chat::udp::udp_chat;
+use rust_03::chat::{ChatOptions, INTERRUPTS, Input, Pace, Rekey, TICK, catch_interrupts, chat};
+use rust_03::cipher::{CipherKind, CipherPair};
+use rust_03::datagram::{MAX_DATAGRAM, UdpLink};
+use rust_03::dh::{
+    DhGroup, DhParams, Exchange, MODP_PRIVATE_LEN, U2048, authenticate, keyed_secret,
+    perform_dh_exchange, test_secret,
+};
+use rust_03::proto::{
+    FRAME_VERSION, FRAME_VERSION_NONCE, MAX_FRAME, MAX_MESSAGE, MAX_NICK, MIN_MESSAGE, check_nick,
+};
+use rust_03::relay::{RELAY_CLIENT, RELAY_SERVER, forward_pair, relay_role, wait_for_pair};
+use rust_03::selftest::{SelftestOptions, run_selftest};
+use rust_03::transcript::{HISTORY, LogFormat, Transcript};
+use rust_03::{Transport, VERBOSITY, is_timeout, say, style};
 
 /// Stream cipher chat with Diffie-Hellman key generation
+#[derive(Clone)]
 enum Command {
     Server(u16),
     Client(String),
+    /// Pass frames between two clients that cannot reach each other.
+    Relay(u16),
+    /// Chat with ourselves over loopback and check every message.
+    Selftest,
 }
 
 struct Args {
     command: Command,
+    /// Our nickname; `user-PORT` when not given.
+    nick: Option<String>,
+    /// Derive the private key from this instead of the OS generator, so
+    /// runs can be reproduced. Not secure.
+    seed: Option<u64>,
+    cipher: CipherKind,
+    verbosity: u8,
+    /// Transcript file for `--log`.
+    log: Option<String>,
+    log_format: LogFormat,
+    dh: DhKind,
+    /// File holding DH p and g in hex, instead of the built-in pair.
+    /// Only with `--dh small`.
+    dh_params: Option<String>,
+    /// Rekey after sending this many frames under one key; `None` never.
+    rekey_messages: Option<u64>,
+    /// Rekey once a key is this old; `None` never.
+    rekey_interval: Option<Duration>,
+    /// PING the peer after this long without hearing from it; `None`
+    /// never.
+    keepalive: Option<Duration>,
+    /// Warn about a message the peer has not acknowledged after this
+    /// long; `None` never.
+    ack_timeout: Option<Duration>,
+    /// Give up on a peer that goes quiet this long during the handshake
+    /// or partway through a frame; `None` never.
+    timeout: Option<Duration>,
+    retry: Retry,
+    /// Address or hostname the server listens on.
+    bind: String,
+    /// Try a hostname's IPv4 addresses before its IPv6 ones.
+    prefer_ipv4: bool,
+    max_message: usize,
+    /// Prefix received messages with the local time.
+    timestamps: bool,
+    /// Show when the peer sent each message and how long it took.
+    show_latency: bool,
+    /// Send lines exactly as typed and show messages exactly as sent.
+    raw: bool,
+    /// Print what `/stats` shows when the chat ends.
+    stats_on_exit: bool,
+    /// Messages kept for `/history` and `/save`.
+    history: usize,
+    /// Fingerprint the exchange must produce, as 32 lowercase hex digits.
+    require_fingerprint: Option<String>,
+    /// Also show the fingerprint as emoji.
+    sas: bool,
+    /// File keeping our long-term private key, instead of a fresh one
+    /// per connection.
+    key_file: Option<PathBuf>,
+    /// File of peer keys seen before, checked and added to.
+    known_peers: Option<PathBuf>,
+    /// Replace a changed key in `known_peers` instead of hanging up.
+    accept_new_key: bool,
+    /// Server: end after the first client instead of waiting for more.
+    once: bool,
+    /// Server: where to write the address it listens on. Client: where
+    /// to read the port to connect to.
+    port_file: Option<PathBuf>,
+    /// File of messages to send instead of reading the keyboard; `-`
+    /// for stdin.
+    script: Option<String>,
+    /// With `script`, wait this long after each message instead of for
+    /// the peer's reply.
+    script_delay: Option<Duration>,
+    /// Pad each message to a multiple of this many bytes; `None` never.
+    pad: Option<usize>,
+    /// Client: reach the peer through the relay at this address.
+    via: Option<String>,
+    /// `--transport udp`: one datagram per frame instead of a TCP stream.
+    udp: bool,
+    /// Newest frame format to offer the peer.
+    frame_format: u8,
+    /// Never color output, even on a terminal.
+    no_color: bool,
+    /// Secret both sides must know, from `--psk` or `--psk-file`. Never
+    /// sent or shown.
+    psk: Option<Vec<u8>>,
+    /// Use the secret from this seed instead of a key exchange.
+    test_seed: Option<u64>,
+}
+
+impl Args {
+    /// The settings for a chat on local `port`, which names us if
+    /// `--nick` did not.
+    fn chat_options(&self, port: u16) -> ChatOptions {
+        ChatOptions {
+            nick: self
+                .nick
+                .clone()
+                .unwrap_or_else(|| format!("user-{}", port)),
+            keepalive: self.keepalive,
+            ack_timeout: self.ack_timeout,
+            timeout: self.timeout,
+            max_message: self.max_message,
+            pad: self.pad.unwrap_or(1),
+            timestamps: self.timestamps,
+            show_latency: self.show_latency,
+            raw: self.raw,
+            stats_on_exit: self.stats_on_exit,
+            history: self.history,
+            frame_format: self.frame_format,
+            test_seed: self.test_seed,
+            pace: self.script.as_ref().map(|_| match self.script_delay {
+                Some(delay) => Pace::Delay(delay),
+                None => Pace::Reply,
+            }),
+        }
+    }
+}
+
+/// How the client retries a connection the server is not ready for.
+#[derive(Clone, Copy)]
+struct Retry {
+    /// Connection attempts in all; `None` keeps trying.
+    attempts: Option<u32>,
+    /// Wait after the first failure. It doubles after each failure after
+    /// that, up to `MAX_RETRY_DELAY`.
+    delay: Duration,
+}
+
+/// Default for `--retry-delay`, in seconds.
+const RETRY_DELAY_SECS: f64 = 1.0;
+
+/// Longest wait between two connection attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Default for `--rekey-messages`.
+const REKEY_MESSAGES: u64 = 1000;
+
+/// Default for `--rekey-minutes`.
+const REKEY_MINUTES: f64 = 60.0;
+
+/// Default for `--keepalive`, in seconds.
+const KEEPALIVE_SECS: u64 = 30;
+
+/// Default for `--ack-timeout`, in seconds.
+const ACK_TIMEOUT_SECS: u64 = 10;
+
+/// Default for `--timeout`, in seconds.
+const TIMEOUT_SECS: u64 = 30;
+
+/// Which DH group `--dh` picked.
+#[derive(Clone, Copy, PartialEq)]
+enum DhKind {
+    /// 64-bit numbers that fit on a slide. Not secure.
+    Small,
+    Modp2048,
 }
 
 fn print_help() {
     println!("Stream cipher chat with Diffie-Hellman key generation\n");
-    println!("Usage: streamchat <server PORT | client ADDRESS>\n");
+    println!(
+        "Usage: streamchat <server PORT | client ADDRESS | relay PORT | selftest> [OPTIONS]\n"
+    );
+    println!("Options:");
+    println!("  -v, --verbose Show network and crypto summaries; -vv adds every key and byte");
+    println!("  --no-color    Plain output even on a terminal; NO_COLOR does the same");
+    println!("  --cipher NAME chacha20 or lcg (insecure, for teaching) [default: chacha20]");
+    println!("  --log FILE    Append every message sent and received to FILE");
+    println!("  --log-format F  text or json (one object per line) [default: text]");
+    println!(
+        "  --dh GROUP    modp2048 (RFC 3526) or small (64-bit, for teaching) [default: modp2048]"
+    );
+    println!("  --dh-params FILE  With --dh small, read p and g (two hex values) from FILE");
+    println!(
+        "  --rekey-messages N  New keys after sending N messages, 0 for never [default: {}]",
+        REKEY_MESSAGES
+    );
+    println!(
+        "  --rekey-minutes M   New keys every M minutes, 0 for never [default: {}]",
+        REKEY_MINUTES
+    );
+    println!(
+        "  --keepalive SECS  Ping a silent peer after SECS, 0 for never [default: {}]",
+        KEEPALIVE_SECS
+    );
+    println!(
+        "  --ack-timeout SECS  Warn if a message is not delivered within SECS, 0 for never [default: {}]",
+        ACK_TIMEOUT_SECS
+    );
+    println!(
+        "  --timeout SECS  Give up on a peer stalled mid-handshake or mid-frame, 0 for never [default: {}]",
+        TIMEOUT_SECS
+    );
+    println!("  --retry N     Client: try to connect up to N times [default: 1]");
+    println!(
+        "  --retry-delay SECS  Wait before retrying, doubling each time up to {}s [default: {}]",
+        MAX_RETRY_DELAY.as_secs(),
+        RETRY_DELAY_SECS
+    );
+    println!("  --wait        Client: keep retrying until the server is up");
+    println!(
+        "  --bind ADDR   Server or relay: address or hostname to listen on [default: 0.0.0.0]"
+    );
+    println!(
+        "  --once        Server or relay: exit after the first client or pair instead of waiting"
+    );
+    println!(
+        "  --port-file FILE  Server or relay: write the address it listens on to FILE; PORT 0 picks one"
+    );
+    println!("                Client: ADDRESS (or --via) is a host; connect to the port in FILE");
+    println!(
+        "  --via ADDR    Client: reach the peer through the relay at ADDR; ADDRESS only names it"
+    );
+    println!("  --prefer-ipv4 Try a hostname's IPv4 addresses before its IPv6 ones");
+    println!(
+        "  --transport T Server or client: tcp, or udp with one datagram per message [default: tcp]"
+    );
+    println!(
+        "  --frame-format N  Newest frame format to offer: 9, a keystream per message from random"
+    );
+    println!("                bytes in front of it, or 8, one running keystream [default: 9]");
+    println!(
+        "  --max-message BYTES  Largest message sent or accepted [default: {}]",
+        MAX_MESSAGE
+    );
+    println!(
+        "  --pad N       Pad every message to a multiple of N bytes (1 to {}) to hide its length",
+        MIN_MESSAGE
+    );
+    println!("  --timestamps  Show the local time each message arrived");
+    println!("  --show-latency  Show when the peer sent each message, by its clock, and the delay");
+    println!("  --raw         Send lines exactly as typed and show messages exactly as sent, with");
+    println!("                control characters escaped");
+    println!("  --stats-on-exit  Show the traffic statistics /stats gives when the chat ends");
+    println!(
+        "  --history N   Keep the last N messages for /history and /save [default: {}]",
+        HISTORY
+    );
+    println!("  --require-fingerprint HEX  Hang up unless the key fingerprint is HEX");
+    println!("  --sas         Also show the key fingerprint as seven emoji");
+    println!(
+        "  --key-file FILE  Keep our private key in FILE, made on first use, so peers can pin it"
+    );
+    println!("  --known-peers FILE  Remember each peer's key in FILE; hang up if it changes");
+    println!("  --accept-new-key  With --known-peers, save a changed key instead of hanging up");
+    println!("  --psk SECRET  Hang up unless the peer knows SECRET too; it is never sent");
+    println!("  --psk-file FILE  Read the pre-shared secret from FILE, out of sight of ps");
+    println!(
+        "  --script FILE Send each line of FILE (- for stdin) once the peer replies, then leave"
+    );
+    println!("  --script-delay SECS  With --script, wait SECS after each line instead");
+    println!("  --insecure-deterministic-seed N");
+    println!("                Derive the private key from N; for tests only, NOT secure");
+    println!(
+        "  --test-seed N Skip the key exchange: both sides use a secret and nonces fixed by N,"
+    );
+    println!("                so every run uses the same keystreams; for tests only, NOT secure");
+    println!(
+        "  --nick NAME   Name shown to the peer (max {} bytes) [default: user-PORT]",
+        MAX_NICK
+    );
 }
 
 fn parse_args() -> Result<Args, String> {
-    let mut it = std::env::args().skip(1);
-    if let Some(first) = it.next() {
-        match first.as_str() {
-            "server" => {
-                let port: u16 = it
+    let args = std::env::args_os()
+        .skip(1)
+        .map(|a| {
+            a.into_string()
+                .map_err(|a| format!("invalid UTF-8 in argument {:?}", a))
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+    let mut it = args.into_iter();
+    let command = match it.next().as_deref() {
+        Some("server") => {
+            let port: u16 = it
+                .next()
+                .ok_or("server requires PORT")?
+                .parse()
+                .map_err(|_| "invalid PORT".to_string())?;
+            Command::Server(port)
+        }
+        Some("client") => Command::Client(it.next().ok_or("client requires ADDRESS")?),
+        Some("relay") => {
+            let port: u16 = it
+                .next()
+                .ok_or("relay requires PORT")?
+                .parse()
+                .map_err(|_| "invalid PORT".to_string())?;
+            Command::Relay(port)
+        }
+        Some("selftest") => Command::Selftest,
+        Some("-h" | "--help") => {
+            print_help();
+            std::process::exit(0);
+        }
+        Some(_) => {
+            return Err(
+                "expected 'server PORT', 'client ADDRESS', 'relay PORT' or 'selftest'".to_string(),
+            );
+        }
+        None => return Err("missing subcommand".to_string()),
+    };
+
+    let mut nick = None;
+    let mut seed = None;
+    let mut test_seed = None;
+    let mut cipher = CipherKind::ChaCha20;
+    let mut verbosity = 0u8;
+    let mut log = None;
+    let mut log_format = None;
+    let mut dh = DhKind::Modp2048;
+    let mut dh_params = None;
+    let mut rekey_messages = Some(REKEY_MESSAGES);
+    let mut rekey_interval = Some(Duration::from_secs_f64(REKEY_MINUTES * 60.0));
+    let mut keepalive = Some(Duration::from_secs(KEEPALIVE_SECS));
+    let mut ack_timeout = Some(Duration::from_secs(ACK_TIMEOUT_SECS));
+    let mut timeout = Some(Duration::from_secs(TIMEOUT_SECS));
+    let mut attempts = None;
+    let mut retry_delay = None;
+    let mut wait = false;
+    let mut bind = None;
+    let mut prefer_ipv4 = false;
+    let mut max_message = MAX_MESSAGE;
+    let mut pad = None;
+    let mut timestamps = false;
+    let mut show_latency = false;
+    let mut raw = false;
+    let mut stats_on_exit = false;
+    let mut history = HISTORY;
+    let mut require_fingerprint = None;
+    let mut sas = false;
+    let mut key_file = None;
+    let mut known_peers = None;
+    let mut accept_new_key = false;
+    let mut once = false;
+    let mut via = None;
+    let mut udp = false;
+    let mut frame_format = FRAME_VERSION_NONCE;
+    let mut no_color = false;
+    let mut port_file = None;
+    let mut script = None;
+    let mut script_delay = None;
+    let mut psk = None;
+    let mut psk_file = None;
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--nick" => {
+                let name = it.next().ok_or("--nick requires NAME")?;
+                nick = Some(check_nick(name.as_bytes())?);
+            }
+            "--insecure-deterministic-seed" => {
+                let n = it
                     .next()
-                    .ok_or("server requires PORT")?
+                    .ok_or("--insecure-deterministic-seed requires N")?;
+                seed = Some(n.parse().map_err(|_| format!("invalid seed '{}'", n))?);
+            }
+            "--test-seed" => {
+                let n = it.next().ok_or("--test-seed requires N")?;
+                test_seed = Some(n.parse().map_err(|_| format!("invalid seed '{}'", n))?);
+            }
+            "-v" | "--verbose" => verbosity = verbosity.saturating_add(1),
+            "-vv" => verbosity = verbosity.saturating_add(2),
+            "--dh" => {
+                dh = match it.next().as_deref() {
+                    Some("modp2048") => DhKind::Modp2048,
+                    Some("small") => DhKind::Small,
+                    _ => return Err("--dh requires 'modp2048' or 'small'".to_string()),
+                };
+            }
+            "--rekey-messages" => {
+                let n = it.next().ok_or("--rekey-messages requires N")?;
+                let n: u64 = n
+                    .parse()
+                    .map_err(|_| format!("invalid message count '{}'", n))?;
+                rekey_messages = (n > 0).then_some(n);
+            }
+            "--rekey-minutes" => {
+                let m = it.next().ok_or("--rekey-minutes requires M")?;
+                let minutes: f64 = match m.parse() {
+                    Ok(minutes) if f64::is_finite(minutes) && minutes >= 0.0 => minutes,
+                    _ => return Err(format!("invalid number of minutes '{}'", m)),
+                };
+                rekey_interval = (minutes > 0.0).then(|| Duration::from_secs_f64(minutes * 60.0));
+            }
+            "--keepalive" => {
+                let secs = it.next().ok_or("--keepalive requires SECS")?;
+                let secs: u64 = secs
+                    .parse()
+                    .map_err(|_| format!("invalid number of seconds '{}'", secs))?;
+                keepalive = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "--ack-timeout" => {
+                let secs = it.next().ok_or("--ack-timeout requires SECS")?;
+                let secs: u64 = secs
+                    .parse()
+                    .map_err(|_| format!("invalid number of seconds '{}'", secs))?;
+                ack_timeout = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "--timeout" => {
+                let secs = it.next().ok_or("--timeout requires SECS")?;
+                let secs: u64 = secs
+                    .parse()
+                    .map_err(|_| format!("invalid number of seconds '{}'", secs))?;
+                timeout = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "--retry" => {
+                let n = it.next().ok_or("--retry requires N")?;
+                attempts = match n.parse::<u32>() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => return Err(format!("invalid number of attempts '{}'", n)),
+                };
+            }
+            "--retry-delay" => {
+                let secs = it.next().ok_or("--retry-delay requires SECS")?;
+                retry_delay = match secs.parse() {
+                    Ok(secs) if f64::is_finite(secs) && secs >= 0.0 => {
+                        Some(Duration::from_secs_f64(secs))
+                    }
+                    _ => return Err(format!("invalid number of seconds '{}'", secs)),
+                };
+            }
+            "--wait" => wait = true,
+            "--bind" => bind = Some(it.next().ok_or("--bind requires ADDR")?),
+            "--prefer-ipv4" => prefer_ipv4 = true,
+            "--timestamps" => timestamps = true,
+            "--show-latency" => show_latency = true,
+            "--raw" => raw = true,
+            "--stats-on-exit" => stats_on_exit = true,
+            "--history" => {
+                let n = it.next().ok_or("--history requires N")?;
+                history = n
                     .parse()
-                    .map_err(|_| "invalid PORT".to_string())?;
-                Ok(Args {
-                    command: Command::Server(port),
-                })
+                    .map_err(|_| format!("--history must be a number of messages, got '{}'", n))?;
+            }
+            "--require-fingerprint" => {
+                let hex = it.next().ok_or("--require-fingerprint requires HEX")?;
+                require_fingerprint = Some(parse_fingerprint(&hex).ok_or_else(|| {
+                    format!(
+                        "invalid fingerprint '{}': expected {} hex digits",
+                        hex,
+                        FINGERPRINT_LEN * 2
+                    )
+                })?);
+            }
+            "--sas" => sas = true,
+            "--key-file" => {
+                key_file = Some(PathBuf::from(it.next().ok_or("--key-file requires FILE")?))
+            }
+            "--known-peers" => {
+                known_peers = Some(PathBuf::from(
+                    it.next().ok_or("--known-peers requires FILE")?,
+                ))
+            }
+            "--accept-new-key" => accept_new_key = true,
+            "--once" => once = true,
+            "--no-color" => no_color = true,
+            "--via" => via = Some(it.next().ok_or("--via requires ADDR")?),
+            "--transport" => {
+                udp = match it.next().as_deref() {
+                    Some("tcp") => false,
+                    Some("udp") => true,
+                    _ => return Err("--transport requires 'tcp' or 'udp'".to_string()),
+                };
+            }
+            "--frame-format" => {
+                frame_format = match it.next().as_deref() {
+                    Some("8") => FRAME_VERSION,
+                    Some("9") => FRAME_VERSION_NONCE,
+                    _ => return Err("--frame-format requires 8 or 9".to_string()),
+                };
+            }
+            "--psk" => psk = Some(it.next().ok_or("--psk requires SECRET")?.into_bytes()),
+            "--psk-file" => psk_file = Some(it.next().ok_or("--psk-file requires FILE")?),
+            "--script" => script = Some(it.next().ok_or("--script requires FILE")?),
+            "--script-delay" => {
+                let secs = it.next().ok_or("--script-delay requires SECS")?;
+                script_delay = match secs.parse() {
+                    Ok(secs) if f64::is_finite(secs) && secs >= 0.0 => {
+                        Some(Duration::from_secs_f64(secs))
+                    }
+                    _ => return Err(format!("invalid number of seconds '{}'", secs)),
+                };
+            }
+            "--port-file" => {
+                port_file = Some(PathBuf::from(it.next().ok_or("--port-file requires FILE")?))
+            }
+            "--max-message" => {
+                let n = it.next().ok_or("--max-message requires BYTES")?;
+                max_message = match n.parse() {
+                    Ok(n) if (MIN_MESSAGE..=MAX_FRAME).contains(&n) => n,
+                    _ => {
+                        return Err(format!(
+                            "--max-message must be {} to {} bytes, got '{}'",
+                            MIN_MESSAGE, MAX_FRAME, n
+                        ));
+                    }
+                };
+            }
+            "--pad" => {
+                let n = it.next().ok_or("--pad requires N")?;
+                pad = match n.parse() {
+                    Ok(n) if (1..=MIN_MESSAGE).contains(&n) => Some(n),
+                    _ => {
+                        return Err(format!(
+                            "--pad must be 1 to {} bytes, got '{}'",
+                            MIN_MESSAGE, n
+                        ));
+                    }
+                };
             }
-            "client" => {
-                let addr = it.next().ok_or("client requires ADDRESS")?;
-                Ok(Args {
-                    command: Command::Client(addr),
-                })
+            "--dh-params" => dh_params = Some(it.next().ok_or("--dh-params requires FILE")?),
+            "--log" => log = Some(it.next().ok_or("--log requires FILE")?),
+            "--log-format" => {
+                log_format = match it.next().as_deref() {
+                    Some("text") => Some(LogFormat::Text),
+                    Some("json") => Some(LogFormat::Json),
+                    _ => return Err("--log-format requires 'text' or 'json'".to_string()),
+                };
+            }
+            "--cipher" => {
+                cipher = match it.next().as_deref() {
+                    Some("chacha20" | "chacha") => CipherKind::ChaCha20,
+                    Some("lcg") => CipherKind::Lcg,
+                    _ => return Err("--cipher requires 'chacha20' or 'lcg'".to_string()),
+                };
             }
             "-h" | "--help" => {
                 print_help();
                 std::process::exit(0);
             }
-            _ => Err("expected 'server PORT' or 'client ADDRESS'".to_string()),
+            _ => return Err(format!("unexpected argument '{}'", arg)),
+        }
+    }
+    if log_format.is_some() && log.is_none() {
+        return Err("--log-format only applies with --log".to_string());
+    }
+    if dh_params.is_some() && dh != DhKind::Small {
+        return Err("--dh-params only applies with --dh small".to_string());
+    }
+    if wait && attempts.is_some() {
+        return Err("--wait and --retry cannot be used together".to_string());
+    }
+    let retrying = wait || attempts.is_some() || retry_delay.is_some();
+    if retrying && !matches!(command, Command::Client(_)) {
+        return Err("--retry, --retry-delay and --wait only apply to client".to_string());
+    }
+    let listens = matches!(command, Command::Server(_) | Command::Relay(_));
+    if bind.is_some() && !listens {
+        return Err("--bind only applies to server and relay".to_string());
+    }
+    if once && !listens {
+        return Err("--once only applies to server and relay".to_string());
+    }
+    if via.is_some() && !matches!(command, Command::Client(_)) {
+        return Err("--via only applies to client".to_string());
+    }
+    if let Command::Client(address) = &command
+        && port_file.is_some()
+    {
+        let (name, target) = match &via {
+            Some(via) => ("--via", via),
+            None => ("ADDRESS", address),
+        };
+        if has_port(target) {
+            return Err(format!(
+                "with --port-file, {} is a host without a port, got '{}'",
+                name, target
+            ));
+        }
+    }
+    if udp {
+        if !matches!(command, Command::Server(_) | Command::Client(_)) {
+            return Err("--transport udp only applies to server and client".to_string());
+        }
+        if via.is_some() {
+            return Err("--via does not work over --transport udp".to_string());
+        }
+        if frame_format != FRAME_VERSION_NONCE {
+            return Err(
+                "--frame-format does not apply to --transport udp, which has its own".to_string(),
+            );
+        }
+        if retrying {
+            return Err(
+                "--retry, --retry-delay and --wait do not apply to --transport udp".to_string(),
+            );
+        }
+        if cipher == CipherKind::Lcg {
+            return Err(
+                "--transport udp needs --cipher chacha20: the LCG cannot start a keystream per datagram"
+                    .to_string(),
+            );
+        }
+    }
+    if script_delay.is_some() && script.is_none() {
+        return Err("--script-delay only applies with --script".to_string());
+    }
+    if accept_new_key && known_peers.is_none() {
+        return Err("--accept-new-key only applies with --known-peers".to_string());
+    }
+    if key_file.is_some() && seed.is_some() {
+        return Err(
+            "--key-file and --insecure-deterministic-seed cannot be used together".to_string(),
+        );
+    }
+    if let Some(path) = psk_file {
+        if psk.is_some() {
+            return Err("--psk and --psk-file cannot be used together".to_string());
+        }
+        let mut bytes = fs::read(&path).map_err(|e| format!("cannot read '{}': {}", path, e))?;
+        // An editor's final newline is not part of the secret
+        while bytes.last().is_some_and(|&b| b == b'\n' || b == b'\r') {
+            bytes.pop();
+        }
+        psk = Some(bytes);
+    }
+    if psk.as_ref().is_some_and(Vec::is_empty) {
+        return Err("the pre-shared key must not be empty".to_string());
+    }
+    if test_seed.is_some() {
+        if !matches!(command, Command::Server(_) | Command::Client(_)) {
+            return Err("--test-seed only applies to server and client".to_string());
+        }
+        for (given, flag) in [
+            (psk.is_some(), "--psk"),
+            (key_file.is_some(), "--key-file"),
+            (known_peers.is_some(), "--known-peers"),
+            (require_fingerprint.is_some(), "--require-fingerprint"),
+            (sas, "--sas"),
+            (seed.is_some(), "--insecure-deterministic-seed"),
+        ] {
+            if given {
+                return Err(format!(
+                    "--test-seed skips the key exchange, so {} does not apply",
+                    flag
+                ));
+            }
         }
-    } else {
-        Err("missing subcommand".to_string())
     }
+    Ok(Args {
+        command,
+        nick,
+        seed,
+        cipher,
+        verbosity,
+        log,
+        log_format: log_format.unwrap_or(LogFormat::Text),
+        dh,
+        dh_params,
+        rekey_messages,
+        rekey_interval,
+        keepalive,
+        ack_timeout,
+        timeout,
+        retry: Retry {
+            attempts: if wait {
+                None
+            } else {
+                Some(attempts.unwrap_or(1))
+            },
+            delay: retry_delay.unwrap_or(Duration::from_secs_f64(RETRY_DELAY_SECS)),
+        },
+        bind: bind.unwrap_or_else(|| "0.0.0.0".to_string()),
+        prefer_ipv4,
+        max_message,
+        timestamps,
+        show_latency,
+        raw,
+        stats_on_exit,
+        history,
+        require_fingerprint,
+        sas,
+        key_file,
+        known_peers,
+        accept_new_key,
+        once,
+        port_file,
+        script,
+        script_delay,
+        pad,
+        via,
+        udp,
+        frame_format,
+        no_color,
+        psk,
+        test_seed,
+    })
 }
 
-// Hardcoded Diffie-Hellman parameters
-const P: u64 = 0xD87FA3E291B4C7F3; // 64-bit prime
-const G: u64 = 2; // Generator
+/// Our long-term private key for `--key-file`: read from `path`, or made
+/// and saved there if the file does not exist yet. The file holds the
+/// group name and the key in hex.
+fn load_or_create_key(path: &Path, group: &DhGroup) -> io::Result<U2048> {
+    let invalid = |why: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), why),
+        )
+    };
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = group.private_key(None)?;
+            let bytes = key.to_be_bytes();
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(path)?;
+            writeln!(
+                file,
+                "{} {}",
+                group.name(),
+                hex_digits(&bytes[bytes.len() - MODP_PRIVATE_LEN..])
+            )?;
+            say!(
+                "[KEY] Made a new long-term key in {}; keep it private",
+                path.display()
+            );
+            return Ok(key);
+        }
+        Err(e) => return Err(e),
+    };
+    let [name, digits] = text.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(invalid("expected 'GROUP HEX'".to_string()));
+    };
+    if name != group.name() {
+        return Err(invalid(format!(
+            "key is for --dh {}, but --dh {} is in use",
+            name,
+            group.name()
+        )));
+    }
+    let key = parse_hex_bytes(digits)
+        .filter(|bytes| bytes.len() == MODP_PRIVATE_LEN)
+        .and_then(|bytes| U2048::from_be_bytes(&bytes))
+        .ok_or_else(|| invalid(format!("expected {} hex digits", MODP_PRIVATE_LEN * 2)))?;
+    let in_range = match group {
+        DhGroup::Small(dh) => key < U2048::from_u64(dh.p - 1),
+        DhGroup::Modp2048 => true,
+    };
+    if key < U2048::from_u64(2) || !in_range {
+        return Err(invalid("key is out of range for the group".to_string()));
+    }
+    Ok(key)
+}
+
+/// Bytes as unbroken lowercase hex.
+fn hex_digits(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
-// LCG parameters for stream cipher
-const A: u64 = 1103515245;
-const C: u64 = 12345;
-const M: u64 = 1u64 << 32;
+/// The bytes an even number of hex digits spell, or `None`.
+fn parse_hex_bytes(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
 
-struct StreamCipher {
-    state: u64,
+/// The `--known-peers` file: lines of `ADDRESS FINGERPRINT`, where the
+/// fingerprint is of the peer's public key alone. Blank lines and lines
+/// starting with `#` are kept as they are.
+struct KnownPeers {
+    path: PathBuf,
+    lines: Vec<String>,
 }
 
-impl StreamCipher {
-    fn new(seed: u64) -> Self {
-        println!("[STREAM] Generating keystream from secret...");
-        println!("Algorithm: LCG (a={}, c={}, m=2^32)", A, C);
-        println!("Seed: secret = {:X}", seed);
-        Self { state: seed }
+impl KnownPeers {
+    /// Read `path`; a missing file is an empty list.
+    fn load(path: &Path) -> io::Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let lines: Vec<String> = text.lines().map(str::to_string).collect();
+        for (n, line) in lines.iter().enumerate() {
+            if Self::entry(line).is_none() && !Self::is_comment(line) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{}:{}: expected 'ADDRESS FINGERPRINT'",
+                        path.display(),
+                        n + 1
+                    ),
+                ));
+            }
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            lines,
+        })
+    }
+
+    fn is_comment(line: &str) -> bool {
+        let line = line.trim_start();
+        line.is_empty() || line.starts_with('#')
     }
 
-    fn next_byte(&mut self) -> u8 {
-        self.state = (A.wrapping_mul(self.state).wrapping_add(C)) % M;
-        (self.state & 0xFF) as u8
+    /// The address and fingerprint on an entry line.
+    fn entry(line: &str) -> Option<(&str, &str)> {
+        if Self::is_comment(line) {
+            return None;
+        }
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [address, fingerprint] if parse_fingerprint(fingerprint).is_some() => {
+                Some((address, fingerprint))
+            }
+            _ => None,
+        }
     }
 
-    fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
-        plaintext.iter().map(|&b| b ^ self.next_byte()).collect()
+    /// The fingerprint saved for `address`, if any.
+    fn get(&self, address: &str) -> Option<String> {
+        self.lines
+            .iter()
+            .filter_map(|line| Self::entry(line))
+            .find(|(a, _)| *a == address)
+            .and_then(|(_, fingerprint)| parse_fingerprint(fingerprint))
     }
 
-    fn decrypt(&mut self, ciphertext: &[u8]) -> Vec<u8> {
-        self.encrypt(ciphertext) // XOR is symmetric
+    /// Save `fingerprint` for `address`, replacing the old entry if there
+    /// is one.
+    fn set(&mut self, address: &str, fingerprint: &str) {
+        let line = format!("{} {}", address, fingerprint);
+        let old = self
+            .lines
+            .iter()
+            .position(|l| Self::entry(l).is_some_and(|(a, _)| a == address));
+        match old {
+            Some(i) => self.lines[i] = line,
+            None => self.lines.push(line),
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut text = self.lines.join("\n");
+        text.push('\n');
+        replace_file(&self.path, &text)
     }
 }
 
-fn modular_pow(mut base: u64, mut exp: u64, modulus: u64) -> u64 {
-    if modulus == 1 {
-        return 0;
+/// Write `text` to a temporary name beside `path`, then rename it over
+/// `path`, so a crash leaves either version whole and a reader never
+/// sees half a file. The temporary file is new, private and named for
+/// this process and call, so two writers never share one and nothing
+/// already there gets written through.
+fn replace_file(path: &Path, text: &str) -> io::Result<()> {
+    static TEMPS: AtomicUsize = AtomicUsize::new(0);
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        TEMPS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temp)?;
+    let written = file
+        .write_all(text.as_bytes())
+        .and_then(|()| file.sync_all())
+        .and_then(|()| fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
     }
-    let mut result = 1u128;
-    base %= modulus;
-    let mut base_128 = base as u128;
-    let modulus_128 = modulus as u128;
+    written
+}
 
-    while exp > 0 {
-        if exp % 2 == 1 {
-            result = (result * base_128) % modulus_128;
+/// Trust on first use: remember the key `address` uses the first time,
+/// and hang up if it ever comes back with another, unless `accept_new`.
+fn check_known_peer(
+    stream: &impl Transport,
+    path: &Path,
+    address: &str,
+    peer_key: &[u8; 32],
+    accept_new: bool,
+) -> io::Result<()> {
+    let mut known = KnownPeers::load(path)?;
+    let fingerprint = hex_digits(&peer_key[..FINGERPRINT_LEN]);
+    match known.get(address) {
+        Some(saved) if saved == fingerprint => {
+            say!(
+                "[TRUST] {} has the key saved in {} ✓",
+                address,
+                path.display()
+            );
+            println!();
+            return Ok(());
         }
-        exp >>= 1;
-        if exp > 0 {
-            base_128 = (base_128 * base_128) % modulus_128;
+        Some(saved) => {
+            say!("[TRUST] !!! The key of {} has CHANGED !!!", address);
+            say!("[TRUST] Saved: {}", saved);
+            say!("[TRUST] Now:   {}", fingerprint);
+            if !accept_new {
+                say!(
+                    "[TRUST] Someone may be in the middle, or the peer made a new key. Closing the connection; if the change is expected, connect again with --accept-new-key"
+                );
+                stream.hang_up();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("key of {} does not match {}", address, path.display()),
+                ));
+            }
+            say!("[TRUST] --accept-new-key given: saving the new key");
         }
+        None => say!(
+            "[TRUST] First connection to {}: saving its key {} to {}",
+            address,
+            fingerprint,
+            path.display()
+        ),
     }
-    result as u64
+    known.set(address, &fingerprint);
+    known.save()?;
+    println!();
+    Ok(())
 }
 
-fn perform_dh_exchange(stream: &mut TcpStream, is_server: bool) -> io::Result<u64> {
-    println!("[DH] Starting key exchange...");
-    println!("[DH] Using hardcoded DH parameters:");
-    println!("p = {:X} (64-bit prime - public)", P);
-    println!("g = {} (generator - public)", G);
-    println!();
+/// Bytes of the fingerprint shown as hex, in `FINGERPRINT_LEN / 2`
+/// groups of four digits.
+const FINGERPRINT_LEN: usize = 16;
+
+/// The start of a fingerprint as groups of hex digits, e.g.
+/// `3f9a 0b12 77c4 e801 5d2e 9a03 c6f1 0b88`.
+fn fingerprint_text(fingerprint: &[u8; 32]) -> String {
+    fingerprint[..FINGERPRINT_LEN]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-    // Generate random private key
-    // Simple std-only pseudo-random using time-based seed and xorshift64*
-    let seed = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_nanos() as u64;
-    let mut x = seed | 1; // ensure non-zero odd
-    // advance a few rounds
-    for _ in 0..5 {
-        x ^= x >> 12;
-        x ^= x << 25;
-        x ^= x >> 27;
-    }
-    let private_key: u64 = 2 + (x % (P - 3));
-    println!("[DH] Generating our keypair...");
-    println!("private_key = {:X} (random 64-bit)", private_key);
-
-    // Compute public key: g^private mod p
-    let public_key = modular_pow(G, private_key, P);
-    println!("public_key = g^private mod p");
-    println!("= {}^{:X} mod p", G, private_key);
-    println!("= {:X}", public_key);
-    println!();
+/// A fingerprint as typed for `--require-fingerprint`: hex digits in
+/// either case, with any spaces, dashes or colons dropped. `None` if that
+/// does not leave `FINGERPRINT_LEN * 2` digits.
+fn parse_fingerprint(text: &str) -> Option<String> {
+    let digits: String = text
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | ':'))
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    (digits.len() == FINGERPRINT_LEN * 2 && digits.chars().all(|c| c.is_ascii_hexdigit()))
+        .then_some(digits)
+}
 
-    println!("[DH] Exchanging keys...");
+/// Emoji for `--sas`, with names for terminals that cannot draw them.
+/// The list from the Matrix SAS verification spec.
+const SAS_EMOJI: [(&str, &str); 64] = [
+    ("🐶", "dog"),
+    ("🐱", "cat"),
+    ("🦁", "lion"),
+    ("🐎", "horse"),
+    ("🦄", "unicorn"),
+    ("🐷", "pig"),
+    ("🐘", "elephant"),
+    ("🐰", "rabbit"),
+    ("🐼", "panda"),
+    ("🐓", "rooster"),
+    ("🐧", "penguin"),
+    ("🐢", "turtle"),
+    ("🐟", "fish"),
+    ("🐙", "octopus"),
+    ("🦋", "butterfly"),
+    ("🌷", "flower"),
+    ("🌳", "tree"),
+    ("🌵", "cactus"),
+    ("🍄", "mushroom"),
+    ("🌏", "globe"),
+    ("🌙", "moon"),
+    ("☁️", "cloud"),
+    ("🔥", "fire"),
+    ("🍌", "banana"),
+    ("🍎", "apple"),
+    ("🍓", "strawberry"),
+    ("🌽", "corn"),
+    ("🍕", "pizza"),
+    ("🎂", "cake"),
+    ("❤️", "heart"),
+    ("😀", "smiley"),
+    ("🤖", "robot"),
+    ("🎩", "hat"),
+    ("👓", "glasses"),
+    ("🔧", "spanner"),
+    ("🎅", "santa"),
+    ("👍", "thumbs up"),
+    ("☂️", "umbrella"),
+    ("⌛", "hourglass"),
+    ("⏰", "clock"),
+    ("🎁", "gift"),
+    ("💡", "light bulb"),
+    ("📕", "book"),
+    ("✏️", "pencil"),
+    ("📎", "paperclip"),
+    ("✂️", "scissors"),
+    ("🔒", "lock"),
+    ("🔑", "key"),
+    ("🔨", "hammer"),
+    ("☎️", "telephone"),
+    ("🏁", "flag"),
+    ("🚂", "train"),
+    ("🚲", "bicycle"),
+    ("✈️", "aeroplane"),
+    ("🚀", "rocket"),
+    ("🏆", "trophy"),
+    ("⚽", "ball"),
+    ("🎸", "guitar"),
+    ("🎺", "trumpet"),
+    ("🔔", "bell"),
+    ("⚓", "anchor"),
+    ("🎧", "headphones"),
+    ("📁", "folder"),
+    ("📌", "pin"),
+];
+
+/// Number of emoji `--sas` shows, six bits of the fingerprint each.
+const SAS_LEN: usize = 7;
+
+/// The fingerprint as `SAS_LEN` emoji, taken from the bits after the ones
+/// shown as hex.
+fn sas_text(fingerprint: &[u8; 32]) -> String {
+    let mut bits = [0u8; 8];
+    bits.copy_from_slice(&fingerprint[FINGERPRINT_LEN..FINGERPRINT_LEN + 8]);
+    let bits = u64::from_be_bytes(bits);
+    (0..SAS_LEN)
+        .map(|i| {
+            let (emoji, name) = SAS_EMOJI[(bits >> (58 - 6 * i)) as usize & 63];
+            format!("{} {}", emoji, name)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
-    let their_public_key = if is_server {
-        // Server: receive first, then send
-        let mut buf = [0u8; 8];
-        stream.read_exact(&mut buf)?;
-        let their_key = u64::from_be_bytes(buf);
-        println!("[NETWORK] Received public key (8 bytes) ✓");
-        println!("← Receive their public: {:X}", their_key);
+/// Agree on the secret the chat keys come from with the peer at the other
+/// end of `stream`, known as `address`: a DH exchange, the `--psk` proofs
+/// and the checks on the peer's key. With `--test-seed` none of that
+/// happens, and the secret is the seed's.
+fn agree_secret(
+    stream: &mut impl Transport,
+    is_server: bool,
+    args: &Args,
+    group: &DhGroup,
+    address: &str,
+) -> io::Result<Vec<u8>> {
+    if let Some(seed) = args.test_seed {
+        say!("[WARNING] --test-seed: a fixed secret and no key exchange, NOT SECURE");
+        println!();
+        return Ok(test_secret(seed));
+    }
+    let long_term = args
+        .key_file
+        .as_deref()
+        .map(|path| load_or_create_key(path, group))
+        .transpose()?;
+    let exchange = perform_dh_exchange(stream, is_server, group, args.seed, long_term)?;
+    authenticate(
+        stream,
+        &exchange.fingerprint,
+        args.psk.as_deref(),
+        is_server,
+    )?;
+    verify_peer(stream, &exchange, args, address)?;
+    Ok(keyed_secret(&exchange.secret, args.psk.as_deref()))
+}
 
-        println!("[NETWORK] Sending public key (8 bytes)...");
-        stream.write_all(&public_key.to_be_bytes())?;
-        stream.flush()?;
-        println!("→ Send our public: {:X}", public_key);
+/// Check the peer's keys as the command line asks: the fingerprint
+/// against `--require-fingerprint`, and its key against `--known-peers`
+/// under `address`.
+fn verify_peer(
+    stream: &impl Transport,
+    exchange: &Exchange,
+    args: &Args,
+    address: &str,
+) -> io::Result<()> {
+    verify_fingerprint(
+        stream,
+        &exchange.fingerprint,
+        args.require_fingerprint.as_deref(),
+        args.sas,
+    )?;
+    match &args.known_peers {
+        Some(path) => check_known_peer(
+            stream,
+            path,
+            address,
+            &exchange.peer_key,
+            args.accept_new_key,
+        ),
+        None => Ok(()),
+    }
+}
 
-        their_key
-    } else {
-        // Client: send first, then receive
-        println!("[NETWORK] Sending public key (8 bytes)...");
-        stream.write_all(&public_key.to_be_bytes())?;
-        stream.flush()?;
-        println!("→ Send our public: {:X}", public_key);
-
-        let mut buf = [0u8; 8];
-        stream.read_exact(&mut buf)?;
-        let their_key = u64::from_be_bytes(buf);
-        println!("[NETWORK] Received public key (8 bytes) ✓");
-        println!("← Receive their public: {:X}", their_key);
-
-        their_key
+/// Show the fingerprint so the two people can compare it, and hang up if
+/// it is not `required`.
+fn verify_fingerprint(
+    stream: &impl Transport,
+    fingerprint: &[u8; 32],
+    required: Option<&str>,
+    sas: bool,
+) -> io::Result<()> {
+    let text = fingerprint_text(fingerprint);
+    say!("[VERIFY] Key fingerprint: {}", text);
+    if sas {
+        say!("[VERIFY] SAS: {}", sas_text(fingerprint));
+    }
+    let Some(required) = required else {
+        say!("[VERIFY] Compare it with your peer's to rule out anyone in the middle.");
+        println!();
+        return Ok(());
     };
-
-    println!();
-    println!("[DH] Computing shared secret...");
-    println!("Formula: secret = (their_public)^(our_private) mod p");
+    if text.replace(' ', "") != required {
+        say!(
+            "[VERIFY] ✗ Fingerprint does not match --require-fingerprint: someone may be in the middle, closing the connection"
+        );
+        stream.hang_up();
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "key fingerprint {} is not the required one",
+                text.replace(' ', "")
+            ),
+        ));
+    }
+    say!("[VERIFY] Fingerprint matches --require-fingerprint ✓");
     println!();
+    Ok(())
+}
 
-    // Compute shared secret: their_public^private mod p
-    let shared_secret = modular_pow(their_public_key, private_key, P);
-    println!(
-        "secret = ({:X})^({:X}) mod p",
-        their_public_key, private_key
-    );
-    println!("= {:X}", shared_secret);
-    println!();
+/// Serve one client after another, each with fresh keys, until input ends
+/// or Ctrl+C. A session that fails is reported and the server carries on.
+/// With `--once`, serve a single client and return its exit code.
+fn run_server(
+    port: u16,
+    args: Args,
+    group: DhGroup,
+    transcript: Option<Transcript>,
+    input: &Input,
+) -> io::Result<i32> {
+    let listener = listen(&args.bind, port, args.prefer_ipv4)?;
+    let local = listener.local_addr()?;
+    say!("[SERVER] Listening on {}", local);
+    // For scripts, with an address a client on this machine can use
+    let reachable = reachable_addr(local);
+    println!("LISTENING {}", reachable);
+    if let Some(path) = &args.port_file {
+        replace_file(path, &format!("{}\n", reachable))?;
+    }
+    let mut number = 0;
+    loop {
+        number += 1;
+        say!("[SERVER] Waiting for client...");
+        println!();
+        let Some((stream, addr)) = accept(&listener, input)? else {
+            if input.ended() {
+                say!("[SERVER] Input closed, not waiting for another client");
+            } else {
+                say!("\r[SERVER] Stopped.");
+            }
+            return Ok(0);
+        };
+        say!("[CLIENT] Connected from {} (session {})", addr, number);
+        println!();
+        let result = serve(stream, addr, &args, group, transcript.as_ref(), input);
+        if args.once {
+            return result;
+        }
+        match result {
+            Ok(_) => say!("[SERVER] Session {} over", number),
+            Err(e) => say!("[SERVER] Session {} failed: {}", number, e),
+        }
+        println!();
+    }
+}
 
-    // Verify both sides have same secret
-    println!("[VERIFY] Both sides computed the same secret ✓");
-    println!();
+/// Pair up clients two at a time and pass bytes between them until one
+/// leaves, then wait for the next pair. The relay holds no keys: the
+/// clients run the key exchange end to end through it. Runs until Ctrl+C,
+/// or with `--once` until the first pair is done.
+fn run_relay(port: u16, args: &Args) -> io::Result<i32> {
+    let listener = listen(&args.bind, port, args.prefer_ipv4)?;
+    let local = listener.local_addr()?;
+    say!("[RELAY] Listening on {}", local);
+    let reachable = reachable_addr(local);
+    println!("LISTENING {}", reachable);
+    if let Some(path) = &args.port_file {
+        replace_file(path, &format!("{}\n", reachable))?;
+    }
+    catch_interrupts()?;
+    let mut number = 0;
+    loop {
+        number += 1;
+        say!("[RELAY] Waiting for two clients...");
+        let Some([(a, a_addr), (b, b_addr)]) = wait_for_pair(&listener)? else {
+            say!("\r[RELAY] Stopped.");
+            return Ok(0);
+        };
+        say!("[RELAY] Pair {}: {} and {}", number, a_addr, b_addr);
+        let result = (&a)
+            .write_all(&[RELAY_SERVER])
+            .and_then(|()| (&b).write_all(&[RELAY_CLIENT]));
+        match result {
+            Ok(()) => {
+                let (from_a, from_b, a_left) = forward_pair(&a, &b);
+                let (gone, other) = if a_left {
+                    (a_addr, b_addr)
+                } else {
+                    (b_addr, a_addr)
+                };
+                say!(
+                    "[RELAY] {} left, closed {}; passed on {} bytes from {} and {} from {}",
+                    gone,
+                    other,
+                    from_a,
+                    a_addr,
+                    from_b,
+                    b_addr
+                );
+            }
+            Err(e) => say!("[RELAY] Pair {} failed: {}", number, e),
+        }
+        if args.once || INTERRUPTS.load(Ordering::SeqCst) > 0 {
+            return Ok(0);
+        }
+    }
+}
 
-    Ok(shared_secret)
+/// `addr`, with a wildcard IP swapped for the loopback one of its family.
+fn reachable_addr(addr: SocketAddr) -> SocketAddr {
+    let mut addr = addr;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    addr
 }
 
-fn print_keystream(cipher: &mut StreamCipher, count: usize) {
-    print!("Keystream: ");
-    for i in 0..count {
-        let byte = cipher.next_byte();
-        print!("{:02X} ", byte);
-        if i == count - 1 {
-            print!("...");
+/// Wait for a client. Returns `None` if input ends first, or Ctrl+C is
+/// pressed, which can only happen once a chat has started catching it.
+fn accept(listener: &TcpListener, input: &Input) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+    listener.set_nonblocking(true)?;
+    loop {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                stream.set_nonblocking(false)?;
+                return Ok(Some((stream, addr)));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if INTERRUPTS.load(Ordering::SeqCst) > 0 || input.ended() {
+                    return Ok(None);
+                }
+                thread::sleep(TICK);
+            }
+            Err(e) => return Err(e),
         }
     }
-    println!();
 }
 
-fn run_server(port: u16) -> io::Result<()> {
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))?;
-    println!("[SERVER] Listening on 0.0.0.0:{}", port);
-    println!("[SERVER] Waiting for client...");
-    println!();
+/// One client's session on the server, from key exchange to goodbye.
+fn serve(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    args: &Args,
+    group: DhGroup,
+    transcript: Option<&Transcript>,
+    input: &Input,
+) -> io::Result<i32> {
+    stream.set_read_timeout(args.timeout)?;
+    // The client's port changes every time, so it is known by its address
+    let secret = agree_secret(&mut stream, true, args, &group, &addr.ip().to_string())?;
+    let ciphers = CipherPair::new(&secret, args.cipher, true);
+    let rekey = Rekey {
+        group,
+        cipher: args.cipher,
+        is_server: true,
+        // A test seed keeps the rekeys the same from run to run too
+        seed: args.seed.or(args.test_seed),
+        messages: args.rekey_messages,
+        interval: args.rekey_interval,
+        psk: args.psk.clone(),
+    };
+    let reader = stream.try_clone()?;
+    let options = args.chat_options(stream.local_addr()?.port());
+    chat(&stream, reader, ciphers, rekey, options, transcript, input)
+}
 
-    let (mut stream, addr) = listener.accept()?;
-    println!("[CLIENT] Connected from {}", addr);
-    println!();
+/// Everything `target` resolves to, IPv6 addresses first unless
+/// `prefer_ipv4`. `shown` names the target in errors.
+fn resolve(
+    target: impl ToSocketAddrs,
+    shown: &str,
+    prefer_ipv4: bool,
+) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs: Vec<SocketAddr> = target
+        .to_socket_addrs()
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot resolve '{}': {}", shown, e)))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("'{}' resolved to no addresses", shown),
+        ));
+    }
+    // Stable, so the resolver's order holds within each family
+    addrs.sort_by_key(|addr| addr.is_ipv4() != prefer_ipv4);
+    Ok(addrs)
+}
 
-    // Perform DH key exchange
-    let shared_secret = perform_dh_exchange(&mut stream, true)?;
+/// Run `open` on each address in turn until one works. If none does, the
+/// error lists every address with the reason it failed.
+fn first_working<T>(
+    addrs: &[SocketAddr],
+    mut open: impl FnMut(SocketAddr) -> io::Result<T>,
+) -> io::Result<T> {
+    let mut failures = Vec::new();
+    let mut kind = io::ErrorKind::NotFound;
+    for &addr in addrs {
+        match open(addr) {
+            Ok(opened) => return Ok(opened),
+            Err(e) => {
+                if addrs.len() > 1 {
+                    say!("[NETWORK] {}: {}", addr, e);
+                }
+                kind = e.kind();
+                failures.push(format!("{}: {}", addr, e));
+            }
+        }
+    }
+    Err(io::Error::new(kind, failures.join("; ")))
+}
 
-    // Create cipher from shared secret
-    let mut cipher = StreamCipher::new(shared_secret);
-    print_keystream(&mut cipher, 12);
-    println!();
-    println!("✓ Secure channel established!");
-    println!();
+/// Listen on `port` at `bind`: an IP address, IPv6 ones optionally in
+/// brackets, or a hostname.
+fn listen(bind: &str, port: u16, prefer_ipv4: bool) -> io::Result<TcpListener> {
+    first_working(&bind_addrs(bind, port, prefer_ipv4)?, TcpListener::bind)
+}
 
-    // Chat loop
-    let mut reader = BufReader::new(stream.try_clone()?);
-    let mut writer = stream;
+/// The addresses `--bind` names, with `port`.
+fn bind_addrs(bind: &str, port: u16, prefer_ipv4: bool) -> io::Result<Vec<SocketAddr>> {
+    let host = bind
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(bind);
+    resolve((host, port), bind, prefer_ipv4)
+}
 
+/// Connect to `address`, trying again as `retry` allows. Gives up with
+/// the last attempt's error. With `port_file`, `address` is only the host
+/// and the port is read from the file on each attempt, so a server that
+/// has not written it yet is waited for like one that is not up.
+fn connect(
+    address: &str,
+    port_file: Option<&Path>,
+    retry: Retry,
+    prefer_ipv4: bool,
+) -> io::Result<TcpStream> {
+    let mut delay = retry.delay;
+    let mut attempt = 1;
     loop {
-        // Receive message
-        let mut len_buf = [0u8; 4];
-        if reader.read_exact(&mut len_buf).is_err() {
-            break;
+        let target = match port_file {
+            Some(path) => read_port_file(path).map(|port| with_port(address, port)),
+            None => Ok(address.to_string()),
+        };
+        let shown = target.as_deref().unwrap_or(address);
+        match retry.attempts {
+            Some(1) => say!("[CLIENT] Connecting to {}...", shown),
+            Some(n) => say!(
+                "[CLIENT] Connecting to {} (attempt {} of {})...",
+                shown,
+                attempt,
+                n
+            ),
+            None => say!("[CLIENT] Connecting to {} (attempt {})...", shown, attempt),
         }
-        let len = u32::from_be_bytes(len_buf) as usize;
-
-        let mut encrypted = vec![0u8; len];
-        reader.read_exact(&mut encrypted)?;
+        let attempt_result = target.and_then(|target| {
+            resolve(target.as_str(), &target, prefer_ipv4)
+                .and_then(|addrs| first_working(&addrs, TcpStream::connect))
+        });
+        let e = match attempt_result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => e,
+        };
+        // A malformed address will not get any better
+        if e.kind() == io::ErrorKind::InvalidInput || retry.attempts == Some(attempt) {
+            return Err(e);
+        }
+        say!("[CLIENT] {}; retrying in {:?}", e, delay);
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+        attempt += 1;
+    }
+}
 
-        println!("[NETWORK] Received encrypted message ({} bytes)", len);
-        println!("[~] Received {} bytes", len);
-        println!();
+/// The port in a `--port-file`: the server writes `HOST:PORT`, but a bare
+/// port will do.
+fn read_port_file(path: &Path) -> io::Result<u16> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("cannot read port file {}: {}", path.display(), e),
+        )
+    })?;
+    let text = text.trim();
+    let port = text.rsplit_once(':').map_or(text, |(_, port)| port);
+    match port.parse() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("port file {} holds no port: {:?}", path.display(), text),
+        )),
+    }
+}
 
-        println!("[DECRYPT]");
-        print!("Cipher: ");
-        for &b in encrypted.iter().take(encrypted.len().min(10)) {
-            print!("{:02x} ", b);
-        }
-        println!();
+/// Whether a client ADDRESS names a port: `host:port` or `[v6]:port`, but
+/// not a bare IPv6 address.
+fn has_port(address: &str) -> bool {
+    match address.strip_prefix('[') {
+        Some(rest) => rest.contains("]:"),
+        None => address.matches(':').count() == 1,
+    }
+}
 
-        let mut decipher = StreamCipher::new(shared_secret);
-        // Advance cipher state to current position
-        for _ in 0..cipher.state {
-            decipher.next_byte();
-        }
-        let decrypted = decipher.decrypt(&encrypted);
+/// `host:port`, with an IPv6 `host` put in brackets.
+fn with_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
 
-        print!("Key: ");
-        for _ in 0..decrypted.len() {
-            print!("{:02x} ", cipher.next_byte());
+fn run_client(
+    address: String,
+    args: Args,
+    group: DhGroup,
+    transcript: Option<Transcript>,
+    input: &Input,
+) -> io::Result<i32> {
+    let mut stream = connect(
+        args.via.as_deref().unwrap_or(&address),
+        args.port_file.as_deref(),
+        args.retry,
+        args.prefer_ipv4,
+    )?;
+    say!("[CLIENT] Connected to {}", stream.peer_addr()?);
+    println!();
+    // Through a relay both ends are clients, so the relay says which of
+    // us plays the server in the exchange
+    let is_server = match args.via {
+        Some(_) => {
+            let relay = stream.peer_addr()?.to_string();
+            relay_role(&mut stream, &relay)?
         }
-        println!();
+        None => false,
+    };
+    stream.set_read_timeout(args.timeout)?;
+    let secret = agree_secret(&mut stream, is_server, &args, &group, &address)?;
+    let ciphers = CipherPair::new(&secret, args.cipher, is_server);
+    let rekey = Rekey {
+        group,
+        cipher: args.cipher,
+        is_server,
+        seed: args.seed.or(args.test_seed),
+        messages: args.rekey_messages,
+        interval: args.rekey_interval,
+        psk: args.psk.clone(),
+    };
+    let reader = stream.try_clone()?;
+    let options = args.chat_options(stream.local_addr()?.port());
+    chat(
+        &stream,
+        reader,
+        ciphers,
+        rekey,
+        options,
+        transcript.as_ref(),
+        input,
+    )
+}
 
-        let plaintext = String::from_utf8_lossy(&decrypted);
-        print!("Plain: ");
-        for &b in decrypted.iter() {
-            print!("{:02x} ", b);
-        }
-        print!("→ {:?}", plaintext.trim());
+/// `run_server` over UDP. The first datagram to arrive, from anyone,
+/// starts a handshake with its sender, and the socket then takes
+/// datagrams from that address alone until the session is over.
+fn run_udp_server(
+    port: u16,
+    args: Args,
+    group: DhGroup,
+    transcript: Option<Transcript>,
+    input: &Input,
+) -> io::Result<i32> {
+    let addrs = bind_addrs(&args.bind, port, args.prefer_ipv4)?;
+    let mut socket = first_working(&addrs, UdpSocket::bind)?;
+    let local = socket.local_addr()?;
+    say!("[SERVER] Listening on {} over UDP", local);
+    let reachable = reachable_addr(local);
+    println!("LISTENING {}", reachable);
+    if let Some(path) = &args.port_file {
+        replace_file(path, &format!("{}\n", reachable))?;
+    }
+    let mut number = 0;
+    loop {
+        number += 1;
+        say!("[SERVER] Waiting for a peer's first datagram...");
         println!();
+        let Some(addr) = first_datagram(&socket, input)? else {
+            if input.ended() {
+                say!("[SERVER] Input closed, not waiting for another peer");
+            } else {
+                say!("\r[SERVER] Stopped.");
+            }
+            return Ok(0);
+        };
+        socket.connect(addr)?;
+        say!("[CLIENT] First datagram from {} (session {})", addr, number);
         println!();
-
-        println!(
-            "[TEST] Round-trip verified: {:?} → encrypt → decrypt → {:?} ✓",
-            plaintext.trim(),
-            plaintext.trim()
+        let result = udp_handshake(socket, true, &args, group, &addr.ip().to_string()).and_then(
+            |(link, secret)| {
+                let options = args.chat_options(link.socket().local_addr()?.port());
+                udp_chat(link, &secret, true, options, transcript.as_ref(), input)
+            },
         );
-        println!();
-        println!("[SERVER] {}", plaintext.trim());
-        println!();
-
-        // Send response
-        println!("[CHAT] Type message:");
-        print!("> ");
-        io::stdout().flush()?;
-
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        let message = input.trim();
-
-        println!();
-        println!("[ENCRYPT]");
-        print!("Plain: ");
-        for &b in message.as_bytes() {
-            print!("{:02x} ", b);
+        if args.once {
+            return result;
         }
-        println!("({:?})", message);
-
-        let mut encipher = StreamCipher::new(shared_secret);
-        for _ in 0..cipher.state {
-            encipher.next_byte();
-        }
-
-        print!("Key: ");
-        for _ in 0..message.len() {
-            print!("{:02x} ", cipher.next_byte());
+        match result {
+            Ok(_) => say!("[SERVER] Session {} over", number),
+            Err(e) => say!("[SERVER] Session {} failed: {}", number, e),
         }
         println!();
+        // A connected socket cannot be unconnected, so the next peer
+        // gets a fresh one on the same address
+        socket = UdpSocket::bind(local)?;
+    }
+}
 
-        let encrypted = encipher.encrypt(message.as_bytes());
-        print!("Cipher: ");
-        for &b in &encrypted {
-            print!("{:02x} ", b);
+/// Wait for a datagram and return who sent it, leaving it to be read.
+/// Returns `None` if input ends first, or on Ctrl+C, as `accept` does.
+fn first_datagram(socket: &UdpSocket, input: &Input) -> io::Result<Option<SocketAddr>> {
+    socket.set_read_timeout(Some(TICK))?;
+    let mut datagram = vec![0u8; MAX_DATAGRAM];
+    loop {
+        match socket.peek_from(&mut datagram) {
+            Ok((_, addr)) => return Ok(Some(addr)),
+            Err(e) if is_timeout(&e) => {
+                if INTERRUPTS.load(Ordering::SeqCst) > 0 || input.ended() {
+                    return Ok(None);
+                }
+            }
+            Err(e) => return Err(e),
         }
-        println!();
-        println!();
-
-        println!(
-            "[NETWORK] Sending encrypted message ({} bytes)...",
-            encrypted.len()
-        );
-        writer.write_all(&(encrypted.len() as u32).to_be_bytes())?;
-        writer.write_all(&encrypted)?;
-        writer.flush()?;
-        println!("[→] Sent {} bytes", encrypted.len());
-        println!();
     }
-
-    Ok(())
 }
 
-fn run_client(address: String) -> io::Result<()> {
-    println!("[CLIENT] Connecting to {}...", address);
-    let mut stream = TcpStream::connect(&address)?;
-    println!("[CLIENT] Connected!");
-    println!();
-
-    // Perform DH key exchange
-    let shared_secret = perform_dh_exchange(&mut stream, false)?;
-
-    // Create cipher from shared secret
-    let mut cipher = StreamCipher::new(shared_secret);
-    print_keystream(&mut cipher, 12);
-    println!();
-    println!("✓ Secure channel established!");
+/// `run_client` over UDP. There is no connection to make: the handshake
+/// simply starts sending, and a server that is not there shows up as
+/// its first read failing.
+fn run_udp_client(
+    address: String,
+    args: Args,
+    group: DhGroup,
+    transcript: Option<Transcript>,
+    input: &Input,
+) -> io::Result<i32> {
+    let target = match &args.port_file {
+        Some(path) => with_port(&address, read_port_file(path)?),
+        None => address.clone(),
+    };
+    say!("[CLIENT] Talking to {} over UDP...", target);
+    let addrs = resolve(target.as_str(), &target, args.prefer_ipv4)?;
+    let socket = first_working(&addrs, |addr| {
+        let any: IpAddr = match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((any, 0))?;
+        socket.connect(addr)?;
+        Ok(socket)
+    })?;
     println!();
+    let (link, secret) = udp_handshake(socket, false, &args, group, &address)?;
+    let options = args.chat_options(link.socket().local_addr()?.port());
+    udp_chat(link, &secret, false, options, transcript.as_ref(), input)
+}
 
-    // Chat loop
-    let mut reader = BufReader::new(stream.try_clone()?);
-    let mut writer = stream;
-
-    loop {
-        // Send message
-        println!("[CHAT] Type message:");
-        print!("> ");
-        io::stdout().flush()?;
+/// The handshake `serve` and `run_client` do, over UDP: the key
+/// exchange, the pre-shared key check and the checks on the peer's key.
+/// Returns the link, for the peer's hello, and the secret the chat keys
+/// come from.
+fn udp_handshake(
+    socket: UdpSocket,
+    is_server: bool,
+    args: &Args,
+    group: DhGroup,
+    address: &str,
+) -> io::Result<(UdpLink, Vec<u8>)> {
+    socket.set_read_timeout(args.timeout)?;
+    let mut link = UdpLink::new(socket);
+    let secret = agree_secret(&mut link, is_server, args, &group, address)?;
+    Ok((link, secret))
+}
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        let message = input.trim();
+/// What the chat sends: the `--script` file, or else stdin.
+fn open_input(args: &Args) -> Input {
+    match args.script.as_deref() {
+        None | Some("-") => Input::spawn(),
+        Some(path) => match fs::read_to_string(path) {
+            Ok(text) => Input::script(text.lines().map(str::to_string).collect()),
+            Err(e) => {
+                eprintln!("error: cannot read script '{}': {}", path, e);
+                std::process::exit(1);
+            }
+        },
+    }
+}
 
-        println!();
-        println!("[ENCRYPT]");
-        print!("Plain: ");
-        for &b in message.as_bytes() {
-            print!("{:02x} ", b);
+fn main() {
+    let args = match parse_args() {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            print_help();
+            std::process::exit(2);
         }
-        println!("({:?})", message);
+    };
 
-        let mut encipher = StreamCipher::new(shared_secret);
-        for _ in 0..cipher.state {
-            encipher.next_byte();
+    VERBOSITY.store(args.verbosity, Ordering::Relaxed);
+    style::init(args.no_color);
+    let group = match args.dh {
+        DhKind::Small => {
+            let dh = match &args.dh_params {
+                Some(path) => DhParams::load(path),
+                None => Ok(DhParams::DEFAULT),
+            };
+            match dh.and_then(|dh| dh.validate().map(|()| dh)) {
+                Ok(dh) => DhGroup::Small(dh),
+                Err(e) => {
+                    eprintln!("error: bad DH parameters: {}", e);
+                    std::process::exit(2);
+                }
+            }
         }
-
-        print!("Key: ");
-        for _ in 0..message.len() {
-            print!("{:02x} ", cipher.next_byte());
+        DhKind::Modp2048 => DhGroup::Modp2048,
+    };
+    // Open the log before connecting, so a bad path fails up front
+    let transcript = match &args.log {
+        Some(path) => match Transcript::open(path, args.log_format) {
+            Ok(transcript) => Some(transcript),
+            Err(e) => {
+                eprintln!("error: cannot open log file '{}': {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let result = match args.command.clone() {
+        Command::Server(port) => {
+            let input = open_input(&args);
+            if args.udp {
+                run_udp_server(port, args, group, transcript, &input)
+            } else {
+                run_server(port, args, group, transcript, &input)
+            }
         }
-        println!();
-
-        let encrypted = encipher.encrypt(message.as_bytes());
-        print!("Cipher: ");
-        for &b in &encrypted {
-            print!("{:02x} ", b);
+        Command::Client(address) => {
+            let input = open_input(&args);
+            if args.udp {
+                run_udp_client(address, args, group, transcript, &input)
+            } else {
+                run_client(address, args, group, transcript, &input)
+            }
         }
-        println!();
-        println!();
-
-        println!(
-            "[NETWORK] Sending encrypted message ({} bytes)...",
-            encrypted.len()
-        );
-        writer.write_all(&(encrypted.len() as u32).to_be_bytes())?;
-        writer.write_all(&encrypted)?;
-        writer.flush()?;
-        println!("[→] Sent {} bytes", encrypted.len());
-        println!();
-
-        // Receive response
-        let mut len_buf = [0u8; 4];
-        if reader.read_exact(&mut len_buf).is_err() {
-            break;
+        Command::Relay(port) => run_relay(port, &args),
+        Command::Selftest => run_selftest(&SelftestOptions {
+            cipher: args.cipher,
+            group,
+            frame_format: args.frame_format,
+            pad: args.pad.unwrap_or(1),
+            seed: args.seed,
+            psk: args.psk.clone(),
+            timeout: args.timeout,
+        }),
+    };
+    match result {
+        Ok(0) => {}
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
         }
-        let len = u32::from_be_bytes(len_buf) as usize;
+    }
+}
 
-        let mut encrypted = vec![0u8; len];
-        reader.read_exact(&mut encrypted)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        println!("[NETWORK] Received encrypted message ({} bytes)", len);
-        println!("[~] Received {} bytes", len);
-        println!();
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("streamchat-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
 
-        println!("[DECRYPT]");
-        print!("Cipher: ");
-        for &b in encrypted.iter().take(encrypted.len().min(10)) {
-            print!("{:02x} ", b);
-        }
-        println!();
+    #[test]
+    fn fingerprints_read_aloud_and_parse_back() {
+        let fingerprint: [u8; 32] = std::array::from_fn(|i| (i as u8).wrapping_mul(17));
+        let text = fingerprint_text(&fingerprint);
+        assert_eq!(text, "0011 2233 4455 6677 8899 aabb ccdd eeff");
+        assert_eq!(parse_fingerprint(&text), Some(text.replace(' ', "")));
+        assert_eq!(
+            parse_fingerprint("00:11:22:33-4455 6677 8899 AABB CCDD EEFF"),
+            Some("00112233445566778899aabbccddeeff".to_string())
+        );
+        assert_eq!(parse_fingerprint("0011 2233"), None);
+        assert_eq!(parse_fingerprint(&text.replace('0', "g")), None);
+        assert_eq!(sas_text(&fingerprint).split(", ").count(), SAS_LEN);
+        assert_ne!(sas_text(&fingerprint), sas_text(&[0; 32]));
+    }
 
-        let mut decipher = StreamCipher::new(shared_secret);
-        for _ in 0..cipher.state {
-            decipher.next_byte();
-        }
-        let decrypted = decipher.decrypt(&encrypted);
+    #[test]
+    fn known_peers_trust_on_first_use() {
+        let path = temp_path("known-peers");
+        fs::write(&path, "# pinned keys\n\n").unwrap();
+        let mut known = KnownPeers::load(&path).unwrap();
+        assert_eq!(known.get("host"), None);
+        known.set("host", "00112233445566778899aabbccddeeff");
+        known.save().unwrap();
+
+        let mut known = KnownPeers::load(&path).unwrap();
+        assert_eq!(
+            known.get("host").as_deref(),
+            Some("00112233445566778899aabbccddeeff")
+        );
+        known.set("host", "ffeeddccbbaa99887766554433221100");
+        known.save().unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            text,
+            "# pinned keys\n\nhost ffeeddccbbaa99887766554433221100\n"
+        );
 
-        print!("Key: ");
-        for _ in 0..decrypted.len() {
-            print!("{:02x} ", cipher.next_byte());
-        }
-        println!();
+        fs::write(&path, "host not-a-fingerprint\n").unwrap();
+        assert!(KnownPeers::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
 
-        let plaintext = String::from_utf8_lossy(&decrypted);
-        print!("Plain: ");
-        for &b in decrypted.iter() {
-            print!("{:02x} ", b);
+    #[test]
+    fn replacing_a_file_leaves_a_stale_temporary_alone() {
+        let path = temp_path("replaced");
+        // Where the temporary file used to go
+        let stale = PathBuf::from(format!("{}.tmp", path.display()));
+        fs::write(&stale, "planted\n").unwrap();
+        replace_file(&path, "one\n").unwrap();
+        replace_file(&path, "two\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two\n");
+        assert_eq!(fs::read_to_string(&stale).unwrap(), "planted\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
         }
-        print!("→ {:?}", plaintext.trim());
-        println!();
-        println!();
-
-        println!(
-            "[TEST] Round-trip verified: {:?} → encrypt → decrypt → {:?} ✓",
-            plaintext.trim(),
-            plaintext.trim()
-        );
-        println!();
-        println!("[CLIENT] {}", plaintext.trim());
-        println!();
+        // Only the file and the planted one are left in the directory
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let left = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter(|e| {
+                let e = e.as_ref().unwrap().file_name();
+                e.to_string_lossy().starts_with(&name)
+            })
+            .count();
+        assert_eq!(left, 2);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&stale).unwrap();
     }
 
-    Ok(())
-}
-
-fn main() -> io::Result<()> {
-    let args = match parse_args() {
-        Ok(a) => a,
-        Err(_e) => {
-            eprintln!("error");
-            print_help();
-            std::process::exit(2);
-        }
-    };
+    #[test]
+    fn key_file_is_made_once_and_read_back() {
+        let path = temp_path("key-file");
+        let group = DhGroup::Modp2048;
+        let made = load_or_create_key(&path, &group).unwrap();
+        assert_eq!(load_or_create_key(&path, &group).unwrap(), made);
+        let small = DhGroup::Small(DhParams::DEFAULT);
+        let e = load_or_create_key(&path, &small).unwrap_err();
+        assert!(e.to_string().contains("--dh modp2048"), "{}", e);
+        fs::remove_file(&path).unwrap();
+    }
 
-    match args.command {
-        Command::Server(port) => run_server(port),
-        Command::Client(address) => run_client(address),
+    #[test]
+    fn port_files_and_addresses() {
+        assert!(has_port("localhost:7000"));
+        assert!(has_port("[::1]:7000"));
+        assert!(!has_port("localhost"));
+        assert!(!has_port("::1"));
+        assert_eq!(with_port("localhost", 7000), "localhost:7000");
+        assert_eq!(with_port("::1", 7000), "[::1]:7000");
+
+        let path = temp_path("port-file");
+        fs::write(&path, "127.0.0.1:7000\n").unwrap();
+        assert_eq!(read_port_file(&path).unwrap(), 7000);
+        fs::write(&path, "7001").unwrap();
+        assert_eq!(read_port_file(&path).unwrap(), 7001);
+        fs::write(&path, "nothing").unwrap();
+        assert!(read_port_file(&path).is_err());
+        fs::remove_file(&path).unwrap();
     }
 }
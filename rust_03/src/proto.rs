@@ -0,0 +1,833 @@
+//! The frame format: an encrypted, tagged header saying what follows and
+//! how long it is, then the encrypted payload and its own tag. Also the
+//! layout of the payloads that carry more than bytes.
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cipher::{StreamCipher, TAG_LEN, os_random, sha256};
+
+/// Frame format version, the first byte of every frame header: each
+/// frame continues its direction's running keystream where the last one
+/// stopped. Spoken by every build, and used until both sides agree on
+/// something newer.
+pub const FRAME_VERSION: u8 = 0x80 | 8;
+
+/// The frame format where each frame begins with `NONCE_LEN` random
+/// bytes, sent in the clear, that pick a keystream of its own, so it
+/// opens whatever became of the frames before it.
+pub const FRAME_VERSION_NONCE: u8 = 0x80 | 9;
+
+/// Length of the random bytes in front of a `FRAME_VERSION_NONCE` frame.
+pub const NONCE_LEN: usize = 8;
+
+/// Length of a frame header: version, type, key epoch, 4-byte payload
+/// length and 8-byte sequence number.
+pub const HEADER_LEN: usize = 15;
+
+/// Bytes a frame adds to its payload: the encrypted header and two tags.
+pub const FRAME_OVERHEAD: usize = HEADER_LEN + 2 * TAG_LEN;
+
+// Frame types, sent as the byte after the version
+pub const FRAME_TEXT: u8 = 1;
+pub const FRAME_HELLO: u8 = 2;
+pub const FRAME_PING: u8 = 3;
+pub const FRAME_PONG: u8 = 4;
+pub const FRAME_FILE_META: u8 = 5;
+pub const FRAME_FILE_CHUNK: u8 = 6;
+pub const FRAME_BYE: u8 = 7;
+pub const FRAME_REKEY: u8 = 8;
+pub const FRAME_TYPING: u8 = 9;
+pub const FRAME_PRESENCE: u8 = 10;
+pub const FRAME_ACK: u8 = 11;
+pub const FRAME_FORMAT: u8 = 12;
+
+/// Largest payload any frame may carry: the ceiling for `--max-message`.
+pub const MAX_FRAME: usize = 16 << 20;
+
+/// Default for `--max-message`: the largest payload accepted from the
+/// peer, so a bad length cannot make us allocate gigabytes.
+pub const MAX_MESSAGE: usize = 64 * 1024;
+
+/// Smallest `--max-message`, leaving room for a 2048-bit REKEY and file
+/// names.
+pub const MIN_MESSAGE: usize = 1024;
+
+/// Name of a frame type, or `None` for one this build does not know.
+pub fn frame_name(kind: u8) -> Option<&'static str> {
+    match kind {
+        FRAME_TEXT => Some("TEXT"),
+        FRAME_HELLO => Some("HELLO"),
+        FRAME_PING => Some("PING"),
+        FRAME_PONG => Some("PONG"),
+        FRAME_FILE_META => Some("FILE_META"),
+        FRAME_FILE_CHUNK => Some("FILE_CHUNK"),
+        FRAME_BYE => Some("BYE"),
+        FRAME_REKEY => Some("REKEY"),
+        FRAME_TYPING => Some("TYPING"),
+        FRAME_PRESENCE => Some("PRESENCE"),
+        FRAME_ACK => Some("ACK"),
+        FRAME_FORMAT => Some("FORMAT"),
+        _ => None,
+    }
+}
+
+/// One frame as read off the wire, payload still encrypted.
+pub struct Frame {
+    pub kind: u8,
+    /// Which keys the frame was sealed with; goes up by one at each
+    /// rekey.
+    pub epoch: u8,
+    pub payload: Vec<u8>,
+    pub tag: [u8; TAG_LEN],
+    /// The random bytes in front of a `FRAME_VERSION_NONCE` frame.
+    pub nonce: Option<[u8; NONCE_LEN]>,
+}
+
+impl Frame {
+    /// How many bytes the frame took on the wire.
+    pub fn wire_len(&self) -> usize {
+        self.nonce.map_or(0, |nonce| nonce.len()) + FRAME_OVERHEAD + self.payload.len()
+    }
+}
+
+/// What a frame header says about the payload behind it.
+#[derive(Debug, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub kind: u8,
+    pub epoch: u8,
+    pub len: u32,
+    /// Counts the frames sent in this direction, from 0 for the HELLO.
+    pub seq: u64,
+}
+
+impl Header {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..3].copy_from_slice(&[self.version, self.kind, self.epoch]);
+        bytes[3..7].copy_from_slice(&self.len.to_be_bytes());
+        bytes[7..].copy_from_slice(&self.seq.to_be_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; HEADER_LEN]) -> Self {
+        Self {
+            version: bytes[0],
+            kind: bytes[1],
+            epoch: bytes[2],
+            len: u32::from_be_bytes(bytes[3..7].try_into().unwrap()),
+            seq: u64::from_be_bytes(bytes[7..].try_into().unwrap()),
+        }
+    }
+}
+
+/// Seal frame `seq`, a `kind` frame in key `epoch` carrying `plain`.
+/// Without a `nonce` that is `StreamCipher::seal`, in `FRAME_VERSION`,
+/// moving `cipher` on. With one the frame is in `FRAME_VERSION_NONCE`:
+/// the nonce goes in front, and the rest is sealed under the keystream
+/// it picks, leaving `cipher` as it was. Returns what
+/// `StreamCipher::seal` does.
+pub fn seal_frame(
+    cipher: &mut StreamCipher,
+    nonce: Option<&[u8; NONCE_LEN]>,
+    kind: u8,
+    epoch: u8,
+    seq: u64,
+    plain: &[u8],
+) -> (u64, Vec<u8>, Vec<u8>) {
+    let Some(nonce) = nonce else {
+        return cipher.seal(kind, epoch, seq, plain);
+    };
+    let (start, encrypted, frame) = cipher.for_message(nonce).seal(kind, epoch, seq, plain);
+    (start, encrypted, [&nonce[..], &frame].concat())
+}
+
+/// A nonce for a `FRAME_VERSION_NONCE` frame: random bytes, which must
+/// never repeat under one key.
+pub fn random_nonce() -> io::Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0u8; NONCE_LEN];
+    os_random(&mut nonce)?;
+    Ok(nonce)
+}
+
+/// The nonce `--test-seed` gives frame `seq` in place of a random one,
+/// so a session's frames repeat from run to run. Each direction and
+/// epoch has keys of its own, and sequence numbers do not repeat within
+/// a direction, so neither do nonces under one key.
+pub fn test_nonce(seed: u64, seq: u64) -> [u8; NONCE_LEN] {
+    let digest = sha256(
+        &[
+            b"chat test nonce ",
+            &seed.to_be_bytes()[..],
+            &seq.to_be_bytes(),
+        ]
+        .concat(),
+    );
+    digest[..NONCE_LEN].try_into().unwrap()
+}
+
+/// Send one frame sealed by `seal_frame`: any nonce, the encrypted
+/// header and its tag, then the encrypted payload and its tag. Nothing
+/// about the frame, not even its length, travels in the clear.
+pub fn write_frame(writer: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+    let limit = NONCE_LEN + FRAME_OVERHEAD + MAX_FRAME;
+    if frame.len() > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes exceeds {} bytes", frame.len(), limit),
+        ));
+    }
+    writer.write_all(frame)?;
+    writer.flush()
+}
+
+/// Length of the sequence number that opens every TEXT payload, and
+/// makes up an ACK's.
+pub const SEQ_LEN: usize = 8;
+
+/// Length of the send time that follows it.
+const STAMP_LEN: usize = 8;
+
+/// A TEXT payload: the message's sequence number, when it was sent, as
+/// big-endian milliseconds since the Unix epoch, then the text.
+pub fn encode_text(seq: u64, sent_at: SystemTime, text: &[u8]) -> Vec<u8> {
+    let millis = sent_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut payload = Vec::with_capacity(SEQ_LEN + STAMP_LEN + text.len());
+    payload.extend_from_slice(&seq.to_be_bytes());
+    payload.extend_from_slice(&millis.to_be_bytes());
+    payload.extend_from_slice(text);
+    payload
+}
+
+/// Split a TEXT payload into its sequence number, send time and text, or
+/// `None` if it is too short or the time is out of range.
+pub fn decode_text(payload: &[u8]) -> Option<(u64, SystemTime, &[u8])> {
+    let (seq, rest) = payload.split_first_chunk::<SEQ_LEN>()?;
+    let (stamp, text) = rest.split_first_chunk::<STAMP_LEN>()?;
+    let sent_at = UNIX_EPOCH.checked_add(Duration::from_millis(u64::from_be_bytes(*stamp)))?;
+    Some((u64::from_be_bytes(*seq), sent_at, text))
+}
+
+/// Length of the true length that opens every padded TEXT payload.
+const LEN_PREFIX: usize = 4;
+
+/// How long `plain` is once padded to a multiple of `block` bytes.
+fn padded_len(len: usize, block: usize) -> usize {
+    (LEN_PREFIX + len).next_multiple_of(block)
+}
+
+/// `plain`, after its big-endian length, followed by random bytes up to
+/// the next multiple of `block`. With a `block` of 1 nothing is added,
+/// but the length is still there for the peer to read.
+pub fn pad(plain: &[u8], block: usize) -> io::Result<Vec<u8>> {
+    let mut padded = vec![0u8; padded_len(plain.len(), block)];
+    padded[..LEN_PREFIX].copy_from_slice(&(plain.len() as u32).to_be_bytes());
+    padded[LEN_PREFIX..LEN_PREFIX + plain.len()].copy_from_slice(plain);
+    os_random(&mut padded[LEN_PREFIX + plain.len()..])?;
+    Ok(padded)
+}
+
+/// What `pad` was given, or `None` if the length in `padded` runs past
+/// its end.
+pub fn unpad(padded: &[u8]) -> Option<&[u8]> {
+    let (len, rest) = padded.split_first_chunk::<LEN_PREFIX>()?;
+    rest.get(..u32::from_be_bytes(*len) as usize)
+}
+
+/// The longest text whose padded TEXT payload fits in `max_message`.
+pub fn text_limit(max_message: usize, block: usize) -> usize {
+    max_message / block * block - LEN_PREFIX - SEQ_LEN - STAMP_LEN
+}
+
+/// Longest nickname allowed, in bytes.
+pub const MAX_NICK: usize = 32;
+
+/// A nickname, ours or the peer's: 1 to `MAX_NICK` bytes of UTF-8 without
+/// control characters.
+pub fn check_nick(bytes: &[u8]) -> Result<String, String> {
+    let nick = std::str::from_utf8(bytes).map_err(|_| "nickname is not valid UTF-8".to_string())?;
+    if nick.is_empty() || nick.len() > MAX_NICK {
+        return Err(format!("nickname must be 1 to {} bytes long", MAX_NICK));
+    }
+    if nick.chars().any(char::is_control) {
+        return Err("nickname must not contain control characters".to_string());
+    }
+    Ok(nick.to_string())
+}
+
+// First byte of a PRESENCE payload; the sender's nickname follows
+const PRESENCE_LEFT: u8 = 0;
+const PRESENCE_JOINED: u8 = 1;
+
+/// A PRESENCE payload saying `nick` joined or left.
+pub fn encode_presence(joined: bool, nick: &str) -> Vec<u8> {
+    let state = if joined {
+        PRESENCE_JOINED
+    } else {
+        PRESENCE_LEFT
+    };
+    [&[state], nick.as_bytes()].concat()
+}
+
+/// Whether a PRESENCE payload says joined, and the nickname in it, or
+/// `None` if it is malformed.
+pub fn decode_presence(payload: &[u8]) -> Option<(bool, String)> {
+    let (&state, nick) = payload.split_first()?;
+    let joined = match state {
+        PRESENCE_JOINED => true,
+        PRESENCE_LEFT => false,
+        _ => return None,
+    };
+    Some((joined, check_nick(nick).ok()?))
+}
+
+/// The peer's keys, as we receive: those its frames use now, and the
+/// next epoch's once a rekey is agreed. Frames the peer sent before
+/// switching still open under the old keys; the first one under the new
+/// keys retires them.
+pub struct RecvKeys {
+    cipher: StreamCipher,
+    pub next: Option<(u8, StreamCipher)>,
+    /// Sequence number the peer's next frame must carry.
+    seq: u64,
+    /// Frame format the peer's frames come in from here on.
+    pub format: u8,
+}
+
+impl RecvKeys {
+    pub fn new(cipher: StreamCipher) -> Self {
+        Self {
+            cipher,
+            next: None,
+            seq: 0,
+            format: FRAME_VERSION,
+        }
+    }
+
+    /// Receive one frame written by `write_frame` in `self.format`. Its
+    /// header must open under the current keys or the next epoch's, which
+    /// then replace them, and carry the next sequence number. A header
+    /// that fails any check ends the connection, since nothing after it
+    /// can be found without it. A payload over `limit` bytes, or a TEXT
+    /// frame with no text, is refused before the payload is read. A
+    /// stream that ends partway through a frame fails with
+    /// `UnexpectedEof`.
+    pub fn read_frame(&mut self, reader: &mut impl Read, limit: usize) -> io::Result<Frame> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let nonce = match self.format {
+            FRAME_VERSION_NONCE => {
+                let mut nonce = [0u8; NONCE_LEN];
+                reader.read_exact(&mut nonce)?;
+                Some(nonce)
+            }
+            _ => None,
+        };
+        let mut block = [0u8; HEADER_LEN + TAG_LEN];
+        reader.read_exact(&mut block)?;
+        // A frame with a nonce opens under a copy of the keys, which stay
+        // as they were for the next one
+        let open_header = |cipher: &mut StreamCipher| match &nonce {
+            Some(nonce) => cipher.for_message(nonce).open_header(&block),
+            None => cipher.open_header(&block),
+        };
+        let header = if let Some(header) = open_header(&mut self.cipher) {
+            header
+        } else if let Some((_, next)) = &mut self.next
+            && let Some(header) = open_header(next)
+        {
+            self.cipher = self.next.take().unwrap().1;
+            header
+        } else {
+            return Err(invalid(
+                "frame header failed authentication; is the peer an older build, or using another --cipher?"
+                    .to_string(),
+            ));
+        };
+        if header.version != self.format {
+            return Err(invalid(format!(
+                "peer sent frame format {:#04x}, expected {:#04x}; is it an older build?",
+                header.version, self.format
+            )));
+        }
+        if header.seq != self.seq {
+            return Err(invalid(format!(
+                "peer sent frame {} when {} was due",
+                header.seq, self.seq
+            )));
+        }
+        self.seq += 1;
+        let len = header.len as usize;
+        if len > limit {
+            return Err(invalid(format!(
+                "peer sent a {}-byte frame, limit is {} (--max-message)",
+                len, limit
+            )));
+        }
+        if header.kind == FRAME_TEXT && len <= LEN_PREFIX + SEQ_LEN + STAMP_LEN {
+            return Err(invalid("peer sent an empty message".to_string()));
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload)?;
+        let mut tag = [0u8; TAG_LEN];
+        reader.read_exact(&mut tag)?;
+        Ok(Frame {
+            kind: header.kind,
+            epoch: header.epoch,
+            payload,
+            tag,
+            nonce,
+        })
+    }
+
+    /// Open a frame from `read_frame` with the keys its header opened
+    /// under. The payload has a tag of its own, so one that fails is
+    /// dropped while the connection goes on.
+    pub fn open(&mut self, frame: &Frame) -> Option<(u64, Vec<u8>)> {
+        match &frame.nonce {
+            Some(nonce) => {
+                let mut cipher = self.cipher.for_message(nonce);
+                cipher.skip(HEADER_LEN as u64);
+                cipher.open(frame)
+            }
+            None => self.cipher.open(frame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::{CipherKind, CipherPair};
+    use crate::dh::test_secret;
+    use std::io::Cursor;
+
+    /// Our send keys and the peer's matching receive keys for `secret`.
+    fn keys(secret: &[u8]) -> (StreamCipher, RecvKeys) {
+        let ours = CipherPair::derive(secret, CipherKind::ChaCha20, true);
+        let theirs = CipherPair::derive(secret, CipherKind::ChaCha20, false);
+        (ours.send, RecvKeys::new(theirs.recv))
+    }
+
+    /// `messages` as HELLO frames, one after another as on the wire.
+    fn wire(send: &mut StreamCipher, epoch: u8, first_seq: u64, messages: &[&[u8]]) -> Vec<u8> {
+        let mut wire = Vec::new();
+        for (seq, message) in (first_seq..).zip(messages) {
+            let (_, _, frame) = send.seal(FRAME_HELLO, epoch, seq, message);
+            write_frame(&mut wire, &frame).unwrap();
+        }
+        wire
+    }
+
+    /// Read and open the next frame.
+    fn open_next(recv: &mut RecvKeys, reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+        let frame = recv.read_frame(reader, 100)?;
+        Ok(recv.open(&frame).map(|(_, plain)| plain))
+    }
+
+    #[test]
+    fn frames_round_trip_through_a_stream() {
+        let (mut send, mut recv) = keys(b"secret");
+        let wire = wire(&mut send, 0, 0, &[b"hello", b"again"]);
+        assert_eq!(wire.len(), 2 * FRAME_OVERHEAD + 10);
+        // Nothing of the header shows through
+        assert!(!wire.windows(4).any(|w| w == 5u32.to_be_bytes()));
+        let mut reader = Cursor::new(wire);
+        assert_eq!(
+            open_next(&mut recv, &mut reader).unwrap().unwrap(),
+            b"hello"
+        );
+        assert_eq!(
+            open_next(&mut recv, &mut reader).unwrap().unwrap(),
+            b"again"
+        );
+        let e = recv.read_frame(&mut reader, 100).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn a_truncated_frame_is_an_unexpected_eof() {
+        let (mut send, mut recv) = keys(b"secret");
+        let mut wire = wire(&mut send, 0, 0, &[b"hello"]);
+        wire.pop();
+        let e = recv.read_frame(&mut Cursor::new(wire), 100).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn a_corrupted_header_ends_the_stream() {
+        for at in [0, HEADER_LEN - 1, HEADER_LEN] {
+            let (mut send, mut recv) = keys(b"secret");
+            let mut wire = wire(&mut send, 0, 0, &[b"hello"]);
+            wire[at] ^= 1;
+            let e = recv.read_frame(&mut Cursor::new(wire), 100).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(
+                e.to_string().contains("frame header failed authentication"),
+                "{}",
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn a_corrupted_payload_is_dropped_and_the_next_frame_opens() {
+        let (mut send, mut recv) = keys(b"secret");
+        let mut wire = wire(&mut send, 0, 0, &[b"hello", b"again"]);
+        wire[HEADER_LEN + TAG_LEN] ^= 1;
+        let mut reader = Cursor::new(wire);
+        assert_eq!(open_next(&mut recv, &mut reader).unwrap(), None);
+        assert_eq!(
+            open_next(&mut recv, &mut reader).unwrap().unwrap(),
+            b"again"
+        );
+    }
+
+    #[test]
+    fn other_keys_cannot_read_the_header() {
+        let (mut send, _) = keys(b"secret");
+        let (_, mut recv) = keys(b"another secret");
+        let wire = wire(&mut send, 0, 0, &[b"hello"]);
+        let e = recv.read_frame(&mut Cursor::new(wire), 100).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_dropped_frame_breaks_the_sequence() {
+        let (mut send, mut recv) = keys(b"secret");
+        let wire = wire(&mut send, 0, 0, &[b"hello", b"again"]);
+        let skipped = wire[FRAME_OVERHEAD + 5..].to_vec();
+        assert!(recv.read_frame(&mut Cursor::new(skipped), 100).is_err());
+    }
+
+    #[test]
+    fn oversized_and_empty_frames_are_refused_before_reading_them() {
+        let (mut send, mut recv) = keys(b"secret");
+        // Only the header: the limit is checked before the payload is read
+        let wire = wire(&mut send, 0, 0, &[&[0; 200]]);
+        let header = wire[..HEADER_LEN + TAG_LEN].to_vec();
+        let e = recv
+            .read_frame(&mut Cursor::new(header), 100)
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("limit is 100"), "{}", e);
+
+        let (mut send, mut recv) = keys(b"secret");
+        let empty = encode_text(1, SystemTime::now(), b"");
+        let (_, _, frame) = send.seal(FRAME_TEXT, 0, 0, &pad(&empty, 1).unwrap());
+        let e = recv.read_frame(&mut Cursor::new(frame), 100).err().unwrap();
+        assert!(e.to_string().contains("empty message"), "{}", e);
+    }
+
+    #[test]
+    fn old_keys_stay_until_the_first_frame_under_new_ones() {
+        let (mut old, mut recv) = keys(b"epoch 0");
+        let (mut new, upcoming) = keys(b"epoch 1");
+        recv.next = Some((1, upcoming.cipher));
+        let mut stream = wire(&mut old, 0, 0, &[b"before"]);
+        stream.extend(wire(&mut new, 1, 1, &[b"after"]));
+        stream.extend(wire(&mut old, 0, 2, &[b"stale"]));
+        let mut reader = Cursor::new(stream);
+        assert_eq!(
+            open_next(&mut recv, &mut reader).unwrap().unwrap(),
+            b"before"
+        );
+        assert!(recv.next.is_some());
+        assert_eq!(
+            open_next(&mut recv, &mut reader).unwrap().unwrap(),
+            b"after"
+        );
+        assert!(recv.next.is_none());
+        // The old keys are gone once the new ones are in use
+        assert!(open_next(&mut recv, &mut reader).is_err());
+    }
+
+    /// Open a `FRAME_VERSION_NONCE` frame on its own, with `recv`'s
+    /// keys as they were before any frame.
+    fn open_alone(recv: &StreamCipher, frame: &[u8]) -> Option<Vec<u8>> {
+        let (nonce, rest) = frame.split_first_chunk::<NONCE_LEN>()?;
+        let (block, rest) = rest.split_first_chunk::<{ HEADER_LEN + TAG_LEN }>()?;
+        let mut cipher = recv.for_message(nonce);
+        let header = cipher.open_header(block)?;
+        let (payload, tag) = rest.split_at(header.len as usize);
+        let frame = Frame {
+            kind: header.kind,
+            epoch: header.epoch,
+            payload: payload.to_vec(),
+            tag: tag.try_into().ok()?,
+            nonce: Some(*nonce),
+        };
+        cipher.open(&frame).map(|(_, plain)| plain)
+    }
+
+    #[test]
+    fn frames_with_nonces_open_in_any_order() {
+        for kind in [CipherKind::ChaCha20, CipherKind::Lcg] {
+            let mut send = CipherPair::derive(b"secret", kind, true).send;
+            let recv = CipherPair::derive(b"secret", kind, false).recv;
+            let frames: Vec<Vec<u8>> = (0..5u64)
+                .map(|seq| {
+                    let plain = format!("message {seq}");
+                    let nonce = random_nonce().unwrap();
+                    seal_frame(
+                        &mut send,
+                        Some(&nonce),
+                        FRAME_TEXT,
+                        0,
+                        seq,
+                        plain.as_bytes(),
+                    )
+                    .2
+                })
+                .collect();
+            // Later frames open with earlier ones missing, and again
+            for seq in [4, 1, 3, 0, 2, 4] {
+                let plain = open_alone(&recv, &frames[seq]).unwrap();
+                assert_eq!(plain, format!("message {seq}").as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn identical_plaintexts_seal_differently_under_nonces() {
+        let (mut send, _) = keys(b"secret");
+        let untouched = send.clone();
+        let seal = |send: &mut StreamCipher| {
+            let nonce = random_nonce().unwrap();
+            seal_frame(send, Some(&nonce), FRAME_TEXT, 0, 0, b"same")
+        };
+        let (start, first, a) = seal(&mut send);
+        let (_, second, b) = seal(&mut send);
+        assert_eq!(start, HEADER_LEN as u64);
+        assert_ne!(first, second);
+        assert_ne!(a[..NONCE_LEN], b[..NONCE_LEN]);
+        assert_ne!(a[NONCE_LEN..], b[NONCE_LEN..]);
+        // The direction's keys are left as they were
+        assert_eq!(
+            send.seal(FRAME_TEXT, 0, 0, b"x").2,
+            untouched.clone().seal(FRAME_TEXT, 0, 0, b"x").2
+        );
+    }
+
+    #[test]
+    fn parts_of_frames_with_nonces_do_not_mix() {
+        let ours = CipherPair::derive(b"secret", CipherKind::ChaCha20, true);
+        let recv = CipherPair::derive(b"secret", CipherKind::ChaCha20, false).recv;
+        let mut send = ours.send;
+        let seal = |send: &mut StreamCipher, plain: &[u8]| {
+            let nonce = random_nonce().unwrap();
+            seal_frame(send, Some(&nonce), FRAME_TEXT, 0, 0, plain).2
+        };
+        let (a, b) = (seal(&mut send, b"alpha"), seal(&mut send, b"bravo"));
+        // One frame's payload behind the other's header and nonce
+        let split = NONCE_LEN + HEADER_LEN + TAG_LEN;
+        let spliced = [&a[..split], &b[split..]].concat();
+        assert_eq!(open_alone(&recv, &spliced), None);
+        // Nor does a header open under another frame's nonce
+        let renonced = [&b[..NONCE_LEN], &a[NONCE_LEN..]].concat();
+        assert_eq!(open_alone(&recv, &renonced), None);
+        // Nor under keys that are not the peer's
+        assert_eq!(open_alone(&ours.recv, &a), None);
+        assert_eq!(open_alone(&recv, &a).unwrap(), b"alpha");
+    }
+
+    #[test]
+    fn frames_with_nonces_read_from_a_stream_across_a_rekey() {
+        let (mut old, mut recv) = keys(b"epoch 0");
+        let (mut new, upcoming) = keys(b"epoch 1");
+        recv.format = FRAME_VERSION_NONCE;
+        recv.next = Some((1, upcoming.cipher));
+        let mut stream = Vec::new();
+        for (epoch, seq, plain) in [
+            (0, 0, &b"before"[..]),
+            (0, 1, b"tampered"),
+            (1, 2, b"after"),
+        ] {
+            let send = if epoch == 0 { &mut old } else { &mut new };
+            let nonce = random_nonce().unwrap();
+            let (_, _, frame) = seal_frame(send, Some(&nonce), FRAME_HELLO, epoch, seq, plain);
+            write_frame(&mut stream, &frame).unwrap();
+        }
+        // The last byte of the second frame's payload
+        let at = 2 * (NONCE_LEN + FRAME_OVERHEAD) + 6 + 8 - TAG_LEN - 1;
+        stream[at] ^= 1;
+        let mut reader = Cursor::new(stream);
+        let frame = recv.read_frame(&mut reader, 100).unwrap();
+        assert_eq!(frame.wire_len(), NONCE_LEN + FRAME_OVERHEAD + 6);
+        assert_eq!(recv.open(&frame).unwrap().1, b"before");
+        assert_eq!(open_next(&mut recv, &mut reader).unwrap(), None);
+        assert_eq!(
+            open_next(&mut recv, &mut reader).unwrap().unwrap(),
+            b"after"
+        );
+        assert!(recv.next.is_none());
+    }
+
+    #[test]
+    fn a_frame_without_a_nonce_fails_where_one_is_due() {
+        let (mut send, mut recv) = keys(b"secret");
+        recv.format = FRAME_VERSION_NONCE;
+        let wire = wire(&mut send, 0, 0, &[b"hello"]);
+        let e = recv.read_frame(&mut Cursor::new(wire), 100).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_test_seed_gives_the_same_frames_every_run() {
+        let secret = test_secret(7);
+        let mut send = CipherPair::derive(&secret, CipherKind::ChaCha20, true).send;
+        let sent_at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        let text = pad(&encode_text(1, sent_at, b"hi"), 1).unwrap();
+        // A hello, then the first message once both offered format 9
+        let frames = [
+            (FRAME_HELLO, None, &b"srv"[..]),
+            (FRAME_FORMAT, None, &[FRAME_VERSION_NONCE]),
+            (FRAME_TEXT, Some(test_nonce(7, 2)), &text),
+        ];
+        let mut wire = Vec::new();
+        for (seq, (kind, nonce, plain)) in (0..).zip(frames) {
+            let (_, _, frame) = seal_frame(&mut send, nonce.as_ref(), kind, 0, seq, plain);
+            write_frame(&mut wire, &frame).unwrap();
+        }
+        let hex: String = wire.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(
+            hex,
+            "a8572eccdb25e957112cc53a28feff4e66d335761f21186ed55a3cab641737f8\
+            f7d26f6a406d0287525e692f04b767b29b92b595d50f74dce7c422860f8eb157\
+            8fc1332b6514dd5aabf437b9d2b051d0a7d46ae11818cab69b219fba8e990502\
+            c2deeded5ed88627b00942152217dc381fd755e39b34b930c8994c352921d628\
+            95ac06f5f35bf8b64f3b5e4663d795fc2454dd97ef0a498088e951ad3b81e471\
+            ce72847b37af864330210d460c2061f350d84c3b95913fa2001d218c5033481f\
+            a8e94b7fc08acf0cddf6e55e61952d43ead1bca24b76593f4ddcf41c2509f082\
+            aa756398323466c87f2cd0fb5e66148e84ce706eca5afaef0cf7d668ef102385\
+            c681442a58b4665c4370ee1788a149"
+        );
+
+        let mut recv = RecvKeys::new(CipherPair::derive(&secret, CipherKind::ChaCha20, false).recv);
+        let mut reader = Cursor::new(wire);
+        assert_eq!(open_next(&mut recv, &mut reader).unwrap().unwrap(), b"srv");
+        open_next(&mut recv, &mut reader).unwrap().unwrap();
+        recv.format = FRAME_VERSION_NONCE;
+        let plain = open_next(&mut recv, &mut reader).unwrap().unwrap();
+        assert_eq!(
+            unpad(&plain).and_then(decode_text),
+            Some((1, sent_at, &b"hi"[..]))
+        );
+    }
+
+    #[test]
+    fn text_payload_round_trip() {
+        let sent_at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let payload = encode_text(42, sent_at, b"hi");
+        assert_eq!(decode_text(&payload), Some((42, sent_at, &b"hi"[..])));
+        assert_eq!(decode_text(&payload[..SEQ_LEN + STAMP_LEN - 1]), None);
+    }
+
+    #[test]
+    fn multibyte_text_survives_every_keystream_offset() {
+        let texts = [
+            "🦀 crab",
+            "日本語のテキスト",
+            "tab\tin the middle\t",
+            "  spaced  ",
+            "é",
+        ];
+        for kind in [CipherKind::Lcg, CipherKind::ChaCha20] {
+            let mut send = CipherPair::derive(b"utf-8", kind, true).send;
+            let mut recv = RecvKeys::new(CipherPair::derive(b"utf-8", kind, false).recv);
+            let mut wire = Vec::new();
+            let mut seq = 0;
+            // A filler message of 1 to 4 bytes first, so each text starts at
+            // every offset into a 4-byte character
+            for filler in 1..=4 {
+                for text in texts {
+                    for (kind, plain) in [
+                        (FRAME_HELLO, vec![b'-'; filler]),
+                        (
+                            FRAME_TEXT,
+                            pad(&encode_text(seq, SystemTime::now(), text.as_bytes()), 7).unwrap(),
+                        ),
+                    ] {
+                        let (_, _, frame) = send.seal(kind, 0, seq, &plain);
+                        write_frame(&mut wire, &frame).unwrap();
+                        seq += 1;
+                    }
+                }
+            }
+            let mut reader = Cursor::new(wire);
+            for _ in 1..=4 {
+                for text in texts {
+                    open_next(&mut recv, &mut reader).unwrap().unwrap();
+                    let frame = recv.read_frame(&mut reader, 100).unwrap();
+                    let (_, plain) = recv.open(&frame).unwrap();
+                    let (_, _, got) = unpad(&plain).and_then(decode_text).unwrap();
+                    assert_eq!(std::str::from_utf8(got), Ok(text));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn padding_fills_whole_blocks() {
+        // One under, exactly, and one over a block with the length prefix
+        for (len, padded) in [(59, 64), (60, 64), (61, 128)] {
+            let plain = vec![b'x'; len];
+            let block = pad(&plain, 64).unwrap();
+            assert_eq!(block.len(), padded);
+            assert_eq!(unpad(&block), Some(&plain[..]));
+        }
+        assert_eq!(pad(b"abc", 1).unwrap().len(), LEN_PREFIX + 3);
+        let mut overlong = pad(b"abc", 64).unwrap();
+        overlong[..LEN_PREFIX].copy_from_slice(&100u32.to_be_bytes());
+        assert_eq!(unpad(&overlong), None);
+        assert_eq!(text_limit(1100, 64), 1068);
+        assert_eq!(text_limit(MAX_MESSAGE, 1), MAX_MESSAGE - 20);
+    }
+
+    #[test]
+    fn presence_round_trip() {
+        assert_eq!(
+            decode_presence(&encode_presence(true, "alice")),
+            Some((true, "alice".to_string()))
+        );
+        assert_eq!(
+            decode_presence(&encode_presence(false, "bob")),
+            Some((false, "bob".to_string()))
+        );
+        assert_eq!(decode_presence(&[7, b'a']), None);
+        assert_eq!(decode_presence(&[PRESENCE_JOINED]), None);
+    }
+
+    #[test]
+    fn typing_frames_round_trip_empty() {
+        for nonce in [None, Some(random_nonce().unwrap())] {
+            let (mut send, mut recv) = keys(b"typing");
+            if nonce.is_some() {
+                recv.format = FRAME_VERSION_NONCE;
+            }
+            let (_, _, frame) = seal_frame(&mut send, nonce.as_ref(), FRAME_TYPING, 0, 0, &[]);
+            let mut wire = Vec::new();
+            write_frame(&mut wire, &frame).unwrap();
+            let frame = recv
+                .read_frame(&mut Cursor::new(wire), MIN_MESSAGE)
+                .unwrap();
+            assert_eq!(frame.kind, FRAME_TYPING);
+            assert_eq!(frame_name(frame.kind), Some("TYPING"));
+            assert_eq!(recv.open(&frame).unwrap().1, b"");
+        }
+    }
+
+    #[test]
+    fn nicknames_are_checked() {
+        assert_eq!(check_nick("ålice".as_bytes()), Ok("ålice".to_string()));
+        assert!(check_nick(b"").is_err());
+        assert!(check_nick(&[b'a'; MAX_NICK]).is_ok());
+        assert!(check_nick(&[b'a'; MAX_NICK + 1]).is_err());
+        assert!(check_nick(b"a\x1b[31m").is_err());
+        assert!(check_nick(&[0xff]).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! The relay: a meeting point for two clients that cannot reach each
+//! other. It pairs them up, tells each which part to take in the key
+//! exchange, then passes bytes between them without holding any keys.
+
+use std::io::{self, Read};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::chat::{INTERRUPTS, TICK};
+use crate::say;
+
+// What the relay tells each client of a pair once both are there: which
+// part it takes in the key exchange
+pub const RELAY_SERVER: u8 = b'S';
+pub const RELAY_CLIENT: u8 = b'C';
+
+/// How long the relay lets one side of a pair finish once the other has
+/// gone.
+const RELAY_LINGER: Duration = Duration::from_secs(2);
+
+/// Accept clients until two are connected. One that leaves while waiting
+/// for a partner is dropped. Returns `None` on Ctrl+C.
+pub fn wait_for_pair(listener: &TcpListener) -> io::Result<Option<[(TcpStream, SocketAddr); 2]>> {
+    listener.set_nonblocking(true)?;
+    let mut first: Option<(TcpStream, SocketAddr)> = None;
+    loop {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                stream.set_nonblocking(true)?;
+                match first.take() {
+                    Some(first) => {
+                        first.0.set_nonblocking(false)?;
+                        stream.set_nonblocking(false)?;
+                        return Ok(Some([first, (stream, addr)]));
+                    }
+                    None => {
+                        say!("[RELAY] {} connected, waiting for a partner", addr);
+                        first = Some((stream, addr));
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if INTERRUPTS.load(Ordering::SeqCst) > 0 {
+                    return Ok(None);
+                }
+                // Clients say nothing until paired, so anything but
+                // `WouldBlock` means this one has gone
+                if let Some((stream, addr)) = &first
+                    && !matches!(stream.peek(&mut [0]), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+                {
+                    say!("[RELAY] {} left before a partner came", addr);
+                    first = None;
+                }
+                thread::sleep(TICK);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Copy bytes each way between `a` and `b` until one side closes. The
+/// other then sees the end of the stream, as if its peer had hung up, and
+/// is cut off if it lingers. Returns the bytes passed on from each, and
+/// whether `a` was the one to leave.
+pub fn forward_pair(a: &TcpStream, b: &TcpStream) -> (u64, u64, bool) {
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::scope(|scope| {
+        let (a_tx, b_tx) = (done_tx.clone(), done_tx);
+        let from_a = scope.spawn(move || pipe(a, b, a_tx, true));
+        let from_b = scope.spawn(move || pipe(b, a, b_tx, false));
+        let cut_off = || {
+            let _ = a.shutdown(Shutdown::Both);
+            let _ = b.shutdown(Shutdown::Both);
+        };
+        let a_left = loop {
+            match done_rx.recv_timeout(TICK) {
+                Ok(a_left) => break a_left,
+                Err(_) if INTERRUPTS.load(Ordering::SeqCst) > 0 => {
+                    cut_off();
+                    break true;
+                }
+                Err(_) => {}
+            }
+        };
+        if done_rx.recv_timeout(RELAY_LINGER).is_err() {
+            cut_off();
+        }
+        let copied = |side: thread::ScopedJoinHandle<u64>| side.join().unwrap_or(0);
+        (copied(from_a), copied(from_b), a_left)
+    })
+}
+
+/// Copy from `from` to `to` until `from` closes, then pass that on and
+/// send `tag` to `done`. Returns the bytes copied.
+fn pipe(from: &TcpStream, to: &TcpStream, done: mpsc::Sender<bool>, tag: bool) -> u64 {
+    let copied = io::copy(&mut &*from, &mut &*to).unwrap_or(0);
+    let _ = to.shutdown(Shutdown::Write);
+    let _ = done.send(tag);
+    copied
+}
+
+/// Wait for the relay at `relay` to pair us up, and return whether we
+/// take the server's part in the key exchange.
+pub fn relay_role(stream: &mut impl Read, relay: &str) -> io::Result<bool> {
+    say!("[RELAY] Waiting for the relay to find our peer...");
+    let mut role = [0u8; 1];
+    stream.read_exact(&mut role)?;
+    match role[0] {
+        RELAY_SERVER | RELAY_CLIENT => {
+            say!("[RELAY] Paired ✓");
+            println!();
+            Ok(role[0] == RELAY_SERVER)
+        }
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a relay: it sent byte {:#04x}", relay, other),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn the_role_byte_picks_a_side() {
+        assert!(relay_role(&mut &[RELAY_SERVER][..], "relay").unwrap());
+        assert!(!relay_role(&mut &[RELAY_CLIENT][..], "relay").unwrap());
+        let err = relay_role(&mut &b"HTTP/1.1"[..], "example:80").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            err.to_string().contains("example:80 is not a relay"),
+            "{}",
+            err
+        );
+        let err = relay_role(&mut &[][..], "relay").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn a_pair_is_forwarded_both_ways_until_one_leaves() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut alice = TcpStream::connect(addr).unwrap();
+        let mut bob = TcpStream::connect(addr).unwrap();
+        let [(a, _), (b, _)] = wait_for_pair(&listener).unwrap().unwrap();
+        thread::scope(|scope| {
+            let relay = scope.spawn(|| forward_pair(&a, &b));
+            alice.write_all(b"ping").unwrap();
+            let mut got = [0u8; 4];
+            bob.read_exact(&mut got).unwrap();
+            assert_eq!(&got, b"ping");
+            bob.write_all(b"pong!").unwrap();
+            let mut got = [0u8; 5];
+            alice.read_exact(&mut got).unwrap();
+            assert_eq!(&got, b"pong!");
+
+            // Alice hangs up: Bob sees the stream end, then hangs up too
+            drop(alice);
+            let mut rest = Vec::new();
+            bob.read_to_end(&mut rest).unwrap();
+            assert!(rest.is_empty());
+            drop(bob);
+            assert_eq!(relay.join().unwrap(), (4, 5, true));
+        });
+    }
+}
@@ -0,0 +1,232 @@
+//! `streamchat selftest`: both ends of a chat in one process, checking
+//! that every message survives the round trip across rekeys.
+
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::chat::{Rekey, Sender};
+use crate::cipher::{CipherKind, CipherPair, os_random};
+use crate::dh::{DhGroup, authenticate, keyed_secret, perform_dh_exchange};
+use crate::proto::{
+    FRAME_BYE, FRAME_REKEY, FRAME_TEXT, MAX_MESSAGE, RecvKeys, decode_text, encode_text,
+    frame_name, pad, unpad,
+};
+use crate::say;
+use crate::session::SessionStats;
+
+/// Round trips `selftest` makes.
+const SELFTEST_MESSAGES: usize = 300;
+
+/// How often `selftest` rekeys, in messages, so it crosses a few epochs.
+const SELFTEST_REKEY: u64 = 50;
+
+/// What the self-test runs with, from the command line.
+pub struct SelftestOptions {
+    pub cipher: CipherKind,
+    pub group: DhGroup,
+    /// The frame format both ends use.
+    pub frame_format: u8,
+    /// Pad each message to a multiple of this many bytes.
+    pub pad: usize,
+    /// `--seed`.
+    pub seed: Option<u64>,
+    /// `--psk`.
+    pub psk: Option<Vec<u8>>,
+    /// `--timeout`.
+    pub timeout: Option<Duration>,
+}
+
+/// Run a server and a client in this process over loopback. The client
+/// sends random messages, the server echoes each one, and the client
+/// checks every reply against what it sent. Returns 0 if all came back.
+pub fn run_selftest(args: &SelftestOptions) -> io::Result<i32> {
+    say!(
+        "[SELFTEST] {} over --dh {}, frame format {:#04x}: {} round trips, new keys every {} messages",
+        args.cipher.name(),
+        args.group.name(),
+        args.frame_format,
+        SELFTEST_MESSAGES,
+        SELFTEST_REKEY
+    );
+    let started = Instant::now();
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let addr = listener.local_addr()?;
+    let (echoed, outcome) = thread::scope(|scope| {
+        let server = scope.spawn(|| -> io::Result<usize> {
+            let (stream, _) = listener.accept()?;
+            selftest_side(stream, true, args, |reader, recv, sender| {
+                selftest_echo(reader, recv, sender)
+            })
+        });
+        let outcome = TcpStream::connect(addr).and_then(|stream| {
+            selftest_side(stream, false, args, |reader, recv, sender| {
+                selftest_check(reader, recv, sender, args.pad)
+            })
+        });
+        let echoed = server
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("server panicked")));
+        (echoed, outcome)
+    });
+    let elapsed = started.elapsed();
+    match (outcome, echoed) {
+        (Ok(epochs), Ok(echoed)) => {
+            say!(
+                "[SELFTEST] PASS: {}/{} round trips, {} messages echoed, {} rekeys, {:.2?}",
+                SELFTEST_MESSAGES,
+                SELFTEST_MESSAGES,
+                echoed,
+                epochs,
+                elapsed
+            );
+            Ok(0)
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            say!("[SELFTEST] FAIL after {:.2?}: {}", elapsed, e);
+            Ok(1)
+        }
+    }
+}
+
+/// One end of the self-test: key exchange, then `run` over the channel.
+fn selftest_side(
+    mut stream: TcpStream,
+    is_server: bool,
+    args: &SelftestOptions,
+    run: impl FnOnce(&mut BufReader<TcpStream>, &mut RecvKeys, &Sender<&TcpStream>) -> io::Result<usize>,
+) -> io::Result<usize> {
+    stream.set_read_timeout(args.timeout)?;
+    let exchange = perform_dh_exchange(&mut stream, is_server, &args.group, args.seed, None)?;
+    let psk = args.psk.as_deref();
+    authenticate(&mut stream, &exchange.fingerprint, psk, is_server)?;
+    let secret = keyed_secret(&exchange.secret, psk);
+    let CipherPair { send, recv } = CipherPair::derive(&secret, args.cipher, is_server);
+    let rekey = Rekey {
+        group: args.group,
+        cipher: args.cipher,
+        is_server,
+        seed: args.seed,
+        messages: Some(SELFTEST_REKEY),
+        interval: None,
+        psk: args.psk.clone(),
+    };
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let stats = SessionStats::new();
+    let sender = Sender::new(&stream, &stats, send, rekey, MAX_MESSAGE, None);
+    let mut recv = RecvKeys::new(recv);
+    // Both ends are ours, so there is nothing to agree on
+    sender.set_format(args.frame_format);
+    recv.format = args.frame_format;
+    run(&mut reader, &mut recv, &sender)
+}
+
+/// The next TEXT or BYE frame from the peer, opened, acting on any REKEY
+/// on the way. Anything else, or a frame that fails authentication, is
+/// an error.
+fn selftest_receive(
+    reader: &mut impl Read,
+    recv: &mut RecvKeys,
+    sender: &Sender<impl Write>,
+) -> io::Result<(u8, Vec<u8>)> {
+    loop {
+        let frame = recv.read_frame(reader, MAX_MESSAGE)?;
+        let Some((_, plain)) = recv.open(&frame) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "a {}-byte {} frame failed authentication",
+                    frame.payload.len(),
+                    frame_name(frame.kind).unwrap_or("unknown")
+                ),
+            ));
+        };
+        match frame.kind {
+            FRAME_REKEY => recv.next = Some(sender.finish_rekey(&plain)?),
+            FRAME_TEXT | FRAME_BYE => return Ok((frame.kind, plain)),
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected frame type {}", kind),
+                ));
+            }
+        }
+    }
+}
+
+/// Server side: send back every message until BYE. Returns how many.
+fn selftest_echo(
+    reader: &mut impl Read,
+    recv: &mut RecvKeys,
+    sender: &Sender<impl Write>,
+) -> io::Result<usize> {
+    let mut echoed = 0;
+    loop {
+        match selftest_receive(reader, recv, sender)? {
+            (FRAME_TEXT, plain) => {
+                sender.send(FRAME_TEXT, &plain)?;
+                echoed += 1;
+            }
+            _ => return Ok(echoed),
+        }
+    }
+}
+
+/// Client side: send random messages of random length, padded to a
+/// multiple of `block`, and check that each comes back unchanged, then
+/// say BYE. Returns the rekeys made.
+fn selftest_check(
+    reader: &mut impl Read,
+    recv: &mut RecvKeys,
+    sender: &Sender<impl Write>,
+    block: usize,
+) -> io::Result<usize> {
+    for n in 1..=SELFTEST_MESSAGES {
+        let mut len = [0u8; 2];
+        os_random(&mut len)?;
+        let mut text = vec![0u8; 1 + u16::from_be_bytes(len) as usize % 1024];
+        os_random(&mut text)?;
+        let payload = pad(&encode_text(n as u64, SystemTime::now(), &text), block)?;
+        sender.send(FRAME_TEXT, &payload)?;
+        let (kind, reply) = selftest_receive(reader, recv, sender)?;
+        let text_back = unpad(&reply).and_then(decode_text).map(|(_, _, text)| text);
+        if kind != FRAME_TEXT || reply != payload || text_back != Some(&text[..]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "round trip {}: sent {} bytes, got back something else",
+                    n,
+                    payload.len()
+                ),
+            ));
+        }
+    }
+    sender.send(FRAME_BYE, &[])?;
+    Ok(sender.epoch() as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dh::DhParams;
+    use crate::proto::{FRAME_VERSION, FRAME_VERSION_NONCE};
+
+    #[test]
+    fn every_cipher_and_frame_format_passes() {
+        for cipher in [CipherKind::ChaCha20, CipherKind::Lcg] {
+            for frame_format in [FRAME_VERSION, FRAME_VERSION_NONCE] {
+                let options = SelftestOptions {
+                    cipher,
+                    group: DhGroup::Small(DhParams::DEFAULT),
+                    frame_format,
+                    pad: 16,
+                    seed: None,
+                    psk: Some(b"shared".to_vec()),
+                    timeout: Some(Duration::from_secs(10)),
+                };
+                assert_eq!(run_selftest(&options).unwrap(), 0, "{}", cipher.name());
+            }
+        }
+    }
+}
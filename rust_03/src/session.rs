@@ -0,0 +1,466 @@
+//! How a chat session is going and how it ended: the counters both chat
+//! threads keep, and the summary printed once it is over.
+
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::duration_text;
+
+/// Why a chat session ended.
+#[derive(Debug)]
+pub enum Ending {
+    /// We left, with `/quit`, end of input or Ctrl+C.
+    Left,
+    /// The peer sent BYE.
+    Bye,
+    /// The connection closed between frames without a BYE.
+    Closed,
+    /// The connection closed partway through a frame.
+    Truncated,
+    /// The connection was reset or aborted.
+    Reset,
+    /// The peer stopped answering keepalive pings.
+    Unreachable,
+    Failed(io::Error),
+}
+
+impl Ending {
+    /// How an error reading or writing between frames ends the session.
+    pub fn from_error(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Ending::Closed,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Ending::Reset,
+            _ => Ending::Failed(e),
+        }
+    }
+
+    /// How an error partway through a frame ends the session: running
+    /// out of stream there means the frame was cut short.
+    pub fn within_frame(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Ending::Truncated,
+            _ => Ending::from_error(e),
+        }
+    }
+
+    /// Whether the session ended the way sessions are meant to.
+    pub fn is_clean(&self) -> bool {
+        matches!(self, Ending::Left | Ending::Bye | Ending::Closed)
+    }
+
+    /// The exit code for a session that ended this way.
+    pub fn exit_code(&self) -> i32 {
+        if self.is_clean() { 0 } else { 1 }
+    }
+}
+
+impl fmt::Display for Ending {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ending::Left => write!(f, "you left"),
+            Ending::Bye => write!(f, "the peer left"),
+            Ending::Closed => write!(f, "peer closed the connection"),
+            Ending::Truncated => write!(f, "protocol error: short frame"),
+            Ending::Reset => write!(f, "connection reset"),
+            Ending::Unreachable => write!(f, "peer unreachable"),
+            Ending::Failed(e) if e.kind() == io::ErrorKind::InvalidData => {
+                write!(f, "protocol error: {}", e)
+            }
+            Ending::Failed(e) => write!(f, "connection failed: {}", e),
+        }
+    }
+}
+
+/// Counters the sending and receiving threads update as the chat goes
+/// on, read by `/stats` and the summary at the end.
+pub struct SessionStats {
+    started: Instant,
+    sent: AtomicUsize,
+    received: AtomicUsize,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    tampered: AtomicUsize,
+    round_trips: Mutex<RoundTrips>,
+}
+
+/// Round trips timed so far, from sending a message to its ACK.
+#[derive(Clone, Copy, Default)]
+struct RoundTrips {
+    count: u32,
+    total: Duration,
+    max: Duration,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionStats {
+    /// Counters for a session starting now.
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            sent: AtomicUsize::new(0),
+            received: AtomicUsize::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            tampered: AtomicUsize::new(0),
+            round_trips: Mutex::new(RoundTrips::default()),
+        }
+    }
+
+    /// Messages sent so far.
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::SeqCst)
+    }
+
+    /// Messages received so far.
+    pub fn received(&self) -> usize {
+        self.received.load(Ordering::SeqCst)
+    }
+
+    pub fn message_sent(&self) {
+        self.sent.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn message_received(&self) {
+        self.received.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// A frame of `bytes` on the wire, of whatever type, went out.
+    pub fn frame_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::SeqCst);
+    }
+
+    /// A frame of `bytes` on the wire came in.
+    pub fn frame_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::SeqCst);
+    }
+
+    /// A frame failed authentication and was dropped.
+    pub fn frame_dropped(&self) {
+        self.tampered.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// A message was acknowledged `rtt` after it was sent.
+    pub fn round_trip(&self, rtt: Duration) {
+        let mut trips = self.round_trips.lock().unwrap();
+        trips.count += 1;
+        trips.total += rtt;
+        trips.max = trips.max.max(rtt);
+    }
+
+    /// The counters as they stand.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let trips = *self.round_trips.lock().unwrap();
+        StatsSnapshot {
+            elapsed: self.started.elapsed(),
+            sent: self.sent(),
+            received: self.received(),
+            bytes_sent: self.bytes_sent.load(Ordering::SeqCst),
+            bytes_received: self.bytes_received.load(Ordering::SeqCst),
+            tampered: self.tampered.load(Ordering::SeqCst),
+            round_trips: trips.count,
+            average_rtt: (trips.count > 0).then(|| trips.total / trips.count),
+            max_rtt: (trips.count > 0).then_some(trips.max),
+        }
+    }
+}
+
+/// `SessionStats` read at one moment.
+#[derive(Debug, PartialEq)]
+pub struct StatsSnapshot {
+    pub elapsed: Duration,
+    /// Messages sent and received.
+    pub sent: usize,
+    pub received: usize,
+    /// Bytes written and read on the connection, whole frames.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Frames dropped for failing authentication.
+    pub tampered: usize,
+    /// Messages whose round trip was timed, and how long those took.
+    pub round_trips: u32,
+    pub average_rtt: Option<Duration>,
+    pub max_rtt: Option<Duration>,
+}
+
+impl StatsSnapshot {
+    /// The traffic so far as `[STATS]` lines, for `/stats`.
+    pub fn lines(&self) -> Vec<String> {
+        let latency = match (self.average_rtt, self.max_rtt) {
+            (Some(average), Some(max)) => format!(
+                "average {}, max {} over {} message(s)",
+                millis_text(average),
+                millis_text(max),
+                self.round_trips
+            ),
+            _ => "no messages acknowledged yet".to_string(),
+        };
+        vec![
+            format!("[STATS] Session time: {}", duration_text(self.elapsed)),
+            format!(
+                "[STATS] Messages sent: {}, received: {}",
+                self.sent, self.received
+            ),
+            format!(
+                "[STATS] Bytes sent: {}, received: {}",
+                self.bytes_sent, self.bytes_received
+            ),
+            format!("[STATS] Round trip: {}", latency),
+            format!(
+                "[STATS] Frames dropped for failing authentication: {}",
+                self.tampered
+            ),
+        ]
+    }
+}
+
+/// A round trip in whole milliseconds, or tenths under ten.
+fn millis_text(duration: Duration) -> String {
+    let millis = duration.as_secs_f64() * 1000.0;
+    if millis < 10.0 {
+        format!("{:.1}ms", millis)
+    } else {
+        format!("{:.0}ms", millis)
+    }
+}
+
+/// What a session amounted to, printed however it ends.
+pub struct SessionSummary {
+    pub stats: StatsSnapshot,
+    /// Messages the peer never acknowledged.
+    pub unacked: usize,
+    pub ending: String,
+}
+
+impl SessionSummary {
+    /// The summary as lines for the terminal, each with its `[TAG]`.
+    pub fn lines(&self) -> Vec<String> {
+        let stats = &self.stats;
+        let mut lines = vec![
+            format!(
+                "[SESSION] Session ended after {} ({}); messages sent: {}, received: {}",
+                duration_text(stats.elapsed),
+                self.ending,
+                stats.sent,
+                stats.received
+            ),
+            format!(
+                "[SESSION] Bytes sent: {}, received: {}",
+                stats.bytes_sent, stats.bytes_received
+            ),
+            format!(
+                "[SECURITY] Messages dropped for failing authentication: {}",
+                stats.tampered
+            ),
+        ];
+        if self.unacked > 0 {
+            lines.push(format!(
+                "[WARNING] {} message(s) never acknowledged by the peer",
+                self.unacked
+            ));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::{CipherKind, CipherPair};
+    use crate::proto::{FRAME_HELLO, RecvKeys, write_frame};
+    use std::io::{BufRead, BufReader, Cursor, Read};
+
+    /// Two HELLO frames as on the wire, and the keys to read them.
+    fn two_frames() -> (Vec<u8>, RecvKeys) {
+        let mut send = CipherPair::derive(b"secret", CipherKind::ChaCha20, true).send;
+        let recv = CipherPair::derive(b"secret", CipherKind::ChaCha20, false).recv;
+        let mut wire = Vec::new();
+        for (seq, text) in [b"one", b"two"].into_iter().enumerate() {
+            let (_, _, frame) = send.seal(FRAME_HELLO, 0, seq as u64, text);
+            write_frame(&mut wire, &frame).unwrap();
+        }
+        (wire, RecvKeys::new(recv))
+    }
+
+    /// Read frames as the chat does, waiting between them for one to
+    /// begin, until the stream ends.
+    fn read_all(reader: impl Read, mut keys: RecvKeys) -> (usize, Ending) {
+        let mut reader = BufReader::new(reader);
+        let mut frames = 0;
+        loop {
+            match reader.fill_buf() {
+                Ok([]) => return (frames, Ending::Closed),
+                Ok(_) => {}
+                Err(e) => return (frames, Ending::from_error(e)),
+            }
+            match keys.read_frame(&mut reader, 100) {
+                Ok(frame) => {
+                    assert!(keys.open(&frame).is_some());
+                    frames += 1;
+                }
+                Err(e) => return (frames, Ending::within_frame(e)),
+            }
+        }
+    }
+
+    /// A connection that delivers `data`, then is reset.
+    struct ResetAfter(Cursor<Vec<u8>>);
+
+    impl Read for ResetAfter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::ErrorKind::ConnectionReset.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    #[test]
+    fn a_close_between_frames_is_clean() {
+        let (wire, keys) = two_frames();
+        let (frames, ending) = read_all(Cursor::new(wire), keys);
+        assert_eq!(frames, 2);
+        assert!(matches!(ending, Ending::Closed));
+        assert_eq!(ending.exit_code(), 0);
+        assert_eq!(ending.to_string(), "peer closed the connection");
+    }
+
+    #[test]
+    fn a_close_within_a_frame_is_a_protocol_error() {
+        let (wire, _) = two_frames();
+        // Cut short in the second frame's header, then in its tag
+        for cut in [wire.len() / 2 + 5, wire.len() - 1] {
+            let (_, keys) = two_frames();
+            let (frames, ending) = read_all(Cursor::new(wire[..cut].to_vec()), keys);
+            assert_eq!(frames, 1);
+            assert!(matches!(ending, Ending::Truncated), "{ending:?}");
+            assert_eq!(ending.exit_code(), 1);
+            assert_eq!(ending.to_string(), "protocol error: short frame");
+        }
+    }
+
+    #[test]
+    fn a_reset_is_reported_as_one() {
+        let (wire, keys) = two_frames();
+        let (frames, ending) = read_all(ResetAfter(Cursor::new(wire.clone())), keys);
+        assert_eq!(frames, 2);
+        assert!(matches!(ending, Ending::Reset));
+
+        let (_, keys) = two_frames();
+        let cut = wire[..wire.len() - 3].to_vec();
+        let (frames, ending) = read_all(ResetAfter(Cursor::new(cut)), keys);
+        assert_eq!(frames, 1);
+        assert!(matches!(ending, Ending::Reset));
+        assert_eq!(ending.exit_code(), 1);
+        assert_eq!(ending.to_string(), "connection reset");
+
+        let ending = Ending::from_error(io::ErrorKind::BrokenPipe.into());
+        assert!(matches!(ending, Ending::Reset));
+    }
+
+    #[test]
+    fn other_failures_say_what_they_were() {
+        let bad = io::Error::new(io::ErrorKind::InvalidData, "peer sent an empty message");
+        let ending = Ending::within_frame(bad);
+        assert_eq!(
+            ending.to_string(),
+            "protocol error: peer sent an empty message"
+        );
+        assert_eq!(ending.exit_code(), 1);
+        let slow = io::Error::new(io::ErrorKind::TimedOut, "timed out after 2s");
+        assert_eq!(
+            Ending::from_error(slow).to_string(),
+            "connection failed: timed out after 2s"
+        );
+        assert!(Ending::Left.is_clean() && Ending::Bye.is_clean());
+        assert!(!Ending::Unreachable.is_clean());
+    }
+
+    #[test]
+    fn stats_add_up_what_happened() {
+        let stats = SessionStats::new();
+        for rtt in [12, 30, 18] {
+            stats.message_sent();
+            stats.frame_sent(100);
+            stats.round_trip(Duration::from_millis(rtt));
+        }
+        stats.message_received();
+        stats.frame_received(120);
+        stats.frame_received(40);
+        stats.frame_dropped();
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.sent, snapshot.received), (3, 1));
+        assert_eq!((snapshot.bytes_sent, snapshot.bytes_received), (300, 160));
+        assert_eq!(snapshot.tampered, 1);
+        assert_eq!(snapshot.round_trips, 3);
+        assert_eq!(snapshot.average_rtt, Some(Duration::from_millis(20)));
+        assert_eq!(snapshot.max_rtt, Some(Duration::from_millis(30)));
+        assert!(snapshot.elapsed < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn stats_read_as_lines() {
+        let mut snapshot = SessionStats::new().snapshot();
+        snapshot.elapsed = Duration::from_secs(75);
+        assert_eq!(
+            snapshot.lines(),
+            [
+                "[STATS] Session time: 1m 15s",
+                "[STATS] Messages sent: 0, received: 0",
+                "[STATS] Bytes sent: 0, received: 0",
+                "[STATS] Round trip: no messages acknowledged yet",
+                "[STATS] Frames dropped for failing authentication: 0",
+            ]
+        );
+        snapshot.round_trips = 2;
+        snapshot.average_rtt = Some(Duration::from_micros(4_250));
+        snapshot.max_rtt = Some(Duration::from_micros(120_400));
+        assert_eq!(
+            snapshot.lines()[3],
+            "[STATS] Round trip: average 4.2ms, max 120ms over 2 message(s)"
+        );
+    }
+
+    #[test]
+    fn summaries_list_counts_bytes_and_reason() {
+        let stats = SessionStats::new();
+        for _ in 0..3 {
+            stats.message_sent();
+        }
+        stats.message_received();
+        stats.message_received();
+        stats.frame_sent(420);
+        stats.frame_received(310);
+        let mut summary = SessionSummary {
+            stats: StatsSnapshot {
+                elapsed: Duration::from_secs(75),
+                ..stats.snapshot()
+            },
+            unacked: 0,
+            ending: Ending::Closed.to_string(),
+        };
+        assert_eq!(
+            summary.lines(),
+            [
+                "[SESSION] Session ended after 1m 15s (peer closed the connection); messages sent: 3, received: 2",
+                "[SESSION] Bytes sent: 420, received: 310",
+                "[SECURITY] Messages dropped for failing authentication: 0",
+            ]
+        );
+        summary.unacked = 1;
+        assert_eq!(
+            summary.lines().last().unwrap(),
+            "[WARNING] 1 message(s) never acknowledged by the peer"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Terminal colors for the chat: our own messages in one color, the
+//! peer's nickname in its own, status lines dim and trouble red. Every
+//! line printed goes through here, so nothing else writes escape codes.
+
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether to color output, decided once at startup.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+
+/// Colors nicknames are drawn from: neither red nor green, which mean
+/// trouble and our own messages.
+const NICK_COLORS: [&str; 8] = [
+    "\x1b[36m", "\x1b[35m", "\x1b[33m", "\x1b[34m", "\x1b[96m", "\x1b[95m", "\x1b[93m", "\x1b[94m",
+];
+
+/// Whether to color: not with `--no-color`, nor when `NO_COLOR` is set to
+/// anything but the empty string (see no-color.org), nor when stdout is
+/// not a terminal.
+pub fn wanted(no_color_flag: bool, no_color_env: Option<&OsStr>, tty: bool) -> bool {
+    !no_color_flag && no_color_env.is_none_or(OsStr::is_empty) && tty
+}
+
+/// Decide from the environment whether to color from now on.
+pub fn init(no_color_flag: bool) {
+    let env = std::env::var_os("NO_COLOR");
+    let on = wanted(no_color_flag, env.as_deref(), io::stdout().is_terminal());
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+/// What a line of output is about, which sets its color.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// Something we sent.
+    Mine,
+    /// Connection and protocol status.
+    Status,
+    /// A warning or error.
+    Alert,
+}
+
+/// The role of a line by its `[TAG]`, or `None` for one without a tag.
+pub fn role(line: &str) -> Option<Role> {
+    let line = line.trim_start_matches('\r');
+    let tag = &line[..line.find(']')? + 1];
+    if !tag.starts_with('[') {
+        return None;
+    }
+    let trouble = ["✗", "!!!", " failed", " FAIL"];
+    Some(match tag {
+        "[…]" | "[✓]" => Role::Mine,
+        "[WARNING]" | "[ERROR]" => Role::Alert,
+        _ if trouble.iter().any(|word| line.contains(word)) => Role::Alert,
+        _ => Role::Status,
+    })
+}
+
+/// `line` colored for its role, after any carriage returns that lead it.
+pub fn line(line: &str) -> Cow<'_, str> {
+    let Some(role) = role(line).filter(|_| ENABLED.load(Ordering::Relaxed)) else {
+        return Cow::Borrowed(line);
+    };
+    let color = match role {
+        Role::Mine => GREEN,
+        Role::Status => DIM,
+        Role::Alert => RED,
+    };
+    let text = line.trim_start_matches('\r');
+    let returns = &line[..line.len() - text.len()];
+    Cow::Owned(format!("{}{}{}{}", returns, color, text, RESET))
+}
+
+/// Index into `NICK_COLORS` for `nick`: FNV-1a over its bytes, so a name
+/// keeps its color from one run to the next.
+pub fn nick_color(nick: &str) -> usize {
+    let hash = nick.bytes().fold(0x811c_9dc5_u32, |hash, b| {
+        (hash ^ b as u32).wrapping_mul(0x0100_0193)
+    });
+    hash as usize % NICK_COLORS.len()
+}
+
+/// `<nick>`, in the nickname's color.
+pub fn nick(nick: &str) -> Cow<'_, str> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Cow::Owned(format!("<{}>", nick));
+    }
+    Cow::Owned(format!(
+        "{}<{}>{}",
+        NICK_COLORS[nick_color(nick)],
+        nick,
+        RESET
+    ))
+}
+
+/// `text` as it can safely be shown, for `--raw`: every character kept,
+/// but control characters written as escapes such as `\t` and `\x1b`, so
+/// a peer cannot move the cursor or recolor the terminal, and bytes that
+/// are not UTF-8 shown as `\xNN` rather than replaced.
+pub fn visible(text: &[u8]) -> String {
+    let mut shown = String::with_capacity(text.len());
+    for chunk in text.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\t' => shown.push_str("\\t"),
+                '\n' => shown.push_str("\\n"),
+                '\r' => shown.push_str("\\r"),
+                c if c.is_control() && (c as u32) < 0x80 => {
+                    shown.push_str(&format!("\\x{:02x}", c as u32))
+                }
+                c if c.is_control() => shown.push_str(&format!("\\u{{{:x}}}", c as u32)),
+                c => shown.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            shown.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    shown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_only_on_a_terminal_without_opting_out() {
+        let empty = OsStr::new("");
+        let set = OsStr::new("1");
+        assert!(wanted(false, None, true));
+        assert!(wanted(false, Some(empty), true));
+        assert!(!wanted(false, Some(set), true));
+        assert!(!wanted(true, None, true));
+        assert!(!wanted(false, None, false));
+    }
+
+    #[test]
+    fn nick_colors_are_stable() {
+        assert_eq!(nick_color("alice"), 7);
+        assert_eq!(nick_color("bob"), 4);
+        assert_eq!(nick_color("alice"), nick_color("alice"));
+    }
+
+    #[test]
+    fn lines_are_classified_by_tag() {
+        assert_eq!(role("[…] #1 hi"), Some(Role::Mine));
+        assert_eq!(role("\r[✓] #1 hi"), Some(Role::Mine));
+        assert_eq!(role("[CHAT] <bob> joined"), Some(Role::Status));
+        assert_eq!(role("[WARNING] NOT SECURE"), Some(Role::Alert));
+        assert_eq!(
+            role("[VERIFY] ✗ Peer computed a different secret"),
+            Some(Role::Alert)
+        );
+        assert_eq!(role("[SELFTEST] FAIL: 3 mismatches"), Some(Role::Alert));
+        assert_eq!(role("<bob> [CHAT] is not a tag here"), None);
+        assert_eq!(role("no tag"), None);
+    }
+
+    #[test]
+    fn raw_text_keeps_characters_and_escapes_controls() {
+        let text = "  lead\ttab 🦀 日本語 ";
+        assert_eq!(visible(text.as_bytes()), "  lead\\ttab 🦀 日本語 ");
+        assert_eq!(visible(b"a\r\nb"), "a\\r\\nb");
+        assert_eq!(visible(b"\x1b[31mred\x7f"), "\\x1b[31mred\\x7f");
+        assert_eq!(visible("\u{9b}2J".as_bytes()), "\\u{9b}2J");
+        // Not UTF-8: the bad bytes show, the good ones around them stay
+        assert_eq!(visible(b"ok\xff\xfe\xe6\x97ok"), "ok\\xff\\xfe\\xe6\\x97ok");
+        assert_eq!(visible(b"back\\slash"), "back\\slash");
+    }
+}
@@ -0,0 +1,411 @@
+//! What is kept of a chat besides the screen: the `--log` transcript,
+//! the scrollback behind `/history` and `/save`, and the clock times
+//! they are shown with.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::style;
+
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    /// `TIME > <nick> text` for sent messages, `<` for received ones.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Which way a logged message went.
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// The `--log` file. Only decrypted message text is ever written to it,
+/// never keys or keystream, whatever the verbosity.
+pub struct Transcript {
+    file: Mutex<File>,
+    format: LogFormat,
+}
+
+impl Transcript {
+    /// Open `path` for appending, creating it if needed.
+    pub fn open(path: &str, format: LogFormat) -> io::Result<Self> {
+        let file = File::options().append(true).create(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            format,
+        })
+    }
+
+    /// Append one message and flush it, so a crash loses nothing.
+    pub fn record(&self, direction: Direction, nick: &str, text: &str) -> io::Result<()> {
+        let line = log_line(self.format, SystemTime::now(), direction, nick, text);
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.flush()
+    }
+}
+
+/// One message as a `format` log line, newline included.
+fn log_line(
+    format: LogFormat,
+    time: SystemTime,
+    direction: Direction,
+    nick: &str,
+    text: &str,
+) -> String {
+    let time = iso8601(time);
+    match format {
+        LogFormat::Text => {
+            let marker = match direction {
+                Direction::Sent => '>',
+                Direction::Received => '<',
+            };
+            format!("{} {} <{}> {}\n", time, marker, nick, text)
+        }
+        LogFormat::Json => {
+            let direction = match direction {
+                Direction::Sent => "sent",
+                Direction::Received => "received",
+            };
+            format!(
+                "{{\"time\":\"{}\",\"direction\":\"{}\",\"nick\":{},\"text\":{}}}\n",
+                time,
+                direction,
+                json_string(nick),
+                json_string(text)
+            )
+        }
+    }
+}
+
+/// Default for `--history`.
+pub const HISTORY: usize = 200;
+
+/// One message in the scrollback.
+struct Entry {
+    direction: Direction,
+    time: SystemTime,
+    nick: String,
+    text: String,
+}
+
+/// The last `--history` messages either way, for `/history` and `/save`.
+/// Both chat threads add to it, so it sits behind a lock.
+pub struct History {
+    limit: usize,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            entries: Mutex::new(VecDeque::with_capacity(limit)),
+        }
+    }
+
+    /// Add a message, forgetting the oldest once `limit` are kept.
+    pub fn push(&self, direction: Direction, time: SystemTime, nick: &str, text: &str) {
+        if self.limit == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.limit {
+            entries.pop_front();
+        }
+        entries.push_back(Entry {
+            direction,
+            time,
+            nick: nick.to_string(),
+            text: text.to_string(),
+        });
+    }
+
+    /// The last `count` messages, oldest first, as `[HH:MM:SS] <nick> text`
+    /// with the time from `clock`.
+    pub fn replay(&self, count: usize, clock: impl Fn(SystemTime) -> String) -> Vec<String> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .skip(entries.len().saturating_sub(count))
+            .map(|entry| {
+                format!(
+                    "[{}] {} {}",
+                    clock(entry.time),
+                    style::nick(&entry.nick),
+                    entry.text
+                )
+            })
+            .collect()
+    }
+
+    /// Write every message kept to `path` in the `--log` text format.
+    /// Returns how many there were.
+    pub fn save(&self, path: &Path) -> io::Result<usize> {
+        let entries = self.entries.lock().unwrap();
+        let text: String = entries
+            .iter()
+            .map(|entry| {
+                log_line(
+                    LogFormat::Text,
+                    entry.time,
+                    entry.direction,
+                    &entry.nick,
+                    &entry.text,
+                )
+            })
+            .collect();
+        fs::write(path, text)?;
+        Ok(entries.len())
+    }
+}
+
+/// `s` as a quoted JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `time` as an ISO-8601 UTC timestamp with milliseconds, e.g.
+/// `2024-05-01T12:30:00.250Z`.
+fn iso8601(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem / 60 % 60,
+        rem % 60,
+        since.subsec_millis()
+    )
+}
+
+/// `time` as `HH:MM:SS` in a zone `offset` seconds east of UTC.
+pub fn clock_text(time: SystemTime, offset: i64) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let of_day = (secs + offset).rem_euclid(86_400);
+    format!(
+        "{:02}:{:02}:{:02}",
+        of_day / 3_600,
+        of_day / 60 % 60,
+        of_day % 60
+    )
+}
+
+/// `time` as `HH:MM:SS` on the local clock.
+pub fn local_clock(time: SystemTime) -> String {
+    clock_text(time, utc_offset(time))
+}
+
+/// A delay of `millis` for display: `350 ms`, `2.4 s`. Negative when the
+/// peer's clock is ahead of ours.
+pub fn latency_text(millis: i64) -> String {
+    if millis.abs() < 1_000 {
+        format!("{} ms", millis)
+    } else {
+        format!("{:.1} s", millis as f64 / 1_000.0)
+    }
+}
+
+/// Milliseconds from `sent_at` to `now`, negative if `sent_at` is later.
+pub fn millis_between(sent_at: SystemTime, now: SystemTime) -> i64 {
+    match now.duration_since(sent_at) {
+        Ok(d) => d.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+/// Seconds the local zone is east of UTC at `time`, or 0 if unknown.
+#[cfg(unix)]
+fn utc_offset(time: SystemTime) -> i64 {
+    use std::ffi::{c_char, c_int, c_long};
+
+    /// `struct tm` as glibc, musl and the BSDs lay it out.
+    #[repr(C)]
+    struct Tm {
+        _fields: [c_int; 9],
+        gmtoff: c_long,
+        _zone: *const c_char,
+    }
+    unsafe extern "C" {
+        fn localtime_r(time: *const c_long, result: *mut Tm) -> *mut Tm;
+    }
+
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as c_long;
+    let mut tm = Tm {
+        _fields: [0; 9],
+        gmtoff: 0,
+        _zone: std::ptr::null(),
+    };
+    // SAFETY: both pointers are to live locals of the types the C
+    // function expects.
+    if unsafe { localtime_r(&secs, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.gmtoff as i64
+}
+
+/// Seconds the local zone is east of UTC now, or 0 if unknown.
+#[cfg(windows)]
+fn utc_offset(_time: SystemTime) -> i64 {
+    #[repr(C)]
+    struct TimeZoneInformation {
+        bias: i32,
+        _standard_name: [u16; 32],
+        _standard_date: [u16; 8],
+        standard_bias: i32,
+        _daylight_name: [u16; 32],
+        _daylight_date: [u16; 8],
+        daylight_bias: i32,
+    }
+    const TIME_ZONE_ID_STANDARD: u32 = 1;
+    const TIME_ZONE_ID_DAYLIGHT: u32 = 2;
+    const TIME_ZONE_ID_INVALID: u32 = u32::MAX;
+    unsafe extern "system" {
+        fn GetTimeZoneInformation(info: *mut TimeZoneInformation) -> u32;
+    }
+
+    let mut info = TimeZoneInformation {
+        bias: 0,
+        _standard_name: [0; 32],
+        _standard_date: [0; 8],
+        standard_bias: 0,
+        _daylight_name: [0; 32],
+        _daylight_date: [0; 8],
+        daylight_bias: 0,
+    };
+    // SAFETY: the pointer is to a live local of the expected layout.
+    let bias = match unsafe { GetTimeZoneInformation(&mut info) } {
+        TIME_ZONE_ID_INVALID => return 0,
+        TIME_ZONE_ID_STANDARD => info.bias + info.standard_bias,
+        TIME_ZONE_ID_DAYLIGHT => info.bias + info.daylight_bias,
+        _ => info.bias,
+    };
+    // The bias is minutes to add to local time to get UTC
+    -i64::from(bias) * 60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("streamchat-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn clock_and_latency_text() {
+        let time = UNIX_EPOCH + Duration::from_secs(86_400 + 3_600 + 2 * 60 + 3);
+        assert_eq!(clock_text(time, 0), "01:02:03");
+        assert_eq!(clock_text(time, -7_200), "23:02:03");
+        assert_eq!(latency_text(350), "350 ms");
+        assert_eq!(latency_text(-2_400), "-2.4 s");
+    }
+
+    #[test]
+    fn transcripts_record_both_directions() {
+        let path = temp_path("transcript");
+        let transcript = Transcript::open(path.to_str().unwrap(), LogFormat::Json).unwrap();
+        transcript
+            .record(Direction::Sent, "alice", "hi \"bob\"")
+            .unwrap();
+        transcript
+            .record(Direction::Received, "bob", "hi\n")
+            .unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#""direction":"sent""#), "{}", lines[0]);
+        assert!(lines[0].contains(r#""text":"hi \"bob\"""#), "{}", lines[0]);
+        assert!(lines[1].contains(r#""nick":"bob""#), "{}", lines[1]);
+        assert!(lines[1].contains(r#""text":"hi\n""#), "{}", lines[1]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn history_keeps_the_latest_messages() {
+        let history = History::new(3);
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        for (n, direction) in [Direction::Sent, Direction::Received]
+            .into_iter()
+            .cycle()
+            .take(5)
+            .enumerate()
+        {
+            let nick = match direction {
+                Direction::Sent => "alice",
+                Direction::Received => "bob",
+            };
+            history.push(direction, at(n as u64), nick, &format!("message {}", n));
+        }
+        let clock = |time| clock_text(time, 3_600);
+        assert_eq!(
+            history.replay(10, clock),
+            [
+                "[01:00:02] <alice> message 2",
+                "[01:00:03] <bob> message 3",
+                "[01:00:04] <alice> message 4",
+            ]
+        );
+        assert_eq!(history.replay(1, clock), ["[01:00:04] <alice> message 4"]);
+        assert!(History::new(0).replay(5, clock).is_empty());
+    }
+
+    #[test]
+    fn history_saves_as_a_text_log() {
+        let history = History::new(HISTORY);
+        let time = UNIX_EPOCH + Duration::from_millis(1_714_566_600_250);
+        history.push(Direction::Sent, time, "alice", "hi");
+        history.push(Direction::Received, time, "bob", "hello\tthere");
+        let path = temp_path("history");
+        assert_eq!(history.save(&path).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "2024-05-01T12:30:00.250Z > <alice> hi\n2024-05-01T12:30:00.250Z < <bob> hello\tthere\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
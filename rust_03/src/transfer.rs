@@ -0,0 +1,549 @@
+//! Files sent with `/send`: a FILE_META frame announcing the name, size
+//! and checksum, then FILE_CHUNK frames with the contents. The receiver
+//! writes to a partial file and only gives it its name once the checksum
+//! matches.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::chat::{Sender, prompt};
+use crate::cipher::Sha256;
+use crate::proto::{FRAME_FILE_CHUNK, FRAME_FILE_META};
+use crate::{say, say_to};
+
+/// Size of each FILE_CHUNK payload.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Directory incoming files are saved in, relative to where we run.
+pub const RECEIVED_DIR: &str = "received";
+
+/// What a FILE_META frame announces. Encoded as the size (8 bytes,
+/// big-endian), the SHA-256 of the contents, then the name.
+struct FileMeta {
+    name: String,
+    size: u64,
+    checksum: [u8; 32],
+}
+
+impl FileMeta {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(40 + self.name.len());
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&self.checksum);
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 40 {
+            return None;
+        }
+        let (size, rest) = bytes.split_at(8);
+        let (checksum, name) = rest.split_at(32);
+        Some(Self {
+            name: String::from_utf8(name.to_vec()).ok()?,
+            size: u64::from_be_bytes(size.try_into().ok()?),
+            checksum: checksum.try_into().ok()?,
+        })
+    }
+}
+
+/// The name to save an incoming file under: the last component of what
+/// the peer sent, so the file cannot land outside the directory it is
+/// received into. `None` if nothing usable is left.
+fn safe_file_name(name: &str) -> Option<&str> {
+    let name = name.rsplit(['/', '\\', ':']).next()?;
+    if name.is_empty() || name == "." || name == ".." || name.chars().any(char::is_control) {
+        return None;
+    }
+    Some(name)
+}
+
+/// Print progress each time another tenth of `total` has been passed.
+/// Returns whether anything was printed.
+fn report_progress(
+    out: &mut impl Write,
+    verb: &str,
+    name: &str,
+    before: u64,
+    after: u64,
+    total: u64,
+) -> bool {
+    let tenth = |n: u64| n.saturating_mul(10) / total.max(1);
+    if tenth(after) <= tenth(before) {
+        return false;
+    }
+    let _ = say_to!(
+        out,
+        "\r[FILE] {} {}: {}/{} bytes ({}%)",
+        verb,
+        name,
+        after,
+        total,
+        tenth(after) * 10
+    );
+    true
+}
+
+/// Send the file at `path` as a FILE_META frame then FILE_CHUNK frames.
+/// Should reading fail partway, an empty chunk tells the peer to give up.
+pub fn send_file(
+    sender: &Sender<impl Write>,
+    path: &Path,
+    quitting: &AtomicBool,
+) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no usable file name"))?
+        .to_string();
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; CHUNK_SIZE.min(sender.max_message)];
+
+    // First pass for the size and checksum the peer will check against
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    let meta = FileMeta {
+        name,
+        size,
+        checksum: hasher.finish(),
+    };
+    sender.send(FRAME_FILE_META, &meta.encode())?;
+    say!("\r[FILE] Sending {} ({} bytes)...", meta.name, size);
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut sent = 0u64;
+    while sent < size {
+        let read = if quitting.load(Ordering::SeqCst) {
+            Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "cancelled on /quit",
+            ))
+        } else {
+            file.read(&mut buf).and_then(|n| match n {
+                0 => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file shrank while sending",
+                )),
+                n => Ok(n.min((size - sent) as usize)),
+            })
+        };
+        let n = match read {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = sender.send(FRAME_FILE_CHUNK, &[]);
+                return Err(e);
+            }
+        };
+        sender.send(FRAME_FILE_CHUNK, &buf[..n])?;
+        let mut out = io::stdout().lock();
+        report_progress(&mut out, "Sending", &meta.name, sent, sent + n as u64, size);
+        sent += n as u64;
+    }
+    let mut out = io::stdout().lock();
+    say_to!(out, "\r[FILE] Sent {} ({} bytes)", meta.name, size)?;
+    prompt(&mut out)
+}
+
+/// A file being received. It is written to `part` and only renamed to
+/// `path` once the size and checksum match; dropping it before then
+/// removes the partial file.
+pub struct Incoming {
+    meta: FileMeta,
+    path: PathBuf,
+    part: PathBuf,
+    /// `None` once writing has failed: the rest is read and discarded.
+    file: Option<File>,
+    received: u64,
+    hasher: Sha256,
+}
+
+impl Incoming {
+    /// Pick a free name in `dir` and create the partial file. Without a
+    /// usable file, the transfer is still tracked so its chunks can be
+    /// discarded.
+    fn create(meta: FileMeta, dir: &Path) -> (Self, io::Result<()>) {
+        let name = safe_file_name(&meta.name).unwrap_or("unnamed").to_string();
+        let mut path = dir.join(&name);
+        let mut n = 1;
+        while path.exists() || path.with_extension(part_extension(&path)).exists() {
+            path = numbered(dir, &name, n);
+            n += 1;
+        }
+        let part = path.with_extension(part_extension(&path));
+        let file = fs::create_dir_all(dir)
+            .and_then(|_| File::options().write(true).create_new(true).open(&part));
+        let (file, result) = match file {
+            Ok(file) => (Some(file), Ok(())),
+            Err(e) => (None, Err(e)),
+        };
+        let incoming = Self {
+            meta,
+            path,
+            part,
+            file,
+            received: 0,
+            hasher: Sha256::new(),
+        };
+        (incoming, result)
+    }
+
+    /// Check the size and checksum, then move the file into place. A file
+    /// that appeared under the chosen name meanwhile is kept, and the next
+    /// free number is taken instead.
+    fn finish(mut self) -> io::Result<PathBuf> {
+        let file = self
+            .file
+            .take()
+            .ok_or_else(|| io::Error::other("nothing was saved"))?;
+        file.sync_all()?;
+        drop(file);
+        let hasher = std::mem::take(&mut self.hasher);
+        if self.received != self.meta.size || hasher.finish() != self.meta.checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checksum mismatch, file discarded",
+            ));
+        }
+        // Unlike rename, a hard link never replaces an existing file. The
+        // partial name goes when this is dropped.
+        let name = safe_file_name(&self.meta.name)
+            .unwrap_or("unnamed")
+            .to_string();
+        let dir = self.path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        let mut n = 1;
+        loop {
+            match fs::hard_link(&self.part, &self.path) {
+                Ok(()) => return Ok(self.path.clone()),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    self.path = numbered(&dir, &name, n);
+                    n += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Give up on the file when the connection ends partway through it,
+    /// saying how far it got. Dropping it removes the partial file.
+    pub fn interrupted(self) {
+        say!(
+            "\r[FILE] Receiving {} interrupted after {} of {} bytes; partial file removed",
+            self.meta.name,
+            self.received,
+            self.meta.size
+        );
+    }
+}
+
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.part);
+    }
+}
+
+/// The `n`th alternative to `name` in `dir`: `name.n`.
+fn numbered(dir: &Path, name: &str, n: usize) -> PathBuf {
+    dir.join(format!("{}.{}", name, n))
+}
+
+/// The extension for `path`'s partial file: its own with `.part` added.
+fn part_extension(path: &Path) -> String {
+    match path.extension() {
+        Some(ext) => format!("{}.part", ext.to_string_lossy()),
+        None => "part".to_string(),
+    }
+}
+
+/// Start receiving the file a FILE_META frame announces into `dir`.
+/// Empty files are complete at once.
+pub fn start_incoming(out: &mut impl Write, dir: &Path, payload: &[u8]) -> Option<Incoming> {
+    let Some(meta) = FileMeta::decode(payload) else {
+        let _ = say_to!(out, "\r[FILE] Ignoring a malformed file announcement");
+        return None;
+    };
+    let size = meta.size;
+    let (incoming, created) = Incoming::create(meta, dir);
+    match created {
+        Ok(()) => {
+            let _ = say_to!(
+                out,
+                "\r[FILE] Receiving {} ({} bytes) into {}",
+                incoming.meta.name,
+                size,
+                incoming.path.display()
+            );
+        }
+        Err(e) => {
+            let _ = say_to!(
+                out,
+                "\r[FILE] Cannot save {}: {}; discarding it",
+                incoming.meta.name,
+                e
+            );
+        }
+    }
+    if size == 0 {
+        let mut slot = Some(incoming);
+        finish_incoming(out, &mut slot);
+        return None;
+    }
+    Some(incoming)
+}
+
+/// Add one FILE_CHUNK's data to the file being received. An empty chunk
+/// means the sender gave up. Returns whether anything was printed.
+pub fn receive_chunk(out: &mut impl Write, slot: &mut Option<Incoming>, data: &[u8]) -> bool {
+    let Some(incoming) = slot else {
+        let _ = say_to!(
+            out,
+            "\r[FILE] Ignoring file data with no transfer in progress"
+        );
+        return true;
+    };
+    if data.is_empty() {
+        let _ = say_to!(
+            out,
+            "\r[FILE] Peer cancelled sending {}; partial file removed",
+            incoming.meta.name
+        );
+        *slot = None;
+        return true;
+    }
+    let mut printed = false;
+    if let Some(file) = &mut incoming.file
+        && let Err(e) = file.write_all(data)
+    {
+        let _ = say_to!(
+            out,
+            "\r[FILE] Writing {} failed: {}; discarding the rest",
+            incoming.part.display(),
+            e
+        );
+        incoming.file = None;
+        let _ = fs::remove_file(&incoming.part);
+        printed = true;
+    }
+    incoming.hasher.update(data);
+    let before = incoming.received;
+    incoming.received += data.len() as u64;
+    if incoming.file.is_some() {
+        let (name, after, size) = (&incoming.meta.name, incoming.received, incoming.meta.size);
+        printed |= report_progress(out, "Receiving", name, before, after, size);
+    }
+    if incoming.received >= incoming.meta.size {
+        printed |= finish_incoming(out, slot);
+    }
+    printed
+}
+
+/// Complete the transfer in `slot` and say how it went. One whose file
+/// could not be written was already reported, so it ends quietly.
+fn finish_incoming(out: &mut impl Write, slot: &mut Option<Incoming>) -> bool {
+    let Some(incoming) = slot.take() else {
+        return false;
+    };
+    if incoming.file.is_none() {
+        return false;
+    }
+    let (name, size) = (incoming.meta.name.clone(), incoming.meta.size);
+    let _ = match incoming.finish() {
+        Ok(path) => say_to!(
+            out,
+            "\r[FILE] Saved {} ({} bytes, checksum OK)",
+            path.display(),
+            size
+        ),
+        Err(e) => say_to!(out, "\r[FILE] Receiving {} failed: {}", name, e),
+    };
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::Rekey;
+    use crate::cipher::{CipherKind, CipherPair};
+    use crate::dh::{DhGroup, DhParams, test_secret};
+    use crate::proto::{MAX_MESSAGE, RecvKeys};
+    use crate::session::SessionStats;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("streamchat-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The frames `send_file` writes for `path`, opened, and what it
+    /// returned.
+    fn sent_frames(path: &Path, quitting: bool) -> (Vec<(u8, Vec<u8>)>, io::Result<()>) {
+        let keys = |is_server| CipherPair::derive(&test_secret(1), CipherKind::ChaCha20, is_server);
+        let rekey = Rekey {
+            group: DhGroup::Small(DhParams::DEFAULT),
+            cipher: CipherKind::ChaCha20,
+            is_server: true,
+            seed: Some(7),
+            messages: None,
+            interval: None,
+            psk: None,
+        };
+        let stats = SessionStats::new();
+        let mut wire = Vec::new();
+        let sender = Sender::new(&mut wire, &stats, keys(true).send, rekey, MAX_MESSAGE, None);
+        let result = send_file(&sender, path, &AtomicBool::new(quitting));
+        drop(sender);
+        let mut recv = RecvKeys::new(keys(false).recv);
+        let mut reader = &wire[..];
+        let mut frames = Vec::new();
+        while !reader.is_empty() {
+            let frame = recv.read_frame(&mut reader, MAX_MESSAGE).unwrap();
+            frames.push((frame.kind, recv.open(&frame).unwrap().1));
+        }
+        (frames, result)
+    }
+
+    /// Feed `frames` to the receiving side, saving into `dir`.
+    fn receive(dir: &Path, frames: &[(u8, Vec<u8>)]) -> String {
+        let mut out = Vec::new();
+        let mut incoming = None;
+        for (kind, payload) in frames {
+            match *kind {
+                FRAME_FILE_META => incoming = start_incoming(&mut out, dir, payload),
+                _ => {
+                    receive_chunk(&mut out, &mut incoming, payload);
+                }
+            }
+        }
+        assert!(incoming.is_none(), "the transfer should be over");
+        String::from_utf8(out).unwrap()
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn incoming_names_stay_inside_the_directory() {
+        assert_eq!(safe_file_name("notes.txt"), Some("notes.txt"));
+        assert_eq!(safe_file_name("../../etc/passwd"), Some("passwd"));
+        assert_eq!(safe_file_name("C:\\Users\\bob\\a.txt"), Some("a.txt"));
+        assert_eq!(safe_file_name("dir/"), None);
+        assert_eq!(safe_file_name(".."), None);
+        assert_eq!(safe_file_name("bell\u{7}"), None);
+    }
+
+    #[test]
+    fn file_announcements_round_trip() {
+        let meta = FileMeta {
+            name: "notes.txt".to_string(),
+            size: 1234,
+            checksum: [9; 32],
+        };
+        let decoded = FileMeta::decode(&meta.encode()).unwrap();
+        assert_eq!(decoded.name, "notes.txt");
+        assert_eq!(decoded.size, 1234);
+        assert_eq!(decoded.checksum, [9; 32]);
+        assert!(FileMeta::decode(&[0; 39]).is_none());
+    }
+
+    #[test]
+    fn a_sent_file_is_saved_whole_without_replacing_another() {
+        let dir = temp_dir("transfer-whole");
+        let source = dir.join("data.bin");
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i * 7) as u8).collect();
+        fs::write(&source, &data).unwrap();
+        let (frames, result) = sent_frames(&source, false);
+        result.unwrap();
+        assert_eq!(frames.len(), 4, "a file announcement then three chunks");
+
+        let received = dir.join("received");
+        fs::create_dir_all(&received).unwrap();
+        fs::write(received.join("data.bin"), b"already here").unwrap();
+        let out = receive(&received, &frames);
+        assert!(out.contains("checksum OK"), "{}", out);
+        assert_eq!(file_names(&received), ["data.bin", "data.bin.1"]);
+        assert_eq!(fs::read(received.join("data.bin.1")).unwrap(), data);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_file_created_during_the_transfer_is_not_replaced() {
+        let dir = temp_dir("transfer-late");
+        let source = dir.join("data.bin");
+        let data: Vec<u8> = (0..CHUNK_SIZE + 100).map(|i| (i * 3) as u8).collect();
+        fs::write(&source, &data).unwrap();
+        let (frames, result) = sent_frames(&source, false);
+        result.unwrap();
+
+        let received = dir.join("received");
+        let (last, first) = frames.split_last().unwrap();
+        let mut out = Vec::new();
+        let mut incoming = start_incoming(&mut out, &received, &first[0].1);
+        for (_, payload) in &first[1..] {
+            receive_chunk(&mut out, &mut incoming, payload);
+        }
+        // Both the name and its first alternative turn up before the end
+        fs::write(received.join("data.bin"), b"late").unwrap();
+        fs::write(received.join("data.bin.1"), b"later").unwrap();
+        receive_chunk(&mut out, &mut incoming, &last.1);
+        assert!(incoming.is_none());
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("checksum OK"), "{}", out);
+        assert_eq!(
+            file_names(&received),
+            ["data.bin", "data.bin.1", "data.bin.2"]
+        );
+        assert_eq!(fs::read(received.join("data.bin")).unwrap(), b"late");
+        assert_eq!(fs::read(received.join("data.bin.1")).unwrap(), b"later");
+        assert_eq!(fs::read(received.join("data.bin.2")).unwrap(), data);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_corrupted_file_is_discarded() {
+        let dir = temp_dir("transfer-corrupt");
+        let source = dir.join("data.bin");
+        fs::write(&source, vec![1u8; 1000]).unwrap();
+        let (mut frames, result) = sent_frames(&source, false);
+        result.unwrap();
+        frames[1].1[500] ^= 1;
+
+        let received = dir.join("received");
+        let out = receive(&received, &frames);
+        assert!(out.contains("checksum mismatch"), "{}", out);
+        assert!(file_names(&received).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_cancelled_file_leaves_nothing_behind() {
+        let dir = temp_dir("transfer-cancel");
+        let source = dir.join("data.bin");
+        fs::write(&source, vec![1u8; 1000]).unwrap();
+        let (frames, result) = sent_frames(&source, true);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(frames.last().unwrap(), &(FRAME_FILE_CHUNK, vec![]));
+
+        let received = dir.join("received");
+        let out = receive(&received, &frames);
+        assert!(out.contains("Peer cancelled"), "{}", out);
+        assert!(file_names(&received).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
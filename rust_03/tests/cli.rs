@@ -0,0 +1,984 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rust_03::datagram::{DATAGRAM_OVERHEAD, DATAGRAM_VERSION};
+
+fn streamchat(args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rust_03"));
+    command
+        .args(args)
+        .env_remove("NO_COLOR")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    command
+}
+
+/// A fresh path in the temp directory for this test.
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("streamchat-{}-{}", name, std::process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+/// A script file holding `lines`.
+fn script(name: &str, lines: &[&str]) -> PathBuf {
+    let path = temp_path(name);
+    fs::write(&path, lines.join("\n") + "\n").unwrap();
+    path
+}
+
+/// Start a server or relay on port 0 and wait until it has written its
+/// port file. Its stdin stays open, as for someone at the keyboard.
+fn listen(mode: &str, name: &str, args: &[&str]) -> (Child, PathBuf) {
+    let (mut command, port_file) = listener(mode, name);
+    command.args(args);
+    (start(command, &port_file), port_file)
+}
+
+/// The command for a server or relay on port 0, and the port file it
+/// will write.
+fn listener(mode: &str, name: &str) -> (Command, PathBuf) {
+    let port_file = temp_path(name);
+    let command = streamchat(&[mode, "0", "--port-file", port_file.to_str().unwrap()]);
+    (command, port_file)
+}
+
+/// Spawn `command` and wait until it has written `port_file`.
+fn start(mut command: Command, port_file: &PathBuf) -> Child {
+    let child = command
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("spawn listener");
+    let started = Instant::now();
+    while fs::read_to_string(port_file).map_or(true, |text| !text.ends_with('\n')) {
+        assert!(started.elapsed() < Duration::from_secs(10), "no port file");
+        thread::sleep(Duration::from_millis(20));
+    }
+    child
+}
+
+/// A client of the listener behind `port_file`.
+fn client(port_file: &PathBuf, args: &[&str]) -> Command {
+    let mut command = streamchat(&["client", "127.0.0.1", "--port-file"]);
+    command.arg(port_file).args(args);
+    command
+}
+
+/// Wait for `child` and return whether it succeeded and all it printed.
+/// Its stdin stays open meanwhile, so it has to end on its own.
+fn finish(mut child: Child) -> (bool, String) {
+    let stdin = child.stdin.take();
+    let out = child.wait_with_output().expect("wait for streamchat");
+    drop(stdin);
+    let mut text = String::from_utf8(out.stdout).unwrap();
+    text.push_str(&String::from_utf8(out.stderr).unwrap());
+    (out.status.success(), text)
+}
+
+fn run(mut command: Command) -> (bool, String) {
+    finish(command.stdin(Stdio::null()).spawn().expect("spawn client"))
+}
+
+/// The last line a peer prints on connecting, once the secure channel is
+/// up and the hellos are exchanged.
+const CONNECTED: &str = "/help lists the other commands";
+
+/// A child's stdout, read on a thread of its own so that a test can wait
+/// for a line to appear and still check everything at the end.
+struct Watch {
+    lines: mpsc::Receiver<String>,
+    seen: String,
+}
+
+impl Watch {
+    fn new(child: &mut Child) -> Self {
+        let stdout = child.stdout.take().expect("piped stdout");
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                if line.map(|line| tx.send(line)).is_err() {
+                    break;
+                }
+            }
+        });
+        Watch {
+            lines,
+            seen: String::new(),
+        }
+    }
+
+    /// Wait until a line containing `text` has been printed.
+    fn until(&mut self, text: &str) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let Ok(line) = self.lines.recv_timeout(left) else {
+                panic!("no {text:?} in:\n{}", self.seen);
+            };
+            self.seen.push_str(&line);
+            self.seen.push('\n');
+            if line.contains(text) {
+                return;
+            }
+        }
+    }
+
+    /// Like `finish`, with the lines already seen at the front.
+    fn finish(self, child: Child) -> (bool, String) {
+        let (ok, stderr) = finish(child);
+        let mut text = self.seen;
+        for line in self.lines {
+            text.push_str(&line);
+            text.push('\n');
+        }
+        text.push_str(&stderr);
+        (ok, text)
+    }
+}
+
+/// Whether `out` shows each of `messages`, in order.
+fn in_order(out: &str, messages: &[String]) -> bool {
+    let mut rest = out;
+    messages
+        .iter()
+        .all(|message| match rest.find(message.as_str()) {
+            Some(at) => {
+                rest = &rest[at + message.len()..];
+                true
+            }
+            None => false,
+        })
+}
+
+#[cfg(unix)]
+fn signal(child: &Child, name: &str) {
+    let status = Command::new("kill")
+        .args([name, &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+/// Wait until every thread of `child` has stopped for a SIGSTOP, which
+/// can take effect some time after `kill` returns. Without /proc to ask,
+/// a short wait has to do.
+#[cfg(unix)]
+fn wait_until_stopped(child: &Child) {
+    let tasks = PathBuf::from(format!("/proc/{}/task", child.id()));
+    if !tasks.exists() {
+        thread::sleep(Duration::from_millis(200));
+        return;
+    }
+    let stopped = || {
+        fs::read_dir(&tasks).unwrap().all(|task| {
+            let stat = fs::read_to_string(task.unwrap().path().join("stat")).unwrap_or_default();
+            // The state comes right after the command name in parentheses
+            stat.rsplit(')')
+                .next()
+                .is_some_and(|rest| rest.trim_start().starts_with('T'))
+        })
+    };
+    let started = Instant::now();
+    while !stopped() {
+        assert!(started.elapsed() < Duration::from_secs(5), "still running");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// A UDP proxy in front of the server at `server`. Chat datagrams from
+/// the client are passed on out of order: the one numbered `held` waits
+/// until the one after it has gone, then goes twice. Everything else,
+/// the handshake included, passes straight through, until `stop` is set.
+fn reordering_proxy(
+    server: SocketAddr,
+    held: u64,
+    stop: Arc<AtomicBool>,
+) -> (SocketAddr, JoinHandle<()>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let address = socket.local_addr().unwrap();
+    let proxy = thread::spawn(move || {
+        let mut client = None;
+        let mut holding = None;
+        let mut datagram = [0u8; 65_536];
+        while !stop.load(Ordering::SeqCst) {
+            let Ok((len, from)) = socket.recv_from(&mut datagram) else {
+                continue;
+            };
+            let datagram = &datagram[..len];
+            if from == server {
+                socket.send_to(datagram, client.unwrap()).unwrap();
+                continue;
+            }
+            client = Some(from);
+            let seq = (len >= DATAGRAM_OVERHEAD && datagram[0] == DATAGRAM_VERSION)
+                .then(|| u64::from_be_bytes(datagram[2..10].try_into().unwrap()));
+            if seq == Some(held) {
+                holding = Some(datagram.to_vec());
+                continue;
+            }
+            socket.send_to(datagram, server).unwrap();
+            if let Some(late) = holding.take() {
+                socket.send_to(&late, server).unwrap();
+                socket.send_to(&late, server).unwrap();
+            }
+        }
+    });
+    (address, proxy)
+}
+
+#[test]
+fn selftest_passes() {
+    let (ok, out) = run(streamchat(&["selftest"]));
+    assert!(ok, "{out}");
+    assert!(out.contains("[SELFTEST] PASS"), "{out}");
+}
+
+#[test]
+fn scripted_chat_is_delivered_and_acknowledged() {
+    let server_script = script("server-script", &["hi", "fine"]);
+    let client_script = script("client-script", &["hello", "how are you"]);
+    let (server, port_file) = listen(
+        "server",
+        "scripted",
+        &[
+            "--once",
+            "--nick",
+            "srv",
+            "--script",
+            server_script.to_str().unwrap(),
+        ],
+    );
+    let (ok, client_out) = run(client(
+        &port_file,
+        &["--nick", "cli", "--script", client_script.to_str().unwrap()],
+    ));
+    let (server_ok, server_out) = finish(server);
+    assert!(ok, "{client_out}");
+    assert!(server_ok, "{server_out}");
+    for line in [
+        "<srv> hi",
+        "<srv> fine",
+        "[✓] #1 hello",
+        "[✓] #2 how are you",
+    ] {
+        assert!(
+            client_out.contains(line),
+            "{line:?} missing from {client_out}"
+        );
+    }
+    for line in [
+        "<cli> hello",
+        "<cli> how are you",
+        "[✓] #1 hi",
+        "[✓] #2 fine",
+    ] {
+        assert!(
+            server_out.contains(line),
+            "{line:?} missing from {server_out}"
+        );
+    }
+    assert!(
+        server_out.contains("messages sent: 2, received: 2"),
+        "{server_out}"
+    );
+}
+
+#[test]
+fn raw_mode_sends_lines_exactly() {
+    let server_script = script("raw-server", &["ok"]);
+    let client_script = script("raw-client", &["", "  lead\ttab 🦀 日本語 "]);
+    let (server, port_file) = listen(
+        "server",
+        "raw",
+        &[
+            "--once",
+            "--raw",
+            "--nick",
+            "srv",
+            "--script",
+            server_script.to_str().unwrap(),
+        ],
+    );
+    let (ok, client_out) = run(client(
+        &port_file,
+        &[
+            "--raw",
+            "--nick",
+            "cli",
+            "--script",
+            client_script.to_str().unwrap(),
+        ],
+    ));
+    let (server_ok, server_out) = finish(server);
+    assert!(ok, "{client_out}");
+    assert!(server_ok, "{server_out}");
+    assert!(
+        client_out.contains("[CHAT] Empty message not sent"),
+        "{client_out}"
+    );
+    assert!(
+        client_out.contains("[✓] #1   lead\\ttab 🦀 日本語 \n"),
+        "{client_out}"
+    );
+    assert!(
+        server_out.contains("<cli>   lead\\ttab 🦀 日本語 \n"),
+        "{server_out}"
+    );
+}
+
+#[test]
+fn raw_mode_sends_bytes_that_are_not_utf8_unchanged() {
+    let (server, port_file) = listen("server", "raw-bytes", &["--once", "--raw"]);
+    let mut client = client(&port_file, &["--raw", "--nick", "cli"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("spawn client");
+    let mut watch = Watch::new(&mut client);
+    client
+        .stdin
+        .as_mut()
+        .unwrap()
+        .write_all(b"caf\xe9 \xff\xfe ok\n//x\x80\n")
+        .unwrap();
+    watch.until("[✓] #2 ");
+    drop(client.stdin.take());
+    let (ok, client_out) = watch.finish(client);
+    let (server_ok, server_out) = finish(server);
+    assert!(ok, "{client_out}");
+    assert!(server_ok, "{server_out}");
+    // A raw peer shows each byte that is not UTF-8 as it came
+    assert!(
+        server_out.contains("<cli> caf\\xe9 \\xff\\xfe ok\n"),
+        "{server_out}"
+    );
+    assert!(server_out.contains("<cli> /x\\x80\n"), "{server_out}");
+    assert!(!server_out.contains('\u{fffd}'), "{server_out}");
+    assert!(
+        client_out.contains("[✓] #1 caf\\xe9 \\xff\\xfe ok\n"),
+        "{client_out}"
+    );
+}
+
+#[test]
+fn stats_show_traffic_on_request_and_on_exit() {
+    let server_script = script("stats-server", &["hi"]);
+    let client_script = script("stats-client", &["/stats", "hello"]);
+    let (server, port_file) = listen(
+        "server",
+        "stats",
+        &["--once", "--script", server_script.to_str().unwrap()],
+    );
+    let (ok, out) = run(client(
+        &port_file,
+        &[
+            "--stats-on-exit",
+            "--pad",
+            "64",
+            "--script",
+            client_script.to_str().unwrap(),
+        ],
+    ));
+    finish(server);
+    assert!(ok, "{out}");
+    // The server's line may or may not have arrived by the time we ask
+    let asked = out
+        .find("[STATS] Messages sent: 0, received: ")
+        .expect(&out);
+    let on_exit = out
+        .find("[STATS] Messages sent: 1, received: 1")
+        .expect(&out);
+    assert!(asked < on_exit, "{out}");
+    assert!(
+        out[on_exit..].contains("[STATS] Round trip: average "),
+        "{out}"
+    );
+    assert!(
+        out.contains("[STATS] Cipher: chacha20, key epoch 0"),
+        "{out}"
+    );
+    assert!(out.contains("padding to 64 bytes"), "{out}");
+    assert!(out.contains("[STATS] Frame format 0x89"), "{out}");
+}
+
+#[test]
+fn the_older_frame_format_offered_is_used() {
+    let (server, port_file) = listen("server", "frame-format", &["--once", "-v"]);
+    let lines = script("frame-format-client", &["/stats"]);
+    let (ok, out) = run(client(
+        &port_file,
+        &[
+            "--frame-format",
+            "8",
+            "-v",
+            "--script",
+            lines.to_str().unwrap(),
+        ],
+    ));
+    let (server_ok, server_out) = finish(server);
+    assert!(ok, "{out}");
+    assert!(server_ok, "{server_out}");
+    assert!(
+        out.contains("[STREAM] Frame format 0x88: one running keystream"),
+        "{out}"
+    );
+    assert!(out.contains("[STATS] Frame format 0x88"), "{out}");
+    assert!(
+        server_out.contains("[STREAM] Frame format 0x88"),
+        "{server_out}"
+    );
+    assert!(!server_out.contains("unknown frame type"), "{server_out}");
+}
+
+#[test]
+fn slash_commands_are_dispatched() {
+    let server_script = script("commands-server", &["ok"]);
+    let client_script = script("commands-client", &["/dance", "/help", "//slash"]);
+    let (server, port_file) = listen(
+        "server",
+        "commands",
+        &["--once", "--script", server_script.to_str().unwrap()],
+    );
+    let (ok, client_out) = run(client(
+        &port_file,
+        &["--nick", "cli", "--script", client_script.to_str().unwrap()],
+    ));
+    let (_, server_out) = finish(server);
+    assert!(ok, "{client_out}");
+    let unknown = client_out
+        .find("[ERROR] Unknown command /dance; the commands are:")
+        .expect(&client_out);
+    assert!(client_out[unknown..].contains("/send PATH"), "{client_out}");
+    assert!(client_out.contains("[CHAT] Commands:"), "{client_out}");
+    assert!(server_out.contains("<cli> /slash"), "{server_out}");
+    assert!(!server_out.contains("dance"), "{server_out}");
+}
+
+#[test]
+fn history_is_replayed_and_saved() {
+    let saved = temp_path("saved-history");
+    let save = format!("/save {}", saved.display());
+    let server_script = script("history-server", &["hi"]);
+    let client_script = script("history-client", &["hello", "/history", &save]);
+    let (server, port_file) = listen(
+        "server",
+        "history",
+        &[
+            "--once",
+            "--nick",
+            "srv",
+            "--script",
+            server_script.to_str().unwrap(),
+        ],
+    );
+    let (ok, out) = run(client(
+        &port_file,
+        &["--nick", "cli", "--script", client_script.to_str().unwrap()],
+    ));
+    finish(server);
+    assert!(ok, "{out}");
+    assert!(out.contains("] <cli> hello\n"), "{out}");
+    assert!(out.contains("] <srv> hi\n"), "{out}");
+    assert!(out.contains("[HISTORY] Saved 2 message(s)"), "{out}");
+    let log = fs::read_to_string(&saved).unwrap();
+    assert!(log.contains("> <cli> hello\n"), "{log}");
+    assert!(log.contains("< <srv> hi\n"), "{log}");
+    fs::remove_file(&saved).unwrap();
+}
+
+#[test]
+fn udp_chat_survives_reordering_and_repeats() {
+    let (server, port_file) = listen(
+        "server",
+        "udp",
+        &["--once", "--transport", "udp", "--nick", "srv"],
+    );
+    let server_addr = fs::read_to_string(&port_file)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    // Datagrams 0 and 1 are the HELLO and PRESENCE; 2 is "one"
+    let (proxy_addr, proxy) = reordering_proxy(server_addr, 2, Arc::clone(&stop));
+    let lines = script("udp-client", &["one", "two", "three"]);
+    let (ok, client_out) = run(streamchat(&[
+        "client",
+        &proxy_addr.to_string(),
+        "--transport",
+        "udp",
+        "--nick",
+        "cli",
+        "--script",
+        lines.to_str().unwrap(),
+        "--script-delay",
+        "0.2",
+    ]));
+    let (server_ok, server_out) = finish(server);
+    stop.store(true, Ordering::SeqCst);
+    proxy.join().unwrap();
+    assert!(ok, "{client_out}");
+    assert!(server_ok, "{server_out}");
+    for line in [
+        "[UDP] 1 datagram(s) missing before #3",
+        "[UDP] Datagram #2 arrived out of order",
+        "[UDP] Dropped a repeat of datagram #2",
+        "<cli> one",
+        "<cli> two",
+        "<cli> three",
+        "(the peer left)",
+        "[UDP] Peer's datagrams: 1 out of order, 1 repeated, 0 missing",
+    ] {
+        assert!(
+            server_out.contains(line),
+            "{line:?} missing from {server_out}"
+        );
+    }
+    // "two" overtook "one" but each was shown once
+    assert!(server_out.find("<cli> two") < server_out.find("<cli> one"));
+    assert_eq!(server_out.matches("<cli> one").count(), 1, "{server_out}");
+    for seq in 1..=3 {
+        assert!(client_out.contains(&format!("[✓] #{seq} ")), "{client_out}");
+    }
+}
+
+#[test]
+fn a_test_seed_repeats_the_session() {
+    let lines = script("test-seed-client", &["hello"]);
+    // The keystream each message used; the send time in the message
+    // changes from run to run, but not these
+    let keys = |out: &str| {
+        out.lines()
+            .filter(|line| line.starts_with("Keystream: ") || line.starts_with("Key ["))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    let session = |name: &str| {
+        let (server, port_file) = listen("server", name, &["--once", "--test-seed", "42", "-vv"]);
+        let (ok, out) = run(client(
+            &port_file,
+            &[
+                "--test-seed",
+                "42",
+                "-vv",
+                "--script",
+                lines.to_str().unwrap(),
+                "--script-delay",
+                "0.2",
+            ],
+        ));
+        let (server_ok, server_out) = finish(server);
+        assert!(ok, "{out}");
+        assert!(server_ok, "{server_out}");
+        assert!(!out.contains("[VERIFY]"), "{out}");
+        (keys(&out), keys(&server_out))
+    };
+    let (first, server) = session("test-seed-1");
+    assert_eq!(
+        first,
+        [
+            "Keystream: 60 79 71 2F A4 7A 77 22 36 DB 80 B8 ...",
+            "Key [15..40]: ea b0 e2 ae d0 09 9d 8e a2 5c a3 72 7c 8c 45 24 73 19 e6 3f b3 80 37 d0 08 ",
+        ]
+    );
+    // The server read the message with the same keystream
+    assert_eq!(server[1], first[1]);
+    assert_eq!(session("test-seed-2").0, first);
+}
+
+#[test]
+fn server_takes_one_client_after_another() {
+    let (server, port_file) = listen("server", "one-after-another", &[]);
+    for n in 1..=2 {
+        let message = format!("message {n}");
+        let lines = script(&format!("client-{n}"), &[&message]);
+        let (ok, out) = run(client(
+            &port_file,
+            &["--script", lines.to_str().unwrap(), "--script-delay", "0.2"],
+        ));
+        assert!(ok, "{out}");
+        assert!(out.contains(&format!("[✓] #1 {message}")), "{out}");
+    }
+    let mut server = server;
+    server.kill().unwrap();
+    let (_, out) = finish(server);
+    assert!(
+        out.contains("(session 1)") && out.contains("(session 2)"),
+        "{out}"
+    );
+}
+
+#[test]
+fn pre_shared_keys_must_match() {
+    let lines = script("psk-script", &["secret plans"]);
+    let reply = script("psk-reply", &["noted"]);
+    let chat = |name: &str, server_psk: &str, client_psk: &str| {
+        let (server, port_file) = listen(
+            "server",
+            name,
+            &[
+                "--once",
+                "--psk",
+                server_psk,
+                "--script",
+                reply.to_str().unwrap(),
+            ],
+        );
+        let client = run(client(
+            &port_file,
+            &["--psk", client_psk, "--script", lines.to_str().unwrap()],
+        ));
+        (finish(server), client)
+    };
+    let ((_, server_out), (ok, out)) = chat("psk-match", "swordfish", "swordfish");
+    assert!(ok, "{out}");
+    assert!(server_out.contains("secret plans"), "{server_out}");
+
+    let ((server_ok, server_out), (ok, out)) = chat("psk-mismatch", "swordfish", "marlin");
+    assert!(!ok && !server_ok);
+    assert!(
+        server_out.contains("the pre-shared keys differ"),
+        "{server_out}"
+    );
+    assert!(
+        out.contains("the server rejected our pre-shared key"),
+        "{out}"
+    );
+    assert!(!server_out.contains("secret plans"), "{server_out}");
+}
+
+#[test]
+fn relay_pairs_two_clients() {
+    let (relay, port_file) = listen("relay", "relay", &["--once"]);
+    let chat = |nick: &str, line: &str| {
+        let lines = script(&format!("relay-{nick}"), &[line]);
+        let mut command = streamchat(&["client", "peer", "--via", "127.0.0.1", "--port-file"]);
+        command
+            .arg(&port_file)
+            .args(["--nick", nick, "--script", lines.to_str().unwrap()])
+            .stdin(Stdio::null())
+            .spawn()
+            .expect("spawn client")
+    };
+    let alice = chat("alice", "hello bob");
+    let bob = chat("bob", "hello alice");
+    let (alice_ok, alice_out) = finish(alice);
+    let (bob_ok, bob_out) = finish(bob);
+    assert!(alice_ok, "{alice_out}");
+    assert!(bob_ok, "{bob_out}");
+    assert!(alice_out.contains("<bob> hello alice"), "{alice_out}");
+    assert!(bob_out.contains("<alice> hello bob"), "{bob_out}");
+    let (ok, out) = finish(relay);
+    assert!(ok, "{out}");
+}
+
+/// Run a one-line chat with a client that connects to `host`, the
+/// server's port coming from its port file. Returns the client's output.
+fn chat_with_host(name: &str, host: &str, server_args: &[&str]) -> String {
+    let (server, port_file) = listen("server", name, &[&["--once"], server_args].concat());
+    let client_script = script(&format!("{}-script", name), &["over here"]);
+    let mut client = streamchat(&["client", host, "--port-file"]);
+    client.arg(&port_file).args([
+        "--nick",
+        "cli",
+        "--script",
+        client_script.to_str().unwrap(),
+        "--script-delay",
+        "0.5",
+    ]);
+    let (ok, client_out) = run(client);
+    let (server_ok, server_out) = finish(server);
+    assert!(ok, "{client_out}");
+    assert!(server_ok, "{server_out}");
+    assert!(client_out.contains("[✓] #1 over here"), "{client_out}");
+    assert!(server_out.contains("<cli> over here"), "{server_out}");
+    client_out
+}
+
+#[test]
+fn clients_connect_over_ipv6() {
+    if TcpListener::bind("[::1]:0").is_err() {
+        eprintln!("skipped: IPv6 loopback is unavailable");
+        return;
+    }
+    let out = chat_with_host("ipv6", "::1", &["--bind", "::1"]);
+    assert!(out.contains("Connecting to [::1]:"), "{out}");
+}
+
+#[test]
+fn clients_connect_by_host_name() {
+    let out = chat_with_host("hostname", "localhost", &[]);
+    assert!(out.contains("Connecting to localhost:"), "{out}");
+}
+
+#[test]
+fn a_client_retries_until_the_server_is_up() {
+    let (mut server, port_file) = listener("server", "late");
+    server.args(["--once", "--nick", "srv"]);
+    let client_script = script("late-script", &["made it"]);
+    let mut client = client(
+        &port_file,
+        &[
+            "--nick",
+            "cli",
+            "--retry",
+            "10",
+            "--retry-delay",
+            "0.2",
+            "--script",
+            client_script.to_str().unwrap(),
+            "--script-delay",
+            "0.5",
+        ],
+    )
+    .stdin(Stdio::null())
+    .spawn()
+    .expect("spawn client");
+    let mut watch = Watch::new(&mut client);
+    // Nothing to connect to yet, not even a port file
+    watch.until("retrying in");
+    let server = start(server, &port_file);
+    let (ok, client_out) = watch.finish(client);
+    let (server_ok, server_out) = finish(server);
+    assert!(ok, "{client_out}");
+    assert!(server_ok, "{server_out}");
+    assert!(client_out.contains("(attempt 1 of 10)"), "{client_out}");
+    assert!(client_out.contains("retrying in"), "{client_out}");
+    assert!(client_out.contains("[✓] #1 made it"), "{client_out}");
+    assert!(server_out.contains("<cli> made it"), "{server_out}");
+}
+
+#[test]
+fn a_client_gives_up_after_its_last_retry() {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let (ok, out) = run(streamchat(&[
+        "client",
+        &address,
+        "--retry",
+        "2",
+        "--retry-delay",
+        "0.1",
+    ]));
+    assert!(!ok);
+    assert!(out.contains("(attempt 1 of 2)"), "{out}");
+    assert!(out.contains("(attempt 2 of 2)"), "{out}");
+    assert!(!out.contains("attempt 3"), "{out}");
+    assert_eq!(out.matches("retrying in").count(), 1, "{out}");
+    assert!(out.contains("error: "), "{out}");
+}
+
+#[test]
+fn a_refused_connection_is_reported_plainly() {
+    // Bound and dropped, so nothing is listening there
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let (ok, out) = run(streamchat(&["client", &address]));
+    assert!(!ok);
+    assert!(out.contains("error: "), "{out}");
+    assert!(!out.contains("Custom {"), "{out}");
+}
+
+#[test]
+fn a_silent_listener_times_out() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    // Accept, then say nothing until the client gives up
+    let silent = thread::spawn(move || listener.accept().map(|(stream, _)| stream));
+    let started = Instant::now();
+    let (ok, out) = run(streamchat(&["client", &address, "--timeout", "1"]));
+    assert!(!ok);
+    assert!(
+        out.contains("timed out after 1s waiting for the peer's public key"),
+        "{out}"
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+    drop(silent.join().unwrap());
+}
+
+#[test]
+fn a_killed_peer_is_noticed() {
+    let (server, port_file) = listen("server", "killed", &["--once"]);
+    let mut client = client(&port_file, &[])
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("spawn client");
+    let mut watch = Watch::new(&mut client);
+    writeln!(client.stdin.as_mut().unwrap(), "hello").unwrap();
+    // Once it is acknowledged, the server has it
+    watch.until("[✓] #1 hello");
+    client.kill().unwrap();
+    let _ = client.wait();
+    let (ok, out) = finish(server);
+    assert!(ok, "{out}");
+    assert!(out.contains("hello"), "{out}");
+    assert!(
+        out.contains("closed the connection without saying goodbye"),
+        "{out}"
+    );
+    assert!(out.contains("(peer closed the connection)"), "{out}");
+}
+
+#[cfg(unix)]
+#[test]
+fn ctrl_c_leaves_with_a_goodbye() {
+    let (server, port_file) = listen("server", "interrupted", &["--once"]);
+    let mut client = client(&port_file, &["--nick", "cli"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("spawn client");
+    let mut watch = Watch::new(&mut client);
+    writeln!(client.stdin.as_mut().unwrap(), "hello").unwrap();
+    watch.until("[✓] #1 hello");
+    signal(&client, "-INT");
+    let (_, client_out) = watch.finish(client);
+    let (_, server_out) = finish(server);
+    assert!(client_out.contains("Leaving, sending BYE"), "{client_out}");
+    assert!(server_out.contains("<cli> left"), "{server_out}");
+    assert!(client_out.contains("(you left)"), "{client_out}");
+    assert!(server_out.contains("(the peer left)"), "{server_out}");
+    assert!(
+        !server_out.contains("without saying goodbye"),
+        "{server_out}"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn a_paused_peer_acknowledges_late() {
+    let (mut server, port_file) = listen("server", "paused", &["--once"]);
+    let mut server_watch = Watch::new(&mut server);
+    let mut client = client(&port_file, &["--ack-timeout", "1"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("spawn client");
+    let mut watch = Watch::new(&mut client);
+    server_watch.until(CONNECTED);
+    watch.until(CONNECTED);
+    signal(&server, "-STOP");
+    wait_until_stopped(&server);
+    writeln!(client.stdin.as_mut().unwrap(), "are you there").unwrap();
+    watch.until("#1 not delivered after 1s");
+    signal(&server, "-CONT");
+    watch.until("[✓] #1 are you there (late)");
+    drop(client.stdin.take());
+    let (_, out) = watch.finish(client);
+    server_watch.finish(server);
+    let warning = out.find("#1 not delivered after 1s").expect(&out);
+    let late = out.find("[✓] #1 are you there (late)").expect(&out);
+    assert!(warning < late, "{out}");
+}
+
+#[cfg(unix)]
+#[test]
+fn a_peer_gone_silent_is_unreachable_within_the_keepalive() {
+    let (mut server, port_file) = listen("server", "silent", &["--once", "--keepalive", "1"]);
+    let mut server_watch = Watch::new(&mut server);
+    let mut client = client(&port_file, &[])
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("spawn client");
+    let mut watch = Watch::new(&mut client);
+    server_watch.until(CONNECTED);
+    watch.until(CONNECTED);
+    // Connected but no longer reading or answering pings
+    signal(&client, "-STOP");
+    wait_until_stopped(&client);
+    let stopped = Instant::now();
+    // One quiet second, then two unanswered pings a second apart
+    while server.try_wait().unwrap().is_none() && stopped.elapsed() < Duration::from_secs(8) {
+        thread::sleep(Duration::from_millis(50));
+    }
+    let waited = stopped.elapsed();
+    let _ = server.kill();
+    signal(&client, "-CONT");
+    let _ = client.kill();
+    let _ = client.wait();
+    let (_, out) = server_watch.finish(server);
+    assert!(out.contains("Peer unreachable"), "{out}");
+    assert!(out.contains("(peer unreachable)"), "{out}");
+    assert!(waited < Duration::from_secs(5), "took {:?}: {out}", waited);
+}
+
+#[test]
+fn both_ends_send_at_once() {
+    const ROUNDS: usize = 20;
+    let (mut server, port_file) = listen("server", "at-once", &["--once"]);
+    let mut server_watch = Watch::new(&mut server);
+    let mut client = client(&port_file, &[])
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("spawn client");
+    let mut client_watch = Watch::new(&mut client);
+    let said = |who: &str| -> Vec<String> {
+        (0..ROUNDS)
+            .map(|n| format!("{} says {:02}", who, n))
+            .collect()
+    };
+    let (server_said, client_said) = (said("server"), said("client"));
+    // Both type everything without waiting to hear from the other
+    let mut server_in = server.stdin.take().unwrap();
+    let mut client_in = client.stdin.take().unwrap();
+    for (to_server, to_client) in server_said.iter().zip(&client_said) {
+        writeln!(server_in, "{}", to_server).unwrap();
+        writeln!(client_in, "{}", to_client).unwrap();
+    }
+    server_watch.until(client_said.last().unwrap());
+    client_watch.until(server_said.last().unwrap());
+    drop((server_in, client_in));
+    let (_, server_out) = server_watch.finish(server);
+    let (_, client_out) = client_watch.finish(client);
+    assert!(in_order(&server_out, &client_said), "{server_out}");
+    assert!(in_order(&client_out, &server_said), "{client_out}");
+}
+
+/// `len` bytes from a xorshift generator, the same for the same `seed`.
+fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed.max(1);
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn a_scripted_file_arrives_whole_before_the_client_leaves() {
+    let dir = temp_path("file-dir");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("payload.bin");
+    let data = random_bytes(0x5eed, 300 * 1024 + 17);
+    fs::write(&file, &data).unwrap();
+    let (mut command, port_file) = listener("server", "file");
+    command.arg("--once").current_dir(&dir);
+    let server = start(command, &port_file);
+    let lines = script("file-script", &[&format!("/send {}", file.display())]);
+    let (ok, out) = run(client(&port_file, &["--script", lines.to_str().unwrap()]));
+    let (server_ok, server_out) = finish(server);
+    assert!(ok, "{out}");
+    assert!(server_ok, "{server_out}");
+    assert!(out.contains("[FILE] Sent payload.bin"), "{out}");
+    assert!(!out.contains("failed"), "{out}");
+    assert!(server_out.contains("checksum OK"), "{server_out}");
+    assert_eq!(fs::read(dir.join("received/payload.bin")).unwrap(), data);
+    fs::remove_dir_all(&dir).unwrap();
+}